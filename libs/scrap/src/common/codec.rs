@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     ops::{Deref, DerefMut},
     sync::{Arc, Mutex},
     time::Instant,
@@ -14,6 +14,7 @@ use crate::vram::*;
 use crate::{
     aom::{self, AomDecoder, AomEncoder, AomEncoderConfig},
     common::GoogleImage,
+    tile::{TileDecoder, TileEncoder, TileEncoderConfig},
     vpxcodec::{self, VpxDecoder, VpxDecoderConfig, VpxEncoder, VpxEncoderConfig, VpxVideoCodecId},
     CodecFormat, EncodeInput, EncodeYuvFormat, ImageRgb, ImageTexture,
 };
@@ -36,6 +37,9 @@ lazy_static::lazy_static! {
     static ref ENCODE_CODEC_FORMAT: Arc<Mutex<CodecFormat>> = Arc::new(Mutex::new(CodecFormat::VP9));
     static ref THREAD_LOG_TIME: Arc<Mutex<Option<Instant>>> = Arc::new(Mutex::new(None));
     static ref USABLE_ENCODING: Arc<Mutex<Option<SupportedEncoding>>> = Arc::new(Mutex::new(None));
+    static ref LOW_BANDWIDTH: Arc<Mutex<bool>> = Arc::new(Mutex::new(false));
+    // The peers which announced the tile decoder, there is no field of it in SupportedDecoding.
+    static ref PEER_TILE_DECODINGS: Arc<Mutex<HashSet<i32>>> = Default::default();
 }
 
 pub const ENCODE_NEED_SWITCH: &'static str = "ENCODE_NEED_SWITCH";
@@ -44,6 +48,7 @@ pub const ENCODE_NEED_SWITCH: &'static str = "ENCODE_NEED_SWITCH";
 pub enum EncoderCfg {
     VPX(VpxEncoderConfig),
     AOM(AomEncoderConfig),
+    TILE(TileEncoderConfig),
     #[cfg(feature = "hwcodec")]
     HWRAM(HwRamEncoderConfig),
     #[cfg(feature = "vram")]
@@ -97,6 +102,7 @@ pub struct Decoder {
     vp8: Option<VpxDecoder>,
     vp9: Option<VpxDecoder>,
     av1: Option<AomDecoder>,
+    tile: Option<TileDecoder>,
     #[cfg(feature = "hwcodec")]
     h264_ram: Option<HwRamDecoder>,
     #[cfg(feature = "hwcodec")]
@@ -120,6 +126,8 @@ pub enum EncodingUpdate {
     Update(i32, SupportedDecoding),
     Remove(i32),
     NewOnlyVP9(i32),
+    // The peer can decode the tile frames carried by the VP9 frames.
+    TileDecoding(i32),
     Check,
 }
 
@@ -133,6 +141,9 @@ impl Encoder {
             EncoderCfg::AOM(_) => Ok(Encoder {
                 codec: Box::new(AomEncoder::new(config, i444)?),
             }),
+            EncoderCfg::TILE(_) => Ok(Encoder {
+                codec: Box::new(TileEncoder::new(config, i444)?),
+            }),
 
             #[cfg(feature = "hwcodec")]
             EncoderCfg::HWRAM(_) => match HwRamEncoder::new(config, i444) {
@@ -164,12 +175,19 @@ impl Encoder {
     pub fn update(update: EncodingUpdate) {
         log::info!("update:{:?}", update);
         let mut decodings = PEER_DECODINGS.lock().unwrap();
+        let mut tile_decodings = PEER_TILE_DECODINGS.lock().unwrap();
         match update {
             EncodingUpdate::Update(id, decoding) => {
                 decodings.insert(id, decoding);
+                // The new peer needs a key frame of the tiles.
+                crate::tile::request_key_frame();
             }
             EncodingUpdate::Remove(id) => {
                 decodings.remove(&id);
+                tile_decodings.remove(&id);
+            }
+            EncodingUpdate::TileDecoding(id) => {
+                tile_decodings.insert(id);
             }
             EncodingUpdate::NewOnlyVP9(id) => {
                 decodings.insert(
@@ -187,6 +205,11 @@ impl Encoder {
         let av1_useable = decodings.len() > 0
             && decodings.iter().all(|(_, s)| s.ability_av1 > 0)
             && !disable_av1();
+        let tile_useable = decodings.len() > 0
+            && decodings
+                .iter()
+                .all(|(id, s)| s.ability_vp9 > 0 && tile_decodings.contains(id));
+        drop(tile_decodings);
         let _all_support_h264_decoding =
             decodings.len() > 0 && decodings.iter().all(|(_, s)| s.ability_h264 > 0);
         let _all_support_h265_decoding =
@@ -303,9 +326,13 @@ impl Encoder {
             }
             PreferCodec::Auto => auto_codec,
         };
+        // The link is too slow for the video codecs, the preference does not matter.
+        if tile_useable && *LOW_BANDWIDTH.lock().unwrap() {
+            *format = CodecFormat::Tile;
+        }
         if decodings.len() > 0 {
             log::info!(
-                "usable: vp8={vp8_useable}, av1={av1_useable}, h264={h264_useable}, h265={h265_useable}",
+                "usable: vp8={vp8_useable}, av1={av1_useable}, h264={h264_useable}, h265={h265_useable}, tile={tile_useable}",
             );
             log::info!(
                 "connection count: {}, used preference: {:?}, encoder: {:?}",
@@ -316,6 +343,15 @@ impl Encoder {
         }
     }
 
+    /// Switch to the tile codec if all peers support it, or back from it.
+    pub fn set_low_bandwidth(v: bool) {
+        let old = std::mem::replace(&mut *LOW_BANDWIDTH.lock().unwrap(), v);
+        if old != v {
+            log::info!("low bandwidth: {v}");
+            Self::update(EncodingUpdate::Check);
+        }
+    }
+
     #[inline]
     pub fn negotiated_codec() -> CodecFormat {
         ENCODE_CODEC_FORMAT.lock().unwrap().clone()
@@ -358,6 +394,7 @@ impl Encoder {
                 VpxVideoCodecId::VP9 => CodecFormat::VP9,
             },
             EncoderCfg::AOM(_) => CodecFormat::AV1,
+            EncoderCfg::TILE(_) => CodecFormat::Tile,
             #[cfg(feature = "hwcodec")]
             EncoderCfg::HWRAM(hw) => {
                 let name = hw.name.to_lowercase();
@@ -404,6 +441,7 @@ impl Encoder {
                 VpxVideoCodecId::VP9 => decodings.iter().all(|d| d.1.i444.vp9),
             },
             EncoderCfg::AOM(_) => decodings.iter().all(|d| d.1.i444.av1),
+            EncoderCfg::TILE(_) => false,
             #[cfg(feature = "hwcodec")]
             EncoderCfg::HWRAM(_) => false,
             #[cfg(feature = "vram")]
@@ -593,6 +631,7 @@ impl Decoder {
             vp8,
             vp9,
             av1,
+            tile: None,
             #[cfg(feature = "hwcodec")]
             h264_ram,
             #[cfg(feature = "hwcodec")]
@@ -637,6 +676,11 @@ impl Decoder {
                     bail!("vp8 decoder not available");
                 }
             }
+            // The tile frames ride on the VP9 frames, see `tile`.
+            video_frame::Union::Vp9s(vp9s) if crate::tile::is_tile_frames(vp9s) => {
+                let tile = self.tile.get_or_insert_with(TileDecoder::new);
+                Decoder::handle_tiles_video_frame(tile, vp9s, rgb, chroma)
+            }
             video_frame::Union::Vp9s(vp9s) => {
                 if let Some(vp9) = &mut self.vp9 {
                     Decoder::handle_vpxs_video_frame(vp9, vp9s, rgb, chroma)
@@ -755,6 +799,23 @@ impl Decoder {
         }
     }
 
+    // rgb [in/out] fmt and stride must be set in ImageRgb
+    fn handle_tiles_video_frame(
+        decoder: &mut TileDecoder,
+        tiles: &EncodedVideoFrames,
+        rgb: &mut ImageRgb,
+        chroma: &mut Option<Chroma>,
+    ) -> ResultType<bool> {
+        for tile in tiles.frames.iter() {
+            decoder.decode(&tile.data)?;
+        }
+        // A frame without changed tiles is valid too, not a failure of the decoder.
+        decoder.to(rgb)?;
+        // RGB without chroma subsampling.
+        *chroma = Some(Chroma::I444);
+        Ok(true)
+    }
+
     // rgb [in/out] fmt and stride must be set in ImageRgb
     #[cfg(feature = "hwcodec")]
     fn handle_hwram_video_frame(
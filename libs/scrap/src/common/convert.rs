@@ -15,7 +15,7 @@ generate_call_macro!(call_yuv, false);
 
 #[cfg(not(target_os = "ios"))]
 pub fn convert_to_yuv(
-    captured: &PixelBuffer,
+    captured: &impl TraitPixelBuffer,
     dst_fmt: EncodeYuvFormat,
     dst: &mut Vec<u8>,
     mid_data: &mut Vec<u8>,
@@ -44,13 +44,11 @@ pub fn convert_to_yuv(
                 src_width * src_pixfmt.bytes_per_pixel()
             );
         }
-        if src.len() < src_stride[0] * src_height {
-            bail!(
-                "wrong src len, {} < {} * {}",
-                src.len(),
-                src_stride[0],
-                src_height
-            );
+        // The last row may be shorter than the stride, e.g. a cropped buffer.
+        let src_len =
+            src_stride[0] * (src_height.max(1) - 1) + src_width * src_pixfmt.bytes_per_pixel();
+        if src.len() < src_len {
+            bail!("wrong src len, {} < {}", src.len(), src_len);
         }
     }
     let align = |x: usize| (x + 63) / 64 * 64;
@@ -191,6 +189,39 @@ pub fn convert_to_yuv(
                 src_height as _,
             ));
         }
+        (crate::Pixfmt::BGRA, crate::Pixfmt::BGRA)
+        | (crate::Pixfmt::RGBA, crate::Pixfmt::BGRA)
+        | (crate::Pixfmt::RGB565LE, crate::Pixfmt::BGRA) => {
+            let dst_stride = dst_fmt.stride[0];
+            dst.resize(dst_fmt.h * dst_stride, 0);
+            match src_pixfmt {
+                crate::Pixfmt::BGRA => call_yuv!(ARGBCopy(
+                    src.as_ptr(),
+                    src_stride[0] as _,
+                    dst.as_mut_ptr(),
+                    dst_stride as _,
+                    src_width as _,
+                    src_height as _,
+                )),
+                crate::Pixfmt::RGBA => call_yuv!(ABGRToARGB(
+                    src.as_ptr(),
+                    src_stride[0] as _,
+                    dst.as_mut_ptr(),
+                    dst_stride as _,
+                    src_width as _,
+                    src_height as _,
+                )),
+                crate::Pixfmt::RGB565LE => call_yuv!(RGB565ToARGB(
+                    src.as_ptr(),
+                    src_stride[0] as _,
+                    dst.as_mut_ptr(),
+                    dst_stride as _,
+                    src_width as _,
+                    src_height as _,
+                )),
+                _ => bail!(unsupported),
+            }
+        }
         _ => {
             bail!(unsupported);
         }
@@ -52,6 +52,11 @@ pub mod aom;
 #[cfg(not(any(target_os = "ios")))]
 pub mod camera;
 pub mod record;
+pub mod record_cast;
+pub mod record_crypt;
+pub mod record_index;
+pub mod region;
+pub mod tile;
 mod vpx;
 
 #[repr(usize)]
@@ -201,6 +206,24 @@ impl Frame<'_> {
             Frame::Texture(texture) => Ok(EncodeInput::Texture(*texture)),
         }
     }
+
+    /// Like `to`, but only the part `rect` of the frame is converted.
+    pub fn to_region<'a>(
+        &'a self,
+        yuvfmt: EncodeYuvFormat,
+        rect: &region::CaptureRect,
+        yuv: &'a mut Vec<u8>,
+        mid_data: &mut Vec<u8>,
+    ) -> ResultType<EncodeInput<'a>> {
+        match self {
+            Frame::PixelBuffer(pixelbuffer) => {
+                let cropped = region::crop(pixelbuffer, rect)?;
+                convert_to_yuv(&cropped, yuvfmt, yuv, mid_data)?;
+                Ok(EncodeInput::YUV(yuv))
+            }
+            Frame::Texture(_) => bail!("can not crop a texture frame"),
+        }
+    }
 }
 
 pub enum EncodeInput<'a> {
@@ -299,6 +322,8 @@ pub enum CodecFormat {
     AV1,
     H264,
     H265,
+    // Only negotiated by the encoder side, the tile frames are sent as VP9 frames.
+    Tile,
     Unknown,
 }
 
@@ -348,6 +373,7 @@ impl ToString for CodecFormat {
             CodecFormat::AV1 => "AV1".into(),
             CodecFormat::H264 => "H264".into(),
             CodecFormat::H265 => "H265".into(),
+            CodecFormat::Tile => "Tile".into(),
             CodecFormat::Unknown => "Unknown".into(),
         }
     }
@@ -1,25 +1,38 @@
-use crate::CodecFormat;
+use crate::{
+    record_crypt,
+    record_index::{IndexWriter, RecordEvent},
+    CodecFormat,
+};
 #[cfg(feature = "hwcodec")]
 use hbb_common::anyhow::anyhow;
 use hbb_common::{
     bail, chrono, log,
     message_proto::{message, video_frame, EncodedVideoFrame, Message},
+    sodiumoxide::crypto::box_,
     ResultType,
 };
 #[cfg(feature = "hwcodec")]
 use hwcodec::mux::{MuxContext, Muxer};
 use std::{
+    collections::HashMap,
     fs::{File, OpenOptions},
     io,
     ops::{Deref, DerefMut},
     path::PathBuf,
-    sync::mpsc::Sender,
-    time::Instant,
+    sync::{mpsc::Sender, Arc, Mutex, Weak},
+    time::{Duration, Instant},
 };
-use webm::mux::{self, Segment, Track, VideoTrack, Writer};
+use webm::mux::{self, AudioTrack, Segment, Track, VideoTrack, Writer};
 
 const MIN_SECS: u64 = 1;
 
+fn is_webm(format: CodecFormat) -> bool {
+    matches!(
+        format,
+        CodecFormat::VP8 | CodecFormat::VP9 | CodecFormat::AV1
+    )
+}
+
 #[derive(Debug, Clone)]
 pub struct RecorderContext {
     pub server: bool,
@@ -27,15 +40,38 @@ pub struct RecorderContext {
     pub dir: String,
     pub display_idx: usize,
     pub camera: bool,
+    /// Record all the displays of the session into one file with a video track per display,
+    /// VP8, VP9 and AV1 only.
+    pub multi_display: bool,
+    /// Encrypt the finished files and their index for this key, see `record_crypt`.
+    pub public_key: Option<box_::PublicKey>,
     pub tx: Option<Sender<RecordState>>,
 }
 
+impl RecorderContext {
+    pub(crate) fn send_state(&self, state: RecordState) {
+        // An encrypted file is only announced once it is encrypted.
+        if self.public_key.is_some() && !matches!(state, RecordState::Encrypted(_)) {
+            return;
+        }
+        self.tx.as_ref().map(|tx| tx.send(state));
+    }
+}
+
+/// The format of the Opus audio frames to record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordAudioFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
 #[derive(Debug, Clone)]
 pub struct RecorderContext2 {
     pub filename: String,
     pub width: usize,
     pub height: usize,
     pub format: CodecFormat,
+    pub audio: Option<RecordAudioFormat>,
 }
 
 impl RecorderContext2 {
@@ -47,16 +83,17 @@ impl RecorderContext2 {
             + "_"
             + &ctx.id.clone()
             + &chrono::Local::now().format("_%Y%m%d%H%M%S%3f_").to_string()
-            + &format!(
-                "{}{}_",
-                if ctx.camera { "camera" } else { "display" },
-                ctx.display_idx
-            )
+            + &if ctx.multi_display && is_webm(self.format) {
+                format!("{}s_", if ctx.camera { "camera" } else { "display" })
+            } else {
+                format!(
+                    "{}{}_",
+                    if ctx.camera { "camera" } else { "display" },
+                    ctx.display_idx
+                )
+            }
             + &self.format.to_string().to_lowercase()
-            + if self.format == CodecFormat::VP9
-                || self.format == CodecFormat::VP8
-                || self.format == CodecFormat::AV1
-            {
+            + if is_webm(self.format) {
                 ".webm"
             } else {
                 ".mp4"
@@ -77,6 +114,15 @@ pub trait RecorderApi {
     where
         Self: Sized;
     fn write_video(&mut self, frame: &EncodedVideoFrame) -> bool;
+    /// Write an Opus frame, `pts` is in ms on the clock of the video frames.
+    fn write_audio(&mut self, _data: &[u8], _pts: i64) -> bool {
+        false
+    }
+    /// Write an event to the index of the recording, `pts` is in ms on the clock of the video
+    /// frames.
+    fn write_event(&mut self, _event: &RecordEvent, _pts: i64) -> bool {
+        false
+    }
 }
 
 #[derive(Debug)]
@@ -85,6 +131,8 @@ pub enum RecordState {
     NewFrame,
     WriteTail,
     RemoveFile,
+    /// The file is finished and encrypted to this path, instead of all the other states.
+    Encrypted(String),
 }
 
 pub struct Recorder {
@@ -93,6 +141,12 @@ pub struct Recorder {
     ctx2: Option<RecorderContext2>,
     pts: Option<i64>,
     check_failed: bool,
+    audio_format: Option<RecordAudioFormat>,
+    // The audio format changed after the current file was started.
+    audio_format_changed: bool,
+    // The pts of the first video frame of the current file and when it was written, audio frames
+    // and events are stamped by the time elapsed since.
+    time_base: Option<(i64, Instant)>,
 }
 
 impl Deref for Recorder {
@@ -117,9 +171,49 @@ impl Recorder {
             ctx2: None,
             pts: None,
             check_failed: false,
+            audio_format: None,
+            audio_format_changed: false,
+            time_base: None,
         })
     }
 
+    pub fn set_audio_format(&mut self, format: RecordAudioFormat) {
+        if self.audio_format != Some(format) {
+            log::info!("record audio format: {:?}", format);
+            self.audio_format = Some(format);
+            // Tracks can not be added to a started file, start a new one at the next key frame.
+            self.audio_format_changed = self.inner.is_some();
+        }
+    }
+
+    pub fn write_audio(&mut self, data: &[u8]) {
+        if self.audio_format_changed {
+            return;
+        }
+        let Some(pts) = self.current_pts() else {
+            return;
+        };
+        self.as_mut().map(|x| x.write_audio(data, pts));
+    }
+
+    pub fn write_event(&mut self, event: &RecordEvent) {
+        let Some(pts) = self.current_pts() else {
+            return;
+        };
+        if let Some(x) = self.as_mut() {
+            x.write_event(event, pts);
+        }
+    }
+
+    fn current_pts(&self) -> Option<i64> {
+        self.time_base
+            .map(|(pts, start)| pts + start.elapsed().as_millis() as i64)
+    }
+
+    fn is_multi_track(&self, format: CodecFormat) -> bool {
+        self.ctx.multi_display && is_webm(format)
+    }
+
     fn check(&mut self, w: usize, h: usize, format: CodecFormat) -> ResultType<()> {
         match self.ctx2 {
             Some(ref ctx2) => {
@@ -128,6 +222,7 @@ impl Recorder {
                         width: w,
                         height: h,
                         format,
+                        audio: self.audio_format,
                         filename: Default::default(),
                     };
                     ctx2.set_filename(&self.ctx)?;
@@ -140,6 +235,7 @@ impl Recorder {
                     width: w,
                     height: h,
                     format,
+                    audio: self.audio_format,
                     filename: Default::default(),
                 };
                 ctx2.set_filename(&self.ctx)?;
@@ -151,6 +247,17 @@ impl Recorder {
             bail!("ctx2 is None");
         };
         if self.inner.is_none() {
+            if self.is_multi_track(format) {
+                self.inner = Some(Box::new(TrackRecorder::new(
+                    self.ctx.clone(),
+                    (*ctx2).clone(),
+                )?));
+                self.pts = None;
+                self.time_base = None;
+                self.audio_format_changed = false;
+                // The state of the shared file is sent by itself.
+                return Ok(());
+            }
             self.inner = match format {
                 CodecFormat::VP8 | CodecFormat::VP9 | CodecFormat::AV1 => Some(Box::new(
                     WebmRecorder::new(self.ctx.clone(), (*ctx2).clone())?,
@@ -165,6 +272,8 @@ impl Recorder {
             };
             // pts is None when new inner is created
             self.pts = None;
+            self.time_base = None;
+            self.audio_format_changed = false;
             self.send_state(RecordState::NewFile(ctx2.filename.clone()));
         }
         Ok(())
@@ -188,7 +297,9 @@ impl Recorder {
             bail!("check failed");
         }
         let format = CodecFormat::from(frame);
-        if format == CodecFormat::Unknown {
+        // The tiles are not a video stream of the containers.
+        let tiles = matches!(frame, video_frame::Union::Vp9s(f) if crate::tile::is_tile_frames(f));
+        if format == CodecFormat::Unknown || tiles {
             bail!("unsupported frame type");
         }
         let res = self.check(w, h, format);
@@ -232,7 +343,9 @@ impl Recorder {
             }
             _ => bail!("unsupported frame type"),
         }
-        self.send_state(RecordState::NewFrame);
+        if !self.is_multi_track(format) {
+            self.send_state(RecordState::NewFrame);
+        }
         Ok(())
     }
 
@@ -250,8 +363,13 @@ impl Recorder {
         }
         let old_pts = self.pts;
         self.pts = Some(pts);
-        if old_pts.clone().unwrap_or_default() > pts {
-            log::info!("pts {:?} -> {}, change record filename", old_pts, pts);
+        let audio_changed = key && self.audio_format_changed;
+        if old_pts.clone().unwrap_or_default() > pts || audio_changed {
+            if audio_changed {
+                log::info!("audio format changed, change record filename");
+            } else {
+                log::info!("pts {:?} -> {}, change record filename", old_pts, pts);
+            }
             self.inner = None;
             self.ctx2 = None;
             let res = self.check(w, h, format);
@@ -262,17 +380,26 @@ impl Recorder {
             }
             self.pts = Some(pts);
         }
+        if self.time_base.is_none() {
+            self.time_base = Some((pts, Instant::now()));
+        }
         Ok(())
     }
 
     fn send_state(&self, state: RecordState) {
-        self.ctx.tx.as_ref().map(|tx| tx.send(state));
+        self.ctx.send_state(state);
     }
 }
 
 struct WebmRecorder {
     vt: VideoTrack,
+    at: Option<AudioTrack>,
+    // Timestamps of a track must increase.
+    next_audio_ns: u64,
     webm: Option<Segment<Writer<File>>>,
+    index: Option<IndexWriter>,
+    // pts of the first written frame
+    first_pts: Option<i64>,
     ctx: RecorderContext,
     ctx2: RecorderContext2,
     key: bool,
@@ -282,42 +409,19 @@ struct WebmRecorder {
 
 impl RecorderApi for WebmRecorder {
     fn new(ctx: RecorderContext, ctx2: RecorderContext2) -> ResultType<Self> {
-        let out = match {
-            OpenOptions::new()
-                .write(true)
-                .create_new(true)
-                .open(&ctx2.filename)
-        } {
-            Ok(file) => file,
-            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => File::create(&ctx2.filename)?,
-            Err(e) => return Err(e.into()),
-        };
-        let mut webm = match mux::Segment::new(mux::Writer::new(out)) {
-            Some(v) => v,
-            None => bail!("Failed to create webm mux"),
+        let mut webm = new_segment(&ctx2.filename)?;
+        let vt = add_video_track(&mut webm, ctx2.width, ctx2.height, ctx2.format)?;
+        let at = match ctx2.audio {
+            Some(audio) => Some(add_audio_track(&mut webm, &audio)?),
+            None => None,
         };
-        let vt = webm.add_video_track(
-            ctx2.width as _,
-            ctx2.height as _,
-            None,
-            if ctx2.format == CodecFormat::VP9 {
-                mux::VideoCodecId::VP9
-            } else if ctx2.format == CodecFormat::VP8 {
-                mux::VideoCodecId::VP8
-            } else {
-                mux::VideoCodecId::AV1
-            },
-        );
-        if ctx2.format == CodecFormat::AV1 {
-            // [129, 8, 12, 0] in 3.6.0, but zero works
-            let codec_private = vec![0, 0, 0, 0];
-            if !webm.set_codec_private(vt.track_number(), &codec_private) {
-                bail!("Failed to set codec private");
-            }
-        }
         Ok(WebmRecorder {
             vt,
+            at,
+            next_audio_ns: 0,
             webm: Some(webm),
+            index: IndexWriter::new(&ctx2.filename),
+            first_pts: None,
             ctx,
             ctx2,
             key: false,
@@ -336,29 +440,602 @@ impl RecorderApi for WebmRecorder {
                 .add_frame(&frame.data, frame.pts as u64 * 1_000_000, frame.key);
             if ok {
                 self.written = true;
+                self.first_pts.get_or_insert(frame.pts);
             }
             ok
         } else {
             false
         }
     }
+
+    fn write_audio(&mut self, data: &[u8], pts: i64) -> bool {
+        // Start with the video key frame.
+        if !self.key {
+            return false;
+        }
+        let Some(at) = self.at.as_mut() else {
+            return false;
+        };
+        let ns = (pts.max(0) as u64 * 1_000_000).max(self.next_audio_ns);
+        self.next_audio_ns = ns + 1;
+        at.add_frame(data, ns, true)
+    }
+
+    fn write_event(&mut self, event: &RecordEvent, pts: i64) -> bool {
+        match (self.index.as_mut(), self.first_pts) {
+            (Some(index), Some(first_pts)) => index.write(event, pts - first_pts),
+            _ => false,
+        }
+    }
+}
+
+// Remove a file which is not worth keeping, or encrypt it in the background if required, then
+// send its final state.
+pub(crate) fn finish_file(
+    ctx: &RecorderContext,
+    filename: &str,
+    index: Option<IndexWriter>,
+    keep: bool,
+) {
+    if !keep {
+        std::fs::remove_file(filename).ok();
+        if let Some(index) = index {
+            index.remove();
+        }
+        ctx.send_state(RecordState::RemoveFile);
+        return;
+    }
+    let Some(pk) = ctx.public_key else {
+        ctx.send_state(RecordState::WriteTail);
+        return;
+    };
+    let index = index.map(|index| index.close());
+    let filename = filename.to_owned();
+    let tx = ctx.tx.clone();
+    std::thread::spawn(move || {
+        if let Some(index) = index {
+            if let Err(e) = record_crypt::encrypt_file(&index, &pk) {
+                log::error!("Failed to encrypt {}: {}", index, e);
+            }
+        }
+        match record_crypt::encrypt_file(&filename, &pk) {
+            Ok(path) => {
+                if let Some(tx) = tx {
+                    tx.send(RecordState::Encrypted(path)).ok();
+                }
+            }
+            Err(e) => log::error!("Failed to encrypt {}: {}", filename, e),
+        }
+    });
+}
+
+fn new_segment(filename: &str) -> ResultType<Segment<Writer<File>>> {
+    let out = match {
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(filename)
+    } {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => File::create(filename)?,
+        Err(e) => return Err(e.into()),
+    };
+    match mux::Segment::new(mux::Writer::new(out)) {
+        Some(v) => Ok(v),
+        None => bail!("Failed to create webm mux"),
+    }
+}
+
+fn add_video_track(
+    webm: &mut Segment<Writer<File>>,
+    width: usize,
+    height: usize,
+    format: CodecFormat,
+) -> ResultType<VideoTrack> {
+    let vt = webm.add_video_track(
+        width as _,
+        height as _,
+        None,
+        if format == CodecFormat::VP9 {
+            mux::VideoCodecId::VP9
+        } else if format == CodecFormat::VP8 {
+            mux::VideoCodecId::VP8
+        } else {
+            mux::VideoCodecId::AV1
+        },
+    );
+    if format == CodecFormat::AV1 {
+        // [129, 8, 12, 0] in 3.6.0, but zero works
+        let codec_private = vec![0, 0, 0, 0];
+        if !webm.set_codec_private(vt.track_number(), &codec_private) {
+            bail!("Failed to set codec private");
+        }
+    }
+    Ok(vt)
+}
+
+fn add_audio_track(
+    webm: &mut Segment<Writer<File>>,
+    audio: &RecordAudioFormat,
+) -> ResultType<AudioTrack> {
+    let at = webm.add_audio_track(
+        audio.sample_rate as _,
+        audio.channels as _,
+        None,
+        mux::AudioCodecId::Opus,
+    );
+    if !webm.set_codec_private(at.track_number(), &opus_head(audio)) {
+        bail!("Failed to set opus codec private");
+    }
+    Ok(at)
+}
+
+// The identification header of Opus in Matroska, https://wiki.xiph.org/OggOpus#ID_Header
+fn opus_head(audio: &RecordAudioFormat) -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    head.push(1); // version
+    head.push(audio.channels as u8);
+    head.extend_from_slice(&0u16.to_le_bytes()); // pre-skip
+    head.extend_from_slice(&audio.sample_rate.to_le_bytes());
+    head.extend_from_slice(&0i16.to_le_bytes()); // output gain
+    head.push(0); // channel mapping family, mono or stereo
+    head
 }
 
 impl Drop for WebmRecorder {
     fn drop(&mut self) {
         let _ = std::mem::replace(&mut self.webm, None).map_or(false, |webm| webm.finalize(None));
-        let mut state = RecordState::WriteTail;
-        if !self.written || self.start.elapsed().as_secs() < MIN_SECS {
-            std::fs::remove_file(&self.ctx2.filename).ok();
-            state = RecordState::RemoveFile;
+        finish_file(
+            &self.ctx,
+            &self.ctx2.filename,
+            self.index.take(),
+            self.written && self.start.elapsed().as_secs() >= MIN_SECS,
+        );
+    }
+}
+
+// Recording of all the displays of a session into one WebM file, with a video track per display.
+//
+// Tracks can only be added before the first frame is written, so the frames are buffered from
+// the first key frame of every display, and the file is started once all the registered displays
+// have one, or after `MULTI_TRACK_WAIT`. A display which joins later, or changes its resolution
+// or codec, finalizes the file and starts a new one from the last key frame of every display.
+//
+// The displays are synchronized by the time their first key frame arrives, their pts are mapped
+// to the time since, and the frames are written in this order, `MULTI_TRACK_REORDER_MS` late.
+// Compositing the displays into one canvas is not done, it would require decoding and encoding
+// all the frames again.
+
+const MULTI_TRACK_WAIT: Duration = Duration::from_secs(2);
+const MULTI_TRACK_MIN_WAIT: Duration = Duration::from_millis(500);
+const MULTI_TRACK_REORDER_MS: i64 = 300;
+// Frames since the last key frame of a display kept for a new file.
+const MULTI_TRACK_GOP_LIMIT: usize = 16 * 1024 * 1024;
+
+lazy_static::lazy_static! {
+    static ref MULTI_TRACKS: Mutex<HashMap<String, Weak<Mutex<MultiTrack>>>> = Default::default();
+}
+
+#[derive(Clone)]
+struct TrackFrame {
+    // `None` for audio
+    display: Option<usize>,
+    // ms since `MultiTrack::start`
+    ms: i64,
+    data: Vec<u8>,
+    key: bool,
+}
+
+struct TrackSpec {
+    width: usize,
+    height: usize,
+    format: CodecFormat,
+    // The first pts and its ms since `MultiTrack::start`.
+    base: Option<(i64, i64)>,
+    gop: Vec<TrackFrame>,
+    gop_size: usize,
+}
+
+struct MultiTrackFile {
+    webm: Option<Segment<Writer<File>>>,
+    tracks: HashMap<usize, (VideoTrack, usize, usize, CodecFormat)>,
+    audio: Option<AudioTrack>,
+    audio_format: Option<RecordAudioFormat>,
+    index: Option<IndexWriter>,
+    filename: String,
+    // ms of the first frame
+    origin: i64,
+    // Timestamps of a track must increase.
+    next_ns: HashMap<Option<usize>, u64>,
+    written: bool,
+    start: Instant,
+}
+
+struct MultiTrack {
+    ctx: RecorderContext,
+    audio: Option<RecordAudioFormat>,
+    specs: HashMap<usize, TrackSpec>,
+    file: Option<MultiTrackFile>,
+    start: Instant,
+    wait_start: Option<Instant>,
+    pending: Vec<TrackFrame>,
+    pending_events: Vec<(i64, RecordEvent)>,
+}
+
+// Only accessed with the lock held.
+unsafe impl Send for MultiTrack {}
+
+impl MultiTrack {
+    fn new(ctx: RecorderContext) -> Self {
+        Self {
+            ctx,
+            audio: None,
+            specs: Default::default(),
+            file: None,
+            start: Instant::now(),
+            wait_start: None,
+            pending: vec![],
+            pending_events: vec![],
+        }
+    }
+
+    fn key(ctx: &RecorderContext) -> String {
+        format!("{}_{}_{}_{}", ctx.server, ctx.id, ctx.camera, ctx.dir)
+    }
+
+    fn now_ms(&self) -> i64 {
+        self.start.elapsed().as_millis() as i64
+    }
+
+    fn register(
+        &mut self,
+        display: usize,
+        width: usize,
+        height: usize,
+        format: CodecFormat,
+        audio: Option<RecordAudioFormat>,
+    ) {
+        self.specs.insert(
+            display,
+            TrackSpec {
+                width,
+                height,
+                format,
+                base: None,
+                gop: vec![],
+                gop_size: 0,
+            },
+        );
+        if self.file.is_none() {
+            // Not started yet, the buffered frames may not match the new track.
+            self.pending.retain(|f| f.display != Some(display));
+        }
+        let changed = self.file.as_ref().map_or(false, |file| {
+            file.tracks
+                .get(&display)
+                .map_or(true, |t| (t.1, t.2, t.3) != (width, height, format))
+                || file.audio_format != audio
+        });
+        self.audio = audio;
+        if changed {
+            log::info!(
+                "display {} or audio changed, start a new record file",
+                display
+            );
+            self.restart();
+        }
+    }
+
+    fn unregister(&mut self, display: usize) {
+        self.specs.remove(&display);
+    }
+
+    fn write(&mut self, display: usize, data: &[u8], pts: i64, key: bool) -> bool {
+        let now_ms = self.now_ms();
+        let Some(spec) = self.specs.get_mut(&display) else {
+            return false;
+        };
+        let (base_pts, base_ms) = match spec.base {
+            Some(base) => base,
+            None if key => *spec.base.insert((pts, now_ms)),
+            None => return false,
+        };
+        let frame = TrackFrame {
+            display: Some(display),
+            ms: pts - base_pts + base_ms,
+            data: data.to_vec(),
+            key,
+        };
+        if key {
+            spec.gop.clear();
+            spec.gop_size = 0;
+        }
+        if spec.gop_size + data.len() <= MULTI_TRACK_GOP_LIMIT && (key || !spec.gop.is_empty()) {
+            spec.gop_size += data.len();
+            spec.gop.push(frame.clone());
+        } else {
+            spec.gop.clear();
+            spec.gop_size = 0;
+        }
+        let missing = self
+            .file
+            .as_ref()
+            .map_or(false, |file| !file.tracks.contains_key(&display));
+        if missing {
+            if !key {
+                return false;
+            }
+            log::info!("display {} joined, start a new record file", display);
+            // The frame is in the gop.
+            self.restart();
+        } else {
+            self.pending.push(frame);
+        }
+        if self.file.is_none() {
+            self.try_start();
+        }
+        self.flush(false);
+        true
+    }
+
+    // ms of `pts` of the first display, the audio and the events are the same for all the
+    // displays.
+    fn shared_ms(&self, display: usize, pts: i64) -> Option<i64> {
+        if self.specs.keys().min() != Some(&display) {
+            return None;
+        }
+        let (base_pts, base_ms) = self.specs.get(&display).and_then(|s| s.base)?;
+        Some(pts - base_pts + base_ms)
+    }
+
+    fn write_audio(&mut self, display: usize, data: &[u8], pts: i64) -> bool {
+        if self.audio.is_none() {
+            return false;
+        }
+        let Some(ms) = self.shared_ms(display, pts) else {
+            return false;
+        };
+        self.pending.push(TrackFrame {
+            display: None,
+            ms,
+            data: data.to_vec(),
+            key: true,
+        });
+        true
+    }
+
+    fn write_event(&mut self, display: usize, event: &RecordEvent, pts: i64) -> bool {
+        let Some(ms) = self.shared_ms(display, pts) else {
+            return false;
+        };
+        self.pending_events.push((ms, event.clone()));
+        true
+    }
+
+    fn try_start(&mut self) {
+        let waited = self.wait_start.get_or_insert_with(Instant::now).elapsed();
+        let ready = self.specs.values().all(|s| s.base.is_some());
+        if !(ready && waited >= MULTI_TRACK_MIN_WAIT || waited >= MULTI_TRACK_WAIT) {
+            return;
+        }
+        self.wait_start = None;
+        match self.create_file() {
+            Ok(file) => {
+                self.send_state(RecordState::NewFile(file.filename.clone()));
+                self.file = Some(file);
+            }
+            Err(e) => {
+                log::error!("Failed to create multi-track record file: {}", e);
+                self.pending.clear();
+                self.pending_events.clear();
+            }
+        }
+    }
+
+    fn create_file(&mut self) -> ResultType<MultiTrackFile> {
+        let mut displays: Vec<usize> = self
+            .specs
+            .iter()
+            .filter(|(_, s)| s.base.is_some())
+            .map(|(d, _)| *d)
+            .collect();
+        displays.sort();
+        let Some(first) = displays.first().and_then(|d| self.specs.get(d)) else {
+            bail!("no key frame");
+        };
+        let mut ctx2 = RecorderContext2 {
+            filename: Default::default(),
+            width: first.width,
+            height: first.height,
+            format: first.format,
+            audio: self.audio,
+        };
+        ctx2.set_filename(&self.ctx)?;
+        let mut webm = new_segment(&ctx2.filename)?;
+        let mut tracks = HashMap::new();
+        for d in displays.iter() {
+            let s = &self.specs[d];
+            let vt = add_video_track(&mut webm, s.width, s.height, s.format)?;
+            tracks.insert(*d, (vt, s.width, s.height, s.format));
+        }
+        let audio = match self.audio {
+            Some(audio) => Some(add_audio_track(&mut webm, &audio)?),
+            None => None,
+        };
+        // Frames of the displays which are left out wait for the next file.
+        self.pending
+            .retain(|f| f.display.map_or(true, |d| tracks.contains_key(&d)));
+        let origin = self
+            .pending
+            .iter()
+            .filter(|f| f.display.is_some())
+            .map(|f| f.ms)
+            .min()
+            .unwrap_or_else(|| self.now_ms());
+        log::info!(
+            "multi-track record file {}, displays {:?}",
+            ctx2.filename,
+            displays
+        );
+        Ok(MultiTrackFile {
+            webm: Some(webm),
+            tracks,
+            audio,
+            audio_format: self.audio,
+            index: IndexWriter::new(&ctx2.filename),
+            filename: ctx2.filename,
+            origin,
+            next_ns: Default::default(),
+            written: false,
+            start: Instant::now(),
+        })
+    }
+
+    fn flush(&mut self, all: bool) {
+        let Some(file) = self.file.as_mut() else {
+            return;
+        };
+        let deadline = if all {
+            i64::MAX
+        } else {
+            self.start.elapsed().as_millis() as i64 - MULTI_TRACK_REORDER_MS
+        };
+        self.pending.sort_by_key(|f| f.ms);
+        let n = self.pending.iter().take_while(|f| f.ms <= deadline).count();
+        let mut written = false;
+        for f in self.pending.drain(..n) {
+            if f.ms < file.origin {
+                continue;
+            }
+            let next_ns = file.next_ns.entry(f.display).or_default();
+            let ns = ((f.ms - file.origin) as u64 * 1_000_000).max(*next_ns);
+            *next_ns = ns + 1;
+            let ok = match f.display {
+                Some(d) => file
+                    .tracks
+                    .get_mut(&d)
+                    .map_or(false, |t| t.0.add_frame(&f.data, ns, f.key)),
+                None => file
+                    .audio
+                    .as_mut()
+                    .map_or(false, |at| at.add_frame(&f.data, ns, true)),
+            };
+            written |= ok;
+        }
+        let n = self
+            .pending_events
+            .iter()
+            .take_while(|(ms, _)| *ms <= deadline)
+            .count();
+        for (ms, event) in self.pending_events.drain(..n) {
+            if let Some(index) = file.index.as_mut() {
+                index.write(&event, ms - file.origin);
+            }
+        }
+        if written {
+            file.written = true;
+            self.send_state(RecordState::NewFrame);
         }
-        self.ctx.tx.as_ref().map(|tx| tx.send(state));
+    }
+
+    fn finalize(&mut self) {
+        self.flush(true);
+        let Some(mut file) = self.file.take() else {
+            return;
+        };
+        let _ = std::mem::replace(&mut file.webm, None).map_or(false, |webm| webm.finalize(None));
+        finish_file(
+            &self.ctx,
+            &file.filename,
+            file.index.take(),
+            file.written && file.start.elapsed().as_secs() >= MIN_SECS,
+        );
+    }
+
+    // Finalize the current file, the next one starts from the last key frame of every display.
+    fn restart(&mut self) {
+        self.finalize();
+        self.pending = self
+            .specs
+            .values()
+            .flat_map(|s| s.gop.iter().cloned())
+            .collect();
+        self.wait_start = None;
+    }
+
+    fn send_state(&self, state: RecordState) {
+        self.ctx.send_state(state);
+    }
+}
+
+impl Drop for MultiTrack {
+    fn drop(&mut self) {
+        self.finalize();
+    }
+}
+
+struct TrackRecorder {
+    display: usize,
+    shared: Arc<Mutex<MultiTrack>>,
+}
+
+impl RecorderApi for TrackRecorder {
+    fn new(ctx: RecorderContext, ctx2: RecorderContext2) -> ResultType<Self> {
+        let key = MultiTrack::key(&ctx);
+        let display = ctx.display_idx;
+        let shared = {
+            let mut lock = MULTI_TRACKS.lock().unwrap();
+            lock.retain(|_, v| v.strong_count() > 0);
+            match lock.get(&key).and_then(|v| v.upgrade()) {
+                Some(shared) => shared,
+                None => {
+                    let shared = Arc::new(Mutex::new(MultiTrack::new(ctx)));
+                    lock.insert(key, Arc::downgrade(&shared));
+                    shared
+                }
+            }
+        };
+        shared
+            .lock()
+            .unwrap()
+            .register(display, ctx2.width, ctx2.height, ctx2.format, ctx2.audio);
+        Ok(Self { display, shared })
+    }
+
+    fn write_video(&mut self, frame: &EncodedVideoFrame) -> bool {
+        self.shared
+            .lock()
+            .unwrap()
+            .write(self.display, &frame.data, frame.pts, frame.key)
+    }
+
+    fn write_audio(&mut self, data: &[u8], pts: i64) -> bool {
+        self.shared
+            .lock()
+            .unwrap()
+            .write_audio(self.display, data, pts)
+    }
+
+    fn write_event(&mut self, event: &RecordEvent, pts: i64) -> bool {
+        self.shared
+            .lock()
+            .unwrap()
+            .write_event(self.display, event, pts)
+    }
+}
+
+impl Drop for TrackRecorder {
+    fn drop(&mut self) {
+        self.shared.lock().unwrap().unregister(self.display);
     }
 }
 
 #[cfg(feature = "hwcodec")]
 struct HwRecorder {
     muxer: Option<Muxer>,
+    index: Option<IndexWriter>,
+    // pts of the first written frame
+    first_pts: Option<i64>,
     ctx: RecorderContext,
     ctx2: RecorderContext2,
     written: bool,
@@ -366,6 +1043,7 @@ struct HwRecorder {
     start: Instant,
 }
 
+// The muxer of hwcodec has no audio track, H264 and H265 recordings are silent.
 #[cfg(feature = "hwcodec")]
 impl RecorderApi for HwRecorder {
     fn new(ctx: RecorderContext, ctx2: RecorderContext2) -> ResultType<Self> {
@@ -379,6 +1057,8 @@ impl RecorderApi for HwRecorder {
         .map_err(|_| anyhow!("Failed to create hardware muxer"))?;
         Ok(HwRecorder {
             muxer: Some(muxer),
+            index: IndexWriter::new(&ctx2.filename),
+            first_pts: None,
             ctx,
             ctx2,
             written: false,
@@ -399,25 +1079,33 @@ impl RecorderApi for HwRecorder {
                 .unwrap_or_default();
             if ok {
                 self.written = true;
+                self.first_pts.get_or_insert(frame.pts);
             }
             ok
         } else {
             false
         }
     }
+
+    fn write_event(&mut self, event: &RecordEvent, pts: i64) -> bool {
+        match (self.index.as_mut(), self.first_pts) {
+            (Some(index), Some(first_pts)) => index.write(event, pts - first_pts),
+            _ => false,
+        }
+    }
 }
 
 #[cfg(feature = "hwcodec")]
 impl Drop for HwRecorder {
     fn drop(&mut self) {
         self.muxer.as_mut().map(|m| m.write_tail().ok());
-        let mut state = RecordState::WriteTail;
-        if !self.written || self.start.elapsed().as_secs() < MIN_SECS {
-            // The process cannot access the file because it is being used by another process
-            self.muxer = None;
-            std::fs::remove_file(&self.ctx2.filename).ok();
-            state = RecordState::RemoveFile;
-        }
-        self.ctx.tx.as_ref().map(|tx| tx.send(state));
+        // The process cannot access the file because it is being used by another process
+        self.muxer = None;
+        finish_file(
+            &self.ctx,
+            &self.ctx2.filename,
+            self.index.take(),
+            self.written && self.start.elapsed().as_secs() >= MIN_SECS,
+        );
     }
 }
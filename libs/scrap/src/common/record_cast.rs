@@ -0,0 +1,236 @@
+// Recording of a terminal session in the asciicast v2 format of asciinema,
+// https://docs.asciinema.org/manual/asciicast/v2/
+//
+// The first line is the header, then an event per line, `[time, code, data]`, time is in seconds
+// since the start of the recording:
+// - `o`, the output of the terminal
+// - `i`, the input sent to the terminal, only if `record_input` is set, it has the passwords typed
+//   at the prompts which are not echoed
+// - `r`, a resize, `<cols>x<rows>`
+// - `m`, a marker
+//
+// The recording shares the directory, the upload and the encryption of the screen recordings, see
+// `RecorderContext`.
+
+use crate::record::{finish_file, RecordState, RecorderContext};
+use hbb_common::{chrono, log, serde_json, ResultType};
+use serde::Serialize;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const MIN_SECS: u64 = 1;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const TERM: &str = "xterm-256color";
+
+#[derive(Serialize)]
+struct Header<'a> {
+    version: u8,
+    width: u16,
+    height: u16,
+    timestamp: u64,
+    title: &'a str,
+    env: Env<'a>,
+}
+
+#[derive(Serialize)]
+struct Env<'a> {
+    #[serde(rename = "SHELL")]
+    shell: &'a str,
+    #[serde(rename = "TERM")]
+    term: &'a str,
+}
+
+pub struct CastRecorder {
+    ctx: RecorderContext,
+    filename: String,
+    file: Option<BufWriter<File>>,
+    start: Instant,
+    last_flush: Instant,
+    written: bool,
+    record_input: bool,
+    // The incomplete UTF-8 characters at the end of the last output and input.
+    output_tail: Vec<u8>,
+    input_tail: Vec<u8>,
+}
+
+impl CastRecorder {
+    pub fn new(
+        ctx: RecorderContext,
+        terminal_id: i32,
+        cols: u16,
+        rows: u16,
+        shell: &str,
+        record_input: bool,
+    ) -> ResultType<Self> {
+        if !PathBuf::from(&ctx.dir).exists() {
+            std::fs::create_dir_all(&ctx.dir)?;
+        }
+        let file = format!(
+            "{}_{}{}terminal{}.cast",
+            if ctx.server { "incoming" } else { "outgoing" },
+            ctx.id,
+            chrono::Local::now().format("_%Y%m%d%H%M%S%3f_"),
+            terminal_id
+        );
+        let filename = PathBuf::from(&ctx.dir)
+            .join(file)
+            .to_string_lossy()
+            .to_string();
+        let mut file = BufWriter::new(
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&filename)?,
+        );
+        let title = format!("{} terminal {}", ctx.id, terminal_id);
+        let header = Header {
+            version: 2,
+            width: cols,
+            height: rows,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            title: &title,
+            env: Env { shell, term: TERM },
+        };
+        write_line(&mut file, &header, true)?;
+        log::info!("Start terminal recording: {}", filename);
+        ctx.send_state(RecordState::NewFile(filename.clone()));
+        Ok(Self {
+            ctx,
+            filename,
+            file: Some(file),
+            start: Instant::now(),
+            last_flush: Instant::now(),
+            written: false,
+            record_input,
+            output_tail: vec![],
+            input_tail: vec![],
+        })
+    }
+
+    pub fn write_output(&mut self, data: &[u8]) {
+        let text = take_utf8(&mut self.output_tail, data);
+        if !text.is_empty() {
+            self.written = true;
+            self.write_event("o", &text);
+        }
+    }
+
+    pub fn write_input(&mut self, data: &[u8]) {
+        if !self.record_input {
+            return;
+        }
+        let text = take_utf8(&mut self.input_tail, data);
+        if !text.is_empty() {
+            self.write_event("i", &text);
+        }
+    }
+
+    pub fn write_resize(&mut self, cols: u16, rows: u16) {
+        self.write_event("r", &format!("{}x{}", cols, rows));
+    }
+
+    pub fn write_marker(&mut self, label: &str) {
+        self.write_event("m", label);
+    }
+
+    fn write_event(&mut self, code: &str, data: &str) {
+        let Some(file) = self.file.as_mut() else {
+            return;
+        };
+        let time = self.start.elapsed().as_secs_f64();
+        let flush = self.last_flush.elapsed() >= FLUSH_INTERVAL;
+        if let Err(e) = write_line(file, &(time, code, data), flush) {
+            log::error!(
+                "Failed to write terminal recording {}: {}",
+                self.filename,
+                e
+            );
+            self.file = None;
+            return;
+        }
+        if flush {
+            self.last_flush = Instant::now();
+            self.ctx.send_state(RecordState::NewFrame);
+        }
+    }
+}
+
+fn write_line(file: &mut BufWriter<File>, value: &impl Serialize, flush: bool) -> io::Result<()> {
+    serde_json::to_writer(&mut *file, value)?;
+    file.write_all(b"\n")?;
+    if flush {
+        file.flush()?;
+    }
+    Ok(())
+}
+
+impl Drop for CastRecorder {
+    fn drop(&mut self) {
+        if let Some(mut file) = self.file.take() {
+            file.flush().ok();
+        }
+        finish_file(
+            &self.ctx,
+            &self.filename,
+            None,
+            self.written && self.start.elapsed().as_secs() >= MIN_SECS,
+        );
+    }
+}
+
+// Append `data` to `tail`, returns the complete UTF-8 text and keeps the incomplete character at
+// the end in `tail`. Invalid bytes are replaced.
+fn take_utf8(tail: &mut Vec<u8>, data: &[u8]) -> String {
+    tail.extend_from_slice(data);
+    let mut text = String::new();
+    let mut rest = &tail[..];
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(s) => {
+                text.push_str(s);
+                rest = &[];
+                break;
+            }
+            Err(e) => {
+                let (valid, after) = rest.split_at(e.valid_up_to());
+                text.push_str(std::str::from_utf8(valid).unwrap_or_default());
+                match e.error_len() {
+                    Some(len) => {
+                        text.push(char::REPLACEMENT_CHARACTER);
+                        rest = &after[len..];
+                    }
+                    None => {
+                        rest = after;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    *tail = rest.to_vec();
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_utf8() {
+        let mut tail = vec![];
+        let text = "\u{4f60}\u{597d}".as_bytes();
+        assert_eq!(take_utf8(&mut tail, &text[..4]), "\u{4f60}");
+        assert_eq!(tail, &text[3..4]);
+        assert_eq!(take_utf8(&mut tail, &text[4..]), "\u{597d}");
+        assert!(tail.is_empty());
+        assert_eq!(take_utf8(&mut tail, b"a\xffb"), "a\u{fffd}b");
+        assert!(tail.is_empty());
+    }
+}
@@ -0,0 +1,238 @@
+// Encryption of the finished recordings for the public key set by the admin, so that only the
+// holder of the secret key can watch them.
+//
+// A random key is sealed (`crypto_box_seal`) for the public key, and the file is encrypted with
+// it in chunks of `CHUNK_SIZE` (`crypto_secretbox`), like the STREAM construction of age. The
+// nonce of a chunk is its counter and a flag of the last chunk, so that chunks can not be
+// reordered and a cut file is detected.
+//
+// `magic | sealed key | chunk...`, every chunk is `CHUNK_SIZE` + `MACBYTES` except the last one,
+// which is shorter, and empty if the size of the file is a multiple of `CHUNK_SIZE`.
+//
+// The muxers seek back to write the header when a file is finished, so the file is encrypted
+// once finished, and removed. It is in plain text while it is recorded.
+
+use hbb_common::{
+    bail, log,
+    sodiumoxide::{
+        base64,
+        crypto::{box_, sealedbox, secretbox},
+    },
+    ResultType,
+};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+};
+
+pub const OPTION_PUBLIC_KEY: &str = "recording-public-key";
+pub const ENCRYPTED_SUFFIX: &str = ".enc";
+const MAGIC: &[u8] = b"RDREC\x01";
+const CHUNK_SIZE: usize = 64 * 1024;
+const SEALED_KEY_LEN: usize = secretbox::KEYBYTES + sealedbox::SEALBYTES;
+
+/// Parse the value of `recording-public-key`, `None` if it is empty.
+pub fn public_key_option(value: &str) -> ResultType<Option<box_::PublicKey>> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    let Ok(bytes) = base64::decode(value, base64::Variant::Original) else {
+        bail!("Invalid {}, not base64", OPTION_PUBLIC_KEY);
+    };
+    match box_::PublicKey::from_slice(&bytes) {
+        Some(pk) => Ok(Some(pk)),
+        None => bail!(
+            "Invalid {}, {} bytes expected",
+            OPTION_PUBLIC_KEY,
+            box_::PUBLICKEYBYTES
+        ),
+    }
+}
+
+/// A new key pair, base64 of (public key, secret key).
+pub fn gen_keypair() -> (String, String) {
+    let (pk, sk) = box_::gen_keypair();
+    (
+        base64::encode(pk, base64::Variant::Original),
+        base64::encode(sk, base64::Variant::Original),
+    )
+}
+
+/// Write the base64 secret key to a new file only readable by the owner.
+pub fn write_secret_key(path: &str, sk: &str) -> ResultType<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(sk.as_bytes())?;
+    Ok(())
+}
+
+pub fn parse_secret_key(value: &str) -> ResultType<box_::SecretKey> {
+    let Ok(bytes) = base64::decode(value.trim(), base64::Variant::Original) else {
+        bail!("Invalid secret key, not base64");
+    };
+    match box_::SecretKey::from_slice(&bytes) {
+        Some(sk) => Ok(sk),
+        None => bail!(
+            "Invalid secret key, {} bytes expected",
+            box_::SECRETKEYBYTES
+        ),
+    }
+}
+
+fn chunk_nonce(counter: u64, last: bool) -> secretbox::Nonce {
+    let mut nonce = [0u8; secretbox::NONCEBYTES];
+    nonce[..8].copy_from_slice(&counter.to_be_bytes());
+    nonce[8] = last as u8;
+    secretbox::Nonce(nonce)
+}
+
+// Read until `buf` is full or the end of the input.
+fn read_full(input: &mut impl Read, buf: &mut [u8]) -> ResultType<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match input.read(&mut buf[n..])? {
+            0 => break,
+            m => n += m,
+        }
+    }
+    Ok(n)
+}
+
+pub fn encrypt(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    pk: &box_::PublicKey,
+) -> ResultType<()> {
+    let key = secretbox::gen_key();
+    output.write_all(MAGIC)?;
+    output.write_all(&sealedbox::seal(&key.0, pk))?;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut counter = 0u64;
+    loop {
+        let n = read_full(input, &mut buf)?;
+        let last = n < CHUNK_SIZE;
+        output.write_all(&secretbox::seal(
+            &buf[..n],
+            &chunk_nonce(counter, last),
+            &key,
+        ))?;
+        if last {
+            break;
+        }
+        counter += 1;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+/// Returns the size of the plain text.
+pub fn decrypt(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    sk: &box_::SecretKey,
+) -> ResultType<u64> {
+    let mut magic = [0u8; MAGIC.len()];
+    if read_full(input, &mut magic)? != MAGIC.len() || magic != MAGIC {
+        bail!("Not an encrypted recording");
+    }
+    let mut sealed = [0u8; SEALED_KEY_LEN];
+    if read_full(input, &mut sealed)? != SEALED_KEY_LEN {
+        bail!("Truncated recording");
+    }
+    let Ok(key) = sealedbox::open(&sealed, &sk.public_key(), sk) else {
+        bail!("The recording is not encrypted for this key");
+    };
+    let Some(key) = secretbox::Key::from_slice(&key) else {
+        bail!("Invalid file key");
+    };
+    let mut buf = vec![0u8; CHUNK_SIZE + secretbox::MACBYTES];
+    let mut counter = 0u64;
+    let mut size = 0u64;
+    loop {
+        let n = read_full(input, &mut buf)?;
+        let last = n < buf.len();
+        let Ok(plain) = secretbox::open(&buf[..n], &chunk_nonce(counter, last), &key) else {
+            bail!("Recording is corrupted or truncated at chunk {}", counter);
+        };
+        output.write_all(&plain)?;
+        size += plain.len() as u64;
+        if last {
+            break;
+        }
+        counter += 1;
+    }
+    output.flush()?;
+    Ok(size)
+}
+
+/// Encrypt `path` to `path.enc` and remove `path`, returns the new path.
+pub fn encrypt_file(path: &str, pk: &box_::PublicKey) -> ResultType<String> {
+    let out_path = format!("{}{}", path, ENCRYPTED_SUFFIX);
+    let res = (|| -> ResultType<()> {
+        let mut input = BufReader::new(File::open(path)?);
+        let mut output = BufWriter::new(File::create(&out_path)?);
+        encrypt(&mut input, &mut output, pk)
+    })();
+    if let Err(e) = res {
+        std::fs::remove_file(&out_path).ok();
+        return Err(e);
+    }
+    std::fs::remove_file(path)?;
+    log::info!("recording encrypted: {}", out_path);
+    Ok(out_path)
+}
+
+/// Decrypt `path` to `out`, or to `path` without the `.enc` suffix.
+pub fn decrypt_file(path: &str, out: Option<&str>, sk: &box_::SecretKey) -> ResultType<String> {
+    let out_path = match out {
+        Some(out) => out.to_owned(),
+        None => match path.strip_suffix(ENCRYPTED_SUFFIX) {
+            Some(p) => p.to_owned(),
+            None => bail!(
+                "No output file, and {} does not end with {}",
+                path,
+                ENCRYPTED_SUFFIX
+            ),
+        },
+    };
+    let res = (|| -> ResultType<u64> {
+        let mut input = BufReader::new(File::open(path)?);
+        let mut output = BufWriter::new(File::create(&out_path)?);
+        decrypt(&mut input, &mut output, sk)
+    })();
+    if let Err(e) = res {
+        std::fs::remove_file(&out_path).ok();
+        return Err(e);
+    }
+    Ok(out_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt() {
+        let (pk, sk) = box_::gen_keypair();
+        let (_, other_sk) = box_::gen_keypair();
+        for len in [0, 10, CHUNK_SIZE, CHUNK_SIZE * 2 + 7] {
+            let plain: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut encrypted = vec![];
+            encrypt(&mut &plain[..], &mut encrypted, &pk).unwrap();
+            let mut out = vec![];
+            assert_eq!(
+                decrypt(&mut &encrypted[..], &mut out, &sk).unwrap(),
+                len as u64
+            );
+            assert_eq!(out, plain);
+            assert!(decrypt(&mut &encrypted[..], &mut vec![], &other_sk).is_err());
+            if len >= CHUNK_SIZE {
+                let cut = MAGIC.len() + SEALED_KEY_LEN + CHUNK_SIZE + secretbox::MACBYTES;
+                assert!(decrypt(&mut &encrypted[..cut], &mut vec![], &sk).is_err());
+            }
+        }
+    }
+}
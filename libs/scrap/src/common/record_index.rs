@@ -0,0 +1,201 @@
+// Index of the events of a session recording, so that a moment can be found without watching the
+// whole video.
+//
+// The index is written next to the recording as json lines, `<recording>.events.jsonl`, one
+// event per line. `ms` of an event is the time since the first frame of the recording, which is
+// the position to seek the video to.
+
+use hbb_common::{chrono, log, serde_json, ResultType};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    time::Duration,
+};
+
+pub const INDEX_SUFFIX: &str = ".events.jsonl";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordEventKind {
+    Key,
+    Mouse,
+    Clipboard,
+    File,
+    Chat,
+    Display,
+    Permission,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordEvent {
+    /// ms since the first frame of the recording, set by the recorder.
+    #[serde(default)]
+    pub ms: i64,
+    /// Local time, RFC 3339.
+    #[serde(default)]
+    pub time: String,
+    pub kind: RecordEventKind,
+    /// The id of the peer the event comes from.
+    #[serde(default)]
+    pub peer: String,
+    #[serde(default)]
+    pub detail: String,
+}
+
+impl RecordEvent {
+    pub fn new(kind: RecordEventKind, peer: &str, detail: impl Into<String>) -> Self {
+        Self {
+            ms: 0,
+            time: chrono::Local::now().to_rfc3339(),
+            kind,
+            peer: peer.to_owned(),
+            detail: detail.into(),
+        }
+    }
+}
+
+pub fn index_path(recording: &str) -> String {
+    format!("{}{}", recording, INDEX_SUFFIX)
+}
+
+pub(crate) struct IndexWriter {
+    file: File,
+    path: String,
+}
+
+impl IndexWriter {
+    pub(crate) fn new(recording: &str) -> Option<Self> {
+        let path = index_path(recording);
+        match File::create(&path) {
+            Ok(file) => Some(Self { file, path }),
+            Err(e) => {
+                log::error!("Failed to create record index {}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn write(&mut self, event: &RecordEvent, ms: i64) -> bool {
+        let mut event = event.clone();
+        event.ms = ms.max(0);
+        let Ok(mut line) = serde_json::to_string(&event) else {
+            return false;
+        };
+        line.push('\n');
+        self.file.write_all(line.as_bytes()).is_ok()
+    }
+
+    /// Close the index, returns its path.
+    pub(crate) fn close(self) -> String {
+        self.path
+    }
+
+    /// Remove the index with its recording.
+    pub(crate) fn remove(self) {
+        let path = self.path;
+        drop(self.file);
+        std::fs::remove_file(&path).ok();
+    }
+}
+
+/// The events of a recording.
+#[derive(Debug, Default)]
+pub struct RecordIndex {
+    pub events: Vec<RecordEvent>,
+}
+
+impl RecordIndex {
+    /// Load the index of a recording, `path` is the recording or the index itself.
+    pub fn load(path: &str) -> ResultType<Self> {
+        let path = if path.ends_with(INDEX_SUFFIX) {
+            path.to_owned()
+        } else {
+            index_path(path)
+        };
+        let mut events = vec![];
+        for line in BufReader::new(File::open(&path)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            // The last line may be cut by a crash.
+            match serde_json::from_str::<RecordEvent>(&line) {
+                Ok(event) => events.push(event),
+                Err(e) => log::warn!("Invalid line of record index {}: {}", path, e),
+            }
+        }
+        events.sort_by_key(|e| e.ms);
+        Ok(Self { events })
+    }
+
+    /// Events of `kind`, or of all kinds, whose detail contains `text`, case insensitive.
+    pub fn find<'a>(
+        &'a self,
+        kind: Option<RecordEventKind>,
+        text: &str,
+    ) -> impl Iterator<Item = &'a RecordEvent> + 'a {
+        let text = text.to_lowercase();
+        self.events.iter().filter(move |e| {
+            kind.map_or(true, |k| k == e.kind) && e.detail.to_lowercase().contains(&text)
+        })
+    }
+
+    /// The position of the first event matching [`RecordIndex::find`].
+    pub fn seek(&self, kind: Option<RecordEventKind>, text: &str) -> Option<Duration> {
+        self.find(kind, text)
+            .next()
+            .map(|e| Duration::from_millis(e.ms as u64))
+    }
+
+    /// Events from `from` to `to` of the recording, both inclusive.
+    pub fn range(&self, from: Duration, to: Duration) -> impl Iterator<Item = &RecordEvent> {
+        let (from, to) = (from.as_millis() as i64, to.as_millis() as i64);
+        self.events
+            .iter()
+            .filter(move |e| e.ms >= from && e.ms <= to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index() {
+        let recording = std::env::temp_dir()
+            .join(format!("record_index_{}.webm", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let mut writer = IndexWriter::new(&recording).unwrap();
+        let peer = "123456789";
+        writer.write(
+            &RecordEvent::new(RecordEventKind::Chat, peer, "hello"),
+            1_000,
+        );
+        writer.write(
+            &RecordEvent::new(RecordEventKind::File, peer, "remove_file /tmp/Report.pdf"),
+            65_000,
+        );
+        writer.write(
+            &RecordEvent::new(RecordEventKind::Key, peer, "Ctrl+C"),
+            70_000,
+        );
+        let index = RecordIndex::load(&recording).unwrap();
+        assert_eq!(index.events.len(), 3);
+        assert_eq!(
+            index.seek(Some(RecordEventKind::File), "report.pdf"),
+            Some(Duration::from_secs(65))
+        );
+        assert_eq!(index.seek(Some(RecordEventKind::Chat), "report"), None);
+        assert_eq!(index.find(None, "").count(), 3);
+        assert_eq!(
+            index
+                .range(Duration::from_secs(60), Duration::from_secs(70))
+                .count(),
+            2
+        );
+        writer.remove();
+        assert!(RecordIndex::load(&recording).is_err());
+    }
+}
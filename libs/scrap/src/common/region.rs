@@ -0,0 +1,359 @@
+// Capture of a part of the display, a rectangle or a window, cropped before the encoding.
+//
+// The crop is a view into the captured pixel buffer with its stride, there is no copy before the
+// conversion to the encoder's format.
+//
+// The controller sends the extension messages (see `new_ext_message` of the main crate) with the
+// id `EXT_ID`, `[kind: u8][payload]`, integers are big endian and strings are `[len: u16][utf8]`.
+//
+// - `SET display: i32, x: i32, y: i32, width: u32, height: u32, window: u64`, a rectangle of the
+//   display, or a window if it is not 0, or the whole display if both are empty.
+// - `LIST` -> `WINDOWS [window: u64, x: i32, y: i32, width: u32, height: u32, title]`, the visible
+//   top-level windows, in screen coordinates.
+
+use crate::{Pixfmt, TraitPixelBuffer};
+use hbb_common::{
+    bail,
+    bytes::{Buf, BufMut},
+    ResultType,
+};
+
+pub const EXT_ID: &str = "@rustdesk/capture-region";
+
+const KIND_SET: u8 = 0x01;
+const KIND_LIST: u8 = 0x02;
+const KIND_WINDOWS: u8 = 0x03;
+
+/// A rectangle relative to the origin of the display.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CaptureRect {
+    pub x: i32,
+    pub y: i32,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl CaptureRect {
+    /// The part of the rectangle in a display of `width` x `height`, with even position and size
+    /// for the chroma subsampling of the encoders, or None if it is out of the display.
+    pub fn clamp(&self, width: usize, height: usize) -> Option<CaptureRect> {
+        let (width, height) = (width as i64, height as i64);
+        let x0 = (self.x as i64).clamp(0, width) & !1;
+        let y0 = (self.y as i64).clamp(0, height) & !1;
+        let x1 = (self.x as i64 + self.width as i64).clamp(0, width);
+        let y1 = (self.y as i64 + self.height as i64).clamp(0, height);
+        let (w, h) = ((x1 - x0) & !1, (y1 - y0) & !1);
+        if w <= 0 || h <= 0 {
+            return None;
+        }
+        Some(CaptureRect {
+            x: x0 as _,
+            y: y0 as _,
+            width: w as _,
+            height: h as _,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct WindowInfo {
+    pub window: u64,
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    pub title: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegionMessage {
+    Set {
+        display: i32,
+        rect: CaptureRect,
+        window: u64,
+    },
+    List,
+    Windows(Vec<WindowInfo>),
+}
+
+impl RegionMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        match self {
+            RegionMessage::Set {
+                display,
+                rect,
+                window,
+            } => {
+                buf.put_u8(KIND_SET);
+                buf.put_i32(*display);
+                buf.put_i32(rect.x);
+                buf.put_i32(rect.y);
+                buf.put_u32(rect.width as _);
+                buf.put_u32(rect.height as _);
+                buf.put_u64(*window);
+            }
+            RegionMessage::List => buf.put_u8(KIND_LIST),
+            RegionMessage::Windows(windows) => {
+                buf.put_u8(KIND_WINDOWS);
+                for w in windows {
+                    buf.put_u64(w.window);
+                    buf.put_i32(w.x);
+                    buf.put_i32(w.y);
+                    buf.put_u32(w.width);
+                    buf.put_u32(w.height);
+                    let title = w.title.as_bytes();
+                    let title = &title[..title.len().min(u16::MAX as _)];
+                    buf.put_u16(title.len() as _);
+                    buf.put_slice(title);
+                }
+            }
+        }
+        buf
+    }
+
+    pub fn decode(mut data: &[u8]) -> ResultType<Self> {
+        if data.is_empty() {
+            bail!("empty capture region message");
+        }
+        match data.get_u8() {
+            KIND_SET => {
+                if data.remaining() < 28 {
+                    bail!("short capture region message");
+                }
+                Ok(RegionMessage::Set {
+                    display: data.get_i32(),
+                    rect: CaptureRect {
+                        x: data.get_i32(),
+                        y: data.get_i32(),
+                        width: data.get_u32() as _,
+                        height: data.get_u32() as _,
+                    },
+                    window: data.get_u64(),
+                })
+            }
+            KIND_LIST => Ok(RegionMessage::List),
+            KIND_WINDOWS => {
+                let mut windows = vec![];
+                while data.has_remaining() {
+                    if data.remaining() < 26 {
+                        bail!("short window info");
+                    }
+                    let (window, x, y) = (data.get_u64(), data.get_i32(), data.get_i32());
+                    let (width, height) = (data.get_u32(), data.get_u32());
+                    let len = data.get_u16() as usize;
+                    if data.remaining() < len {
+                        bail!("short window title");
+                    }
+                    let title = String::from_utf8_lossy(&data[..len]).into_owned();
+                    data.advance(len);
+                    windows.push(WindowInfo {
+                        window,
+                        x,
+                        y,
+                        width,
+                        height,
+                        title,
+                    });
+                }
+                Ok(RegionMessage::Windows(windows))
+            }
+            kind => bail!("unknown capture region message {}", kind),
+        }
+    }
+}
+
+pub struct CroppedPixelBuffer<'a> {
+    data: &'a [u8],
+    width: usize,
+    height: usize,
+    stride: Vec<usize>,
+    pixfmt: Pixfmt,
+}
+
+impl<'a> TraitPixelBuffer for CroppedPixelBuffer<'a> {
+    fn data(&self) -> &[u8] {
+        self.data
+    }
+
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn stride(&self) -> Vec<usize> {
+        self.stride.clone()
+    }
+
+    fn pixfmt(&self) -> Pixfmt {
+        self.pixfmt
+    }
+}
+
+/// Crop a packed pixel buffer, `rect` must be clamped to its size.
+pub fn crop<'a>(
+    captured: &'a impl TraitPixelBuffer,
+    rect: &CaptureRect,
+) -> ResultType<CroppedPixelBuffer<'a>> {
+    let pixfmt = captured.pixfmt();
+    if !matches!(pixfmt, Pixfmt::BGRA | Pixfmt::RGBA | Pixfmt::RGB565LE) {
+        bail!("unsupported pixfmt to crop: {:?}", pixfmt);
+    }
+    let stride = captured.stride();
+    let Some(&stride0) = stride.first() else {
+        bail!("no stride");
+    };
+    let (x, y) = (rect.x as usize, rect.y as usize);
+    if rect.x < 0
+        || rect.y < 0
+        || x + rect.width > captured.width()
+        || y + rect.height > captured.height()
+        || rect.width == 0
+        || rect.height == 0
+    {
+        bail!(
+            "crop {:?} out of {}x{}",
+            rect,
+            captured.width(),
+            captured.height()
+        );
+    }
+    let bpp = pixfmt.bytes_per_pixel();
+    let start = y * stride0 + x * bpp;
+    let end = (y + rect.height - 1) * stride0 + (x + rect.width) * bpp;
+    let data = captured.data();
+    if data.len() < end {
+        bail!("wrong src len, {} < {}", data.len(), end);
+    }
+    Ok(CroppedPixelBuffer {
+        data: &data[start..end],
+        width: rect.width,
+        height: rect.height,
+        stride: vec![stride0],
+        pixfmt,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clamp() {
+        let rect = |x, y, width, height| CaptureRect {
+            x,
+            y,
+            width,
+            height,
+        };
+        assert_eq!(
+            rect(10, 20, 100, 50).clamp(1920, 1080),
+            Some(rect(10, 20, 100, 50))
+        );
+        assert_eq!(
+            rect(11, 21, 101, 51).clamp(1920, 1080),
+            Some(rect(10, 20, 102, 52))
+        );
+        assert_eq!(
+            rect(-100, -50, 300, 150).clamp(1920, 1080),
+            Some(rect(0, 0, 200, 100))
+        );
+        assert_eq!(
+            rect(1800, 1000, 300, 300).clamp(1920, 1080),
+            Some(rect(1800, 1000, 120, 80))
+        );
+        assert_eq!(rect(2000, 0, 100, 100).clamp(1920, 1080), None);
+        assert_eq!(rect(-200, 0, 100, 100).clamp(1920, 1080), None);
+    }
+
+    #[test]
+    fn test_message() {
+        let messages = vec![
+            RegionMessage::Set {
+                display: 1,
+                rect: CaptureRect {
+                    x: -10,
+                    y: 20,
+                    width: 300,
+                    height: 200,
+                },
+                window: 0,
+            },
+            RegionMessage::List,
+            RegionMessage::Windows(vec![
+                WindowInfo {
+                    window: 0x1234,
+                    x: -8,
+                    y: 0,
+                    width: 800,
+                    height: 600,
+                    title: "Terminal — bash".to_owned(),
+                },
+                WindowInfo::default(),
+            ]),
+        ];
+        for m in messages {
+            assert_eq!(RegionMessage::decode(&m.encode()).unwrap(), m);
+        }
+        assert!(RegionMessage::decode(&[]).is_err());
+        assert!(RegionMessage::decode(&[KIND_SET, 0, 0]).is_err());
+        assert!(RegionMessage::decode(&[KIND_WINDOWS, 0]).is_err());
+    }
+
+    struct Buffer(Vec<u8>);
+
+    impl TraitPixelBuffer for Buffer {
+        fn data(&self) -> &[u8] {
+            &self.0
+        }
+
+        fn width(&self) -> usize {
+            4
+        }
+
+        fn height(&self) -> usize {
+            4
+        }
+
+        fn stride(&self) -> Vec<usize> {
+            vec![20]
+        }
+
+        fn pixfmt(&self) -> Pixfmt {
+            Pixfmt::BGRA
+        }
+    }
+
+    #[test]
+    fn test_crop() {
+        let buffer = Buffer((0..80).collect());
+        let cropped = crop(
+            &buffer,
+            &CaptureRect {
+                x: 2,
+                y: 2,
+                width: 2,
+                height: 2,
+            },
+        )
+        .unwrap();
+        let data = cropped.data();
+        assert_eq!(data.len(), 20 + 8);
+        assert_eq!(data[0], 48);
+        assert_eq!(data[20], 68);
+        assert_eq!(cropped.stride(), vec![20]);
+        assert!(crop(
+            &buffer,
+            &CaptureRect {
+                x: 3,
+                y: 0,
+                width: 2,
+                height: 2,
+            },
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,409 @@
+// Lossless or near-lossless codec of the changed tiles of the screen, for links too slow for the
+// video codecs, e.g. text-heavy work over a satellite link of 256 kbps.
+//
+// The screen is cut in tiles of `TILE_SIZE`, only the tiles which changed since the previous
+// frame are sent, the first frame is a key frame with all of them. The pixels are sent as RGB,
+// with the difference to the left pixel like the `Sub` filter of PNG, and the whole frame is
+// compressed by zstd.
+//
+// The quality drops the low bits of the channels, lossless from the balanced quality.
+//
+// `header | zstd(tile...)`, header is `magic | version u8 | flags u8 | width u32 | height u32 |
+// tile count u32`, little endian, and a tile is `column u16 | row u16 | pixels`.
+//
+// There is no message of the tiles in the protocol, they are sent as VP9 frames. The magic starts
+// with 0, which is not a valid frame marker of VP9, so the decoder tells them apart. A peer
+// announces the decoder with the `EXT_ID` extension message, and the tiles are only used if all
+// the peers did.
+//
+// A key frame is sent every `KEY_FRAME_INTERVAL`, and once a new peer joins.
+
+use crate::{
+    codec::{base_bitrate, EncoderApi, EncoderCfg, BR_BALANCED, BR_SPEED},
+    EncodeInput, EncodeYuvFormat, ImageFormat, ImageRgb, Pixfmt,
+};
+use hbb_common::{
+    anyhow::anyhow,
+    bail,
+    bytes::Bytes,
+    compress::{compress, decompress},
+    message_proto::{EncodedVideoFrame, EncodedVideoFrames, VideoFrame},
+    ResultType,
+};
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+pub const EXT_ID: &str = "@rustdesk/tile-decoding";
+const MAGIC: [u8; 3] = [0, b'T', b'L'];
+const VERSION: u8 = 1;
+const FLAG_KEY: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 14;
+const KEY_FRAME_INTERVAL: Duration = Duration::from_secs(30);
+const TILE_SIZE: usize = 64;
+// RGB
+const BPP: usize = 3;
+
+// Bumped to request a key frame from all the encoders.
+static KEY_FRAME_REQUEST: AtomicUsize = AtomicUsize::new(0);
+
+pub fn request_key_frame() {
+    KEY_FRAME_REQUEST.fetch_add(1, Ordering::SeqCst);
+}
+
+pub fn is_tile_frame(data: &[u8]) -> bool {
+    data.starts_with(&MAGIC)
+}
+
+pub fn is_tile_frames(frames: &EncodedVideoFrames) -> bool {
+    frames
+        .frames
+        .first()
+        .map_or(false, |f| is_tile_frame(&f.data))
+}
+
+#[derive(Debug, Clone)]
+pub struct TileEncoderConfig {
+    pub width: u32,
+    pub height: u32,
+    pub quality: f32,
+}
+
+fn quality_mask(ratio: f32) -> u8 {
+    if ratio >= BR_BALANCED {
+        0xFF
+    } else if ratio >= BR_SPEED {
+        0xFC
+    } else {
+        0xF8
+    }
+}
+
+fn tile_rect(w: usize, h: usize, col: usize, row: usize) -> (usize, usize, usize, usize) {
+    let (x, y) = (col * TILE_SIZE, row * TILE_SIZE);
+    (x, y, TILE_SIZE.min(w - x), TILE_SIZE.min(h - y))
+}
+
+pub struct TileEncoder {
+    width: usize,
+    height: usize,
+    ratio: f32,
+    mask: u8,
+    // RGB of the previous frame, as decoded by the peer.
+    prev: Vec<u8>,
+    cur: Vec<u8>,
+    key: bool,
+    last_key: Instant,
+    key_request: usize,
+}
+
+impl EncoderApi for TileEncoder {
+    fn new(cfg: EncoderCfg, _i444: bool) -> ResultType<Self>
+    where
+        Self: Sized,
+    {
+        let EncoderCfg::TILE(cfg) = cfg else {
+            bail!("wrong encoder config");
+        };
+        if cfg.width == 0 || cfg.height == 0 {
+            bail!("invalid size {}x{}", cfg.width, cfg.height);
+        }
+        Ok(Self {
+            width: cfg.width as _,
+            height: cfg.height as _,
+            ratio: cfg.quality,
+            mask: quality_mask(cfg.quality),
+            prev: vec![],
+            cur: vec![],
+            key: true,
+            last_key: Instant::now(),
+            key_request: KEY_FRAME_REQUEST.load(Ordering::SeqCst),
+        })
+    }
+
+    fn encode_to_message(&mut self, input: EncodeInput, ms: i64) -> ResultType<VideoFrame> {
+        let data = self.encode(input.yuv()?)?;
+        let key = data[MAGIC.len() + 1] & FLAG_KEY != 0;
+        let mut vf = VideoFrame::new();
+        vf.set_vp9s(EncodedVideoFrames {
+            frames: vec![EncodedVideoFrame {
+                data: Bytes::from(data),
+                key,
+                pts: ms,
+                ..Default::default()
+            }]
+            .into(),
+            ..Default::default()
+        });
+        Ok(vf)
+    }
+
+    fn yuvfmt(&self) -> EncodeYuvFormat {
+        EncodeYuvFormat {
+            pixfmt: Pixfmt::BGRA,
+            w: self.width,
+            h: self.height,
+            stride: vec![self.width * 4],
+            u: 0,
+            v: 0,
+        }
+    }
+
+    #[cfg(feature = "vram")]
+    fn input_texture(&self) -> bool {
+        false
+    }
+
+    fn set_quality(&mut self, ratio: f32) -> ResultType<()> {
+        self.ratio = ratio;
+        // The tiles quantized by the old mask differ from the new ones, and are sent again.
+        self.mask = quality_mask(ratio);
+        Ok(())
+    }
+
+    fn bitrate(&self) -> u32 {
+        (base_bitrate(self.width as _, self.height as _) as f32 * self.ratio) as u32
+    }
+
+    fn support_changing_quality(&self) -> bool {
+        true
+    }
+
+    fn latency_free(&self) -> bool {
+        true
+    }
+
+    fn is_hardware(&self) -> bool {
+        false
+    }
+
+    fn disable(&self) {}
+}
+
+impl TileEncoder {
+    /// Encode a BGRA frame of `yuvfmt`, returns the frame data.
+    pub fn encode(&mut self, bgra: &[u8]) -> ResultType<Vec<u8>> {
+        let (w, h) = (self.width, self.height);
+        if bgra.len() < w * h * 4 {
+            bail!("wrong frame len, {} < {}", bgra.len(), w * h * 4);
+        }
+        self.cur.resize(w * h * BPP, 0);
+        let mask = self.mask;
+        for (src, dst) in bgra.chunks_exact(4).zip(self.cur.chunks_exact_mut(BPP)) {
+            dst[0] = src[2] & mask;
+            dst[1] = src[1] & mask;
+            dst[2] = src[0] & mask;
+        }
+        let key_request = KEY_FRAME_REQUEST.load(Ordering::SeqCst);
+        let key = self.key
+            || self.key_request != key_request
+            || self.last_key.elapsed() >= KEY_FRAME_INTERVAL;
+        if key {
+            self.key_request = key_request;
+            self.last_key = Instant::now();
+        }
+        let (cols, rows) = (
+            (w + TILE_SIZE - 1) / TILE_SIZE,
+            (h + TILE_SIZE - 1) / TILE_SIZE,
+        );
+        let mut body = vec![];
+        let mut count = 0u32;
+        for row in 0..rows {
+            for col in 0..cols {
+                let (x, y, tw, th) = tile_rect(w, h, col, row);
+                let changed = key
+                    || (y..y + th).any(|yy| {
+                        let start = (yy * w + x) * BPP;
+                        let end = start + tw * BPP;
+                        self.cur[start..end] != self.prev[start..end]
+                    });
+                if !changed {
+                    continue;
+                }
+                count += 1;
+                body.extend_from_slice(&(col as u16).to_le_bytes());
+                body.extend_from_slice(&(row as u16).to_le_bytes());
+                for yy in y..y + th {
+                    let start = (yy * w + x) * BPP;
+                    let line = &self.cur[start..start + tw * BPP];
+                    for i in 0..line.len() {
+                        let left = if i >= BPP { line[i - BPP] } else { 0 };
+                        body.push(line[i].wrapping_sub(left));
+                    }
+                }
+            }
+        }
+        std::mem::swap(&mut self.prev, &mut self.cur);
+        self.key = false;
+        let mut data = Vec::with_capacity(HEADER_LEN + body.len() / 4);
+        data.extend_from_slice(&MAGIC);
+        data.push(VERSION);
+        data.push(if key { FLAG_KEY } else { 0 });
+        data.extend_from_slice(&(w as u32).to_le_bytes());
+        data.extend_from_slice(&(h as u32).to_le_bytes());
+        data.extend_from_slice(&count.to_le_bytes());
+        if count > 0 {
+            data.extend_from_slice(&compress(&body));
+        }
+        Ok(data)
+    }
+}
+
+#[derive(Default)]
+pub struct TileDecoder {
+    width: usize,
+    height: usize,
+    // RGB
+    buf: Vec<u8>,
+}
+
+fn read_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
+
+impl TileDecoder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Apply the tiles of a frame, returns whether the image changed.
+    pub fn decode(&mut self, data: &[u8]) -> ResultType<bool> {
+        if data.len() < HEADER_LEN || !is_tile_frame(data) || data[MAGIC.len()] != VERSION {
+            bail!("invalid tile frame");
+        }
+        let pos = MAGIC.len() + 1;
+        let key = data[pos] & FLAG_KEY != 0;
+        let (w, h) = (
+            read_u32(data, pos + 1) as usize,
+            read_u32(data, pos + 5) as usize,
+        );
+        let count = read_u32(data, pos + 9) as usize;
+        if key {
+            if w == 0 || h == 0 || w > 16384 || h > 16384 {
+                bail!("invalid tile frame size {}x{}", w, h);
+            }
+            self.width = w;
+            self.height = h;
+            self.buf = vec![0; w * h * BPP];
+        } else if self.buf.is_empty() || (w, h) != (self.width, self.height) {
+            bail!("no key frame of the tiles");
+        }
+        if count == 0 {
+            return Ok(false);
+        }
+        let body = decompress(&data[HEADER_LEN..]);
+        let mut pos = 0;
+        for _ in 0..count {
+            if pos + 4 > body.len() {
+                bail!("truncated tile frame");
+            }
+            let col = u16::from_le_bytes([body[pos], body[pos + 1]]) as usize;
+            let row = u16::from_le_bytes([body[pos + 2], body[pos + 3]]) as usize;
+            pos += 4;
+            if col * TILE_SIZE >= w || row * TILE_SIZE >= h {
+                bail!("invalid tile {},{}", col, row);
+            }
+            let (x, y, tw, th) = tile_rect(w, h, col, row);
+            if pos + tw * th * BPP > body.len() {
+                bail!("truncated tile frame");
+            }
+            for yy in y..y + th {
+                let start = (yy * w + x) * BPP;
+                let line = &mut self.buf[start..start + tw * BPP];
+                for i in 0..line.len() {
+                    let left = if i >= BPP { line[i - BPP] } else { 0 };
+                    line[i] = body[pos].wrapping_add(left);
+                    pos += 1;
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    // rgb [in/out] fmt and stride must be set in ImageRgb
+    pub fn to(&self, rgb: &mut ImageRgb) -> ResultType<()> {
+        if self.buf.is_empty() {
+            return Err(anyhow!("no tile frame decoded"));
+        }
+        let bytes_per_pixel = match rgb.fmt() {
+            ImageFormat::Raw => 3,
+            ImageFormat::ARGB | ImageFormat::ABGR => 4,
+        };
+        let align = rgb.align().max(1);
+        let bytes_per_row = (self.width * bytes_per_pixel + align - 1) & !(align - 1);
+        rgb.w = self.width;
+        rgb.h = self.height;
+        rgb.raw.resize(self.height * bytes_per_row, 0);
+        let fmt = rgb.fmt();
+        for (src, dst) in self
+            .buf
+            .chunks_exact(self.width * BPP)
+            .zip(rgb.raw.chunks_exact_mut(bytes_per_row))
+        {
+            for (s, d) in src
+                .chunks_exact(BPP)
+                .zip(dst.chunks_exact_mut(bytes_per_pixel))
+            {
+                match fmt {
+                    ImageFormat::Raw => d.copy_from_slice(s),
+                    // B, G, R, A in memory
+                    ImageFormat::ARGB => d.copy_from_slice(&[s[2], s[1], s[0], 0xFF]),
+                    // R, G, B, A in memory
+                    ImageFormat::ABGR => d.copy_from_slice(&[s[0], s[1], s[2], 0xFF]),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::BR_BEST;
+
+    #[test]
+    fn test_tile() {
+        let (w, h) = (150, 70);
+        let mut encoder = TileEncoder::new(
+            EncoderCfg::TILE(TileEncoderConfig {
+                width: w,
+                height: h,
+                quality: BR_BEST,
+            }),
+            false,
+        )
+        .unwrap();
+        let mut decoder = TileDecoder::new();
+        let mut bgra: Vec<u8> = (0..w * h * 4).map(|i| (i * 7 % 251) as u8).collect();
+        let check = |decoder: &TileDecoder, bgra: &[u8]| {
+            let mut rgb = ImageRgb::new(ImageFormat::ARGB, 1);
+            decoder.to(&mut rgb).unwrap();
+            for (d, s) in rgb.raw.chunks_exact(4).zip(bgra.chunks_exact(4)) {
+                assert_eq!(&d[..3], &s[..3]);
+            }
+        };
+        let key = encoder.encode(&bgra).unwrap();
+        assert!(decoder.decode(&key).unwrap());
+        check(&decoder, &bgra);
+        // Unchanged
+        let empty = encoder.encode(&bgra).unwrap();
+        assert_eq!(empty.len(), HEADER_LEN);
+        assert!(!decoder.decode(&empty).unwrap());
+        // One pixel of the last tile
+        let i = ((h as usize - 1) * w as usize + w as usize - 1) * 4;
+        bgra[i] ^= 0xFF;
+        let delta = encoder.encode(&bgra).unwrap();
+        assert_eq!(read_u32(&delta, HEADER_LEN - 4), 1);
+        assert!(decoder.decode(&delta).unwrap());
+        check(&decoder, &bgra);
+        // A delta without its key frame
+        assert!(TileDecoder::new().decode(&delta).is_err());
+        // A new peer
+        request_key_frame();
+        let key = encoder.encode(&bgra).unwrap();
+        assert!(is_tile_frame(&key));
+        assert!(TileDecoder::new().decode(&key).unwrap());
+    }
+}
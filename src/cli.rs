@@ -1,17 +1,51 @@
-use crate::client::*;
+use crate::{
+    client::*,
+    file_delta,
+    transfer_limit::{self, TransferLimiter},
+};
 use async_trait::async_trait;
 use hbb_common::{
+    allow_err, bail,
     config::PeerConfig,
     config::READ_TIMEOUT,
+    fs::{self, get_string, new_send_confirm, DigestCheckResult},
     futures::{SinkExt, StreamExt},
     log,
     message_proto::*,
     protobuf::Message as _,
     rendezvous_proto::ConnType,
-    tokio::{self, sync::mpsc},
-    Stream,
+    timeout,
+    tokio::{
+        self,
+        sync::mpsc,
+        time::{self, Duration, Instant},
+    },
+    ResultType, Stream,
+};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
 };
-use std::sync::{Arc, RwLock};
+
+pub use crate::transfer_limit::TransferPriority;
+
+/// Process exit codes of the non-interactive file transfer mode.
+/// The exec mode exits with the code of the remote command, or one of the `EXEC_` codes.
+pub mod exit_code {
+    pub const OK: i32 = 0;
+    pub const USAGE: i32 = 1;
+    pub const CONNECTION: i32 = 2;
+    pub const LOGIN: i32 = 3;
+    pub const TRANSFER: i32 = 4;
+    pub const TIMEOUT: i32 = 5;
+    /// The remote command is killed after the timeout, as `timeout(1)`.
+    pub const EXEC_TIMEOUT: i32 = 124;
+    /// The peer cannot run the commands, like a Windows peer running as a service.
+    pub const EXEC_UNSUPPORTED: i32 = 126;
+    /// Failed to connect, to login or to start the remote command, as `ssh(1)`.
+    pub const EXEC_FAILED: i32 = 255;
+}
 
 #[derive(Clone)]
 pub struct Session {
@@ -19,6 +53,8 @@ pub struct Session {
     lc: Arc<RwLock<LoginConfigHandler>>,
     sender: mpsc::UnboundedSender<Data>,
     password: String,
+    // Prompt for the password if it is not saved or wrong, the login fails otherwise.
+    interactive: bool,
 }
 
 impl Session {
@@ -27,19 +63,42 @@ impl Session {
         if PeerConfig::load(id).password.is_empty() {
             password = rpassword::prompt_password("Enter password: ").unwrap();
         }
+        Self::with_conn_type(id, ConnType::PORT_FORWARD, sender, password, true)
+    }
+
+    /// A session of the non-interactive modes, which never prompts. An empty `password` uses the
+    /// saved one.
+    pub fn non_interactive(
+        id: &str,
+        conn_type: ConnType,
+        sender: mpsc::UnboundedSender<Data>,
+        password: String,
+    ) -> Self {
+        Self::with_conn_type(id, conn_type, sender, password, false)
+    }
+
+    fn with_conn_type(
+        id: &str,
+        conn_type: ConnType,
+        sender: mpsc::UnboundedSender<Data>,
+        password: String,
+        interactive: bool,
+    ) -> Self {
         let session = Self {
             id: id.to_owned(),
             sender,
             password,
+            interactive,
             lc: Default::default(),
         };
         session.lc.write().unwrap().initialize(
             id.to_owned(),
-            ConnType::PORT_FORWARD,
+            conn_type,
             None,
             false,
             None,
             None,
+            None,
         );
         session
     }
@@ -47,7 +106,7 @@ impl Session {
 
 #[async_trait]
 impl Interface for Session {
-    fn get_login_config_handler(&self) -> Arc<RwLock<LoginConfigHandler>> {
+    fn get_lch(&self) -> Arc<RwLock<LoginConfigHandler>> {
         return self.lc.clone();
     }
 
@@ -55,14 +114,24 @@ impl Interface for Session {
         match msgtype {
             "input-password" => {
                 self.sender
-                    .send(Data::Login((self.password.clone(), true)))
+                    .send(Data::Login((
+                        "".to_owned(),
+                        "".to_owned(),
+                        self.password.clone(),
+                        true,
+                    )))
                     .ok();
             }
+            "re-input-password" if !self.interactive => {
+                log::error!("{}: {}", title, text);
+                self.sender.send(Data::Close).ok();
+            }
             "re-input-password" => {
                 log::error!("{}: {}", title, text);
                 match rpassword::prompt_password("Enter password: ") {
                     Ok(password) => {
-                        let login_data = Data::Login((password, true));
+                        let login_data =
+                            Data::Login(("".to_owned(), "".to_owned(), password, true));
                         self.sender.send(login_data).ok();
                     }
                     Err(e) => {
@@ -87,6 +156,8 @@ impl Interface for Session {
         self.lc.write().unwrap().handle_peer_info(&pi);
     }
 
+    fn set_multiple_windows_session(&self, _sessions: Vec<WindowsSession>) {}
+
     async fn handle_hash(&self, pass: &str, hash: Hash, peer: &mut Stream) {
         log::info!(
             "password={}",
@@ -191,3 +262,1144 @@ pub async fn start_one_port_forward(
     }
     log::info!("port forward (:{}) exit", port);
 }
+
+/// Forward all the mappings of a `port_forward::ForwardProfile` toml file over one connection.
+#[tokio::main(flavor = "current_thread")]
+pub async fn start_port_forward_profile(path: String, key: String, token: String) {
+    let profile = match crate::port_forward::ForwardProfile::load(&path) {
+        Ok(profile) => profile,
+        Err(err) => {
+            log::error!("Failed to load port forward profile {}: {}", path, err);
+            return;
+        }
+    };
+    run_port_forward_profile(profile, key, token).await;
+}
+
+/// Run a local socks5 proxy, the destinations are connected by the peer.
+#[tokio::main(flavor = "current_thread")]
+pub async fn start_socks5_forward(
+    id: String,
+    port: i32,
+    bind: String,
+    allow_lan: bool,
+    key: String,
+    token: String,
+) {
+    let profile = crate::port_forward::ForwardProfile {
+        peer: id,
+        dynamic: vec![crate::port_forward::DynamicMapping {
+            local_port: port,
+            bind: Some(bind),
+            allow_lan,
+        }],
+        ..Default::default()
+    };
+    run_port_forward_profile(profile, key, token).await;
+}
+
+async fn run_port_forward_profile(
+    profile: crate::port_forward::ForwardProfile,
+    key: String,
+    token: String,
+) {
+    crate::common::test_rendezvous_server();
+    crate::common::test_nat_type();
+    let (sender, receiver) = mpsc::unbounded_channel::<Data>();
+    let handler = Session::new(&profile.peer, sender);
+    let peer = profile.peer.clone();
+    if let Err(err) = crate::port_forward::listen_profile(
+        profile,
+        handler.password.clone(),
+        handler.clone(),
+        receiver,
+        &key,
+        &token,
+        handler.lc.clone(),
+    )
+    .await
+    {
+        log::error!("Port forwarding to {} failed: {}", peer, err);
+    }
+    log::info!("port forwarding to {} exit", peer);
+}
+
+/// Print the statistics of the running port forward profiles as json.
+#[tokio::main(flavor = "current_thread")]
+pub async fn print_port_forward_stats() -> i32 {
+    match crate::ipc::get_port_forward_stats(1_000).await {
+        Ok(stats) => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&stats).unwrap_or_default()
+            );
+            exit_code::OK
+        }
+        Err(err) => {
+            eprintln!("Failed to get port forward stats: {}", err);
+            exit_code::CONNECTION
+        }
+    }
+}
+
+/// A single operation of the non-interactive file transfer mode.
+#[derive(Debug, Clone)]
+pub enum FileTransferAction {
+    /// Upload a local file or directory to the remote path.
+    Push { local: String, remote: String },
+    /// Download a remote file or directory to the local path.
+    Pull { remote: String, local: String },
+    /// Print the remote directory as json.
+    List { remote: String },
+    /// Mirror a local directory to the remote one, only the files which differ in size or
+    /// modification time are sent.
+    ///
+    /// Only the changed blocks of the files which exist on both sides are sent, see
+    /// `crate::file_delta`, the peers without it get the changed files whole.
+    Sync {
+        local: String,
+        remote: String,
+        /// Remove the remote files and directories which do not exist locally.
+        delete: bool,
+        /// Print the plan as json without changing anything.
+        dry_run: bool,
+    },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FileTransferOptions {
+    pub include_hidden: bool,
+    /// Overwrite files which already exist on the receiving side, skip them otherwise.
+    pub overwrite: bool,
+    /// Cap of the sending rate, 0 for none.
+    pub max_kbps: u32,
+    pub priority: TransferPriority,
+}
+
+/// Connect to `id` as a file transfer session, run `action` and return the process exit code.
+#[tokio::main(flavor = "current_thread")]
+pub async fn start_file_transfer(
+    id: String,
+    action: FileTransferAction,
+    options: FileTransferOptions,
+    password: String,
+    key: String,
+    token: String,
+) -> i32 {
+    let (sender, mut receiver) = mpsc::unbounded_channel::<Data>();
+    let handler = Session::non_interactive(&id, ConnType::FILE_TRANSFER, sender, password);
+    let mut stream = match cli_login(&handler, &mut receiver, &key, &token).await {
+        Ok(Some(stream)) => stream,
+        Ok(None) => {
+            log::error!("Failed to login {}", id);
+            return exit_code::LOGIN;
+        }
+        Err(err) => {
+            log::error!("Failed to connect {}: {}", id, err);
+            return exit_code::CONNECTION;
+        }
+    };
+    let mut transfer = FileTransfer::new(handler, options);
+    let code = transfer.run(action, &mut stream).await;
+    log::info!("file transfer with {} exit, code: {}", id, code);
+    code
+}
+
+/// Connect and login with the connection type of `handler`, returns the stream once the peer info
+/// is received.
+async fn cli_login(
+    handler: &Session,
+    receiver: &mut mpsc::UnboundedReceiver<Data>,
+    key: &str,
+    token: &str,
+) -> ResultType<Option<Stream>> {
+    let conn_type = handler.lc.read().unwrap().conn_type;
+    let ((mut stream, direct, _pk, _kcp, _stream_type), (feedback, rendezvous_server)) =
+        Client::start(&handler.id, key, token, conn_type, handler.clone()).await?;
+    handler.update_direct(Some(direct));
+    let _keep_it = hc_connection(feedback, rendezvous_server, token).await;
+    loop {
+        tokio::select! {
+            res = timeout(READ_TIMEOUT, stream.next()) => match res {
+                Err(_) => {
+                    bail!("Timeout");
+                }
+                Ok(Some(Ok(bytes))) => {
+                    handler.update_received(true);
+                    let msg_in = Message::parse_from_bytes(&bytes)?;
+                    match msg_in.union {
+                        Some(message::Union::Hash(hash)) => {
+                            handler.handle_hash(&handler.password, hash, &mut stream).await;
+                        }
+                        Some(message::Union::LoginResponse(lr)) => match lr.union {
+                            Some(login_response::Union::Error(err)) => {
+                                if !handler.handle_login_error(&err) {
+                                    return Ok(None);
+                                }
+                            }
+                            Some(login_response::Union::PeerInfo(pi)) => {
+                                handler.handle_peer_info(pi);
+                                if handler.lc.read().unwrap().ext_messages {
+                                    let hello = crate::common::new_ext_message(
+                                        crate::common::EXT_HELLO_ID,
+                                        vec![],
+                                    );
+                                    allow_err!(stream.send(&hello).await);
+                                }
+                                return Ok(Some(stream));
+                            }
+                            _ => {}
+                        }
+                        Some(message::Union::TestDelay(t)) => {
+                            handler.handle_test_delay(t, &mut stream).await;
+                        }
+                        _ => {}
+                    }
+                }
+                Ok(Some(Err(err))) => {
+                    bail!("Connection closed: {}", err);
+                }
+                _ => {
+                    bail!("Reset by the peer");
+                }
+            },
+            d = receiver.recv() => {
+                match d {
+                    Some(Data::Login((os_username, os_password, password, remember))) => {
+                        handler.handle_login_from_ui(os_username, os_password, password, remember, &mut stream).await;
+                    }
+                    Some(Data::Message(msg)) => {
+                        allow_err!(stream.send(&msg).await);
+                    }
+                    Some(Data::Close) => {
+                        return Ok(None);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// A command of the non-interactive exec mode, run without a shell.
+#[derive(Debug, Clone, Default)]
+pub struct ExecOptions {
+    pub command: String,
+    pub args: Vec<String>,
+    /// `NAME=VALUE` items.
+    pub env: Vec<String>,
+    /// Empty for the working dir of the peer service.
+    pub cwd: String,
+    /// 0 for the default timeout of the peer.
+    pub timeout_secs: u32,
+}
+
+/// Connect to `id` as a terminal session, run the command without a terminal and return its exit
+/// code. The stdout and stderr of the command are written to the ones of this process.
+#[tokio::main(flavor = "current_thread")]
+pub async fn start_exec(
+    id: String,
+    options: ExecOptions,
+    password: String,
+    key: String,
+    token: String,
+) -> i32 {
+    let (sender, mut receiver) = mpsc::unbounded_channel::<Data>();
+    let handler = Session::non_interactive(&id, ConnType::TERMINAL, sender, password);
+    // Do not attach to the terminals of the peer.
+    handler.lc.write().unwrap().clear_terminal_service_id();
+    let mut stream = match cli_login(&handler, &mut receiver, &key, &token).await {
+        Ok(Some(stream)) => stream,
+        Ok(None) => {
+            log::error!("Failed to login {}", id);
+            return exit_code::EXEC_FAILED;
+        }
+        Err(err) => {
+            log::error!("Failed to connect {}: {}", id, err);
+            return exit_code::EXEC_FAILED;
+        }
+    };
+    let code = run_exec(&handler, options, &mut stream).await;
+    log::info!("exec on {} exit, code: {}", id, code);
+    code
+}
+
+async fn run_exec(handler: &Session, options: ExecOptions, stream: &mut Stream) -> i32 {
+    use crate::remote_exec::{ExecCommand, ExecMessage};
+    use std::io::Write;
+
+    let exec_id = 1;
+    let msg_out = ExecMessage::Command(ExecCommand {
+        exec_id,
+        command: options.command,
+        args: options.args,
+        env: options
+            .env
+            .iter()
+            .filter_map(|x| x.split_once('='))
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect(),
+        cwd: options.cwd,
+        timeout_ms: options.timeout_secs.saturating_mul(1000),
+    })
+    .to_message();
+    allow_err!(stream.send(&msg_out).await);
+
+    let mut last_recv_time = Instant::now();
+    let mut timer = crate::rustdesk_interval(time::interval(Duration::from_secs(1)));
+    loop {
+        tokio::select! {
+            res = stream.next() => {
+                let bytes = match res {
+                    Some(Ok(bytes)) => bytes,
+                    Some(Err(err)) => {
+                        log::error!("Connection closed: {}", err);
+                        return exit_code::EXEC_FAILED;
+                    }
+                    None => {
+                        log::error!("Reset by the peer");
+                        return exit_code::EXEC_FAILED;
+                    }
+                };
+                last_recv_time = Instant::now();
+                let Ok(msg_in) = Message::parse_from_bytes(&bytes) else {
+                    continue;
+                };
+                match msg_in.union {
+                    Some(message::Union::TestDelay(t)) => {
+                        handler.handle_test_delay(t, stream).await;
+                    }
+                    Some(message::Union::Misc(misc)) => match misc.union {
+                        Some(misc::Union::CloseReason(c)) => {
+                            log::error!("Connection closed by the peer: {}", c);
+                            return exit_code::EXEC_FAILED;
+                        }
+                        Some(misc::Union::PluginRequest(p)) if p.id == crate::remote_exec::EXT_ID => {
+                            match ExecMessage::decode(&p.content) {
+                                Ok(ExecMessage::Output { exec_id: id, is_stderr, data }) if id == exec_id => {
+                                    if is_stderr {
+                                        let mut stderr = std::io::stderr();
+                                        stderr.write_all(&data).ok();
+                                        stderr.flush().ok();
+                                    } else {
+                                        let mut stdout = std::io::stdout();
+                                        stdout.write_all(&data).ok();
+                                        stdout.flush().ok();
+                                    }
+                                }
+                                Ok(ExecMessage::Exit(exit)) if exit.exec_id == exec_id => {
+                                    if exit.unsupported {
+                                        log::error!("Exec is not supported: {}", exit.error);
+                                        return exit_code::EXEC_UNSUPPORTED;
+                                    }
+                                    if !exit.error.is_empty() {
+                                        log::error!("Failed to exec: {}", exit.error);
+                                        return exit_code::EXEC_FAILED;
+                                    }
+                                    if exit.timed_out {
+                                        log::error!("The command is killed after the timeout");
+                                        return exit_code::EXEC_TIMEOUT;
+                                    }
+                                    return exit.exit_code;
+                                }
+                                Ok(_) => {}
+                                Err(e) => log::error!("Invalid exec message: {}", e),
+                            }
+                        }
+                        _ => {}
+                    },
+                    Some(message::Union::MessageBox(msgbox)) => {
+                        handler.msgbox(&msgbox.msgtype, &msgbox.title, &msgbox.text, &msgbox.link);
+                    }
+                    _ => {}
+                }
+            }
+            _ = timer.tick() => {
+                if last_recv_time.elapsed() >= SEC30 {
+                    log::error!("Timeout");
+                    return exit_code::EXEC_FAILED;
+                }
+            }
+        }
+    }
+}
+
+/// Drives one file transfer job on an authenticated stream, without any ui.
+struct FileTransfer {
+    handler: Session,
+    options: FileTransferOptions,
+    job_id: i32,
+    read_jobs: Vec<fs::TransferJob>,
+    write_jobs: Vec<fs::TransferJob>,
+    limiter: TransferLimiter,
+    timer: crate::RustDeskInterval,
+    // Waiting for the remote listing to plan the sync.
+    sync: Option<SyncRequest>,
+    // Remote files and directories being removed by the sync, answered with the id `job_id + 1`.
+    pending_removes: usize,
+    file_delta: file_delta::Client,
+    tx_sync: mpsc::UnboundedSender<SyncEvent>,
+    rx_sync: mpsc::UnboundedReceiver<SyncEvent>,
+    // The job and the files to send whole once the blocks of the changed files are sent.
+    pending_copy: Option<(fs::TransferJob, Vec<String>)>,
+    error: Option<String>,
+}
+
+enum SyncEvent {
+    Send(Message),
+    // The changed files whose blocks could not be sent.
+    DeltaDone(Vec<String>),
+}
+
+struct SyncRequest {
+    local: String,
+    remote: String,
+    delete: bool,
+    dry_run: bool,
+    job: fs::TransferJob,
+}
+
+/// The difference of a local tree from a remote one, by relative path.
+#[derive(Debug, Default, serde_derive::Serialize)]
+struct SyncPlan {
+    /// Files missing on the remote side.
+    copy: Vec<String>,
+    /// Files which differ on the remote side, only their changed blocks are sent.
+    changed: Vec<String>,
+    delete: Vec<String>,
+    /// Directories missing locally, removed with all of their content.
+    delete_dirs: Vec<String>,
+    bytes: u64,
+}
+
+fn is_dir_entry(e: &FileEntry) -> bool {
+    matches!(
+        e.entry_type.enum_value(),
+        Ok(FileType::Dir) | Ok(FileType::DirLink) | Ok(FileType::DirDrive)
+    )
+}
+
+/// Whether the error of a listing is a missing path, the peer only sends the message of the error.
+fn is_not_found_error(error: &str) -> bool {
+    // ENOENT, or ERROR_FILE_NOT_FOUND and ERROR_PATH_NOT_FOUND of Windows
+    ["(os error 2)", "(os error 3)"]
+        .iter()
+        .any(|code| error.ends_with(code))
+}
+
+fn normalize(name: &str) -> String {
+    name.replace('\\', "/")
+}
+
+/// The directories of a listing, including the parents of the files.
+fn listed_dirs(entries: &[FileEntry]) -> BTreeSet<String> {
+    let mut dirs = BTreeSet::new();
+    for e in entries {
+        let name = normalize(&e.name);
+        if is_dir_entry(e) {
+            dirs.insert(name.clone());
+        }
+        let mut parent = name.as_str();
+        while let Some((dir, _)) = parent.rsplit_once('/') {
+            dirs.insert(dir.to_owned());
+            parent = dir;
+        }
+    }
+    dirs.remove("");
+    dirs
+}
+
+fn is_in_dirs(name: &str, dirs: &HashSet<&String>) -> bool {
+    let mut parent = name;
+    while let Some((dir, _)) = parent.rsplit_once('/') {
+        if dirs.contains(&dir.to_owned()) {
+            return true;
+        }
+        parent = dir;
+    }
+    false
+}
+
+impl SyncPlan {
+    fn new(local: &[FileEntry], remote: &[FileEntry], delete: bool) -> Self {
+        let remote_files: HashMap<String, &FileEntry> = remote
+            .iter()
+            .filter(|e| !is_dir_entry(e))
+            .map(|e| (normalize(&e.name), e))
+            .collect();
+        let mut plan = Self::default();
+        for e in local.iter().filter(|e| !is_dir_entry(e)) {
+            match remote_files.get(&normalize(&e.name)) {
+                Some(r) if r.size == e.size && r.modified_time == e.modified_time => continue,
+                Some(_) => plan.changed.push(e.name.clone()),
+                None => plan.copy.push(e.name.clone()),
+            }
+            plan.bytes += e.size;
+        }
+        if delete {
+            let local_dirs = listed_dirs(local);
+            let remote_dirs = listed_dirs(remote);
+            let gone: HashSet<&String> = remote_dirs.difference(&local_dirs).collect();
+            // The directories are removed recursively, skip the content of removed ones.
+            plan.delete_dirs = remote_dirs
+                .iter()
+                .filter(|d| gone.contains(d) && !is_in_dirs(d, &gone))
+                .cloned()
+                .collect();
+            let local_files: HashSet<String> = local.iter().map(|e| normalize(&e.name)).collect();
+            plan.delete = remote
+                .iter()
+                .filter(|e| !is_dir_entry(e))
+                .filter(|e| {
+                    let name = normalize(&e.name);
+                    !local_files.contains(&name) && !is_in_dirs(&name, &gone)
+                })
+                .map(|e| e.name.clone())
+                .collect();
+        }
+        plan
+    }
+}
+
+/// Send the changed blocks of the files which exist on both sides, returns the files which have
+/// to be sent whole.
+async fn sync_changed_files(
+    client: file_delta::Client,
+    local: PathBuf,
+    remote: String,
+    sep: &'static str,
+    files: Vec<String>,
+    mut limiter: TransferLimiter,
+) -> Vec<String> {
+    let mut failed = Vec::new();
+    for name in files {
+        let local_path = fs::TransferJob::join(&local, &name);
+        let remote_path = format!("{}{}{}", remote, sep, name.replace(['/', '\\'], sep));
+        match sync_file_blocks(&client, &local_path, &remote_path, &mut limiter).await {
+            Ok((sent, size)) => log::info!("sync {}: {} of {} bytes sent", name, sent, size),
+            Err(err) => {
+                log::warn!("Failed to send the changed blocks of {}: {}", name, err);
+                failed.push(name);
+            }
+        }
+    }
+    failed
+}
+
+/// Returns the bytes sent and the size of the file.
+async fn sync_file_blocks(
+    client: &file_delta::Client,
+    local: &Path,
+    remote: &str,
+    limiter: &mut TransferLimiter,
+) -> ResultType<(u64, u64)> {
+    let Some((size, modified)) = file_delta::file_meta(local) else {
+        bail!("No such file");
+    };
+    let (remote_size, _) = client.stat(remote).await?;
+    let block_size = file_delta::block_size_for(size.max(remote_size));
+    let (_, remote_sums) = client.sums(remote, block_size).await?;
+    let path = local.to_owned();
+    let (size, sums) =
+        tokio::task::spawn_blocking(move || file_delta::block_sums(&path, block_size)).await??;
+    let ranges = file_delta::changed_ranges(&sums, &remote_sums, size, block_size);
+    let sent = client.upload(local, remote, &ranges, Some(limiter)).await?;
+    client.truncate(remote, size, modified).await?;
+    let path = local.to_owned();
+    let sha256 = tokio::task::spawn_blocking(move || file_delta::sha256_file(&path)).await??;
+    if client.hash(remote).await? != (size, sha256) {
+        bail!("the file differs after sending the changed blocks");
+    }
+    Ok((sent, size))
+}
+
+impl FileTransfer {
+    fn new(handler: Session, options: FileTransferOptions) -> Self {
+        let (tx_sync, rx_sync) = mpsc::unbounded_channel();
+        let tx = tx_sync.clone();
+        let file_delta = file_delta::Client::new(move |msg| {
+            tx.send(SyncEvent::Send(msg)).ok();
+        });
+        Self {
+            handler,
+            limiter: Self::new_limiter(&options),
+            options,
+            job_id: fs::get_next_job_id(),
+            read_jobs: Vec::new(),
+            write_jobs: Vec::new(),
+            timer: crate::rustdesk_interval(time::interval(MILLI1)),
+            sync: None,
+            pending_removes: 0,
+            file_delta,
+            tx_sync,
+            rx_sync,
+            pending_copy: None,
+            error: None,
+        }
+    }
+
+    fn new_limiter(options: &FileTransferOptions) -> TransferLimiter {
+        let mut limiter = TransferLimiter::new(options.priority);
+        limiter.set_session_kbps(Some(options.max_kbps));
+        limiter
+    }
+
+    fn remote_sep(&self) -> &'static str {
+        if self.is_peer_windows() {
+            "\\"
+        } else {
+            "/"
+        }
+    }
+
+    /// Send the files of `plan` and remove the extraneous ones, returns false if there is nothing
+    /// to do.
+    async fn start_sync(&mut self, sync: SyncRequest, plan: SyncPlan, stream: &mut Stream) -> bool {
+        self.pending_removes = plan.delete.len() + plan.delete_dirs.len();
+        let sep = self.remote_sep();
+        let remote = sync.remote.trim_end_matches(|c| c == '/' || c == '\\');
+        let remote_path =
+            |name: &str| format!("{}{}{}", remote, sep, name.replace(['/', '\\'], sep));
+        for (i, name) in plan.delete.iter().enumerate() {
+            log::info!("remove remote {}", name);
+            let mut msg_out = Message::new();
+            let mut file_action = FileAction::new();
+            file_action.set_remove_file(FileRemoveFile {
+                id: self.job_id + 1,
+                path: remote_path(name),
+                file_num: i as _,
+                ..Default::default()
+            });
+            msg_out.set_file_action(file_action);
+            allow_err!(stream.send(&msg_out).await);
+        }
+        for name in plan.delete_dirs.iter() {
+            log::info!("remove remote directory {}", name);
+            let mut msg_out = Message::new();
+            let mut file_action = FileAction::new();
+            file_action.set_remove_dir(FileRemoveDir {
+                id: self.job_id + 1,
+                path: remote_path(name),
+                recursive: true,
+                ..Default::default()
+            });
+            msg_out.set_file_action(file_action);
+            allow_err!(stream.send(&msg_out).await);
+        }
+        let mut copy = plan.copy;
+        if !plan.changed.is_empty() {
+            if self.handler.lc.read().unwrap().ext_messages {
+                let tx = self.tx_sync.clone();
+                let task = sync_changed_files(
+                    self.file_delta.clone(),
+                    PathBuf::from(&sync.local),
+                    remote.to_owned(),
+                    sep,
+                    plan.changed,
+                    Self::new_limiter(&self.options),
+                );
+                tokio::spawn(async move {
+                    tx.send(SyncEvent::DeltaDone(task.await)).ok();
+                });
+                self.pending_copy = Some((sync.job, copy));
+                return true;
+            }
+            copy.extend(plan.changed);
+        }
+        self.start_copy(sync.job, copy, &sync.remote, stream).await || self.pending_removes > 0
+    }
+
+    /// Send `copy` of the files of `job` whole, returns false if there is nothing to send.
+    async fn start_copy(
+        &mut self,
+        mut job: fs::TransferJob,
+        copy: Vec<String>,
+        remote: &str,
+        stream: &mut Stream,
+    ) -> bool {
+        if copy.is_empty() {
+            return false;
+        }
+        let copy: HashSet<String> = copy.into_iter().collect();
+        let files: Vec<FileEntry> = job
+            .files()
+            .iter()
+            .filter(|e| copy.contains(&e.name))
+            .cloned()
+            .collect();
+        job.set_files(files);
+        job.set_overwrite_strategy(Some(true));
+        #[cfg(not(windows))]
+        let files = job.files().clone();
+        #[cfg(windows)]
+        let mut files = job.files().clone();
+        #[cfg(windows)]
+        if !self.is_peer_windows() {
+            fs::transform_windows_path(&mut files);
+        }
+        let total_size = job.total_size();
+        log::info!("sync {} files, {} bytes to send", files.len(), total_size);
+        self.read_jobs.push(job);
+        self.timer = crate::rustdesk_interval(time::interval(MILLI1));
+        allow_err!(
+            stream
+                .send(&fs::new_receive(
+                    self.job_id,
+                    remote.to_owned(),
+                    0,
+                    files,
+                    total_size
+                ))
+                .await
+        );
+        true
+    }
+
+    fn is_peer_windows(&self) -> bool {
+        self.handler.lc.read().unwrap().info.platform == crate::PLATFORM_WINDOWS
+    }
+
+    async fn run(&mut self, action: FileTransferAction, stream: &mut Stream) -> i32 {
+        let od = fs::can_enable_overwrite_detection(self.handler.lc.read().unwrap().version);
+        match action {
+            FileTransferAction::List { remote } => {
+                let mut msg_out = Message::new();
+                let mut file_action = FileAction::new();
+                file_action.set_read_dir(ReadDir {
+                    path: remote,
+                    include_hidden: self.options.include_hidden,
+                    ..Default::default()
+                });
+                msg_out.set_file_action(file_action);
+                allow_err!(stream.send(&msg_out).await);
+            }
+            FileTransferAction::Push { local, remote } => {
+                log::info!("push {} to remote {}", local, remote);
+                match fs::TransferJob::new_read(
+                    self.job_id,
+                    fs::JobType::Generic,
+                    remote.clone(),
+                    fs::DataSource::FilePath(PathBuf::from(&local)),
+                    0,
+                    self.options.include_hidden,
+                    true,
+                    od,
+                ) {
+                    Ok(mut job) => {
+                        job.set_overwrite_strategy(Some(self.options.overwrite));
+                        #[cfg(not(windows))]
+                        let files = job.files().clone();
+                        #[cfg(windows)]
+                        let mut files = job.files().clone();
+                        #[cfg(windows)]
+                        if !self.is_peer_windows() {
+                            fs::transform_windows_path(&mut files);
+                        }
+                        let total_size = job.total_size();
+                        log::info!("{} files, {} bytes to send", files.len(), total_size);
+                        self.read_jobs.push(job);
+                        allow_err!(
+                            stream
+                                .send(&fs::new_receive(self.job_id, remote, 0, files, total_size))
+                                .await
+                        );
+                    }
+                    Err(err) => {
+                        log::error!("Failed to read {}: {}", local, err);
+                        return exit_code::TRANSFER;
+                    }
+                }
+            }
+            FileTransferAction::Sync {
+                local,
+                remote,
+                delete,
+                dry_run,
+            } => {
+                log::info!("sync {} to remote {}", local, remote);
+                // Changed files are always replaced.
+                self.options.overwrite = true;
+                let job = match fs::TransferJob::new_read(
+                    self.job_id,
+                    fs::JobType::Generic,
+                    remote.clone(),
+                    fs::DataSource::FilePath(PathBuf::from(&local)),
+                    0,
+                    self.options.include_hidden,
+                    true,
+                    od,
+                ) {
+                    Ok(job) => job,
+                    Err(err) => {
+                        log::error!("Failed to read {}: {}", local, err);
+                        return exit_code::TRANSFER;
+                    }
+                };
+                self.sync = Some(SyncRequest {
+                    local,
+                    remote: remote.clone(),
+                    delete,
+                    dry_run,
+                    job,
+                });
+                let mut msg_out = Message::new();
+                let mut file_action = FileAction::new();
+                file_action.set_all_files(ReadAllFiles {
+                    id: self.job_id,
+                    path: remote,
+                    include_hidden: self.options.include_hidden,
+                    ..Default::default()
+                });
+                msg_out.set_file_action(file_action);
+                allow_err!(stream.send(&msg_out).await);
+            }
+            FileTransferAction::Pull { remote, local } => {
+                log::info!("pull remote {} to {}", remote, local);
+                let mut job = fs::TransferJob::new_write(
+                    self.job_id,
+                    fs::JobType::Generic,
+                    remote.clone(),
+                    fs::DataSource::FilePath(PathBuf::from(&local)),
+                    0,
+                    self.options.include_hidden,
+                    true,
+                    Vec::new(),
+                    od,
+                );
+                job.set_overwrite_strategy(Some(self.options.overwrite));
+                self.write_jobs.push(job);
+                allow_err!(
+                    stream
+                        .send(&fs::new_send(
+                            self.job_id,
+                            fs::JobType::Generic,
+                            remote,
+                            0,
+                            self.options.include_hidden
+                        ))
+                        .await
+                );
+            }
+        }
+        let mut last_recv_time = Instant::now();
+        loop {
+            tokio::select! {
+                Some(event) = self.rx_sync.recv() => match event {
+                    SyncEvent::Send(msg) => {
+                        allow_err!(stream.send(&msg).await);
+                    }
+                    SyncEvent::DeltaDone(failed) => {
+                        if let Some((job, mut copy)) = self.pending_copy.take() {
+                            copy.extend(failed);
+                            let remote = job.remote.clone();
+                            self.start_copy(job, copy, &remote, stream).await;
+                        }
+                        if self.is_sent() {
+                            return self.finish();
+                        }
+                    }
+                },
+                res = stream.next() => {
+                    match res {
+                        Some(Ok(bytes)) => {
+                            last_recv_time = Instant::now();
+                            if let Some(code) = self.handle_msg_from_peer(&bytes, stream).await {
+                                return code;
+                            }
+                        }
+                        Some(Err(err)) => {
+                            log::error!("Connection closed: {}", err);
+                            return exit_code::CONNECTION;
+                        }
+                        None => {
+                            log::error!("Reset by the peer");
+                            return exit_code::CONNECTION;
+                        }
+                    }
+                }
+                _ = self.timer.tick() => {
+                    if last_recv_time.elapsed() >= SEC30 {
+                        log::error!("Timeout");
+                        return exit_code::TIMEOUT;
+                    }
+                    if !self.read_jobs.is_empty() {
+                        if let Err(err) = transfer_limit::handle_read_jobs(
+                            &mut self.read_jobs,
+                            stream,
+                            &mut self.limiter,
+                        )
+                        .await
+                        {
+                            log::error!("Failed to send files: {}", err);
+                            return exit_code::CONNECTION;
+                        }
+                        if self.is_sent() {
+                            return self.finish();
+                        }
+                    } else {
+                        let interval = Duration::from_secs(1);
+                        self.timer = crate::rustdesk_interval(time::interval_at(Instant::now() + interval, interval));
+                    }
+                }
+            }
+        }
+    }
+
+    fn is_sent(&self) -> bool {
+        self.pending_removes == 0
+            && self.pending_copy.is_none()
+            && self.read_jobs.iter().all(|j| j.id != self.job_id)
+    }
+
+    fn finish(&self) -> i32 {
+        match &self.error {
+            Some(err) => {
+                log::error!("File transfer failed: {}", err);
+                exit_code::TRANSFER
+            }
+            None => {
+                log::info!("File transfer done");
+                exit_code::OK
+            }
+        }
+    }
+
+    fn confirm_req(id: i32, file_num: i32, overwrite: bool) -> FileTransferSendConfirmRequest {
+        FileTransferSendConfirmRequest {
+            id,
+            file_num,
+            union: Some(if overwrite {
+                file_transfer_send_confirm_request::Union::OffsetBlk(0)
+            } else {
+                file_transfer_send_confirm_request::Union::Skip(true)
+            }),
+            ..Default::default()
+        }
+    }
+
+    async fn on_sync_listing(&mut self, remote: &[FileEntry], stream: &mut Stream) -> Option<i32> {
+        let sync = self.sync.take()?;
+        let plan = SyncPlan::new(sync.job.files(), remote, sync.delete);
+        if sync.dry_run {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&plan).unwrap_or_default()
+            );
+            return Some(exit_code::OK);
+        }
+        log::info!(
+            "sync {}: {} new and {} changed files to send, {} files and {} directories to remove",
+            sync.local,
+            plan.copy.len(),
+            plan.changed.len(),
+            plan.delete.len(),
+            plan.delete_dirs.len()
+        );
+        if self.start_sync(sync, plan, stream).await {
+            None
+        } else {
+            log::info!("Already up to date");
+            Some(self.finish())
+        }
+    }
+
+    /// Returns the exit code once the job is finished.
+    async fn handle_msg_from_peer(&mut self, data: &[u8], stream: &mut Stream) -> Option<i32> {
+        let Ok(msg_in) = Message::parse_from_bytes(data) else {
+            return None;
+        };
+        match msg_in.union {
+            Some(message::Union::FileResponse(fr)) => match fr.union {
+                Some(file_response::Union::Dir(fd)) => {
+                    #[cfg(windows)]
+                    let entries = fd.entries.to_vec();
+                    #[cfg(not(windows))]
+                    let mut entries = fd.entries.to_vec();
+                    #[cfg(not(windows))]
+                    if self.is_peer_windows() {
+                        fs::transform_windows_path(&mut entries);
+                    }
+                    if let Some(job) = fs::get_job(fd.id, &mut self.write_jobs) {
+                        log::info!("{} files to receive", entries.len());
+                        job.set_files(entries);
+                    } else if fd.id == self.job_id && self.sync.is_some() {
+                        return self.on_sync_listing(&entries, stream).await;
+                    } else if self.read_jobs.is_empty() {
+                        println!(
+                            "{}",
+                            crate::common::make_fd_to_json(fd.id, fd.path, &entries)
+                        );
+                        return Some(exit_code::OK);
+                    }
+                }
+                Some(file_response::Union::Digest(digest)) => {
+                    let overwrite = self.options.overwrite;
+                    if digest.is_upload {
+                        if let Some(job) = fs::get_job(digest.id, &mut self.read_jobs) {
+                            let req = Self::confirm_req(digest.id, digest.file_num, overwrite);
+                            job.confirm(&req);
+                            allow_err!(stream.send(&new_send_confirm(req)).await);
+                        }
+                    } else if let Some(job) = fs::get_job(digest.id, &mut self.write_jobs) {
+                        let Some(file) = job.files().get(digest.file_num as usize) else {
+                            return None;
+                        };
+                        let fs::DataSource::FilePath(p) = &job.data_source else {
+                            return None;
+                        };
+                        let write_path = get_string(&fs::TransferJob::join(p, &file.name));
+                        let overwrite = match fs::is_write_need_confirmation(&write_path, &digest) {
+                            Ok(DigestCheckResult::IsSame) => false,
+                            Ok(DigestCheckResult::NeedConfirm(_)) => {
+                                if !overwrite {
+                                    log::warn!("Skip existing file {}", write_path);
+                                }
+                                overwrite
+                            }
+                            Ok(DigestCheckResult::NoSuchFile) => true,
+                            Err(err) => {
+                                log::error!("Failed to check digest of {}: {}", write_path, err);
+                                false
+                            }
+                        };
+                        let req = Self::confirm_req(digest.id, digest.file_num, overwrite);
+                        job.confirm(&req);
+                        allow_err!(stream.send(&new_send_confirm(req)).await);
+                    }
+                }
+                Some(file_response::Union::Block(block)) => {
+                    if let Some(job) = fs::get_job(block.id, &mut self.write_jobs) {
+                        if let Err(err) = job.write(block).await {
+                            self.error = Some(err.to_string());
+                        }
+                    }
+                }
+                Some(file_response::Union::Done(d)) => {
+                    if d.id == self.job_id + 1 {
+                        self.pending_removes = self.pending_removes.saturating_sub(1);
+                        if self.is_sent() {
+                            return Some(self.finish());
+                        }
+                    } else if let Some(job) = fs::remove_job(d.id, &mut self.write_jobs) {
+                        job.modify_time();
+                        if let Some(err) = job.job_error() {
+                            self.error = Some(err);
+                        }
+                        return Some(self.finish());
+                    }
+                }
+                Some(file_response::Union::Error(e)) => {
+                    if e.id == crate::common::READ_DIR_ERROR_ID && self.read_jobs.is_empty() {
+                        log::error!("Failed to read remote directory: {}", e.error);
+                        return Some(exit_code::TRANSFER);
+                    }
+                    if e.id == self.job_id && self.sync.is_some() {
+                        if is_not_found_error(&e.error) {
+                            // The remote directory does not exist yet.
+                            log::info!("Failed to read remote directory: {}", e.error);
+                            return self.on_sync_listing(&[], stream).await;
+                        }
+                        log::error!("Failed to read remote directory: {}", e.error);
+                        return Some(exit_code::TRANSFER);
+                    }
+                    if e.id == self.job_id + 1 {
+                        log::error!("Failed to remove remote file: {}", e.error);
+                        self.error = Some(e.error);
+                        self.pending_removes = self.pending_removes.saturating_sub(1);
+                        if self.is_sent() {
+                            return Some(self.finish());
+                        }
+                    } else if e.id == self.job_id {
+                        if let Some(job) = fs::remove_job(e.id, &mut self.write_jobs) {
+                            job.remove_download_file();
+                        }
+                        fs::remove_job(e.id, &mut self.read_jobs);
+                        self.error = Some(e.error);
+                        return Some(self.finish());
+                    }
+                }
+                _ => {}
+            },
+            Some(message::Union::FileAction(action)) => match action.union {
+                Some(file_action::Union::SendConfirm(c)) => {
+                    if let Some(job) = fs::get_job(c.id, &mut self.read_jobs) {
+                        job.confirm(&c);
+                    }
+                }
+                _ => {}
+            },
+            Some(message::Union::Misc(misc)) => match misc.union {
+                Some(misc::Union::CloseReason(c)) => {
+                    log::error!("Connection closed by the peer: {}", c);
+                    return Some(exit_code::CONNECTION);
+                }
+                Some(misc::Union::PluginRequest(p)) if p.id == file_delta::ID => {
+                    self.file_delta.on_response(&p.content);
+                }
+                Some(misc::Union::PluginRequest(p)) if p.id == transfer_limit::EXT_ID => {
+                    transfer_limit::on_network_delay(
+                        &self.handler.id,
+                        transfer_limit::decode_delay(&p.content),
+                    );
+                }
+                _ => {}
+            },
+            Some(message::Union::MessageBox(msgbox)) => {
+                self.handler
+                    .msgbox(&msgbox.msgtype, &msgbox.title, &msgbox.text, &msgbox.link);
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, size: u64, modified_time: u64) -> FileEntry {
+        FileEntry {
+            name: name.to_owned(),
+            size,
+            modified_time,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_sync_plan() {
+        let local = vec![
+            entry("a", 1, 10),
+            entry("dir/b", 2, 20),
+            entry("dir/c", 3, 30),
+        ];
+        let remote = vec![
+            entry("a", 1, 10),
+            entry("dir\\b", 2, 21),
+            entry("d", 4, 40),
+            entry("old/e", 5, 50),
+            entry("old/sub/f", 6, 60),
+            entry("dir/g/h", 7, 70),
+        ];
+        let plan = SyncPlan::new(&local, &remote, false);
+        assert_eq!(plan.copy, vec!["dir/c"]);
+        assert_eq!(plan.changed, vec!["dir/b"]);
+        assert_eq!(plan.bytes, 5);
+        assert!(plan.delete.is_empty());
+        assert!(plan.delete_dirs.is_empty());
+        let plan = SyncPlan::new(&local, &remote, true);
+        assert_eq!(plan.delete, vec!["d"]);
+        assert_eq!(plan.delete_dirs, vec!["dir/g", "old"]);
+    }
+
+    #[test]
+    fn test_not_found_error() {
+        assert!(is_not_found_error("No such file or directory (os error 2)"));
+        assert!(is_not_found_error(
+            "The system cannot find the path specified. (os error 3)"
+        ));
+        assert!(!is_not_found_error("Permission denied (os error 13)"));
+        assert!(!is_not_found_error("Access is denied. (os error 5)"));
+    }
+}
@@ -33,6 +33,7 @@ use crate::{
     create_symmetric_key_msg, decode_id_pk, get_rs_pk, is_keyboard_mode_supported,
     kcp_stream::KcpStream,
     secure_tcp,
+    transfer_limit::TransferPriority,
     ui_interface::{get_builtin_option, use_texture_render},
     ui_session_interface::{InvokeUiSession, Session},
 };
@@ -75,8 +76,8 @@ use hbb_common::{
 pub use helper::*;
 use scrap::{
     codec::Decoder,
-    record::{Recorder, RecorderContext},
-    CodecFormat, ImageFormat, ImageRgb, ImageTexture,
+    record::{RecordAudioFormat, Recorder, RecorderContext},
+    record_crypt, CodecFormat, ImageFormat, ImageRgb, ImageTexture,
 };
 
 #[cfg(not(target_os = "ios"))]
@@ -93,6 +94,7 @@ pub mod file_trait;
 pub mod helper;
 pub mod io_loop;
 pub mod screenshot;
+pub mod transfer_queue;
 
 pub const MILLI1: Duration = Duration::from_millis(1);
 pub const SEC30: Duration = Duration::from_secs(30);
@@ -1540,6 +1542,7 @@ pub struct VideoHandler {
     pub texture: ImageTexture,
     recorder: Arc<Mutex<Option<Recorder>>>,
     record: bool,
+    record_audio_format: Option<RecordAudioFormat>,
     _display: usize, // useful for debug
     fail_counter: usize,
     first_frame: bool,
@@ -1572,6 +1575,7 @@ impl VideoHandler {
             texture: Default::default(),
             recorder: Default::default(),
             record: false,
+            record_audio_format: None,
             _display,
             fail_counter: 0,
             first_frame: true,
@@ -1651,21 +1655,62 @@ impl VideoHandler {
     pub fn record_screen(&mut self, start: bool, id: String, display_idx: usize, camera: bool) {
         self.record = false;
         if start {
-            self.recorder = Recorder::new(RecorderContext {
-                server: false,
-                id,
-                dir: crate::ui_interface::video_save_directory(false),
-                display_idx,
-                camera,
-                tx: None,
+            // Do not record in plain text if the key is invalid.
+            self.recorder = record_crypt::public_key_option(&Config::get_option(
+                record_crypt::OPTION_PUBLIC_KEY,
+            ))
+            .and_then(|public_key| {
+                Recorder::new(RecorderContext {
+                    server: false,
+                    id,
+                    dir: crate::ui_interface::video_save_directory(false),
+                    display_idx,
+                    camera,
+                    multi_display: LocalConfig::get_option("record-displays-in-one-file") == "Y",
+                    public_key,
+                    tx: None,
+                })
             })
-            .map_or(Default::default(), |r| Arc::new(Mutex::new(Some(r))));
+            .map_or_else(
+                |e| {
+                    log::error!("Failed to record: {}", e);
+                    Default::default()
+                },
+                |mut r| {
+                    if let Some(format) = self.record_audio_format {
+                        r.set_audio_format(format);
+                    }
+                    Arc::new(Mutex::new(Some(r)))
+                },
+            );
         } else {
             self.recorder = Default::default();
         }
 
         self.record = start;
     }
+
+    /// Set the format of the audio frames to record.
+    pub fn set_record_audio_format(&mut self, f: &AudioFormat) {
+        let format = RecordAudioFormat {
+            sample_rate: f.sample_rate,
+            channels: f.channels as _,
+        };
+        self.record_audio_format = Some(format);
+        if let Some(r) = self.recorder.lock().unwrap().as_mut() {
+            r.set_audio_format(format);
+        }
+    }
+
+    /// Record an audio frame along with the video.
+    pub fn record_audio(&mut self, frame: &AudioFrame) {
+        if !self.record {
+            return;
+        }
+        if let Some(r) = self.recorder.lock().unwrap().as_mut() {
+            r.write_audio(&frame.data);
+        }
+    }
 }
 
 // The source of sent password
@@ -1755,6 +1800,8 @@ pub struct LoginConfigHandler {
     pub enable_trusted_devices: bool,
     pub record_state: bool,
     pub record_permission: bool,
+    /// The peer handles the extension messages.
+    pub ext_messages: bool,
 }
 
 impl Deref for LoginConfigHandler {
@@ -2477,6 +2524,7 @@ impl LoginConfigHandler {
             self.version = hbb_common::get_version_number(&pi.version);
         }
         self.features = pi.features.clone().into_option();
+        self.ext_messages = crate::common::is_support_ext_message(pi);
         let serde = PeerInfoSerde {
             username: pi.username.clone(),
             hostname: pi.hostname.clone(),
@@ -2728,6 +2776,12 @@ impl LoginConfigHandler {
             "terminal-service-id"
         }
     }
+
+    /// Login to a new terminal service instead of the persistent one, without saving the config.
+    pub fn clear_terminal_service_id(&mut self) {
+        let key = self.get_key_terminal_service_id();
+        self.config.options.remove(key);
+    }
 }
 
 /// Media data.
@@ -2769,6 +2823,7 @@ pub fn start_video_thread<F, T>(
         sync_cpu_usage();
         get_hwcodec_config();
         let mut video_handler = None;
+        let mut audio_format = None;
         let mut count = 0;
         let mut duration = std::time::Duration::ZERO;
         let mut skip_beginning = 0;
@@ -2801,6 +2856,9 @@ pub fn start_video_thread<F, T>(
                         let format = CodecFormat::from(&vf);
                         if video_handler.is_none() {
                             let mut handler = VideoHandler::new(format, display);
+                            if let Some(f) = audio_format.as_ref() {
+                                handler.set_record_audio_format(f);
+                            }
                             let record_state = session.lc.read().unwrap().record_state;
                             let record_permission = session.lc.read().unwrap().record_permission;
                             let id = session.lc.read().unwrap().id.clone();
@@ -2889,6 +2947,17 @@ pub fn start_video_thread<F, T>(
                             handler.record_screen(start, id, display, is_view_camera);
                         }
                     }
+                    MediaData::AudioFormat(f) => {
+                        if let Some(handler) = video_handler.as_mut() {
+                            handler.set_record_audio_format(&f);
+                        }
+                        audio_format = Some(f);
+                    }
+                    MediaData::AudioFrame(af) => {
+                        if let Some(handler) = video_handler.as_mut() {
+                            handler.record_audio(&af);
+                        }
+                    }
                     _ => {}
                 }
             } else {
@@ -3648,6 +3717,8 @@ pub enum Data {
     RemoveFile((i32, String, i32, bool)),
     CreateDir((i32, String, bool)),
     CancelJob(i32),
+    // (kbps, priority) of the files sent by this session, kbps 0 for no cap.
+    SetTransferLimit((u32, TransferPriority)),
     RemovePortForward(i32),
     AddPortForward((i32, String, i32)),
     #[cfg(all(target_os = "windows", not(feature = "flutter")))]
@@ -3656,6 +3727,10 @@ pub enum Data {
     SetConfirmOverrideFile((i32, i32, bool, bool, bool)),
     AddJob((i32, JobType, String, String, i32, bool, bool)),
     ResumeJob((i32, bool)),
+    // (id, file_num, error) of a finished transfer job after its files are verified.
+    TransferVerified((i32, i32, Option<String>)),
+    // (id, is_remote, file_num, error) of the interrupted file finished on resuming.
+    PartialFileResumed((i32, bool, i32, Option<String>)),
     RecordScreen(bool),
     ElevateDirect,
     ElevateWithLogon(String, String),
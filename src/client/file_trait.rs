@@ -1,6 +1,7 @@
 use hbb_common::{fs, log, message_proto::*};
 
 use super::{Data, Interface};
+use crate::transfer_limit::TransferPriority;
 
 pub trait FileManager: Interface {
     fn get_home_dir(&self) -> String {
@@ -51,6 +52,15 @@ pub trait FileManager: Interface {
         self.send(Data::CancelJob(id));
     }
 
+    /// `priority` is one of `low`, `normal` and `high`.
+    fn set_transfer_limit(&self, kbps: u32, priority: String) {
+        let Some(priority) = TransferPriority::from_name(&priority) else {
+            log::error!("Invalid file transfer priority: {}", priority);
+            return;
+        };
+        self.send(Data::SetTransferLimit((kbps, priority)));
+    }
+
     fn read_empty_dirs(&self, path: String, include_hidden: bool) {
         let mut msg_out = Message::new();
         let mut file_action = FileAction::new();
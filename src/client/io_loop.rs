@@ -4,10 +4,11 @@ use crate::clipboard::{update_clipboard, ClipboardSide};
 use crate::{audio_service, clipboard::CLIPBOARD_INTERVAL, ConnInner, CLIENT_SERVER};
 use crate::{
     client::{
-        self, new_voice_call_request, Client, Data, Interface, MediaData, MediaSender,
-        QualityStatus, MILLI1, SEC30,
+        self, new_voice_call_request, transfer_queue::TransferQueueHandle, Client, Data, Interface,
+        MediaData, MediaSender, QualityStatus, MILLI1, SEC30,
     },
     common::get_default_sound_input,
+    transfer_limit::{self, TransferLimiter},
     ui_session_interface::{InvokeUiSession, Session},
 };
 #[cfg(feature = "unix-file-copy-paste")]
@@ -53,6 +54,8 @@ use std::{
     },
 };
 
+const SYNC_JOBS_INTERVAL: Duration = Duration::from_secs(10);
+
 pub struct Remote<T: InvokeUiSession> {
     handler: Session<T>,
     audio_sender: MediaSender,
@@ -65,7 +68,11 @@ pub struct Remote<T: InvokeUiSession> {
     write_jobs: Vec<fs::TransferJob>,
     remove_jobs: HashMap<i32, RemoveJob>,
     timer: crate::RustDeskInterval,
+    transfer_limiter: TransferLimiter,
     last_update_jobs_status: (Instant, HashMap<i32, u64>),
+    transfer_queue: Option<TransferQueueHandle>,
+    file_delta: Option<crate::file_delta::Client>,
+    last_sync_jobs: Instant,
     is_connected: bool,
     first_frame: bool,
     #[cfg(any(target_os = "windows", feature = "unix-file-copy-paste"))]
@@ -77,6 +84,7 @@ pub struct Remote<T: InvokeUiSession> {
     video_threads: HashMap<usize, VideoThread>,
     chroma: Arc<RwLock<Option<Chroma>>>,
     last_record_state: bool,
+    audio_format: Option<AudioFormat>,
     sent_close_reason: bool,
 }
 
@@ -103,6 +111,19 @@ impl<T: InvokeUiSession> Remote<T> {
         receiver: mpsc::UnboundedReceiver<Data>,
         sender: mpsc::UnboundedSender<Data>,
     ) -> Self {
+        let transfer_queue = if handler.is_file_transfer() {
+            Some(TransferQueueHandle::load(&handler.get_id()))
+        } else {
+            None
+        };
+        let file_delta = if handler.is_file_transfer() {
+            let sender = sender.clone();
+            Some(crate::file_delta::Client::new(move |msg| {
+                sender.send(Data::Message(msg)).ok();
+            }))
+        } else {
+            None
+        };
         Self {
             handler,
             audio_sender: crate::client::start_audio_thread(),
@@ -112,7 +133,11 @@ impl<T: InvokeUiSession> Remote<T> {
             write_jobs: Vec::new(),
             remove_jobs: Default::default(),
             timer: crate::rustdesk_interval(time::interval(SEC30)),
+            transfer_limiter: TransferLimiter::new(Default::default()),
             last_update_jobs_status: (Instant::now(), Default::default()),
+            transfer_queue,
+            file_delta,
+            last_sync_jobs: Instant::now(),
             is_connected: false,
             first_frame: false,
             #[cfg(any(target_os = "windows", feature = "unix-file-copy-paste"))]
@@ -126,6 +151,7 @@ impl<T: InvokeUiSession> Remote<T> {
             video_threads: Default::default(),
             chroma: Default::default(),
             last_record_state: false,
+            audio_format: None,
             sent_close_reason: false,
         }
     }
@@ -269,7 +295,7 @@ impl<T: InvokeUiSession> Remote<T> {
                                 break;
                             }
                             if !self.read_jobs.is_empty() {
-                                if let Err(err) = fs::handle_read_jobs(&mut self.read_jobs, &mut peer).await {
+                                if let Err(err) = transfer_limit::handle_read_jobs(&mut self.read_jobs, &mut peer, &mut self.transfer_limiter).await {
                                     self.handler.msgbox("error", "Connection Error", &err.to_string(), "");
                                     break;
                                 }
@@ -407,6 +433,115 @@ impl<T: InvokeUiSession> Remote<T> {
         }
     }
 
+    /// The `file_delta` client if the peer supports it.
+    fn peer_file_delta(&self) -> Option<crate::file_delta::Client> {
+        if self.handler.lc.read().unwrap().ext_messages {
+            self.file_delta.clone()
+        } else {
+            None
+        }
+    }
+
+    /// Finish the file `file_num` interrupted in the last session in the background, returns
+    /// false if it can not be resumed inside the file.
+    fn resume_partial_file(&mut self, id: i32, is_remote: bool, file_num: i32) -> bool {
+        let (Some(q), Some(peer)) = (self.transfer_queue.clone(), self.peer_file_delta()) else {
+            return false;
+        };
+        if !q.is_partial(id, file_num) {
+            return false;
+        }
+        let sender = self.sender.clone();
+        let peer_windows = self.handler.peer_platform() == "Windows";
+        tokio::spawn(async move {
+            let err = q
+                .resume_file(id, file_num, peer, peer_windows)
+                .await
+                .err()
+                .map(|e| e.to_string());
+            sender
+                .send(Data::PartialFileResumed((id, is_remote, file_num, err)))
+                .ok();
+        });
+        true
+    }
+
+    /// Resume the job from `file_num`.
+    async fn resume_job(&mut self, id: i32, is_remote: bool, file_num: i32, peer: &mut Stream) {
+        if let Some(count) = self.transfer_queue.as_ref().and_then(|q| q.file_count(id)) {
+            if file_num as usize >= count {
+                // The last file was finished by `resume_partial_file`.
+                fs::remove_job(id, &mut self.write_jobs);
+                fs::remove_job(id, &mut self.read_jobs);
+                self.verify_job(id, file_num - 1, None);
+                return;
+            }
+        }
+        if is_remote {
+            if let Some(job) = get_job(id, &mut self.write_jobs) {
+                job.is_last_job = false;
+                job.file_num = file_num;
+                allow_err!(
+                    peer.send(&fs::new_send(
+                        id,
+                        fs::JobType::Generic,
+                        job.remote.clone(),
+                        job.file_num,
+                        job.show_hidden
+                    ))
+                    .await
+                );
+            }
+        } else {
+            if let Some(job) = get_job(id, &mut self.read_jobs) {
+                match &job.data_source {
+                    fs::DataSource::FilePath(p) => {
+                        job.is_last_job = false;
+                        job.file_num = file_num;
+                        allow_err!(
+                            peer.send(&fs::new_receive(
+                                id,
+                                p.to_string_lossy().to_string(),
+                                job.file_num,
+                                job.files.clone(),
+                                job.total_size(),
+                            ))
+                            .await
+                        );
+                        self.timer = crate::rustdesk_interval(time::interval(MILLI1));
+                    }
+                    fs::DataSource::MemoryCursor(_) => {
+                        // unreachable!()
+                        log::error!("Resume job with memory cursor");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Verify the files of a finished job with the peer before reporting it.
+    fn verify_job(&mut self, id: i32, file_num: i32, err: Option<String>) {
+        match self.transfer_queue.clone() {
+            Some(q) if err.is_none() && q.contains(id) => {
+                let sender = self.sender.clone();
+                let peer = self.peer_file_delta();
+                let peer_windows = self.handler.peer_platform() == "Windows";
+                tokio::spawn(async move {
+                    let err = q.verify(id, peer, peer_windows).await;
+                    sender
+                        .send(Data::TransferVerified((id, file_num, err)))
+                        .ok();
+                });
+            }
+            q => {
+                if let Some(q) = q {
+                    q.remove(id);
+                }
+                self.handle_job_status(id, file_num, err);
+            }
+        }
+    }
+
     fn handle_job_status(&mut self, id: i32, file_num: i32, err: Option<String>) {
         if let Some(job) = self.remove_jobs.get_mut(&id) {
             if job.no_confirm {
@@ -572,6 +707,10 @@ impl<T: InvokeUiSession> Remote<T> {
                 let od = can_enable_overwrite_detection(self.handler.lc.read().unwrap().version);
                 if is_remote {
                     log::debug!("New job {}, write to {} from remote {}", id, to, path);
+                    if let (Some(q), fs::JobType::Generic) = (self.transfer_queue.as_ref(), r#type)
+                    {
+                        q.add(id, true, &path, &to, include_hidden);
+                    }
                     let to = match r#type {
                         fs::JobType::Generic => fs::DataSource::FilePath(PathBuf::from(&to)),
                         fs::JobType::Printer => {
@@ -632,6 +771,12 @@ impl<T: InvokeUiSession> Remote<T> {
                                 fs::transform_windows_path(&mut files);
                             }
                             let total_size = job.total_size();
+                            if let (Some(q), fs::JobType::Generic) =
+                                (self.transfer_queue.as_ref(), r#type)
+                            {
+                                q.add(id, false, &to, &path, include_hidden);
+                                q.set_files(id, job.files());
+                            }
                             self.read_jobs.push(job);
                             self.timer = crate::rustdesk_interval(time::interval(MILLI1));
                             allow_err!(
@@ -663,6 +808,9 @@ impl<T: InvokeUiSession> Remote<T> {
                         od,
                     );
                     job.is_last_job = true;
+                    if let Some(q) = self.transfer_queue.as_ref() {
+                        q.add(id, true, &path, &to, include_hidden);
+                    }
                     self.write_jobs.push(job);
                 } else {
                     match fs::TransferJob::new_read(
@@ -694,6 +842,10 @@ impl<T: InvokeUiSession> Remote<T> {
                                 true,
                             );
                             job.is_last_job = true;
+                            if let Some(q) = self.transfer_queue.as_ref() {
+                                q.add(id, false, &to, &path, include_hidden);
+                                q.set_files(id, job.files());
+                            }
                             self.read_jobs.push(job);
                             self.timer = crate::rustdesk_interval(time::interval(MILLI1));
                         }
@@ -701,44 +853,39 @@ impl<T: InvokeUiSession> Remote<T> {
                 }
             }
             Data::ResumeJob((id, is_remote)) => {
-                if is_remote {
-                    if let Some(job) = get_job(id, &mut self.write_jobs) {
-                        job.is_last_job = false;
-                        allow_err!(
-                            peer.send(&fs::new_send(
-                                id,
-                                fs::JobType::Generic,
-                                job.remote.clone(),
-                                job.file_num,
-                                job.show_hidden
-                            ))
-                            .await
-                        );
-                    }
+                let jobs = if is_remote {
+                    &mut self.write_jobs
                 } else {
-                    if let Some(job) = get_job(id, &mut self.read_jobs) {
-                        match &job.data_source {
-                            fs::DataSource::FilePath(p) => {
-                                job.is_last_job = false;
-                                allow_err!(
-                                    peer.send(&fs::new_receive(
-                                        id,
-                                        p.to_string_lossy().to_string(),
-                                        job.file_num,
-                                        job.files.clone(),
-                                        job.total_size(),
-                                    ))
-                                    .await
-                                );
-                            }
-                            fs::DataSource::MemoryCursor(_) => {
-                                // unreachable!()
-                                log::error!("Resume job with memory cursor");
-                            }
-                        }
+                    &mut self.read_jobs
+                };
+                if let Some(job) = get_job(id, jobs) {
+                    let mut file_num = job.file_num;
+                    if let Some(q) = self.transfer_queue.as_ref() {
+                        file_num = q.resume_file_num(id, file_num);
+                    }
+                    if !self.resume_partial_file(id, is_remote, file_num) {
+                        self.resume_job(id, is_remote, file_num, peer).await;
                     }
                 }
             }
+            Data::PartialFileResumed((id, is_remote, file_num, err)) => {
+                let file_num = match err {
+                    Some(err) => {
+                        log::error!("Failed to resume file {} of job {}: {}", file_num, id, err);
+                        file_num
+                    }
+                    None => {
+                        if let Some(q) = self.transfer_queue.as_ref() {
+                            q.progress(id, file_num + 1);
+                        }
+                        file_num + 1
+                    }
+                };
+                self.resume_job(id, is_remote, file_num, peer).await;
+            }
+            Data::TransferVerified((id, file_num, err)) => {
+                self.handle_job_status(id, file_num, err);
+            }
             Data::SetNoConfirm(id) => {
                 if let Some(job) = self.remove_jobs.get_mut(&id) {
                     job.no_confirm = true;
@@ -762,7 +909,7 @@ impl<T: InvokeUiSession> Remote<T> {
                         if remember {
                             job.set_overwrite_strategy(Some(need_override));
                         }
-                        job.confirm(&FileTransferSendConfirmRequest {
+                        let req = FileTransferSendConfirmRequest {
                             id,
                             file_num,
                             union: if need_override {
@@ -771,7 +918,11 @@ impl<T: InvokeUiSession> Remote<T> {
                                 Some(file_transfer_send_confirm_request::Union::Skip(true))
                             },
                             ..Default::default()
-                        });
+                        };
+                        job.confirm(&req);
+                        if let Some(q) = self.transfer_queue.as_ref() {
+                            q.confirm(&req);
+                        }
                     }
                 } else {
                     if let Some(job) = fs::get_job(id, &mut self.write_jobs) {
@@ -791,6 +942,9 @@ impl<T: InvokeUiSession> Remote<T> {
                             ..Default::default()
                         };
                         job.confirm(&req);
+                        if let Some(q) = self.transfer_queue.as_ref() {
+                            q.confirm(&req);
+                        }
                         file_action.set_send_confirm(req);
                         msg.set_file_action(file_action);
                         allow_err!(peer.send(&msg).await);
@@ -845,6 +999,13 @@ impl<T: InvokeUiSession> Remote<T> {
                 }
                 let _ = fs::remove_job(id, &mut self.read_jobs);
                 self.remove_jobs.remove(&id);
+                if let Some(q) = self.transfer_queue.as_ref() {
+                    q.remove(id);
+                }
+            }
+            Data::SetTransferLimit((kbps, priority)) => {
+                self.transfer_limiter.set_session_kbps(Some(kbps));
+                self.transfer_limiter.set_priority(priority);
             }
             Data::RemoveDir((id, path)) => {
                 let mut msg_out = Message::new();
@@ -1027,6 +1188,10 @@ impl<T: InvokeUiSession> Remote<T> {
                     &mut self.last_update_jobs_status,
                     &self.handler,
                 );
+                if let Some(q) = self.transfer_queue.as_ref() {
+                    q.progress(job.id(), job.file_num());
+                    q.upload_progress(job.id(), job.file_num(), job.finished_size());
+                }
             }
             for job in self.write_jobs.iter() {
                 Self::update_job_status(
@@ -1035,13 +1200,26 @@ impl<T: InvokeUiSession> Remote<T> {
                     &mut self.last_update_jobs_status,
                     &mut self.handler,
                 );
+                if let Some(q) = self.transfer_queue.as_ref() {
+                    q.download_progress(job.id(), job.file_num());
+                }
             }
             self.last_update_jobs_status.0 = Instant::now();
         }
+        // Keep the last jobs up to date, they are only saved at the end of the session otherwise.
+        if self.transfer_queue.is_some() && self.last_sync_jobs.elapsed() >= SYNC_JOBS_INTERVAL {
+            self.last_sync_jobs = Instant::now();
+            self.save_jobs_to_local();
+        }
     }
 
     pub async fn sync_jobs_status_to_local(&mut self) -> bool {
         log::info!("sync transfer job status");
+        self.save_jobs_to_local();
+        true
+    }
+
+    fn save_jobs_to_local(&mut self) {
         let mut config: PeerConfig = self.handler.load_config();
         let mut transfer_metas = TransferSerde::default();
         for job in self.read_jobs.iter() {
@@ -1052,12 +1230,51 @@ impl<T: InvokeUiSession> Remote<T> {
             let json_str = serde_json::to_string(&job.gen_meta()).unwrap_or_default();
             transfer_metas.write_jobs.push(json_str);
         }
-        log::info!("meta: {:?}", transfer_metas);
         if config.transfer != transfer_metas {
+            log::info!("meta: {:?}", transfer_metas);
             config.transfer = transfer_metas;
             self.handler.save_config(config);
         }
-        true
+    }
+
+    fn handle_ext_message(&mut self, p: PluginRequest) {
+        match p.id.as_str() {
+            crate::file_delta::ID => {
+                if let Some(c) = self.file_delta.as_ref() {
+                    c.on_response(&p.content);
+                }
+            }
+            transfer_limit::EXT_ID => transfer_limit::on_network_delay(
+                &self.handler.get_id(),
+                transfer_limit::decode_delay(&p.content),
+            ),
+            crate::terminal_control::EXT_ID => {
+                match crate::terminal_control::ControlMessage::parse(&p.content) {
+                    Ok(crate::terminal_control::ControlMessage::Participants {
+                        participants,
+                        my_conn_id,
+                    }) => self
+                        .handler
+                        .update_terminal_participants(participants, my_conn_id),
+                    Ok(m) => log::debug!("Unexpected terminal control message: {:?}", m),
+                    Err(e) => log::error!("Invalid terminal control message: {}", e),
+                }
+            }
+            crate::remote_exec::EXT_ID => {
+                match crate::remote_exec::ExecMessage::decode(&p.content) {
+                    Ok(m) => self.handler.handle_exec_message(m),
+                    Err(e) => log::error!("Invalid exec message: {}", e),
+                }
+            }
+            scrap::region::EXT_ID => match scrap::region::RegionMessage::decode(&p.content) {
+                Ok(scrap::region::RegionMessage::Windows(windows)) => {
+                    self.handler.update_capture_windows(windows)
+                }
+                Ok(m) => log::debug!("Unexpected capture region message: {:?}", m),
+                Err(e) => log::error!("Invalid capture region message: {}", e),
+            },
+            _ => log::debug!("Unknown extension message {}", p.id),
+        }
     }
 
     async fn send_toggle_virtual_display_msg(&self, peer: &mut Stream) {
@@ -1332,6 +1549,27 @@ impl<T: InvokeUiSession> Remote<T> {
                             }
                         }
                         self.handler.handle_peer_info(pi);
+                        let ext_messages = self.handler.lc.read().unwrap().ext_messages;
+                        if ext_messages {
+                            allow_err!(
+                                peer.send(&crate::common::new_ext_message(
+                                    crate::common::EXT_HELLO_ID,
+                                    vec![]
+                                ))
+                                .await
+                            );
+                        }
+                        if (self.handler.is_default() || self.handler.is_view_camera())
+                            && ext_messages
+                        {
+                            allow_err!(
+                                peer.send(&crate::common::new_ext_message(
+                                    scrap::tile::EXT_ID,
+                                    vec![]
+                                ))
+                                .await
+                            );
+                        }
                         #[cfg(all(target_os = "windows", not(feature = "flutter")))]
                         self.check_clipboard_file_context();
                         if self.handler.is_default() {
@@ -1393,6 +1631,18 @@ impl<T: InvokeUiSession> Remote<T> {
 
                         if self.handler.is_file_transfer() {
                             self.handler.load_last_jobs();
+                            if self
+                                .transfer_queue
+                                .as_ref()
+                                .map_or(false, |q| !q.started_jobs().is_empty())
+                            {
+                                self.handler.msgbox(
+                                    "custom-nocancel-nook-hasclose",
+                                    "Resume transfer",
+                                    "resume-transfer-tip",
+                                    "",
+                                );
+                            }
                         }
 
                         self.is_connected = true;
@@ -1459,6 +1709,9 @@ impl<T: InvokeUiSession> Remote<T> {
                                 .update_folder_files(fd.id, &entries, fd.path, false, false);
                             if let Some(job) = fs::get_job(fd.id, &mut self.write_jobs) {
                                 log::info!("job set_files: {:?}", entries);
+                                if let Some(q) = self.transfer_queue.as_ref() {
+                                    q.set_files(fd.id, &entries);
+                                }
                                 job.set_files(entries);
                             } else if let Some(job) = self.remove_jobs.get_mut(&fd.id) {
                                 job.files = entries;
@@ -1487,6 +1740,9 @@ impl<T: InvokeUiSession> Remote<T> {
                                                     ..Default::default()
                                                 };
                                                 job.confirm(&req);
+                                                if let Some(q) = self.transfer_queue.as_ref() {
+                                                    q.confirm(&req);
+                                                }
                                                 let msg = new_send_confirm(req);
                                                 allow_err!(peer.send(&msg).await);
                                             } else {
@@ -1522,6 +1778,11 @@ impl<T: InvokeUiSession> Remote<T> {
                                                         ..Default::default()
                                                     };
                                                         job.confirm(&req);
+                                                        if let Some(q) =
+                                                            self.transfer_queue.as_ref()
+                                                        {
+                                                            q.confirm(&req);
+                                                        }
                                                         let msg = new_send_confirm(req);
                                                         allow_err!(peer.send(&msg).await);
                                                     }
@@ -1540,6 +1801,11 @@ impl<T: InvokeUiSession> Remote<T> {
                                                                     ..Default::default()
                                                                 };
                                                             job.confirm(&req);
+                                                            if let Some(q) =
+                                                                self.transfer_queue.as_ref()
+                                                            {
+                                                                q.confirm(&req);
+                                                            }
                                                             let msg = new_send_confirm(req);
                                                             allow_err!(peer.send(&msg).await);
                                                         } else {
@@ -1560,6 +1826,11 @@ impl<T: InvokeUiSession> Remote<T> {
                                                         ..Default::default()
                                                     };
                                                         job.confirm(&req);
+                                                        if let Some(q) =
+                                                            self.transfer_queue.as_ref()
+                                                        {
+                                                            q.confirm(&req);
+                                                        }
                                                         let msg = new_send_confirm(req);
                                                         allow_err!(peer.send(&msg).await);
                                                     }
@@ -1575,6 +1846,9 @@ impl<T: InvokeUiSession> Remote<T> {
                         }
                         Some(file_response::Union::Block(block)) => {
                             if let Some(job) = fs::get_job(block.id, &mut self.write_jobs) {
+                                if let Some(q) = self.transfer_queue.as_ref() {
+                                    q.progress(block.id, block.file_num);
+                                }
                                 if let Err(_err) = job.write(block).await {
                                     // to-do: add "skip" for writing job
                                 }
@@ -1601,7 +1875,7 @@ impl<T: InvokeUiSession> Remote<T> {
                             }
                             match job_type {
                                 fs::JobType::Generic => {
-                                    self.handle_job_status(d.id, d.file_num, err);
+                                    self.verify_job(d.id, d.file_num, err);
                                 }
                                 fs::JobType::Printer => {
                                     if let Some(err) = err {
@@ -1640,6 +1914,9 @@ impl<T: InvokeUiSession> Remote<T> {
                             let job_type = fs::remove_job(e.id, &mut self.write_jobs)
                                 .map(|j| j.r#type)
                                 .unwrap_or(fs::JobType::Generic);
+                            if let Some(q) = self.transfer_queue.as_ref() {
+                                q.remove(e.id);
+                            }
                             match job_type {
                                 fs::JobType::Generic => {
                                     self.handle_job_status(e.id, e.file_num, Some(e.error));
@@ -1654,6 +1931,11 @@ impl<T: InvokeUiSession> Remote<T> {
                 }
                 Some(message::Union::Misc(misc)) => match misc.union {
                     Some(misc::Union::AudioFormat(f)) => {
+                        // The video threads record the audio.
+                        for (_, v) in self.video_threads.iter() {
+                            v.video_sender.send(MediaData::AudioFormat(f.clone())).ok();
+                        }
+                        self.audio_format = Some(f.clone());
                         self.audio_sender.send(MediaData::AudioFormat(f)).ok();
                     }
                     Some(misc::Union::ChatMessage(c)) => {
@@ -1829,6 +2111,9 @@ impl<T: InvokeUiSession> Remote<T> {
                         #[cfg(feature = "flutter")]
                         self.handler.switch_back(&self.handler.get_id());
                     }
+                    Some(misc::Union::PluginRequest(p)) if crate::common::is_ext_message(&p.id) => {
+                        self.handle_ext_message(p);
+                    }
                     #[cfg(all(feature = "flutter", feature = "plugin_framework"))]
                     #[cfg(not(any(target_os = "android", target_os = "ios")))]
                     Some(misc::Union::PluginRequest(p)) => {
@@ -1863,6 +2148,13 @@ impl<T: InvokeUiSession> Remote<T> {
                 }
                 Some(message::Union::AudioFrame(frame)) => {
                     if !self.handler.lc.read().unwrap().disable_audio.v {
+                        if self.last_record_state {
+                            for (_, v) in self.video_threads.iter() {
+                                v.video_sender
+                                    .send(MediaData::AudioFrame(Box::new(frame.clone())))
+                                    .ok();
+                            }
+                        }
                         self.audio_sender
                             .send(MediaData::AudioFrame(Box::new(frame)))
                             .ok();
@@ -2303,6 +2595,12 @@ impl<T: InvokeUiSession> Remote<T> {
                 }
             },
         );
+        if let Some(f) = self.audio_format.clone() {
+            video_thread
+                .video_sender
+                .send(MediaData::AudioFormat(f))
+                .ok();
+        }
         self.video_threads.insert(display, video_thread);
         if self.video_threads.len() == 1 {
             let auto_record =
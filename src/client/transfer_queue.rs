@@ -0,0 +1,519 @@
+// Persistent queue of the file transfer jobs to a peer.
+//
+// `Remote::sync_jobs_status_to_local` only saves the unfinished jobs when the session ends, this
+// queue is saved whenever a job is added, makes progress or ends, so that an interrupted job can
+// be resumed by the next session to the peer. Every finished file is recorded with its size,
+// modification time and SHA-256: of the written file for downloads, of the source for uploads,
+// and the file being transferred with the bytes transferred so far.
+//
+// When a job is resumed, it restarts from the first recorded file which was changed since. The
+// interrupted file is finished from its recorded offset by the block requests of
+// `crate::file_delta`, the job goes on with the next file. When a job is done, the size and the
+// SHA-256 of all of its files are compared with the peer before the job is reported as done.
+//
+// Peers without `crate::file_delta` restart the interrupted file, and only the sizes of the
+// files are verified.
+
+use crate::{
+    file_delta::{self, file_meta, sha256_file, DOWNLOAD_SUFFIX},
+    transfer_limit::TransferLimiter,
+};
+use hbb_common::{
+    bail,
+    config::{load_path, store_path, Config},
+    fs, log,
+    message_proto::{
+        file_transfer_send_confirm_request, FileEntry, FileTransferSendConfirmRequest,
+    },
+    tokio::task::spawn_blocking,
+    ResultType,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct FileRecord {
+    pub name: String,
+    /// The size listed by the sending side.
+    pub size: u64,
+    /// Empty until the file is finished.
+    pub sha256: String,
+    /// Seconds since the epoch of the local file when it was hashed.
+    pub modified: u64,
+    /// Bytes transferred of the unfinished file being transferred.
+    pub offset: u64,
+    /// The file was not overwritten on confirmation.
+    pub skipped: bool,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct QueuedJob {
+    pub id: i32,
+    /// Download from the peer.
+    pub is_remote: bool,
+    pub remote: String,
+    pub local: String,
+    pub include_hidden: bool,
+    pub files: Vec<FileRecord>,
+}
+
+impl QueuedJob {
+    fn local_path(&self, i: usize) -> PathBuf {
+        fs::TransferJob::join(&PathBuf::from(&self.local), &self.files[i].name)
+    }
+
+    fn remote_path(&self, i: usize, peer_windows: bool) -> String {
+        let (sep, name) = if peer_windows {
+            ('\\', self.files[i].name.replace('/', "\\"))
+        } else {
+            ('/', self.files[i].name.replace('\\', "/"))
+        };
+        if name.is_empty() {
+            return self.remote.clone();
+        }
+        format!("{}{}{}", self.remote.trim_end_matches(sep), sep, name)
+    }
+
+    /// The number of leading files which are finished and unchanged since.
+    fn verified_count(&self) -> usize {
+        self.files
+            .iter()
+            .enumerate()
+            .take_while(|(i, f)| {
+                f.skipped
+                    || (!f.sha256.is_empty()
+                        && file_meta(&self.local_path(*i)) == Some((f.size, f.modified)))
+            })
+            .count()
+    }
+
+    /// Whether the job was interrupted after transferring a part of its files.
+    pub fn is_started(&self) -> bool {
+        self.files
+            .iter()
+            .any(|f| !f.sha256.is_empty() || f.offset > 0)
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct TransferQueue {
+    pub jobs: Vec<QueuedJob>,
+}
+
+/// The queue of a peer, shared with the hashing threads.
+#[derive(Clone)]
+pub struct TransferQueueHandle {
+    peer: String,
+    inner: Arc<Mutex<TransferQueue>>,
+    // job id -> the `file_num` of the last progress
+    progress: Arc<Mutex<HashMap<i32, i32>>>,
+}
+
+fn queue_path(peer: &str) -> PathBuf {
+    let name: String = peer
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    Config::path("transfer_queue").join(format!("{}.toml", name))
+}
+
+fn download_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(DOWNLOAD_SUFFIX);
+    path.into()
+}
+
+impl TransferQueueHandle {
+    pub fn load(peer: &str) -> Self {
+        Self {
+            peer: peer.to_owned(),
+            inner: Arc::new(Mutex::new(load_path(queue_path(peer)))),
+            progress: Default::default(),
+        }
+    }
+
+    fn store(&self, queue: &TransferQueue) {
+        let path = queue_path(&self.peer);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).ok();
+        }
+        if let Err(e) = store_path(path, queue) {
+            log::error!("Failed to store transfer queue: {}", e);
+        }
+    }
+
+    fn update<R>(&self, f: impl FnOnce(&mut TransferQueue) -> R) -> R {
+        let mut lock = self.inner.lock().unwrap();
+        let old = lock.clone();
+        let res = f(&mut lock);
+        if *lock != old {
+            self.store(&lock);
+        }
+        res
+    }
+
+    fn update_file(&self, id: i32, i: usize, f: impl FnOnce(&mut FileRecord)) {
+        self.update(|q| {
+            if let Some(file) = q
+                .jobs
+                .iter_mut()
+                .find(|j| j.id == id)
+                .and_then(|j| j.files.get_mut(i))
+            {
+                f(file);
+            }
+        });
+    }
+
+    /// Add a new job, or take over the queued job with the same paths for a resumed one.
+    pub fn add(&self, id: i32, is_remote: bool, remote: &str, local: &str, include_hidden: bool) {
+        self.update(|q| {
+            if let Some(job) = q
+                .jobs
+                .iter_mut()
+                .find(|j| j.is_remote == is_remote && j.remote == remote && j.local == local)
+            {
+                job.id = id;
+                job.include_hidden = include_hidden;
+                return;
+            }
+            q.jobs.retain(|j| j.id != id);
+            q.jobs.push(QueuedJob {
+                id,
+                is_remote,
+                remote: remote.to_owned(),
+                local: local.to_owned(),
+                include_hidden,
+                files: vec![],
+            });
+        });
+    }
+
+    /// Set the listing of the sending side, the records of the unchanged files are kept.
+    pub fn set_files(&self, id: i32, entries: &[FileEntry]) {
+        self.update(|q| {
+            let Some(job) = q.jobs.iter_mut().find(|j| j.id == id) else {
+                return;
+            };
+            let files = entries
+                .iter()
+                .map(|e| {
+                    job.files
+                        .iter()
+                        .find(|f| f.name == e.name && f.size == e.size)
+                        .cloned()
+                        .unwrap_or_else(|| FileRecord {
+                            name: e.name.clone(),
+                            size: e.size,
+                            ..Default::default()
+                        })
+                })
+                .collect();
+            job.files = files;
+        });
+    }
+
+    pub fn contains(&self, id: i32) -> bool {
+        self.inner.lock().unwrap().jobs.iter().any(|j| j.id == id)
+    }
+
+    pub fn job(&self, id: i32) -> Option<QueuedJob> {
+        self.inner
+            .lock()
+            .unwrap()
+            .jobs
+            .iter()
+            .find(|j| j.id == id)
+            .cloned()
+    }
+
+    /// The jobs of the last sessions which were interrupted after making progress.
+    pub fn started_jobs(&self) -> Vec<QueuedJob> {
+        self.inner
+            .lock()
+            .unwrap()
+            .jobs
+            .iter()
+            .filter(|j| j.is_started())
+            .cloned()
+            .collect()
+    }
+
+    pub fn remove(&self, id: i32) {
+        self.progress.lock().unwrap().remove(&id);
+        self.update(|q| q.jobs.retain(|j| j.id != id));
+    }
+
+    /// Record the files which are not overwritten.
+    pub fn confirm(&self, req: &FileTransferSendConfirmRequest) {
+        let skipped = matches!(
+            req.union,
+            Some(file_transfer_send_confirm_request::Union::Skip(true))
+        );
+        self.update_file(req.id, req.file_num as _, |f| f.skipped = skipped);
+    }
+
+    /// The files before `file_num` are finished, hash them in the background.
+    pub fn progress(&self, id: i32, file_num: i32) {
+        let last = {
+            let mut lock = self.progress.lock().unwrap();
+            let last = lock.get(&id).copied().unwrap_or(0);
+            if file_num <= last {
+                return;
+            }
+            lock.insert(id, file_num);
+            last
+        };
+        let pending: Vec<(usize, PathBuf)> = {
+            let lock = self.inner.lock().unwrap();
+            let Some(job) = lock.jobs.iter().find(|j| j.id == id) else {
+                return;
+            };
+            (last.max(0) as usize..(file_num as usize).min(job.files.len()))
+                .filter(|i| job.files[*i].sha256.is_empty() && !job.files[*i].skipped)
+                .map(|i| (i, job.local_path(i)))
+                .filter(|(_, p)| p.is_file())
+                .collect()
+        };
+        if pending.is_empty() {
+            return;
+        }
+        let this = self.clone();
+        std::thread::spawn(move || {
+            for (i, path) in pending {
+                if let Err(e) = this.record(id, i, &path) {
+                    log::error!("Failed to hash {:?}: {}", path, e);
+                }
+            }
+        });
+    }
+
+    /// Record the bytes of the file `file_num` written to its `.download` file.
+    pub fn download_progress(&self, id: i32, file_num: i32) {
+        let Some(job) = self.job(id) else {
+            return;
+        };
+        let i = file_num as usize;
+        if i >= job.files.len() {
+            return;
+        }
+        let offset = file_meta(&download_path(&job.local_path(i))).map_or(0, |m| m.0);
+        self.set_offset(id, i, offset);
+    }
+
+    /// Record the bytes of the file `file_num` sent, `finished_size` is of the whole job.
+    pub fn upload_progress(&self, id: i32, file_num: i32, finished_size: u64) {
+        let Some(job) = self.job(id) else {
+            return;
+        };
+        let i = file_num as usize;
+        if i >= job.files.len() {
+            return;
+        }
+        let before: u64 = job.files[..i].iter().map(|f| f.size).sum();
+        self.set_offset(id, i, finished_size.saturating_sub(before));
+    }
+
+    fn set_offset(&self, id: i32, i: usize, offset: u64) {
+        self.update(|q| {
+            if let Some(job) = q.jobs.iter_mut().find(|j| j.id == id) {
+                for (n, f) in job.files.iter_mut().enumerate() {
+                    f.offset = if n == i { offset.min(f.size) } else { 0 };
+                }
+            }
+        });
+    }
+
+    fn record(&self, id: i32, i: usize, path: &Path) -> ResultType<String> {
+        let sha256 = sha256_file(path)?;
+        let Some((size, modified)) = file_meta(path) else {
+            bail!("No such file");
+        };
+        let mut res = Ok(sha256.clone());
+        self.update_file(id, i, |f| {
+            if f.size != size {
+                res = Err(hbb_common::anyhow::anyhow!(
+                    "size is {}, expected {}",
+                    size,
+                    f.size
+                ));
+                return;
+            }
+            f.sha256 = sha256;
+            f.modified = modified;
+            f.offset = 0;
+        });
+        res
+    }
+
+    /// The index of the file to resume `id` from, not beyond `file_num` of the last session.
+    pub fn resume_file_num(&self, id: i32, file_num: i32) -> i32 {
+        let lock = self.inner.lock().unwrap();
+        let Some(job) = lock.jobs.iter().find(|j| j.id == id) else {
+            return file_num;
+        };
+        if job.files.is_empty() {
+            return file_num;
+        }
+        (job.verified_count() as i32).min(file_num)
+    }
+
+    /// The number of files of the job, `None` if the listing of the sending side is unknown.
+    pub fn file_count(&self, id: i32) -> Option<usize> {
+        self.job(id).map(|j| j.files.len()).filter(|n| *n > 0)
+    }
+
+    /// Whether `file_num` was interrupted after transferring a part of it.
+    pub fn is_partial(&self, id: i32, file_num: i32) -> bool {
+        self.job(id)
+            .and_then(|j| j.files.get(file_num as usize).cloned())
+            .map_or(false, |f| f.offset > 0 && f.sha256.is_empty() && !f.skipped)
+    }
+
+    /// Finish the interrupted `file_num` from its recorded offset, the offset is checked against
+    /// the partial file, which is left by the last session in the `.download` file of the
+    /// receiving side.
+    pub async fn resume_file(
+        &self,
+        id: i32,
+        file_num: i32,
+        peer: file_delta::Client,
+        peer_windows: bool,
+    ) -> ResultType<()> {
+        let Some(job) = self.job(id) else {
+            bail!("No such job");
+        };
+        let i = file_num as usize;
+        let Some(f) = job.files.get(i) else {
+            bail!("No such file");
+        };
+        let local = job.local_path(i);
+        let remote = job.remote_path(i, peer_windows);
+        if job.is_remote {
+            let (size, modified) = peer.stat(&remote).await?;
+            if size != f.size {
+                bail!("{} was changed", f.name);
+            }
+            let tmp = download_path(&local);
+            let offset = file_meta(&tmp).map_or(0, |m| m.0).min(f.offset);
+            log::info!("resume download of {} from {}", remote, offset);
+            peer.download(&remote, &tmp, &[(offset, size - offset)])
+                .await?;
+            let file = std::fs::OpenOptions::new().write(true).open(&tmp)?;
+            file.set_len(size)?;
+            file.set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(modified))?;
+            drop(file);
+            std::fs::rename(&tmp, &local)?;
+        } else {
+            let Some((size, modified)) = file_meta(&local) else {
+                bail!("No such file: {:?}", local);
+            };
+            if size != f.size {
+                bail!("{} was changed", f.name);
+            }
+            let tmp = format!("{}{}", remote, DOWNLOAD_SUFFIX);
+            let offset = peer.stat(&tmp).await.map_or(0, |m| m.0).min(f.offset);
+            log::info!("resume upload of {:?} from {}", local, offset);
+            let mut limiter = TransferLimiter::new(Default::default());
+            peer.upload(&local, &tmp, &[(offset, size - offset)], Some(&mut limiter))
+                .await?;
+            peer.truncate(&tmp, size, modified).await?;
+            peer.rename(&tmp, &remote).await?;
+        }
+        Ok(())
+    }
+
+    /// Compare the size and the SHA-256 of all the files of a finished job with the peer, then
+    /// remove it from the queue. Returns the error of the first mismatched file.
+    ///
+    /// Without `peer`, only the sizes are checked.
+    pub async fn verify(
+        &self,
+        id: i32,
+        peer: Option<file_delta::Client>,
+        peer_windows: bool,
+    ) -> Option<String> {
+        let job = self.job(id)?;
+        let mut err = None;
+        for (i, f) in job.files.iter().enumerate() {
+            let path = job.local_path(i);
+            if f.skipped || !path.is_file() {
+                // Empty directories are also listed.
+                continue;
+            }
+            let sha256 = if !f.sha256.is_empty() && file_meta(&path) == Some((f.size, f.modified)) {
+                f.sha256.clone()
+            } else {
+                let this = self.clone();
+                let local = path.clone();
+                match spawn_blocking(move || this.record(id, i, &local)).await {
+                    Ok(Ok(sha256)) => sha256,
+                    Ok(Err(e)) => {
+                        err = Some(format!("Verification of {} failed: {}", f.name, e));
+                        break;
+                    }
+                    Err(e) => {
+                        err = Some(e.to_string());
+                        break;
+                    }
+                }
+            };
+            let Some(peer) = peer.as_ref() else {
+                continue;
+            };
+            match peer.hash(&job.remote_path(i, peer_windows)).await {
+                Ok((size, remote)) if size == f.size && remote == sha256 => {}
+                Ok((size, _)) => {
+                    err = Some(format!(
+                        "Verification of {} failed, the peer has another file of size {}",
+                        f.name, size
+                    ));
+                    break;
+                }
+                Err(e) => {
+                    err = Some(format!("Verification of {} failed: {}", f.name, e));
+                    break;
+                }
+            }
+        }
+        if err.is_none() {
+            log::info!("transfer job {} verified, {} files", id, job.files.len());
+            self.remove(id);
+        }
+        err
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remote_path() {
+        let job = QueuedJob {
+            remote: "C:\\data\\".to_owned(),
+            files: vec![
+                FileRecord {
+                    name: "a/b.txt".to_owned(),
+                    ..Default::default()
+                },
+                FileRecord::default(),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(job.remote_path(0, true), "C:\\data\\a\\b.txt");
+        assert_eq!(job.remote_path(1, true), "C:\\data\\");
+        let job = QueuedJob {
+            remote: "/data".to_owned(),
+            ..job
+        };
+        assert_eq!(job.remote_path(0, false), "/data/a/b.txt");
+    }
+}
@@ -60,6 +60,8 @@ pub const PLATFORM_ANDROID: &str = "Android";
 
 pub const TIMER_OUT: Duration = Duration::from_secs(1);
 pub const DEFAULT_KEEP_ALIVE: i32 = 60_000;
+/// The job id of the error answering a `ReadDir`, which has no job id.
+pub const READ_DIR_ERROR_ID: i32 = -1;
 
 const MIN_VER_MULTI_UI_SESSION: &str = "1.2.4";
 
@@ -890,6 +892,16 @@ pub fn check_software_update() {
 
 #[tokio::main(flavor = "current_thread")]
 pub async fn do_check_software_update() -> hbb_common::ResultType<()> {
+    // The release of the self-hosted update server instead of the one of GitHub.
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    if let Some(url) = crate::updater::manifest::server_url() {
+        let update = tokio::task::spawn_blocking(move || {
+            crate::updater::manifest::check(&crate::hbbs_http::create_http_client(), &url)
+        })
+        .await??;
+        set_software_update_url(update.map(|u| u.notice_url()).unwrap_or_default());
+        return Ok(());
+    }
     let (request, url) =
         hbb_common::version_check_request(hbb_common::VER_TYPE_RUSTDESK_CLIENT.to_string());
     let latest_release_response = create_http_client_async()
@@ -899,7 +911,12 @@ pub async fn do_check_software_update() -> hbb_common::ResultType<()> {
         .await?;
     let bytes = latest_release_response.bytes().await?;
     let resp: hbb_common::VersionCheckResponse = serde_json::from_slice(&bytes)?;
-    let response_url = resp.url;
+    set_software_update_url(resp.url);
+    Ok(())
+}
+
+/// Show the new version notice if the last segment of `response_url` is a newer version.
+pub fn set_software_update_url(response_url: String) {
     let latest_release_version = response_url.rsplit('/').next().unwrap_or_default();
 
     if get_version_number(&latest_release_version) > get_version_number(crate::VERSION) {
@@ -916,7 +933,6 @@ pub async fn do_check_software_update() -> hbb_common::ResultType<()> {
     } else {
         *SOFTWARE_UPDATE_URL.lock().unwrap() = "".to_string();
     }
-    Ok(())
 }
 
 #[inline]
@@ -1150,6 +1166,44 @@ pub fn make_privacy_mode_msg(
     make_privacy_mode_msg_with_details(state, "".to_owned(), impl_key)
 }
 
+/// The id prefix of the extension messages.
+///
+/// Features which need no change of the protocol are carried by `Misc.plugin_request` with an id
+/// of this prefix, they are handled before the plugins and never reach them.
+pub const EXT_PREFIX: &str = "@rustdesk/";
+
+#[inline]
+pub fn is_ext_message(id: &str) -> bool {
+    id.starts_with(EXT_PREFIX)
+}
+
+/// The platform addition of the peer info, the controlled side handles the extension messages.
+pub const PLATFORM_ADDITION_EXT_MESSAGES: &str = "ext_messages";
+
+/// The extension message of the controlling side after `PLATFORM_ADDITION_EXT_MESSAGES`, the
+/// controlled side sends its own extension messages only after it.
+/// The peers without them show a plugin failure for an extension message.
+pub const EXT_HELLO_ID: &str = "@rustdesk/hello";
+
+pub fn is_support_ext_message(pi: &PeerInfo) -> bool {
+    serde_json::from_str::<Map<String, Value>>(&pi.platform_additions)
+        .ok()
+        .and_then(|m| m.get(PLATFORM_ADDITION_EXT_MESSAGES)?.as_bool())
+        .unwrap_or(false)
+}
+
+pub fn new_ext_message(id: &str, content: Vec<u8>) -> Message {
+    let mut misc = Misc::new();
+    misc.set_plugin_request(PluginRequest {
+        id: id.to_owned(),
+        content: content.into(),
+        ..Default::default()
+    });
+    let mut msg_out = Message::new();
+    msg_out.set_misc(misc);
+    msg_out
+}
+
 pub fn is_keyboard_mode_supported(
     keyboard_mode: &KeyboardMode,
     version_number: i64,
@@ -308,6 +308,14 @@ pub fn core_main() -> Option<Vec<String>> {
                 return None;
             }
         }
+        #[cfg(target_os = "linux")]
+        if args[0] == "--apply-update" {
+            // The packages require root, the AppImage runs as the user.
+            if let Err(err) = crate::updater::apply_staged_update() {
+                log::error!("Failed to apply the update: {err}");
+            }
+            return None;
+        }
         if args[0] == "--remove" {
             if args.len() == 2 {
                 // sleep a while so that process of removed exe exit
@@ -399,6 +407,61 @@ pub fn core_main() -> Option<Vec<String>> {
                 }
             }
             return None;
+        } else if args[0] == "--export-audit-log" {
+            if args.len() == 3 || args.len() == 4 {
+                let res = if args.len() == 4 {
+                    std::fs::File::create(&args[3])
+                        .map_err(|e| e.into())
+                        .and_then(|mut f| crate::audit_log::export(&args[1], &args[2], &mut f))
+                } else {
+                    crate::audit_log::export(&args[1], &args[2], &mut std::io::stdout())
+                };
+                match res {
+                    Ok(count) => {
+                        if args.len() == 4 {
+                            println!("{} events exported", count);
+                        }
+                    }
+                    Err(err) => println!("{err}"),
+                }
+            } else {
+                println!("Usage: --export-audit-log <from YYYY-MM-DD> <to YYYY-MM-DD> [file]");
+            }
+            return None;
+        } else if args[0] == "--gen-recording-key" {
+            if args.len() == 2 {
+                use scrap::record_crypt;
+                let (pk, sk) = record_crypt::gen_keypair();
+                match record_crypt::write_secret_key(&args[1], &sk) {
+                    Ok(()) => println!(
+                        "Secret key written to {}, set the option {} to the public key:\n{}",
+                        args[1],
+                        record_crypt::OPTION_PUBLIC_KEY,
+                        pk
+                    ),
+                    Err(err) => println!("{err}"),
+                }
+            } else {
+                println!("Usage: --gen-recording-key <secret key file>");
+            }
+            return None;
+        } else if args[0] == "--decrypt-recording" {
+            if args.len() == 3 || args.len() == 4 {
+                use scrap::record_crypt;
+                let res = std::fs::read_to_string(&args[2])
+                    .map_err(|e| e.into())
+                    .and_then(|sk| record_crypt::parse_secret_key(&sk))
+                    .and_then(|sk| {
+                        record_crypt::decrypt_file(&args[1], args.get(3).map(|s| s.as_str()), &sk)
+                    });
+                match res {
+                    Ok(out) => println!("Decrypted to {}", out),
+                    Err(err) => println!("{err}"),
+                }
+            } else {
+                println!("Usage: --decrypt-recording <file> <secret key file> [output file]");
+            }
+            return None;
         } else if args[0] == "--get-id" {
             println!("{}", crate::ipc::get_id());
             return None;
@@ -0,0 +1,847 @@
+// Block level operations on the files of the peer: comparing the hashes of the transferred
+// files, resuming inside an interrupted file and sending only the changed blocks of a file.
+//
+// The file transfer protocol only sends whole files, these operations are extension messages
+// (see `crate::common::new_ext_message`) with the id `ID`. Every request is answered by one
+// response with the same request id, both are `[kind: u8][request: u32 big endian][payload]`,
+// integers are big endian and strings are `[len: u16][utf8]`.
+//
+// - `STAT path` -> `STAT size: u64, modified: u64`, seconds since the epoch.
+// - `HASH path` -> `HASH size: u64, sha256: [u8; 32]`.
+// - `SUMS block_size: u32, path` -> `SUMS size: u64, [[u8; SUM_LEN]]`, the truncated SHA-256 of
+//   each block, a missing file has no blocks.
+// - `READ offset: u64, len: u32, path` -> `DATA data`.
+// - `WRITE offset: u64, path, data` -> `OK`, the file and its directory are created if missing.
+// - `TRUNCATE size: u64, modified: u64, path` -> `OK`, a `modified` of 0 is not set.
+// - `RENAME path, to` -> `OK`, `to` is replaced.
+//
+// Any request may be answered by `ERROR message`. The controlled side runs them in the
+// connection manager like the other file operations. With one-way file transfer `READ` is
+// refused, `STAT`, `HASH` and `SUMS` only see the files uploaded by the connection (`Uploads`),
+// and `RENAME` only moves the `.download` files of the uploads.
+
+use crate::transfer_limit::TransferLimiter;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use hbb_common::{
+    bail,
+    futures::{stream::FuturesUnordered, StreamExt},
+    message_proto::Message,
+    sha2::{Digest, Sha256},
+    tokio::{self, sync::oneshot},
+    ResultType,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, UNIX_EPOCH},
+};
+
+pub const ID: &str = "@rustdesk/file-delta";
+pub const SUM_LEN: usize = 16;
+pub const MAX_CHUNK: usize = 256 * 1024;
+pub const DOWNLOAD_SUFFIX: &str = ".download";
+const MIN_BLOCK_SIZE: u64 = 64 * 1024;
+const MAX_BLOCKS: u64 = 32 * 1024;
+const HASH_BUF_SIZE: usize = 256 * 1024;
+// Requests in flight while reading or writing a file.
+const WINDOW: usize = 8;
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The files uploaded by a connection, by the file transfer or the requests.
+pub type Uploads = Arc<Mutex<HashSet<PathBuf>>>;
+
+const KIND_STAT: u8 = 0x01;
+const KIND_HASH: u8 = 0x02;
+const KIND_SUMS: u8 = 0x03;
+const KIND_READ: u8 = 0x04;
+const KIND_WRITE: u8 = 0x05;
+const KIND_TRUNCATE: u8 = 0x06;
+const KIND_RENAME: u8 = 0x07;
+const KIND_DATA: u8 = 0x84;
+const KIND_OK: u8 = 0x85;
+const KIND_ERROR: u8 = 0x86;
+
+pub type Sum = [u8; SUM_LEN];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Stat(String),
+    Hash(String),
+    Sums {
+        path: String,
+        block_size: u32,
+    },
+    Read {
+        path: String,
+        offset: u64,
+        len: u32,
+    },
+    Write {
+        path: String,
+        offset: u64,
+        data: Bytes,
+    },
+    Truncate {
+        path: String,
+        size: u64,
+        modified: u64,
+    },
+    Rename {
+        path: String,
+        to: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Stat { size: u64, modified: u64 },
+    Hash { size: u64, sha256: String },
+    Sums { size: u64, sums: Vec<Sum> },
+    Data(Bytes),
+    Ok,
+    Error(String),
+}
+
+fn put_str(buf: &mut BytesMut, s: &str) {
+    let s = &s.as_bytes()[..s.len().min(u16::MAX as usize)];
+    buf.put_u16(s.len() as _);
+    buf.put_slice(s);
+}
+
+fn get_str(buf: &mut &[u8]) -> Option<String> {
+    if buf.remaining() < 2 {
+        return None;
+    }
+    let len = buf.get_u16() as usize;
+    if buf.remaining() < len {
+        return None;
+    }
+    let s = String::from_utf8(buf[..len].to_vec()).ok()?;
+    buf.advance(len);
+    Some(s)
+}
+
+fn get_u32(buf: &mut &[u8]) -> Option<u32> {
+    (buf.remaining() >= 4).then(|| buf.get_u32())
+}
+
+fn get_u64(buf: &mut &[u8]) -> Option<u64> {
+    (buf.remaining() >= 8).then(|| buf.get_u64())
+}
+
+fn header(kind: u8, id: u32, capacity: usize) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(5 + capacity);
+    buf.put_u8(kind);
+    buf.put_u32(id);
+    buf
+}
+
+impl Request {
+    pub fn encode(&self, id: u32) -> Vec<u8> {
+        let buf = match self {
+            Request::Stat(path) => {
+                let mut buf = header(KIND_STAT, id, path.len() + 2);
+                put_str(&mut buf, path);
+                buf
+            }
+            Request::Hash(path) => {
+                let mut buf = header(KIND_HASH, id, path.len() + 2);
+                put_str(&mut buf, path);
+                buf
+            }
+            Request::Sums { path, block_size } => {
+                let mut buf = header(KIND_SUMS, id, path.len() + 6);
+                buf.put_u32(*block_size);
+                put_str(&mut buf, path);
+                buf
+            }
+            Request::Read { path, offset, len } => {
+                let mut buf = header(KIND_READ, id, path.len() + 14);
+                buf.put_u64(*offset);
+                buf.put_u32(*len);
+                put_str(&mut buf, path);
+                buf
+            }
+            Request::Write { path, offset, data } => {
+                let mut buf = header(KIND_WRITE, id, path.len() + 10 + data.len());
+                buf.put_u64(*offset);
+                put_str(&mut buf, path);
+                buf.put_slice(data);
+                buf
+            }
+            Request::Truncate {
+                path,
+                size,
+                modified,
+            } => {
+                let mut buf = header(KIND_TRUNCATE, id, path.len() + 18);
+                buf.put_u64(*size);
+                buf.put_u64(*modified);
+                put_str(&mut buf, path);
+                buf
+            }
+            Request::Rename { path, to } => {
+                let mut buf = header(KIND_RENAME, id, path.len() + to.len() + 4);
+                put_str(&mut buf, path);
+                put_str(&mut buf, to);
+                buf
+            }
+        };
+        buf.to_vec()
+    }
+
+    pub fn decode(data: &[u8]) -> Option<(u32, Request)> {
+        let mut buf = data;
+        if buf.remaining() < 5 {
+            return None;
+        }
+        let kind = buf.get_u8();
+        let id = buf.get_u32();
+        let req = match kind {
+            KIND_STAT => Request::Stat(get_str(&mut buf)?),
+            KIND_HASH => Request::Hash(get_str(&mut buf)?),
+            KIND_SUMS => {
+                let block_size = get_u32(&mut buf)?;
+                Request::Sums {
+                    path: get_str(&mut buf)?,
+                    block_size,
+                }
+            }
+            KIND_READ => {
+                let offset = get_u64(&mut buf)?;
+                let len = get_u32(&mut buf)?;
+                Request::Read {
+                    path: get_str(&mut buf)?,
+                    offset,
+                    len,
+                }
+            }
+            KIND_WRITE => {
+                let offset = get_u64(&mut buf)?;
+                Request::Write {
+                    path: get_str(&mut buf)?,
+                    offset,
+                    data: Bytes::copy_from_slice(buf),
+                }
+            }
+            KIND_TRUNCATE => {
+                let size = get_u64(&mut buf)?;
+                let modified = get_u64(&mut buf)?;
+                Request::Truncate {
+                    path: get_str(&mut buf)?,
+                    size,
+                    modified,
+                }
+            }
+            KIND_RENAME => Request::Rename {
+                path: get_str(&mut buf)?,
+                to: get_str(&mut buf)?,
+            },
+            _ => return None,
+        };
+        Some((id, req))
+    }
+}
+
+impl Response {
+    pub fn encode(&self, id: u32) -> Vec<u8> {
+        let buf = match self {
+            Response::Stat { size, modified } => {
+                let mut buf = header(KIND_STAT, id, 16);
+                buf.put_u64(*size);
+                buf.put_u64(*modified);
+                buf
+            }
+            Response::Hash { size, sha256 } => {
+                let mut buf = header(KIND_HASH, id, 40);
+                buf.put_u64(*size);
+                buf.put_slice(&hex::decode(sha256).unwrap_or_default());
+                buf
+            }
+            Response::Sums { size, sums } => {
+                let mut buf = header(KIND_SUMS, id, 8 + sums.len() * SUM_LEN);
+                buf.put_u64(*size);
+                for sum in sums {
+                    buf.put_slice(sum);
+                }
+                buf
+            }
+            Response::Data(data) => {
+                let mut buf = header(KIND_DATA, id, data.len());
+                buf.put_slice(data);
+                buf
+            }
+            Response::Ok => header(KIND_OK, id, 0),
+            Response::Error(err) => {
+                let mut buf = header(KIND_ERROR, id, err.len() + 2);
+                put_str(&mut buf, err);
+                buf
+            }
+        };
+        buf.to_vec()
+    }
+
+    pub fn decode(data: &[u8]) -> Option<(u32, Response)> {
+        let mut buf = data;
+        if buf.remaining() < 5 {
+            return None;
+        }
+        let kind = buf.get_u8();
+        let id = buf.get_u32();
+        let res = match kind {
+            KIND_STAT => Response::Stat {
+                size: get_u64(&mut buf)?,
+                modified: get_u64(&mut buf)?,
+            },
+            KIND_HASH => {
+                let size = get_u64(&mut buf)?;
+                if buf.len() != 32 {
+                    return None;
+                }
+                Response::Hash {
+                    size,
+                    sha256: hex::encode(buf),
+                }
+            }
+            KIND_SUMS => {
+                let size = get_u64(&mut buf)?;
+                if buf.len() % SUM_LEN != 0 {
+                    return None;
+                }
+                let sums = buf
+                    .chunks(SUM_LEN)
+                    .map(|c| c.try_into().unwrap_or_default())
+                    .collect();
+                Response::Sums { size, sums }
+            }
+            KIND_DATA => Response::Data(Bytes::copy_from_slice(buf)),
+            KIND_OK => Response::Ok,
+            KIND_ERROR => Response::Error(get_str(&mut buf)?),
+            _ => return None,
+        };
+        Some((id, res))
+    }
+}
+
+pub fn file_meta(path: &Path) -> Option<(u64, u64)> {
+    let meta = std::fs::metadata(path).ok()?;
+    if !meta.is_file() {
+        return None;
+    }
+    let modified = meta
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    Some((meta.len(), modified))
+}
+
+pub fn sha256_file(path: &Path) -> ResultType<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; HASH_BUF_SIZE];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// The block size for the sums of a file of `size`, so that a file has at most `MAX_BLOCKS`.
+pub fn block_size_for(size: u64) -> u32 {
+    let mut block_size = MIN_BLOCK_SIZE;
+    while (size + block_size - 1) / block_size > MAX_BLOCKS && block_size < u32::MAX as u64 / 2 {
+        block_size *= 2;
+    }
+    block_size as _
+}
+
+/// The size and the block sums of a file, a missing file has no blocks.
+pub fn block_sums(path: &Path, block_size: u32) -> ResultType<(u64, Vec<Sum>)> {
+    let block_size = block_size as u64;
+    if block_size < MIN_BLOCK_SIZE {
+        bail!("Block size {} is too small", block_size);
+    }
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((0, vec![])),
+        Err(e) => bail!(e),
+    };
+    let size = file.metadata()?.len();
+    if (size + block_size - 1) / block_size > MAX_BLOCKS {
+        bail!("Too many blocks");
+    }
+    let mut sums: Vec<Sum> = Vec::new();
+    let mut buf = vec![0u8; HASH_BUF_SIZE.min(block_size as usize)];
+    let mut left = size;
+    while left > 0 {
+        let mut hasher = Sha256::new();
+        let mut block_left = left.min(block_size);
+        left -= block_left;
+        while block_left > 0 {
+            let n = buf.len().min(block_left as usize);
+            file.read_exact(&mut buf[..n])?;
+            hasher.update(&buf[..n]);
+            block_left -= n as u64;
+        }
+        sums.push(hasher.finalize()[..SUM_LEN].try_into()?);
+    }
+    Ok((size, sums))
+}
+
+/// The `(offset, len)` ranges of the source file of `size` to copy to make the destination
+/// equal, the destination has to be truncated to `size` afterwards.
+pub fn changed_ranges(src: &[Sum], dst: &[Sum], size: u64, block_size: u32) -> Vec<(u64, u64)> {
+    let block_size = block_size as u64;
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for (i, sum) in src.iter().enumerate() {
+        if dst.get(i) == Some(sum) {
+            continue;
+        }
+        let offset = i as u64 * block_size;
+        let len = block_size.min(size.saturating_sub(offset));
+        match ranges.last_mut() {
+            Some(last) if last.0 + last.1 == offset => last.1 += len,
+            _ => ranges.push((offset, len)),
+        }
+    }
+    ranges
+}
+
+fn chunks(ranges: &[(u64, u64)]) -> Vec<(u64, usize)> {
+    let mut chunks = Vec::new();
+    for &(offset, len) in ranges {
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(MAX_CHUNK as u64);
+            chunks.push((offset + done, n as usize));
+            done += n;
+        }
+    }
+    chunks
+}
+
+fn write_at(path: &Path, offset: u64, data: &[u8]) -> ResultType<()> {
+    if let Some(dir) = path.parent() {
+        if !dir.as_os_str().is_empty() {
+            std::fs::create_dir_all(dir)?;
+        }
+    }
+    let mut file = OpenOptions::new().write(true).create(true).open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(data)?;
+    Ok(())
+}
+
+fn truncate(path: &Path, size: u64, modified: u64) -> ResultType<()> {
+    let file = OpenOptions::new().write(true).create(true).open(path)?;
+    file.set_len(size)?;
+    if modified > 0 {
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(modified))?;
+    }
+    Ok(())
+}
+
+// With one-way file transfer, the files of the controlled side are not seen, except the uploads.
+fn check_upload(path: &str, one_way: bool, uploads: &Uploads) -> ResultType<()> {
+    if one_way && !uploads.lock().unwrap().contains(Path::new(path)) {
+        bail!("One-way file transfer is enabled on the controlled side");
+    }
+    Ok(())
+}
+
+fn add_upload(path: &str, one_way: bool, uploads: &Uploads) {
+    if one_way {
+        uploads.lock().unwrap().insert(PathBuf::from(path));
+    }
+}
+
+fn run(req: Request, one_way: bool, uploads: &Uploads) -> ResultType<Response> {
+    if let Request::Stat(path) | Request::Hash(path) | Request::Sums { path, .. } = &req {
+        check_upload(path, one_way, uploads)?;
+    }
+    Ok(match req {
+        Request::Stat(path) => {
+            let Some((size, modified)) = file_meta(Path::new(&path)) else {
+                bail!("No such file: {}", path);
+            };
+            Response::Stat { size, modified }
+        }
+        Request::Hash(path) => {
+            let path = Path::new(&path);
+            let sha256 = sha256_file(path)?;
+            Response::Hash {
+                size: std::fs::metadata(path)?.len(),
+                sha256,
+            }
+        }
+        Request::Sums { path, block_size } => {
+            let (size, sums) = block_sums(Path::new(&path), block_size)?;
+            Response::Sums { size, sums }
+        }
+        Request::Read { path, offset, len } => {
+            if one_way {
+                bail!("One-way file transfer is enabled on the controlled side");
+            }
+            let mut file = File::open(&path)?;
+            file.seek(SeekFrom::Start(offset))?;
+            let mut data = Vec::with_capacity((len as usize).min(MAX_CHUNK));
+            file.take((len as usize).min(MAX_CHUNK) as _)
+                .read_to_end(&mut data)?;
+            Response::Data(data.into())
+        }
+        Request::Write { path, offset, data } => {
+            if data.len() > MAX_CHUNK {
+                bail!("Chunk is too large");
+            }
+            add_upload(&path, one_way, uploads);
+            write_at(Path::new(&path), offset, &data)?;
+            Response::Ok
+        }
+        Request::Truncate {
+            path,
+            size,
+            modified,
+        } => {
+            add_upload(&path, one_way, uploads);
+            truncate(Path::new(&path), size, modified)?;
+            Response::Ok
+        }
+        Request::Rename { path, to } => {
+            if one_way && !path.ends_with(DOWNLOAD_SUFFIX) {
+                bail!("One-way file transfer is enabled on the controlled side");
+            }
+            add_upload(&to, one_way, uploads);
+            std::fs::rename(&path, &to)?;
+            hbb_common::log::info!("file delta renamed {} to {}", path, to);
+            Response::Ok
+        }
+    })
+}
+
+/// Run a request of the peer, blocking, returns the encoded response.
+pub fn handle(data: &[u8], one_way: bool, uploads: &Uploads) -> Vec<u8> {
+    let Some((id, req)) = Request::decode(data) else {
+        return Response::Error("Invalid request".to_owned()).encode(0);
+    };
+    match run(req, one_way, uploads) {
+        Ok(res) => res.encode(id),
+        Err(e) => Response::Error(e.to_string()).encode(id),
+    }
+}
+
+/// Sends the requests to the peer and matches the responses, cheap to clone.
+#[derive(Clone)]
+pub struct Client {
+    send: Arc<dyn Fn(Message) + Send + Sync>,
+    pending: Arc<Mutex<HashMap<u32, oneshot::Sender<Response>>>>,
+    next_id: Arc<AtomicU32>,
+}
+
+impl Client {
+    pub fn new(send: impl Fn(Message) + Send + Sync + 'static) -> Self {
+        Self {
+            send: Arc::new(send),
+            pending: Default::default(),
+            next_id: Arc::new(AtomicU32::new(1)),
+        }
+    }
+
+    /// A response of the peer.
+    pub fn on_response(&self, data: &[u8]) {
+        if let Some((id, res)) = Response::decode(data) {
+            if let Some(tx) = self.pending.lock().unwrap().remove(&id) {
+                tx.send(res).ok();
+            }
+        }
+    }
+
+    async fn request(&self, req: Request) -> ResultType<Response> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, tx);
+        (self.send)(crate::common::new_ext_message(ID, req.encode(id)));
+        let res = tokio::time::timeout(TIMEOUT, rx).await;
+        self.pending.lock().unwrap().remove(&id);
+        match res {
+            Ok(Ok(Response::Error(err))) => bail!(err),
+            Ok(Ok(res)) => Ok(res),
+            Ok(Err(_)) => bail!("Connection closed"),
+            Err(_) => bail!("Timeout"),
+        }
+    }
+
+    pub async fn stat(&self, path: &str) -> ResultType<(u64, u64)> {
+        match self.request(Request::Stat(path.to_owned())).await? {
+            Response::Stat { size, modified } => Ok((size, modified)),
+            res => bail!("Unexpected response {:?}", res),
+        }
+    }
+
+    /// The size and the hex SHA-256 of the file of the peer.
+    pub async fn hash(&self, path: &str) -> ResultType<(u64, String)> {
+        match self.request(Request::Hash(path.to_owned())).await? {
+            Response::Hash { size, sha256 } => Ok((size, sha256)),
+            res => bail!("Unexpected response {:?}", res),
+        }
+    }
+
+    pub async fn sums(&self, path: &str, block_size: u32) -> ResultType<(u64, Vec<Sum>)> {
+        match self
+            .request(Request::Sums {
+                path: path.to_owned(),
+                block_size,
+            })
+            .await?
+        {
+            Response::Sums { size, sums } => Ok((size, sums)),
+            res => bail!("Unexpected response {:?}", res),
+        }
+    }
+
+    /// Set the size, and the modification time if not 0, of the file of the peer, it is created
+    /// if missing.
+    pub async fn truncate(&self, path: &str, size: u64, modified: u64) -> ResultType<()> {
+        self.request(Request::Truncate {
+            path: path.to_owned(),
+            size,
+            modified,
+        })
+        .await?;
+        Ok(())
+    }
+
+    pub async fn rename(&self, path: &str, to: &str) -> ResultType<()> {
+        self.request(Request::Rename {
+            path: path.to_owned(),
+            to: to.to_owned(),
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Write the `ranges` of the local file to the file of the peer, returns the bytes sent.
+    pub async fn upload(
+        &self,
+        local: &Path,
+        remote: &str,
+        ranges: &[(u64, u64)],
+        mut limiter: Option<&mut TransferLimiter>,
+    ) -> ResultType<u64> {
+        let mut file = File::open(local)?;
+        let mut inflight = FuturesUnordered::new();
+        let mut sent = 0;
+        for (offset, len) in chunks(ranges) {
+            while inflight.len() >= WINDOW {
+                if let Some(res) = inflight.next().await {
+                    res?;
+                }
+            }
+            if let Some(limiter) = limiter.as_deref_mut() {
+                while !limiter.ready() {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                limiter.consume(len as _);
+            }
+            let mut data = vec![0u8; len];
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut data)?;
+            inflight.push(self.request(Request::Write {
+                path: remote.to_owned(),
+                offset,
+                data: data.into(),
+            }));
+            sent += len as u64;
+        }
+        while let Some(res) = inflight.next().await {
+            res?;
+        }
+        Ok(sent)
+    }
+
+    /// Read the `ranges` of the file of the peer into the local file, returns the bytes received.
+    pub async fn download(
+        &self,
+        remote: &str,
+        local: &Path,
+        ranges: &[(u64, u64)],
+    ) -> ResultType<u64> {
+        let mut received = 0;
+        let mut inflight = FuturesUnordered::new();
+        let mut chunks = chunks(ranges).into_iter();
+        loop {
+            while inflight.len() < WINDOW {
+                let Some((offset, len)) = chunks.next() else {
+                    break;
+                };
+                let req = Request::Read {
+                    path: remote.to_owned(),
+                    offset,
+                    len: len as _,
+                };
+                inflight.push(async move { (offset, len, self.request(req).await) });
+            }
+            let Some((offset, len, res)) = inflight.next().await else {
+                break;
+            };
+            let Response::Data(data) = res? else {
+                bail!("Unexpected response");
+            };
+            if data.len() != len {
+                bail!("{} was changed during the transfer", remote);
+            }
+            write_at(local, offset, &data)?;
+            received += len as u64;
+        }
+        Ok(received)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let reqs = vec![
+            Request::Stat("/tmp/a".to_owned()),
+            Request::Hash("C:\\a b".to_owned()),
+            Request::Sums {
+                path: "a".to_owned(),
+                block_size: 65536,
+            },
+            Request::Read {
+                path: "a".to_owned(),
+                offset: u64::MAX,
+                len: 3,
+            },
+            Request::Write {
+                path: "a".to_owned(),
+                offset: 5,
+                data: Bytes::from_static(b"abc"),
+            },
+            Request::Truncate {
+                path: "a".to_owned(),
+                size: 1,
+                modified: 2,
+            },
+            Request::Rename {
+                path: "a.download".to_owned(),
+                to: "a".to_owned(),
+            },
+        ];
+        for (i, req) in reqs.into_iter().enumerate() {
+            assert_eq!(Request::decode(&req.encode(i as _)), Some((i as _, req)));
+        }
+        let sha256 = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let responses = vec![
+            Response::Stat {
+                size: 1,
+                modified: 2,
+            },
+            Response::Hash {
+                size: 3,
+                sha256: sha256.to_owned(),
+            },
+            Response::Sums {
+                size: 4,
+                sums: vec![[1; SUM_LEN], [2; SUM_LEN]],
+            },
+            Response::Data(Bytes::from_static(b"abc")),
+            Response::Ok,
+            Response::Error("error".to_owned()),
+        ];
+        for (i, res) in responses.into_iter().enumerate() {
+            assert_eq!(Response::decode(&res.encode(i as _)), Some((i as _, res)));
+        }
+        assert_eq!(Request::decode(&[KIND_STAT, 0, 0, 0, 1, 0, 5, b'a']), None);
+        assert_eq!(Response::decode(&[KIND_OK, 0, 0]), None);
+    }
+
+    #[test]
+    fn test_changed_ranges() {
+        let bs = MIN_BLOCK_SIZE as u32;
+        let b = MIN_BLOCK_SIZE;
+        let src = [[1; SUM_LEN], [2; SUM_LEN], [3; SUM_LEN], [4; SUM_LEN]];
+        let size = b * 3 + 10;
+        assert_eq!(changed_ranges(&src, &src, size, bs), vec![]);
+        assert_eq!(changed_ranges(&src, &[], size, bs), vec![(0, size)]);
+        let dst = [
+            [1; SUM_LEN],
+            [0; SUM_LEN],
+            [0; SUM_LEN],
+            [4; SUM_LEN],
+            [5; SUM_LEN],
+        ];
+        assert_eq!(changed_ranges(&src, &dst, size, bs), vec![(b, b * 2)]);
+        let dst = [[1; SUM_LEN], [2; SUM_LEN], [3; SUM_LEN]];
+        assert_eq!(changed_ranges(&src, &dst, size, bs), vec![(b * 3, 10)]);
+        assert_eq!(block_size_for(0), bs);
+        assert_eq!(block_size_for(MAX_BLOCKS * b), bs);
+        assert_eq!(block_size_for(MAX_BLOCKS * b + 1), bs * 2);
+    }
+
+    #[test]
+    fn test_block_sums() {
+        let path = std::env::temp_dir().join(format!("file_delta_{}", std::process::id()));
+        let data: Vec<u8> = (0..MIN_BLOCK_SIZE * 2 + 3).map(|i| i as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let bs = MIN_BLOCK_SIZE as u32;
+        let (size, sums) = block_sums(&path, bs).unwrap();
+        assert_eq!(size, data.len() as u64);
+        assert_eq!(sums.len(), 3);
+        assert_eq!(
+            sums[2][..],
+            Sha256::digest(&data[MIN_BLOCK_SIZE as usize * 2..])[..SUM_LEN]
+        );
+        let mut changed = data.clone();
+        changed[MIN_BLOCK_SIZE as usize + 1] ^= 1;
+        std::fs::write(&path, &changed).unwrap();
+        let (_, changed_sums) = block_sums(&path, bs).unwrap();
+        assert_eq!(
+            changed_ranges(&changed_sums, &sums, size, bs),
+            vec![(MIN_BLOCK_SIZE, MIN_BLOCK_SIZE)]
+        );
+        assert_eq!(
+            sha256_file(&path).unwrap(),
+            hex::encode(Sha256::digest(&changed))
+        );
+        std::fs::remove_file(&path).ok();
+        assert_eq!(block_sums(&path, bs).unwrap(), (0, vec![]));
+    }
+
+    #[test]
+    fn test_one_way() {
+        let path = std::env::temp_dir().join(format!("file_delta_one_way_{}", std::process::id()));
+        std::fs::write(&path, b"data").unwrap();
+        let path = path.to_string_lossy().to_string();
+        let uploads = Uploads::default();
+        let stat = || run(Request::Stat(path.clone()), true, &uploads);
+        assert!(stat().is_err());
+        assert!(run(Request::Stat(path.clone()), false, &uploads).is_ok());
+        let write = Request::Write {
+            path: path.clone(),
+            offset: 4,
+            data: Bytes::from_static(b"more"),
+        };
+        assert!(run(write, true, &uploads).is_ok());
+        assert!(matches!(stat(), Ok(Response::Stat { size: 8, .. })));
+        let read = Request::Read {
+            path: path.clone(),
+            offset: 0,
+            len: 8,
+        };
+        assert!(run(read, true, &uploads).is_err());
+        std::fs::remove_file(&path).ok();
+    }
+}
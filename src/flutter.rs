@@ -800,6 +800,30 @@ impl InvokeUiSession for FlutterHandler {
         );
     }
 
+    fn update_capture_windows(&self, windows: Vec<scrap::region::WindowInfo>) {
+        let windows = windows
+            .iter()
+            .map(|w| {
+                json!({
+                    "window": w.window,
+                    "x": w.x,
+                    "y": w.y,
+                    "width": w.width,
+                    "height": w.height,
+                    "title": w.title,
+                })
+            })
+            .collect::<Vec<_>>();
+        self.push_event(
+            "capture_windows",
+            &[(
+                "value",
+                &serde_json::to_string(&windows).unwrap_or_default(),
+            )],
+            &[],
+        );
+    }
+
     // unused in flutter
     fn update_transfer_list(&self) {}
 
@@ -1107,6 +1131,45 @@ impl InvokeUiSession for FlutterHandler {
         }
     }
 
+    fn handle_exec_message(&self, message: crate::remote_exec::ExecMessage) {
+        use crate::remote_exec::ExecMessage;
+        let event_data: Vec<(&str, serde_json::Value)> = match message {
+            ExecMessage::Output {
+                exec_id,
+                is_stderr,
+                data,
+            } => vec![
+                ("type", json!("exec_output")),
+                ("exec_id", json!(exec_id)),
+                ("is_stderr", json!(is_stderr)),
+                ("data", json!(crate::encode64(&data))),
+            ],
+            ExecMessage::Exit(exit) => vec![
+                ("type", json!("exec_exit")),
+                ("exec_id", json!(exit.exec_id)),
+                ("exit_code", json!(exit.exit_code)),
+                ("timed_out", json!(exit.timed_out)),
+                ("error", json!(&exit.error)),
+                ("unsupported", json!(exit.unsupported)),
+            ],
+            ExecMessage::Command(_) => return,
+        };
+        self.push_event_("terminal_response", &event_data, &[], &[]);
+    }
+
+    fn update_terminal_participants(
+        &self,
+        participants: Vec<crate::terminal_control::Participant>,
+        my_conn_id: i32,
+    ) {
+        let event_data: Vec<(&str, serde_json::Value)> = vec![
+            ("type", json!("participants")),
+            ("my_conn_id", json!(my_conn_id)),
+            ("participants", json!(participants)),
+        ];
+        self.push_event_("terminal_response", &event_data, &[], &[]);
+    }
+
     fn handle_terminal_response(&self, response: TerminalResponse) {
         use hbb_common::message_proto::terminal_response::Union;
 
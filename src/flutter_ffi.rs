@@ -263,6 +263,26 @@ pub fn session_refresh(session_id: SessionID, display: usize) {
     }
 }
 
+pub fn session_capture_region(
+    session_id: SessionID,
+    display: i32,
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+    window: u64,
+) {
+    if let Some(s) = sessions::get_session_by_session_id(&session_id) {
+        s.capture_region(display, x, y, width, height, window);
+    }
+}
+
+pub fn session_list_capture_windows(session_id: SessionID) {
+    if let Some(s) = sessions::get_session_by_session_id(&session_id) {
+        s.list_capture_windows();
+    }
+}
+
 pub fn session_take_screenshot(session_id: SessionID, display: usize) {
     if let Some(s) = sessions::get_session_by_session_id(&session_id) {
         s.take_screenshot(display as _, session_id.to_string());
@@ -654,6 +674,26 @@ pub fn session_close_terminal(session_id: SessionID, terminal_id: i32) {
     }
 }
 
+pub fn session_transfer_terminal_control(session_id: SessionID, conn_id: i32) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.transfer_terminal_control(conn_id);
+    }
+}
+
+pub fn session_exec_command(
+    session_id: SessionID,
+    exec_id: i32,
+    command: String,
+    args: Vec<String>,
+    env: Vec<String>,
+    cwd: String,
+    timeout_ms: u32,
+) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.exec_command(exec_id, command, args, env, cwd, timeout_ms);
+    }
+}
+
 pub fn session_peer_option(session_id: SessionID, name: String, value: String) {
     if let Some(session) = sessions::get_session_by_session_id(&session_id) {
         session.set_option(name, value);
@@ -757,6 +797,12 @@ pub fn session_cancel_job(session_id: SessionID, act_id: i32) {
     }
 }
 
+pub fn session_set_transfer_limit(session_id: SessionID, kbps: u32, priority: String) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.set_transfer_limit(kbps, priority);
+    }
+}
+
 pub fn session_create_dir(session_id: SessionID, act_id: i32, path: String, is_remote: bool) {
     if let Some(session) = sessions::get_session_by_session_id(&session_id) {
         session.create_dir(act_id, path, is_remote);
@@ -1915,6 +1961,11 @@ pub fn cm_switch_permission(conn_id: i32, name: String, enabled: bool) {
     crate::ui_cm_interface::switch_permission(conn_id, name, enabled)
 }
 
+pub fn cm_set_file_transfer_limit(conn_id: i32, kbps: u32, priority: String) {
+    #[cfg(not(any(target_os = "ios")))]
+    crate::ui_cm_interface::set_file_transfer_limit(conn_id, kbps, priority)
+}
+
 pub fn cm_can_elevate() -> SyncReturn<bool> {
     SyncReturn(crate::ui_cm_interface::can_elevate())
 }
@@ -5,6 +5,7 @@ use serde_json::{Map, Value};
 #[cfg(feature = "flutter")]
 pub mod account;
 mod http_client;
+pub mod record_target;
 pub mod record_upload;
 pub mod sync;
 pub mod downloader;
@@ -1,135 +1,343 @@
-use crate::hbbs_http::create_http_client;
-use bytes::Bytes;
-use hbb_common::{bail, config::Config, lazy_static, log, ResultType};
-use reqwest::blocking::{Body, Client};
+// Uploads the incoming recordings to the target of `record_target`.
+//
+// A finished file whose tail fails is retried in the background, the next files are uploaded
+// meanwhile. The state of a resumable upload is saved next to the file with `STATE_SUFFIX`, an
+// upload interrupted by a restart is resumed once a new recording starts in the same directory.
+
+use crate::hbbs_http::record_target::{self, UploadTarget, OPTION_UPLOAD_URL};
+use hbb_common::{anyhow, bail, config::Config, lazy_static, log, ResultType};
 use scrap::record::RecordState;
-use serde::Serialize;
-use serde_json::Map;
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashSet,
     fs::File,
     io::{prelude::*, SeekFrom},
-    sync::{mpsc::Receiver, Arc, Mutex},
+    path::Path,
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc, Mutex,
+    },
     time::{Duration, Instant},
 };
 
 const MAX_HEADER_LEN: usize = 1024;
 const SHOULD_SEND_TIME: Duration = Duration::from_secs(1);
 const SHOULD_SEND_SIZE: u64 = 1024 * 1024;
+const MAX_PART_SIZE: u64 = 16 * 1024 * 1024;
+const MIN_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_FAILURES: u32 = 10;
+const STATE_SUFFIX: &str = ".upload";
 
 lazy_static::lazy_static! {
     static ref ENABLE: Arc<Mutex<bool>> = Default::default();
+    // The files uploaded by the threads of this process, not to be resumed from their state.
+    static ref UPLOADING: Mutex<HashSet<String>> = Default::default();
 }
 
 pub fn is_enable() -> bool {
-    ENABLE.lock().unwrap().clone()
+    *ENABLE.lock().unwrap() || !Config::get_option(OPTION_UPLOAD_URL).is_empty()
 }
 
 pub fn run(rx: Receiver<RecordState>) {
-    let mut uploader = RecordUploader {
-        client: create_http_client(),
-        api_server: crate::get_api_server(
-            Config::get_option("api-server"),
-            Config::get_option("custom-rendezvous-server"),
-        ),
-        filepath: Default::default(),
-        filename: Default::default(),
-        upload_size: Default::default(),
-        running: Default::default(),
-        last_send: Instant::now(),
-    };
-    std::thread::spawn(move || loop {
-        if let Err(e) = match rx.recv() {
-            Ok(state) => match state {
-                RecordState::NewFile(filepath) => uploader.handle_new_file(filepath),
-                RecordState::NewFrame => {
-                    if uploader.running {
-                        uploader.handle_frame(false)
-                    } else {
-                        Ok(())
-                    }
-                }
-                RecordState::WriteTail => {
-                    if uploader.running {
-                        uploader.handle_tail()
-                    } else {
-                        Ok(())
+    // A new target is created for every file, as the tail of a file may be retried while the
+    // next one is uploaded.
+    match record_target::create_target() {
+        Ok(target) => log::info!("recordings are uploaded to {}", target.name()),
+        Err(e) => {
+            log::error!("Invalid {}: {}", OPTION_UPLOAD_URL, e);
+            return;
+        }
+    }
+    std::thread::spawn(move || {
+        let mut uploader = RecordUploader::default();
+        loop {
+            let state = match uploader.next_retry() {
+                Some(at) => match rx.recv_timeout(at.saturating_duration_since(Instant::now())) {
+                    Ok(state) => Some(state),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    // Nothing else to do but the retries.
+                    Err(RecvTimeoutError::Disconnected) => {
+                        std::thread::sleep(at.saturating_duration_since(Instant::now()));
+                        None
                     }
-                }
-                RecordState::RemoveFile => {
-                    if uploader.running {
-                        uploader.handle_remove()
-                    } else {
-                        Ok(())
+                },
+                None => match rx.recv() {
+                    Ok(state) => Some(state),
+                    Err(e) => {
+                        log::trace!("upload thread stop: {}", e);
+                        break;
                     }
-                }
-            },
-            Err(e) => {
-                log::trace!("upload thread stop: {}", e);
-                break;
+                },
+            };
+            match state {
+                Some(state) => uploader.handle(state),
+                None => uploader.retry_tails(),
             }
-        } {
-            uploader.running = false;
-            log::error!("upload stop: {}", e);
         }
     });
 }
 
+#[derive(Default)]
 struct RecordUploader {
-    client: Client,
-    api_server: String,
-    filepath: String,
-    filename: String,
-    upload_size: u64,
-    running: bool,
-    last_send: Instant,
+    // The file being recorded.
+    current: Option<Upload>,
+    // The finished files whose tail is retried.
+    tails: Vec<Upload>,
 }
+
 impl RecordUploader {
-    fn send<Q, B>(&self, query: &Q, body: B) -> ResultType<()>
-    where
-        Q: Serialize + ?Sized,
-        B: Into<Body>,
-    {
-        match self
-            .client
-            .post(format!("{}/api/record", self.api_server))
-            .query(query)
-            .body(body)
-            .send()
-        {
-            Ok(resp) => {
-                if let Ok(m) = resp.json::<Map<String, serde_json::Value>>() {
-                    if let Some(e) = m.get("error") {
-                        bail!(e.to_string());
-                    }
+    fn handle(&mut self, state: RecordState) {
+        let res = match state {
+            RecordState::NewFile(filepath) => self.handle_new_file(filepath),
+            RecordState::NewFrame => match self.current.as_mut() {
+                Some(upload) => upload.handle_frame(false),
+                None => Ok(()),
+            },
+            RecordState::WriteTail => {
+                self.handle_tail();
+                Ok(())
+            }
+            RecordState::RemoveFile => {
+                if let Some(mut upload) = self.current.take() {
+                    upload.handle_remove();
                 }
                 Ok(())
             }
-            Err(e) => bail!(e.to_string()),
+            // Encrypted files are uploaded once finished.
+            RecordState::Encrypted(filepath) => self.handle_new_file(filepath).map(|_| {
+                self.handle_tail();
+            }),
+        };
+        if let Err(e) = res {
+            self.current = None;
+            log::error!("upload stop: {}", e);
         }
     }
 
     fn handle_new_file(&mut self, filepath: String) -> ResultType<()> {
-        match std::path::PathBuf::from(&filepath).file_name() {
-            Some(filename) => match filename.to_owned().into_string() {
-                Ok(filename) => {
-                    self.filename = filename.clone();
-                    self.filepath = filepath.clone();
-                    self.upload_size = 0;
-                    self.running = true;
-                    self.last_send = Instant::now();
-                    self.send(&[("type", "new"), ("file", &filename)], Bytes::new())?;
-                    Ok(())
+        self.current = None;
+        let upload = Upload::new(filepath)?;
+        self.resume_dir(&upload.filepath);
+        self.current = Some(upload);
+        let upload = self.current.as_mut().unwrap();
+        match upload.ensure_created() {
+            Ok(()) => Ok(()),
+            Err(e) => upload.on_failure(e),
+        }
+    }
+
+    fn handle_tail(&mut self) {
+        if let Some(upload) = self.current.take() {
+            self.tail(upload);
+        }
+    }
+
+    fn tail(&mut self, mut upload: Upload) {
+        let e = match upload.try_tail() {
+            Ok(()) => return,
+            Err(e) => e,
+        };
+        match upload.on_failure(e) {
+            Ok(()) => self.tails.push(upload),
+            // The state is kept, to be resumed later.
+            Err(e) => log::error!("upload of {} stop: {}", upload.filename, e),
+        }
+    }
+
+    fn next_retry(&self) -> Option<Instant> {
+        self.tails.iter().filter_map(|u| u.retry_at).min()
+    }
+
+    fn retry_tails(&mut self) {
+        let now = Instant::now();
+        let (due, wait): (Vec<_>, Vec<_>) = std::mem::take(&mut self.tails)
+            .into_iter()
+            .partition(|u| u.retry_at.map_or(true, |at| at <= now));
+        self.tails = wait;
+        for upload in due {
+            self.tail(upload);
+        }
+    }
+
+    // Resume the uploads interrupted by a restart, from the states left in the directory.
+    fn resume_dir(&mut self, filepath: &str) {
+        let Some(dir) = Path::new(filepath).parent() else {
+            return;
+        };
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path().to_string_lossy().to_string();
+            let Some(filepath) = path.strip_suffix(STATE_SUFFIX) else {
+                continue;
+            };
+            if UPLOADING.lock().unwrap().contains(filepath) {
+                continue;
+            }
+            match Upload::resume(filepath.to_owned()) {
+                Ok(mut upload) => {
+                    upload.retry_at = Some(Instant::now());
+                    log::info!(
+                        "resume upload of {} from {}",
+                        upload.filename,
+                        upload.upload_size
+                    );
+                    self.tails.push(upload);
+                }
+                Err(e) => {
+                    log::error!("failed to resume upload of {}: {}", filepath, e);
+                    std::fs::remove_file(&path).ok();
                 }
+            }
+        }
+    }
+}
+
+// The resumable state of an upload, saved as `<file><STATE_SUFFIX>`.
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedUpload {
+    target: String,
+    filename: String,
+    state: String,
+}
+
+struct Upload {
+    target: Box<dyn UploadTarget>,
+    filepath: String,
+    filename: String,
+    // The size acknowledged by the target, the upload is resumed from here after a failure.
+    upload_size: u64,
+    // The target has accepted the new file.
+    created: bool,
+    last_send: Instant,
+    // Consecutive failures of the file.
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Upload {
+    fn new(filepath: String) -> ResultType<Self> {
+        let filename = match Path::new(&filepath).file_name() {
+            Some(filename) => match filename.to_owned().into_string() {
+                Ok(filename) => filename,
                 Err(_) => bail!("can't parse filename:{:?}", filename),
             },
             None => bail!("can't parse filepath:{}", filepath),
+        };
+        let upload = Self {
+            target: record_target::create_target()?,
+            filepath,
+            filename,
+            upload_size: 0,
+            created: false,
+            last_send: Instant::now(),
+            failures: 0,
+            retry_at: None,
+        };
+        UPLOADING.lock().unwrap().insert(upload.filepath.clone());
+        Ok(upload)
+    }
+
+    fn resume(filepath: String) -> ResultType<Self> {
+        let state_path = format!("{}{}", filepath, STATE_SUFFIX);
+        let saved: SavedUpload = serde_json::from_str(&std::fs::read_to_string(&state_path)?)?;
+        if !Path::new(&filepath).exists() {
+            bail!("The file is removed");
+        }
+        let mut upload = Self::new(filepath)?;
+        if upload.target.name() != saved.target {
+            bail!("The target is changed to {}", upload.target.name());
+        }
+        upload.filename = saved.filename;
+        upload.upload_size = upload.target.restore(&saved.state)?;
+        upload.created = true;
+        Ok(upload)
+    }
+
+    fn state_path(&self) -> String {
+        format!("{}{}", self.filepath, STATE_SUFFIX)
+    }
+
+    fn save_state(&self) {
+        let Some(state) = self.target.state() else {
+            return;
+        };
+        let saved = SavedUpload {
+            target: self.target.name().to_owned(),
+            filename: self.filename.clone(),
+            state,
+        };
+        let saved = serde_json::to_string(&saved).unwrap_or_default();
+        if let Err(e) = std::fs::write(self.state_path(), saved) {
+            log::warn!(
+                "failed to save the upload state of {}: {}",
+                self.filename,
+                e
+            );
+        }
+    }
+
+    fn remove_state(&self) {
+        std::fs::remove_file(self.state_path()).ok();
+    }
+
+    // Back off after a failure, until too many of them.
+    fn on_failure(&mut self, e: anyhow::Error) -> ResultType<()> {
+        self.failures += 1;
+        if self.failures >= MAX_FAILURES {
+            bail!("{} failures, last: {}", self.failures, e);
         }
+        let interval = (MIN_RETRY_INTERVAL * 2u32.pow(self.failures - 1)).min(MAX_RETRY_INTERVAL);
+        log::warn!(
+            "upload of {} failed ({}), retry in {:?}: {}",
+            self.filename,
+            self.failures,
+            interval,
+            e
+        );
+        self.retry_at = Some(Instant::now() + interval);
+        Ok(())
+    }
+
+    fn on_success(&mut self) {
+        self.failures = 0;
+        self.retry_at = None;
+    }
+
+    fn ensure_created(&mut self) -> ResultType<()> {
+        if !self.created {
+            self.target.new_file(&self.filename)?;
+            self.created = true;
+            self.save_state();
+        }
+        Ok(())
     }
 
     fn handle_frame(&mut self, flush: bool) -> ResultType<()> {
         if !flush && self.last_send.elapsed() < SHOULD_SEND_TIME {
             return Ok(());
         }
+        if !flush && self.retry_at.map_or(false, |at| at > Instant::now()) {
+            return Ok(());
+        }
+        match self.send_part(flush) {
+            Ok(()) => {
+                self.on_success();
+                Ok(())
+            }
+            // The tail is retried by the uploader.
+            Err(e) if flush => Err(e),
+            Err(e) => self.on_failure(e),
+        }
+    }
+
+    fn send_part(&mut self, flush: bool) -> ResultType<()> {
+        self.ensure_created()?;
+        if !self.target.streaming() {
+            return Ok(());
+        }
         match File::open(&self.filepath) {
             Ok(mut file) => match file.metadata() {
                 Ok(m) => {
@@ -142,19 +350,12 @@ impl RecordUploader {
                     }
                     let mut buf = Vec::new();
                     match file.seek(SeekFrom::Start(self.upload_size)) {
-                        Ok(_) => match file.read_to_end(&mut buf) {
+                        Ok(_) => match file.by_ref().take(MAX_PART_SIZE).read_to_end(&mut buf) {
                             Ok(length) => {
-                                self.send(
-                                    &[
-                                        ("type", "part"),
-                                        ("file", &self.filename),
-                                        ("offset", &self.upload_size.to_string()),
-                                        ("length", &length.to_string()),
-                                    ],
-                                    buf,
-                                )?;
-                                self.upload_size = len;
+                                self.target.part(self.upload_size, buf)?;
+                                self.upload_size += length as u64;
                                 self.last_send = Instant::now();
+                                self.save_state();
                                 Ok(())
                             }
                             Err(e) => bail!(e.to_string()),
@@ -168,38 +369,45 @@ impl RecordUploader {
         }
     }
 
-    fn handle_tail(&mut self) -> ResultType<()> {
-        self.handle_frame(true)?;
-        match File::open(&self.filepath) {
-            Ok(mut file) => {
-                let mut buf = vec![0u8; MAX_HEADER_LEN];
-                match file.read(&mut buf) {
-                    Ok(length) => {
-                        buf.truncate(length);
-                        self.send(
-                            &[
-                                ("type", "tail"),
-                                ("file", &self.filename),
-                                ("offset", "0"),
-                                ("length", &length.to_string()),
-                            ],
-                            buf,
-                        )?;
-                        log::info!("upload success, file: {}", self.filename);
-                        Ok(())
-                    }
-                    Err(e) => bail!(e.to_string()),
-                }
+    // Send the rest of the file and finish it, once.
+    fn try_tail(&mut self) -> ResultType<()> {
+        loop {
+            let upload_size = self.upload_size;
+            self.handle_frame(true)?;
+            if self.upload_size == upload_size {
+                break;
             }
-            Err(e) => bail!(e.to_string()),
         }
+        let mut file = File::open(&self.filepath)?;
+        let mut buf = vec![0u8; MAX_HEADER_LEN];
+        let length = file.read(&mut buf)?;
+        buf.truncate(length);
+        drop(file);
+        self.target.tail(&self.filepath, buf)?;
+        self.on_success();
+        self.remove_state();
+        log::info!(
+            "upload success, file: {}, target: {}",
+            self.filename,
+            self.target.name()
+        );
+        Ok(())
     }
 
-    fn handle_remove(&mut self) -> ResultType<()> {
-        self.send(
-            &[("type", "remove"), ("file", &self.filename)],
-            Bytes::new(),
-        )?;
-        Ok(())
+    fn handle_remove(&mut self) {
+        self.remove_state();
+        // Nothing has been uploaded yet.
+        if !self.created {
+            return;
+        }
+        if let Err(e) = self.target.remove() {
+            log::warn!("failed to remove {} from the target: {}", self.filename, e);
+        }
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        UPLOADING.lock().unwrap().remove(&self.filepath);
     }
 }
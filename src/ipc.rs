@@ -1,6 +1,7 @@
 use crate::{
     common::CheckTestNatType,
     privacy_mode::PrivacyModeState,
+    transfer_limit::TransferPriority,
     ui_interface::{get_local_option, set_local_option},
 };
 use bytes::Bytes;
@@ -110,6 +111,10 @@ pub enum FS {
         path: String,
         new_name: String,
     },
+    FileDelta {
+        data: Bytes,
+        one_way: bool,
+    },
 }
 
 #[cfg(target_os = "windows")]
@@ -212,6 +217,11 @@ pub enum Data {
         name: String,
         enabled: bool,
     },
+    /// The file transfer cap of a connection, `kbps` 0 for none.
+    FileTransferLimit {
+        kbps: u32,
+        priority: TransferPriority,
+    },
     SystemInfo(Option<String>),
     ClickTime(i64),
     #[cfg(not(any(target_os = "android", target_os = "ios")))]
@@ -286,6 +296,8 @@ pub enum Data {
     TerminalSessionCount(usize),
     #[cfg(target_os = "windows")]
     PortForwardSessionCount(Option<usize>),
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    PortForwardStats(Option<Vec<crate::port_forward::PortForwardStats>>),
     SocksWs(Option<Box<(Option<config::Socks5Server>, String)>>),
 }
 
@@ -749,6 +761,19 @@ async fn handle(data: Data, stream: &mut Connection) {
                 // Port forward session count is only a get value.
             }
         },
+        #[cfg(not(any(target_os = "android", target_os = "ios")))]
+        Data::PortForwardStats(stats) => match stats {
+            Some(stats) => crate::port_forward::update_reported_stats(stats),
+            None => {
+                allow_err!(
+                    stream
+                        .send(&Data::PortForwardStats(Some(
+                            crate::port_forward::reported_stats()
+                        )))
+                        .await
+                );
+            }
+        },
         _ => {}
     }
 }
@@ -1293,6 +1318,28 @@ pub async fn get_port_forward_session_count(ms_timeout: u64) -> ResultType<usize
     bail!("Failed to get port forward session count");
 }
 
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+pub async fn report_port_forward_stats(
+    stats: crate::port_forward::PortForwardStats,
+) -> ResultType<()> {
+    connect(1_000, "")
+        .await?
+        .send(&Data::PortForwardStats(Some(vec![stats])))
+        .await
+}
+
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+pub async fn get_port_forward_stats(
+    ms_timeout: u64,
+) -> ResultType<Vec<crate::port_forward::PortForwardStats>> {
+    let mut c = connect(ms_timeout, "").await?;
+    c.send(&Data::PortForwardStats(None)).await?;
+    if let Some(Data::PortForwardStats(Some(stats))) = c.next_timeout(ms_timeout).await? {
+        return Ok(stats);
+    }
+    bail!("Failed to get port forward stats");
+}
+
 #[cfg(feature = "hwcodec")]
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 #[tokio::main(flavor = "current_thread")]
@@ -258,5 +258,6 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("websocket_tip", "When using WebSocket, only relay connections are supported."),
         ("terminal-admin-login-tip", "Please input the administrator username and password of the controlled side."),
         ("elevation_username_tip", "Input username or domain\\username"),
+        ("resume-transfer-tip", "The transfers interrupted in the last session are in the transfer list, resume them to continue from where they stopped."),
     ].iter().cloned().collect();
 }
@@ -73,3 +73,11 @@ pub mod privacy_mode;
 pub mod virtual_display_manager;
 
 mod kcp_stream;
+
+mod transfer_limit;
+
+mod file_delta;
+
+mod terminal_control;
+
+mod remote_exec;
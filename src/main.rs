@@ -57,9 +57,32 @@ fn main() {
     use hbb_common::log;
     let args = format!(
         "-p, --port-forward=[PORT-FORWARD-OPTIONS] 'Format: remote-id:local-port:remote-port[:remote-host]'
+        --port-forward-profile=[FILE] 'Forward all the mappings of a toml profile over one connection'
+        --port-forward-stats 'Print the statistics of the running port forward profiles'
+        --socks5=[SOCKS5-OPTIONS] 'Format: remote-id:local-port[:bind-address], a socks5 proxy through the peer'
+        --socks5-allow-lan 'Allow the socks5 proxy to listen on a non loopback address'
         -c, --connect=[REMOTE_ID] 'test only'
         -k, --key=[KEY] ''
-       -s, --server=[] 'Start server'",
+       -s, --server=[] 'Start server'
+        --cli-file-transfer=[REMOTE_ID] 'Non-interactive file transfer, use with --push, --pull, --sync or --ls'
+        --password=[PASSWORD] 'Password of --cli-file-transfer or --cli-exec, or the RUSTDESK_PASSWORD env var, the saved one by default'
+        --password-stdin 'Read the password of --cli-file-transfer or --cli-exec from the first line of stdin'
+        --push=[LOCAL_PATH] 'Upload a local file or directory to --to'
+        --pull=[REMOTE_PATH] 'Download a remote file or directory to --to'
+        --sync=[LOCAL_DIR] 'Mirror a local directory to --to, only changed files are sent'
+        --to=[PATH] 'Destination path of --push, --pull or --sync'
+        --delete 'Remove the remote files and directories which do not exist locally, with --sync'
+        --dry-run 'Print what --sync would do as json'
+        --ls=[REMOTE_PATH] 'Print a remote directory as json'
+        --include-hidden 'Include hidden files'
+        --overwrite 'Overwrite existing files instead of skipping them'
+        --limit-kbps=[KBPS] 'Cap the upload rate of --push or --sync'
+        --priority=[PRIORITY] 'low, normal or high, share of the global file transfer cap'
+        --cli-exec=[REMOTE_ID] 'Run a command on the peer without a terminal, exit with its code'
+        --env=[VAR]... 'NAME=VALUE, an environment variable of the --cli-exec command'
+        --cwd=[REMOTE_DIR] 'Working directory of the --cli-exec command'
+        --timeout=[SECS] 'Kill the --cli-exec command after this time'
+        [COMMAND]... 'The --cli-exec command and its arguments, after --'",
     );
     let matches = App::new("rustdesk")
         .version(crate::VERSION)
@@ -105,6 +128,113 @@ fn main() {
             key,
             token,
         );
+    } else if let Some(path) = matches.value_of("port-forward-profile") {
+        let key = matches.value_of("key").unwrap_or("").to_owned();
+        let token = LocalConfig::get_option("access_token");
+        cli::start_port_forward_profile(path.to_owned(), key, token);
+    } else if let Some(p) = matches.value_of("socks5") {
+        let options: Vec<&str> = p.splitn(3, ':').collect();
+        let Some(port) = options.get(1).and_then(|x| x.parse::<i32>().ok()) else {
+            log::error!("Wrong socks5 options");
+            return;
+        };
+        let bind = options.get(2).unwrap_or(&"127.0.0.1").to_string();
+        let key = matches.value_of("key").unwrap_or("").to_owned();
+        let token = LocalConfig::get_option("access_token");
+        let allow_lan = matches.is_present("socks5-allow-lan");
+        cli::start_socks5_forward(options[0].to_owned(), port, bind, allow_lan, key, token);
+    } else if matches.is_present("port-forward-stats") {
+        std::process::exit(cli::print_port_forward_stats());
+    } else if let Some(id) = matches.value_of("cli-file-transfer") {
+        let to = matches.value_of("to").map(|x| x.to_owned());
+        let action = if let Some(local) = matches.value_of("push") {
+            to.map(|remote| cli::FileTransferAction::Push {
+                local: local.to_owned(),
+                remote,
+            })
+        } else if let Some(remote) = matches.value_of("pull") {
+            to.map(|local| cli::FileTransferAction::Pull {
+                remote: remote.to_owned(),
+                local,
+            })
+        } else if let Some(local) = matches.value_of("sync") {
+            to.map(|remote| cli::FileTransferAction::Sync {
+                local: local.to_owned(),
+                remote,
+                delete: matches.is_present("delete"),
+                dry_run: matches.is_present("dry-run"),
+            })
+        } else if let Some(remote) = matches.value_of("ls") {
+            Some(cli::FileTransferAction::List {
+                remote: remote.to_owned(),
+            })
+        } else {
+            None
+        };
+        let Some(action) = action else {
+            log::error!("Wrong file transfer options");
+            std::process::exit(cli::exit_code::USAGE);
+        };
+        let options = cli::FileTransferOptions {
+            include_hidden: matches.is_present("include-hidden"),
+            overwrite: matches.is_present("overwrite"),
+            max_kbps: matches
+                .value_of("limit-kbps")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(0),
+            priority: match matches.value_of("priority") {
+                Some(p) => match cli::TransferPriority::from_name(p) {
+                    Some(p) => p,
+                    None => {
+                        log::error!("Wrong priority: {}", p);
+                        std::process::exit(cli::exit_code::USAGE);
+                    }
+                },
+                None => Default::default(),
+            },
+        };
+        let Some(password) = cli_password(&matches) else {
+            std::process::exit(cli::exit_code::LOGIN);
+        };
+        common::test_rendezvous_server();
+        common::test_nat_type();
+        let key = matches.value_of("key").unwrap_or("").to_owned();
+        let token = LocalConfig::get_option("access_token");
+        let code = cli::start_file_transfer(id.to_owned(), action, options, password, key, token);
+        common::global_clean();
+        std::process::exit(code);
+    } else if let Some(id) = matches.value_of("cli-exec") {
+        let mut command = matches
+            .values_of("COMMAND")
+            .map(|x| x.map(|x| x.to_owned()).collect::<Vec<_>>())
+            .unwrap_or_default();
+        if command.is_empty() {
+            log::error!("No command to exec");
+            std::process::exit(cli::exit_code::EXEC_FAILED);
+        }
+        let options = cli::ExecOptions {
+            command: command.remove(0),
+            args: command,
+            env: matches
+                .values_of("env")
+                .map(|x| x.map(|x| x.to_owned()).collect())
+                .unwrap_or_default(),
+            cwd: matches.value_of("cwd").unwrap_or_default().to_owned(),
+            timeout_secs: matches
+                .value_of("timeout")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(0),
+        };
+        let Some(password) = cli_password(&matches) else {
+            std::process::exit(cli::exit_code::EXEC_FAILED);
+        };
+        common::test_rendezvous_server();
+        common::test_nat_type();
+        let key = matches.value_of("key").unwrap_or("").to_owned();
+        let token = LocalConfig::get_option("access_token");
+        let code = cli::start_exec(id.to_owned(), options, password, key, token);
+        common::global_clean();
+        std::process::exit(code);
     } else if let Some(p) = matches.value_of("connect") {
         common::test_rendezvous_server();
         common::test_nat_type();
@@ -117,3 +247,21 @@ fn main() {
     }
     common::global_clean();
 }
+
+/// The password of the non-interactive modes: `--password-stdin`, `--password` or the
+/// `RUSTDESK_PASSWORD` env var, empty for the saved one. None if stdin can not be read.
+#[cfg(feature = "cli")]
+fn cli_password(matches: &clap::ArgMatches) -> Option<String> {
+    if matches.is_present("password-stdin") {
+        let mut line = String::new();
+        if let Err(e) = std::io::stdin().read_line(&mut line) {
+            hbb_common::log::error!("Failed to read the password from stdin: {}", e);
+            return None;
+        }
+        return Some(line.trim_end_matches(&['\r', '\n'][..]).to_owned());
+    }
+    if let Some(password) = matches.value_of("password") {
+        return Some(password.to_owned());
+    }
+    Some(std::env::var("RUSTDESK_PASSWORD").unwrap_or_default())
+}
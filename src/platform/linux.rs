@@ -7,7 +7,7 @@ use hbb_common::{
     anyhow::anyhow,
     bail,
     config::Config,
-    libc::{c_char, c_int, c_long, c_void},
+    libc::{c_char, c_int, c_long, c_ulong, c_void},
     log,
     message_proto::{DisplayInfo, Resolution},
     regex::{Captures, Regex},
@@ -82,6 +82,22 @@ extern "C" {
 extern "C" {
     fn XOpenDisplay(display_name: *const c_char) -> *mut c_void;
     // fn XCloseDisplay(d: *mut c_void) -> c_int;
+    fn XDefaultRootWindow(d: *mut c_void) -> c_ulong;
+    fn XInternAtom(d: *mut c_void, atom_name: *const c_char, only_if_exists: c_int) -> c_ulong;
+    fn XGetWindowProperty(
+        d: *mut c_void,
+        w: c_ulong,
+        property: c_ulong,
+        long_offset: c_long,
+        long_length: c_long,
+        delete: c_int,
+        req_type: c_ulong,
+        actual_type_return: *mut c_ulong,
+        actual_format_return: *mut c_int,
+        nitems_return: *mut c_ulong,
+        bytes_after_return: *mut c_ulong,
+        prop_return: *mut *mut u8,
+    ) -> c_int;
 }
 
 #[link(name = "Xfixes")]
@@ -184,6 +200,132 @@ pub fn get_focused_display(displays: Vec<DisplayInfo>) -> Option<usize> {
     res
 }
 
+/// The rectangle `(x, y, width, height)` of the X11 window `window` in screen coordinates.
+pub fn get_window_rect(window: u64) -> Option<(i32, i32, i32, i32)> {
+    let mut res = None;
+    XDO.with(|xdo| {
+        if let Ok(xdo) = xdo.try_borrow_mut() {
+            if xdo.is_null() {
+                return;
+            }
+            let window = window as *mut c_void;
+            let (mut x, mut y, mut width, mut height): (c_int, c_int, c_int, c_int) = (0, 0, 0, 0);
+            unsafe {
+                if xdo_get_window_location(
+                    *xdo,
+                    window,
+                    &mut x as _,
+                    &mut y as _,
+                    std::ptr::null_mut(),
+                ) != 0
+                {
+                    return;
+                }
+                if xdo_get_window_size(*xdo, window, &mut width as _, &mut height as _) != 0 {
+                    return;
+                }
+            }
+            res = Some((x, y, width, height));
+        }
+    });
+    res
+}
+
+/// The windows of `_NET_CLIENT_LIST` with a title, from the bottom. Empty if not X11.
+pub fn get_windows() -> Vec<scrap::region::WindowInfo> {
+    let mut windows = vec![];
+    if !*IS_X11 {
+        return windows;
+    }
+    let clients = DISPLAY.with(|conn| {
+        let Ok(d) = conn.try_borrow() else {
+            return vec![];
+        };
+        if d.is_null() {
+            return vec![];
+        }
+        unsafe {
+            let Some((32, data)) =
+                get_window_property(*d, XDefaultRootWindow(*d), "_NET_CLIENT_LIST")
+            else {
+                return vec![];
+            };
+            data.chunks_exact(std::mem::size_of::<c_ulong>())
+                .filter_map(|c| Some(c_ulong::from_ne_bytes(c.try_into().ok()?)))
+                .map(|window| {
+                    let title = get_window_property(*d, window, "_NET_WM_NAME")
+                        .or_else(|| get_window_property(*d, window, "WM_NAME"))
+                        .map(|(_, title)| String::from_utf8_lossy(&title).into_owned());
+                    (window, title)
+                })
+                .collect::<Vec<_>>()
+        }
+    });
+    for (window, title) in clients {
+        let Some(title) = title.filter(|t| !t.is_empty()) else {
+            continue;
+        };
+        let Some((x, y, width, height)) = get_window_rect(window as _) else {
+            continue;
+        };
+        if width <= 0 || height <= 0 {
+            continue;
+        }
+        windows.push(scrap::region::WindowInfo {
+            window: window as _,
+            x,
+            y,
+            width: width as _,
+            height: height as _,
+            title,
+        });
+    }
+    windows
+}
+
+// The format and the data of a property of any type, the items of the format 32 are c_long.
+unsafe fn get_window_property(
+    d: *mut c_void,
+    window: c_ulong,
+    name: &str,
+) -> Option<(c_int, Vec<u8>)> {
+    let name = std::ffi::CString::new(name).ok()?;
+    let property = XInternAtom(d, name.as_ptr(), 1);
+    if property == 0 {
+        return None;
+    }
+    let (mut actual_type, mut format, mut nitems, mut bytes_after) = (0, 0, 0, 0);
+    let mut prop: *mut u8 = std::ptr::null_mut();
+    // AnyPropertyType
+    if XGetWindowProperty(
+        d,
+        window,
+        property,
+        0,
+        1 << 16,
+        0,
+        0,
+        &mut actual_type,
+        &mut format,
+        &mut nitems,
+        &mut bytes_after,
+        &mut prop,
+    ) != 0
+        || prop.is_null()
+    {
+        return None;
+    }
+    let size = match format {
+        8 => 1,
+        16 => std::mem::size_of::<i16>(),
+        32 => std::mem::size_of::<c_long>(),
+        _ => 0,
+    };
+    let data = std::slice::from_raw_parts(prop, nitems as usize * size).to_vec();
+    XFree(prop as _);
+    Some((format, data))
+}
+
 pub fn get_cursor() -> ResultType<Option<u64>> {
     let mut res = None;
     DISPLAY.with(|conn| {
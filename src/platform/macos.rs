@@ -14,8 +14,14 @@ use core_foundation::{
     string::CFStringRef,
 };
 use core_graphics::{
-    display::{kCGNullWindowID, kCGWindowListOptionOnScreenOnly, CGWindowListCopyWindowInfo},
-    window::{kCGWindowName, kCGWindowOwnerPID},
+    display::{
+        kCGNullWindowID, kCGWindowListOptionIncludingWindow, kCGWindowListOptionOnScreenOnly,
+        CGWindowListCopyWindowInfo,
+    },
+    window::{
+        kCGWindowBounds, kCGWindowIsOnscreen, kCGWindowLayer, kCGWindowName, kCGWindowNumber,
+        kCGWindowOwnerName, kCGWindowOwnerPID,
+    },
 };
 use hbb_common::{
     anyhow::anyhow,
@@ -26,9 +32,13 @@ use hbb_common::{
 use include_dir::{include_dir, Dir};
 use objc::rc::autoreleasepool;
 use objc::{class, msg_send, sel, sel_impl};
-use scrap::{libc::c_void, quartz::ffi::*};
+use scrap::{
+    libc::{c_char, c_void},
+    quartz::ffi::*,
+};
 use std::{
     collections::HashMap,
+    ffi::CStr,
     os::unix::process::CommandExt,
     path::{Path, PathBuf},
     process::{Command, Stdio},
@@ -384,6 +394,84 @@ pub fn get_focused_display(displays: Vec<DisplayInfo>) -> Option<usize> {
     autoreleasepool(|| unsafe_get_focused_display(displays))
 }
 
+/// The rectangle `(x, y, width, height)` of the window `window` in points, like the origins of
+/// the displays, None if it is closed or minimized.
+pub fn get_window_rect(window: u64) -> Option<(i32, i32, i32, i32)> {
+    let window = window.try_into().ok()?;
+    autoreleasepool(|| unsafe { get_window_infos(kCGWindowListOptionIncludingWindow, window) })
+        .into_iter()
+        .find(|w| w.window == window as u64)
+        .map(|w| (w.x, w.y, w.width as _, w.height as _))
+}
+
+/// The windows on screen of the normal layer, from the top.
+pub fn get_windows() -> Vec<scrap::region::WindowInfo> {
+    autoreleasepool(|| unsafe {
+        get_window_infos(kCGWindowListOptionOnScreenOnly, kCGNullWindowID)
+    })
+    .into_iter()
+    .filter(|w| !w.title.is_empty() && w.width > 0 && w.height > 0)
+    .collect()
+}
+
+unsafe fn get_window_infos(option: u32, window: u32) -> Vec<scrap::region::WindowInfo> {
+    let mut windows = vec![];
+    let window_list = CGWindowListCopyWindowInfo(option, window);
+    if window_list.is_null() {
+        return windows;
+    }
+    let number = |d: id, key: CFStringRef| -> Option<f64> {
+        let n: id = msg_send![d, valueForKey: key as id];
+        if n.is_null() {
+            return None;
+        }
+        Some(msg_send![n, doubleValue])
+    };
+    let string = |d: id, key: CFStringRef| -> Option<String> {
+        let s: id = msg_send![d, valueForKey: key as id];
+        if s.is_null() {
+            return None;
+        }
+        let s: *const c_char = msg_send![s, UTF8String];
+        (!s.is_null()).then(|| CStr::from_ptr(s).to_string_lossy().into_owned())
+    };
+    let bound = |b: id, key: &str| -> f64 {
+        let n: id = msg_send![b, valueForKey: NSString::alloc(nil).init_str(key)];
+        if n.is_null() {
+            return 0.;
+        }
+        msg_send![n, doubleValue]
+    };
+    for i in 0..CFArrayGetCount(window_list) {
+        let w: id = CFArrayGetValueAtIndex(window_list, i) as _;
+        if number(w, kCGWindowLayer) != Some(0.) || number(w, kCGWindowIsOnscreen) != Some(1.) {
+            continue;
+        }
+        let Some(window) = number(w, kCGWindowNumber) else {
+            continue;
+        };
+        let bounds: id = msg_send![w, valueForKey: kCGWindowBounds as id];
+        if bounds.is_null() {
+            continue;
+        }
+        // The name needs the permission of the screen recording.
+        let title = string(w, kCGWindowName)
+            .filter(|t| !t.is_empty())
+            .or_else(|| string(w, kCGWindowOwnerName))
+            .unwrap_or_default();
+        windows.push(scrap::region::WindowInfo {
+            window: window as _,
+            x: bound(bounds, "X") as _,
+            y: bound(bounds, "Y") as _,
+            width: bound(bounds, "Width") as _,
+            height: bound(bounds, "Height") as _,
+            title,
+        });
+    }
+    CFRelease(window_list as _);
+    windows
+}
+
 fn unsafe_get_focused_display(displays: Vec<DisplayInfo>) -> Option<usize> {
     unsafe {
         let main_screen: id = msg_send![class!(NSScreen), mainScreen];
@@ -50,7 +50,8 @@ use winapi::{
             OpenProcessToken, ProcessIdToSessionId, PROCESS_INFORMATION, STARTUPINFOW,
         },
         securitybaseapi::{
-            AllocateAndInitializeSid, DuplicateToken, EqualSid, FreeSid, GetTokenInformation,
+            AllocateAndInitializeSid, DuplicateToken, EqualSid, FreeSid, GetLengthSid,
+            GetTokenInformation,
         },
         shellapi::ShellExecuteW,
         sysinfoapi::{GetNativeSystemInfo, SYSTEM_INFO},
@@ -58,10 +59,11 @@ use winapi::{
         wingdi::*,
         winnt::{
             SecurityImpersonation, TokenElevation, TokenGroups, TokenImpersonation, TokenType,
-            DOMAIN_ALIAS_RID_ADMINS, ES_AWAYMODE_REQUIRED, ES_CONTINUOUS, ES_DISPLAY_REQUIRED,
-            ES_SYSTEM_REQUIRED, HANDLE, PROCESS_ALL_ACCESS, PROCESS_QUERY_LIMITED_INFORMATION,
-            PSID, SECURITY_BUILTIN_DOMAIN_RID, SECURITY_NT_AUTHORITY, SID_IDENTIFIER_AUTHORITY,
-            TOKEN_ELEVATION, TOKEN_GROUPS, TOKEN_QUERY, TOKEN_TYPE,
+            TokenUser, DOMAIN_ALIAS_RID_ADMINS, ES_AWAYMODE_REQUIRED, ES_CONTINUOUS,
+            ES_DISPLAY_REQUIRED, ES_SYSTEM_REQUIRED, HANDLE, PROCESS_ALL_ACCESS,
+            PROCESS_QUERY_LIMITED_INFORMATION, PSID, SECURITY_BUILTIN_DOMAIN_RID,
+            SECURITY_NT_AUTHORITY, SID_IDENTIFIER_AUTHORITY, TOKEN_ELEVATION, TOKEN_GROUPS,
+            TOKEN_QUERY, TOKEN_TYPE, TOKEN_USER,
         },
         winreg::HKEY_CURRENT_USER,
         winspool::{
@@ -114,6 +116,61 @@ pub fn get_focused_display(displays: Vec<DisplayInfo>) -> Option<usize> {
     }
 }
 
+/// The rectangle `(x, y, width, height)` of the top-level window `window` in screen coordinates,
+/// None if it is closed or minimized.
+pub fn get_window_rect(window: u64) -> Option<(i32, i32, i32, i32)> {
+    unsafe {
+        let hwnd = window as HWND;
+        if IsWindow(hwnd) == FALSE || IsIconic(hwnd) != FALSE {
+            return None;
+        }
+        let mut rect: RECT = mem::zeroed();
+        if GetWindowRect(hwnd, &mut rect as *mut RECT) == 0 {
+            return None;
+        }
+        Some((
+            rect.left,
+            rect.top,
+            rect.right - rect.left,
+            rect.bottom - rect.top,
+        ))
+    }
+}
+
+/// The visible top-level windows with a title, from the top.
+pub fn get_windows() -> Vec<scrap::region::WindowInfo> {
+    unsafe extern "system" fn callback(hwnd: HWND, lparam: LPARAM) -> BOOL {
+        let windows = &mut *(lparam as *mut Vec<scrap::region::WindowInfo>);
+        if IsWindowVisible(hwnd) == FALSE || GetWindowTextLengthW(hwnd) == 0 {
+            return TRUE;
+        }
+        let Some((x, y, width, height)) = get_window_rect(hwnd as _) else {
+            return TRUE;
+        };
+        if width <= 0 || height <= 0 {
+            return TRUE;
+        }
+        let mut title = [0u16; 256];
+        let len = GetWindowTextW(hwnd, title.as_mut_ptr(), title.len() as _);
+        windows.push(scrap::region::WindowInfo {
+            window: hwnd as _,
+            x,
+            y,
+            width: width as _,
+            height: height as _,
+            title: OsString::from_wide(&title[..len.max(0) as usize])
+                .to_string_lossy()
+                .into_owned(),
+        });
+        TRUE
+    }
+    let mut windows = vec![];
+    unsafe {
+        EnumWindows(Some(callback), &mut windows as *mut _ as LPARAM);
+    }
+    windows
+}
+
 pub fn get_cursor_pos() -> Option<(i32, i32)> {
     unsafe {
         #[allow(invalid_value)]
@@ -2244,6 +2301,52 @@ pub fn ensure_primary_token(user_token: HANDLE) -> ResultType<HANDLE> {
     }
 }
 
+/// The SID of the user of `user_token`, or of this process if it is null.
+pub fn get_token_user_sid(user_token: HANDLE) -> ResultType<Vec<u8>> {
+    use hbb_common::platform::windows::RAIIHandle;
+    unsafe {
+        let mut _token = None;
+        let token = if user_token.is_null() {
+            let mut token: HANDLE = mem::zeroed();
+            if OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) == FALSE {
+                bail!(
+                    "Failed to open process token, error {}",
+                    io::Error::last_os_error()
+                )
+            }
+            _token = Some(RAIIHandle(token));
+            token
+        } else {
+            user_token
+        };
+        let mut dw_size: DWORD = 0;
+        GetTokenInformation(token, TokenUser, std::ptr::null_mut(), 0, &mut dw_size);
+        if dw_size == 0 {
+            bail!(
+                "Failed to get token user buffer size, error: {}",
+                io::Error::last_os_error()
+            );
+        }
+        let mut buffer = vec![0u8; dw_size as usize];
+        if GetTokenInformation(
+            token,
+            TokenUser,
+            buffer.as_mut_ptr() as *mut _,
+            dw_size,
+            &mut dw_size,
+        ) == FALSE
+        {
+            bail!(
+                "Failed to get token user information, error: {}",
+                io::Error::last_os_error()
+            );
+        }
+        let sid = (*(buffer.as_ptr() as *const TOKEN_USER)).User.Sid;
+        let len = GetLengthSid(sid) as usize;
+        Ok(std::slice::from_raw_parts(sid as *const u8, len).to_vec())
+    }
+}
+
 pub fn is_user_token_admin(user_token: HANDLE) -> ResultType<bool> {
     if user_token.is_null() || user_token == INVALID_HANDLE_VALUE {
         bail!("Invalid user token provided");
@@ -1,4 +1,8 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
 
 use crate::client::*;
 use hbb_common::{
@@ -12,8 +16,229 @@ use hbb_common::{
     tcp, timeout,
     tokio::{self, net::TcpStream, sync::mpsc},
     tokio_util::codec::{BytesCodec, Framed},
-    ResultType, Stream,
+    toml, ResultType, Stream,
 };
+use serde_derive::{Deserialize, Serialize};
+
+pub mod mux;
+mod socks5;
+
+use mux::{Frame, Mux, MUX_HOST};
+
+const STATS_REPORT_INTERVAL: Duration = Duration::from_secs(3);
+const STATS_EXPIRE: Duration = Duration::from_secs(10);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: u64 = 3_000;
+const SOCKS5_HANDSHAKE_TIMEOUT: u64 = 10_000;
+pub const MIN_REVERSE_PORT: i32 = 1024;
+
+lazy_static::lazy_static! {
+    static ref REPORTED_STATS: Arc<Mutex<HashMap<u32, (Instant, PortForwardStats)>>> = Default::default();
+}
+
+/// Many port mappings forwarded over one connection to `peer`, e.g.
+///
+/// ```toml
+/// peer = "123456789"
+/// bind = "127.0.0.1"
+///
+/// [[mappings]]
+/// local_port = 2222
+/// remote_port = 22
+///
+/// [[mappings]]
+/// local_port = 8080
+/// remote_host = "192.168.1.10"
+/// remote_port = 80
+///
+/// # The peer listens on 127.0.0.1:3128 and tunnels back to proxy.lan:3128 of this side.
+/// [[reverse]]
+/// remote_port = 3128
+/// local_host = "proxy.lan"
+/// local_port = 3128
+///
+/// # A socks5 proxy on 127.0.0.1:1080, the destinations are connected by the peer.
+/// [[dynamic]]
+/// local_port = 1080
+/// ```
+///
+/// Reverse mappings require the `allow-reverse-tunnel` option on the peer, and its
+/// `allow-reverse-tunnel-lan` option for a `remote_bind` which is not a loopback address.
+/// The socks5 proxy has no authentication, it does not use `bind` of the profile and only
+/// listens on a non loopback address with `allow_lan = true`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ForwardProfile {
+    pub peer: String,
+    /// The local address to listen on, `0.0.0.0` if empty.
+    pub bind: String,
+    pub mappings: Vec<ForwardMapping>,
+    pub reverse: Vec<ReverseMapping>,
+    pub dynamic: Vec<DynamicMapping>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ForwardMapping {
+    pub local_port: i32,
+    /// `localhost` of the peer if empty.
+    pub remote_host: String,
+    pub remote_port: i32,
+    /// Overrides `bind` of the profile.
+    pub bind: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReverseMapping {
+    /// The address the peer listens on, `127.0.0.1` if empty.
+    pub remote_bind: String,
+    pub remote_port: i32,
+    /// `localhost` if empty.
+    pub local_host: String,
+    pub local_port: i32,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DynamicMapping {
+    pub local_port: i32,
+    /// `127.0.0.1` if empty.
+    pub bind: Option<String>,
+    /// Allow `bind` to be a non loopback address, which exposes the peer's network to the LAN.
+    pub allow_lan: bool,
+}
+
+impl ForwardProfile {
+    pub fn load(path: &str) -> ResultType<Self> {
+        let profile: Self = toml::from_str(&std::fs::read_to_string(path)?)?;
+        if profile.peer.is_empty() {
+            bail!("No peer");
+        }
+        if profile.mappings.is_empty() && profile.reverse.is_empty() && profile.dynamic.is_empty() {
+            bail!("No port mappings");
+        }
+        for m in profile.mappings.iter() {
+            if m.remote_port <= 0 || m.remote_port > 65535 {
+                bail!("Invalid remote port {}", m.remote_port);
+            }
+        }
+        for d in profile.dynamic.iter() {
+            d.local_addr()?;
+        }
+        for r in profile.reverse.iter() {
+            if r.local_port <= 0 || r.local_port > 65535 {
+                bail!("Invalid local port {}", r.local_port);
+            }
+            // The peer does not listen on privileged ports.
+            if r.remote_port < MIN_REVERSE_PORT || r.remote_port > 65535 {
+                bail!(
+                    "Invalid reverse remote port {}, it must be between {} and 65535",
+                    r.remote_port,
+                    MIN_REVERSE_PORT
+                );
+            }
+        }
+        Ok(profile)
+    }
+}
+
+impl ForwardMapping {
+    fn local_addr(&self, bind: &str) -> String {
+        let bind = self.bind.as_deref().unwrap_or(bind);
+        let bind = if bind.is_empty() { "0.0.0.0" } else { bind };
+        format!("{}:{}", bind, self.local_port)
+    }
+
+    fn remote_addr(&self) -> String {
+        let host = if self.remote_host.is_empty() {
+            "localhost"
+        } else {
+            &self.remote_host
+        };
+        format!("{}:{}", host, self.remote_port)
+    }
+}
+
+impl DynamicMapping {
+    fn local_addr(&self) -> ResultType<String> {
+        let bind = self.bind.as_deref().unwrap_or_default();
+        let bind = if bind.is_empty() { "127.0.0.1" } else { bind };
+        let is_loopback = bind == "localhost"
+            || bind
+                .parse::<std::net::IpAddr>()
+                .map_or(false, |ip| ip.is_loopback());
+        if !is_loopback && !self.allow_lan {
+            bail!(
+                "The socks5 proxy listens on {} without allow_lan, it has no authentication",
+                bind
+            );
+        }
+        Ok(format!("{}:{}", bind, self.local_port))
+    }
+}
+
+impl ReverseMapping {
+    fn local_addr(&self) -> String {
+        let host = if self.local_host.is_empty() {
+            "localhost"
+        } else {
+            &self.local_host
+        };
+        format!("{}:{}", host, self.local_port)
+    }
+
+    fn remote_addr(&self) -> String {
+        let bind = if self.remote_bind.is_empty() {
+            "127.0.0.1"
+        } else {
+            &self.remote_bind
+        };
+        format!("{}:{}", bind, self.remote_port)
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MappingStats {
+    pub local: String,
+    pub remote: String,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub active_connections: usize,
+    pub total_connections: usize,
+    /// The connections are accepted by the peer.
+    #[serde(default)]
+    pub reverse: bool,
+}
+
+/// Statistics of a running forwarding profile, reported to the ipc server of the main process.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PortForwardStats {
+    pub peer_id: String,
+    pub pid: u32,
+    pub connected: bool,
+    pub mappings: Vec<MappingStats>,
+}
+
+pub fn update_reported_stats(stats: Vec<PortForwardStats>) {
+    let mut lock = REPORTED_STATS.lock().unwrap();
+    lock.retain(|_, (t, _)| t.elapsed() < STATS_EXPIRE);
+    for s in stats {
+        lock.insert(s.pid, (Instant::now(), s));
+    }
+}
+
+pub fn reported_stats() -> Vec<PortForwardStats> {
+    let mut v: Vec<PortForwardStats> = REPORTED_STATS
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|(t, _)| t.elapsed() < STATS_EXPIRE)
+        .map(|(_, s)| s.clone())
+        .collect();
+    v.sort_by_key(|s| s.pid);
+    v
+}
 
 fn run_rdp(port: u16) {
     std::process::Command::new("cmdkey")
@@ -70,7 +295,7 @@ pub async fn listen(
                 let id = id.clone();
                 let password = password.clone();
                 let mut forward = Framed::new(forward, BytesCodec::new());
-                match connect_and_login(&id, &password, &mut ui_receiver, interface.clone(), &mut forward, key, token, is_rdp).await {
+                match connect_and_login(&id, &password, &mut ui_receiver, interface.clone(), Some(&mut forward), key, token, is_rdp).await {
                     Ok(Some(stream)) => {
                         let interface = interface.clone();
                         tokio::spawn(async move {
@@ -108,7 +333,7 @@ async fn connect_and_login(
     password: &str,
     ui_receiver: &mut mpsc::UnboundedReceiver<Data>,
     interface: impl Interface,
-    forward: &mut Framed<TcpStream, BytesCodec>,
+    mut forward: Option<&mut Framed<TcpStream, BytesCodec>>,
     key: &str,
     token: &str,
     is_rdp: bool,
@@ -178,7 +403,7 @@ async fn connect_and_login(
                     _ => {}
                 }
             },
-            res = forward.next() => {
+            res = async { forward.as_mut().unwrap().next().await }, if forward.is_some() => {
                 if let Some(Ok(bytes)) = res {
                     buffer.extend(bytes);
                 } else {
@@ -187,13 +412,354 @@ async fn connect_and_login(
             },
         }
     }
-    stream.set_raw();
+    // The multiplexed stream is framed, see `mux`.
+    if forward.is_some() {
+        stream.set_raw();
+    }
     if !buffer.is_empty() {
         allow_err!(stream.send_bytes(buffer.into()).await);
     }
     Ok(Some(stream))
 }
 
+/// Listen on all the mappings of `profile`, the accepted connections are multiplexed over one
+/// connection to the peer, which is established on the first accepted connection and
+/// re-established on demand if it is lost. With reverse mappings, the connection is
+/// established at once and kept alive.
+pub async fn listen_profile(
+    profile: ForwardProfile,
+    password: String,
+    interface: impl Interface,
+    ui_receiver: mpsc::UnboundedReceiver<Data>,
+    key: &str,
+    token: &str,
+    lc: Arc<RwLock<LoginConfigHandler>>,
+) -> ResultType<()> {
+    // (index of the stats, socket, remote address)
+    let (tx_accept, mut rx_accept) = mpsc::unbounded_channel::<(usize, TcpStream, String)>();
+    let mut stats = PortForwardStats {
+        peer_id: profile.peer.clone(),
+        pid: std::process::id(),
+        ..Default::default()
+    };
+    // Accept only after all the listeners are bound, the bound ones are closed if one fails.
+    let mut bound = Vec::new();
+    for m in profile.mappings.iter() {
+        let local = m.local_addr(&profile.bind);
+        let listener = tcp::new_listener(local.as_str(), true).await?;
+        log::info!(
+            "listening on {:?} for {}",
+            listener.local_addr()?,
+            m.remote_addr()
+        );
+        bound.push((listener, stats.mappings.len(), Some(m.remote_addr())));
+        stats.mappings.push(MappingStats {
+            local,
+            remote: m.remote_addr(),
+            ..Default::default()
+        });
+    }
+    // The stats of the reverse mappings follow the ones of `mappings`.
+    let reverse_offset = stats.mappings.len();
+    for r in profile.reverse.iter() {
+        stats.mappings.push(MappingStats {
+            local: r.local_addr(),
+            remote: r.remote_addr(),
+            reverse: true,
+            ..Default::default()
+        });
+    }
+    for d in profile.dynamic.iter() {
+        let local = d.local_addr()?;
+        let listener = tcp::new_listener(local.as_str(), true).await?;
+        log::info!("socks5 listening on {:?}", listener.local_addr()?);
+        bound.push((listener, stats.mappings.len(), None));
+        stats.mappings.push(MappingStats {
+            local,
+            remote: "*".to_owned(),
+            ..Default::default()
+        });
+    }
+    for (listener, i, remote) in bound {
+        spawn_accept(listener, i, remote, tx_accept.clone());
+    }
+    let keep_alive = !profile.reverse.is_empty();
+    lc.write().unwrap().port_forward = (MUX_HOST.to_owned(), 0);
+    let mut ui_receiver = ui_receiver;
+    let mut conn: Option<Stream> = None;
+    let mut mux = Mux::new(true);
+    // channel id -> index of the stats
+    let mut channels: HashMap<u32, usize> = HashMap::new();
+    // listener id -> index of the reverse mapping
+    let mut listeners: HashMap<u32, usize> = HashMap::new();
+    let (tx_connected, mut rx_connected) =
+        mpsc::unbounded_channel::<(u32, String, ResultType<TcpStream>)>();
+    let mut report_timer = tokio::time::interval(STATS_REPORT_INTERVAL);
+    let mut reconnect_timer = tokio::time::interval(RECONNECT_INTERVAL);
+    loop {
+        tokio::select! {
+            Some((i, socket, remote)) = rx_accept.recv() => {
+                if conn.is_none() {
+                    conn = connect_profile(&profile, &password, &mut ui_receiver, interface.clone(), key, token, &mut mux, &mut listeners).await;
+                }
+                let Some(stream) = conn.as_mut() else {
+                    continue;
+                };
+                let id = mux.next_id();
+                let open = Frame::Open(id, remote);
+                if let Err(err) = stream.send_bytes(open.encode()).await {
+                    log::error!("Failed to open channel: {}", err);
+                    continue;
+                }
+                mux.add(id, socket);
+                channels.insert(id, i);
+                stats.mappings[i].active_connections += 1;
+                stats.mappings[i].total_connections += 1;
+            }
+            _ = reconnect_timer.tick(), if keep_alive && conn.is_none() => {
+                conn = connect_profile(&profile, &password, &mut ui_receiver, interface.clone(), key, token, &mut mux, &mut listeners).await;
+            }
+            res = async { conn.as_mut().unwrap().next().await }, if conn.is_some() => {
+                let bytes = match res {
+                    Some(Ok(bytes)) => bytes,
+                    res => {
+                        if let Some(Err(err)) = res {
+                            log::error!("Connection closed: {}", err);
+                        }
+                        log::info!("multiplexed port forwarding connection closed");
+                        conn = None;
+                        mux = Mux::new(true);
+                        channels.clear();
+                        listeners.clear();
+                        stats.mappings.iter_mut().for_each(|m| m.active_connections = 0);
+                        continue;
+                    }
+                };
+                let Some(stream) = conn.as_mut() else {
+                    continue;
+                };
+                match Frame::decode(&bytes) {
+                    Some(Frame::Data(id, data)) => {
+                        let len = data.len() as u64;
+                        if mux.write(id, data) {
+                            if let Some(i) = channels.get(&id) {
+                                stats.mappings[*i].bytes_received += len;
+                            }
+                        } else {
+                            if let Some(i) = channels.remove(&id) {
+                                stats.mappings[i].active_connections -= 1;
+                            }
+                            allow_err!(stream.send_bytes(Frame::Close(id, "".to_owned()).encode()).await);
+                        }
+                    }
+                    Some(Frame::Close(id, err)) => {
+                        if let Some(i) = listeners.remove(&id) {
+                            log::error!("reverse port forwarding of {} failed: {}", profile.reverse[i].remote_addr(), err);
+                            continue;
+                        }
+                        if !err.is_empty() {
+                            log::error!("channel {} closed by the peer: {}", id, err);
+                        }
+                        mux.close(id);
+                        if let Some(i) = channels.remove(&id) {
+                            stats.mappings[i].active_connections -= 1;
+                        }
+                    }
+                    Some(Frame::Ack(id, n)) => mux.ack(id, n),
+                    Some(Frame::Listen(id, addr)) => {
+                        if let Some(i) = listeners.get(&id) {
+                            log::info!("peer listening on {} for {}", addr, profile.reverse[*i].local_addr());
+                        }
+                    }
+                    Some(Frame::Open(id, listener)) => {
+                        // Only connect to the targets of our reverse mappings.
+                        let Some(i) = listener.parse::<u32>().ok().and_then(|l| listeners.get(&l)).copied() else {
+                            let close = Frame::Close(id, "Not supported".to_owned());
+                            allow_err!(stream.send_bytes(close.encode()).await);
+                            continue;
+                        };
+                        if mux.contains(id) {
+                            continue;
+                        }
+                        mux.add_pending(id);
+                        channels.insert(id, reverse_offset + i);
+                        stats.mappings[reverse_offset + i].active_connections += 1;
+                        stats.mappings[reverse_offset + i].total_connections += 1;
+                        let addr = profile.reverse[i].local_addr();
+                        let tx = tx_connected.clone();
+                        tokio::spawn(async move {
+                            let res = match timeout(CONNECT_TIMEOUT, TcpStream::connect(&addr)).await {
+                                Ok(Ok(sock)) => Ok(sock),
+                                Ok(Err(e)) => Err(e.into()),
+                                Err(e) => Err(e.into()),
+                            };
+                            tx.send((id, addr, res)).ok();
+                        });
+                    }
+                    None => {}
+                }
+            }
+            Some((id, addr, res)) = rx_connected.recv() => {
+                if !mux.contains(id) {
+                    continue;
+                }
+                match res {
+                    Ok(sock) => mux.add(id, sock),
+                    Err(err) => {
+                        mux.close(id);
+                        if let Some(i) = channels.remove(&id) {
+                            stats.mappings[i].active_connections -= 1;
+                        }
+                        let err = format!("Failed to access {}: {}", addr, err);
+                        log::error!("{}", err);
+                        if let Some(stream) = conn.as_mut() {
+                            allow_err!(stream.send_bytes(Frame::Close(id, err).encode()).await);
+                        }
+                    }
+                }
+            }
+            Some(frame) = mux.rx_frame.recv() => {
+                let id = frame.channel();
+                match &frame {
+                    Frame::Close(..) => {
+                        if !mux.close(id) {
+                            continue;
+                        }
+                        if let Some(i) = channels.remove(&id) {
+                            stats.mappings[i].active_connections -= 1;
+                        }
+                    }
+                    Frame::Data(_, data) => {
+                        let Some(i) = channels.get(&id) else {
+                            continue;
+                        };
+                        stats.mappings[*i].bytes_sent += data.len() as u64;
+                    }
+                    Frame::Open(..) | Frame::Listen(..) | Frame::Ack(..) => {}
+                }
+                if let Some(stream) = conn.as_mut() {
+                    allow_err!(stream.send_bytes(frame.encode()).await);
+                }
+            }
+            _ = report_timer.tick() => {
+                stats.connected = conn.is_some();
+                let stats = stats.clone();
+                tokio::spawn(async move {
+                    crate::ipc::report_port_forward_stats(stats).await.ok();
+                });
+            }
+            d = ui_receiver.recv() => {
+                match d {
+                    Some(Data::Close) | None => {
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Accept the connections of a local listener, the connections to a dynamic mapping, i.e.
+/// without `remote`, are socks5 ones, the remote address is read from the handshake.
+fn spawn_accept(
+    listener: tokio::net::TcpListener,
+    index: usize,
+    remote: Option<String>,
+    tx: mpsc::UnboundedSender<(usize, TcpStream, String)>,
+) {
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((mut socket, addr)) => {
+                    log::info!("new connection from {:?}", addr);
+                    if let Some(remote) = remote.clone() {
+                        if tx.send((index, socket, remote)).is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    let tx = tx.clone();
+                    tokio::spawn(async move {
+                        match timeout(SOCKS5_HANDSHAKE_TIMEOUT, socks5::handshake(&mut socket))
+                            .await
+                        {
+                            Ok(Ok(remote)) => {
+                                log::debug!("socks5 connection from {:?} to {}", addr, remote);
+                                tx.send((index, socket, remote)).ok();
+                            }
+                            Ok(Err(e)) => log::error!("socks5 handshake failed: {}", e),
+                            Err(_) => log::error!("socks5 handshake timeout"),
+                        }
+                    });
+                }
+                Err(e) => {
+                    log::error!("Failed to accept: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+}
+
+/// Connect to the peer of `profile` in multiplexed mode, and ask it to listen on the reverse
+/// mappings.
+async fn connect_profile(
+    profile: &ForwardProfile,
+    password: &str,
+    ui_receiver: &mut mpsc::UnboundedReceiver<Data>,
+    interface: impl Interface,
+    key: &str,
+    token: &str,
+    mux: &mut Mux,
+    listeners: &mut HashMap<u32, usize>,
+) -> Option<Stream> {
+    let mut stream = match connect_and_login(
+        &profile.peer,
+        password,
+        ui_receiver,
+        interface.clone(),
+        None,
+        key,
+        token,
+        false,
+    )
+    .await
+    {
+        Ok(Some(stream)) => stream,
+        Ok(None) => return None,
+        Err(err) => {
+            interface.on_establish_connection_error(err.to_string());
+            return None;
+        }
+    };
+    log::info!("multiplexed port forwarding connection started");
+    let mut requested = Vec::new();
+    for (i, r) in profile.reverse.iter().enumerate() {
+        let id = mux.next_id();
+        if let Err(err) = stream
+            .send_bytes(Frame::Listen(id, r.remote_addr()).encode())
+            .await
+        {
+            log::error!("Failed to request reverse port forwarding: {}", err);
+            // Close the listeners already requested, the peer closes them with the connection
+            // anyway if the frames cannot be sent.
+            for id in requested {
+                listeners.remove(&id);
+                stream
+                    .send_bytes(Frame::Close(id, "".to_owned()).encode())
+                    .await
+                    .ok();
+            }
+            return None;
+        }
+        listeners.insert(id, i);
+        requested.push(id);
+    }
+    Some(stream)
+}
+
 async fn run_forward(forward: Framed<TcpStream, BytesCodec>, stream: Stream) -> ResultType<()> {
     log::info!("new port forwarding connection started");
     let mut forward = forward;
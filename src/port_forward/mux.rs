@@ -0,0 +1,346 @@
+// Multiplexing of many tcp connections over one authenticated port forwarding stream.
+//
+// The client requests the multiplexed mode by a `PortForward` login request with `MUX_HOST`
+// as host. The stream is not switched to raw mode, each frame is one message of the stream:
+// `[kind: u8][channel: u32 big endian][payload]`.
+//
+// - `OPEN`: payload is the utf8 `host:port` the other side should connect to. An `OPEN` from
+//   the controlled side carries the id of the `LISTEN` the connection was accepted by instead,
+//   the controlling side decides where to connect to.
+// - `DATA`: payload is the data of the channel.
+// - `CLOSE`: payload is an optional utf8 error.
+// - `LISTEN`: reverse forwarding, the controlled side listens on the `bind:port` payload, and
+//   replies with a `LISTEN` carrying the bound address, or a `CLOSE` with the error.
+// - `ACK`: payload is the `u32` big endian count of bytes of the channel written to its socket.
+//
+// Each side may have at most `WINDOW` bytes of a channel not acknowledged, the reading of the
+// socket is paused until the peer acknowledges. A channel exceeding the window is closed.
+//
+// Channels and listeners opened by the controlling side have odd ids, the controlled side uses
+// even ids.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use hbb_common::{
+    log,
+    tokio::{
+        self,
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::{mpsc, Semaphore},
+        task::JoinHandle,
+    },
+};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+pub const MUX_HOST: &str = "<mux>";
+
+const KIND_OPEN: u8 = 0xF1;
+const KIND_DATA: u8 = 0xF2;
+const KIND_CLOSE: u8 = 0xF3;
+const KIND_LISTEN: u8 = 0xF4;
+const KIND_ACK: u8 = 0xF5;
+const READ_BUF_SIZE: usize = 64 * 1024;
+const WINDOW: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Open(u32, String),
+    Data(u32, Bytes),
+    Close(u32, String),
+    Listen(u32, String),
+    Ack(u32, u32),
+}
+
+impl Frame {
+    pub fn channel(&self) -> u32 {
+        match self {
+            Frame::Open(id, _)
+            | Frame::Data(id, _)
+            | Frame::Close(id, _)
+            | Frame::Listen(id, _)
+            | Frame::Ack(id, _) => *id,
+        }
+    }
+
+    pub fn encode(&self) -> Bytes {
+        let ack;
+        let (kind, id, payload): (u8, u32, &[u8]) = match self {
+            Frame::Open(id, addr) => (KIND_OPEN, *id, addr.as_bytes()),
+            Frame::Data(id, data) => (KIND_DATA, *id, data),
+            Frame::Close(id, err) => (KIND_CLOSE, *id, err.as_bytes()),
+            Frame::Listen(id, addr) => (KIND_LISTEN, *id, addr.as_bytes()),
+            Frame::Ack(id, n) => {
+                ack = n.to_be_bytes();
+                (KIND_ACK, *id, &ack)
+            }
+        };
+        let mut buf = BytesMut::with_capacity(5 + payload.len());
+        buf.put_u8(kind);
+        buf.put_u32(id);
+        buf.put_slice(payload);
+        buf.freeze()
+    }
+
+    /// Returns `None` for anything which is not a frame, e.g. a late protobuf message.
+    pub fn decode(data: &[u8]) -> Option<Frame> {
+        if data.len() < 5 {
+            return None;
+        }
+        let id = u32::from_be_bytes([data[1], data[2], data[3], data[4]]);
+        let payload = &data[5..];
+        match data[0] {
+            KIND_OPEN => Some(Frame::Open(id, String::from_utf8(payload.to_vec()).ok()?)),
+            KIND_DATA => Some(Frame::Data(id, Bytes::copy_from_slice(payload))),
+            KIND_CLOSE => Some(Frame::Close(
+                id,
+                String::from_utf8_lossy(payload).to_string(),
+            )),
+            KIND_LISTEN => Some(Frame::Listen(id, String::from_utf8(payload.to_vec()).ok()?)),
+            KIND_ACK => Some(Frame::Ack(id, u32::from_be_bytes(payload.try_into().ok()?))),
+            _ => None,
+        }
+    }
+}
+
+struct Channel {
+    tx: mpsc::UnboundedSender<Bytes>,
+    reader: JoinHandle<()>,
+    // Bytes the reader may still send to the peer.
+    credit: Arc<Semaphore>,
+    // Bytes received from the peer and not written to the socket yet.
+    unacked: Arc<AtomicUsize>,
+}
+
+/// The channels of one multiplexed stream.
+///
+/// Frames to send to the peer are read from `rx_frame`, a `Close` from there means the local
+/// socket is closed, the caller should call [`Mux::close`] before forwarding it.
+/// Connections accepted by the listeners are read from `rx_accepted` with the listener id.
+pub struct Mux {
+    channels: HashMap<u32, Channel>,
+    // Data received before the socket of the channel is connected.
+    pending: HashMap<u32, Vec<Bytes>>,
+    listeners: HashMap<u32, JoinHandle<()>>,
+    tx_frame: mpsc::UnboundedSender<Frame>,
+    pub rx_frame: mpsc::UnboundedReceiver<Frame>,
+    tx_accepted: mpsc::UnboundedSender<(u32, TcpStream)>,
+    pub rx_accepted: mpsc::UnboundedReceiver<(u32, TcpStream)>,
+    next_id: u32,
+}
+
+impl Mux {
+    pub fn new(is_controlling: bool) -> Self {
+        let (tx_frame, rx_frame) = mpsc::unbounded_channel();
+        let (tx_accepted, rx_accepted) = mpsc::unbounded_channel();
+        Self {
+            channels: Default::default(),
+            pending: Default::default(),
+            listeners: Default::default(),
+            tx_frame,
+            rx_frame,
+            tx_accepted,
+            rx_accepted,
+            next_id: if is_controlling { 1 } else { 2 },
+        }
+    }
+
+    pub fn next_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(2);
+        id
+    }
+
+    pub fn len(&self) -> usize {
+        self.channels.len() + self.pending.len()
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        self.channels.contains_key(&id) || self.pending.contains_key(&id)
+    }
+
+    /// The channel is being connected, data will be buffered until [`Mux::add`].
+    pub fn add_pending(&mut self, id: u32) {
+        self.pending.insert(id, Vec::new());
+    }
+
+    pub fn add(&mut self, id: u32, socket: TcpStream) {
+        let (tx, mut rx) = mpsc::unbounded_channel::<Bytes>();
+        let (mut reader, mut writer) = socket.into_split();
+        let credit = Arc::new(Semaphore::new(WINDOW));
+        let unacked = Arc::new(AtomicUsize::new(0));
+        let tx_frame = self.tx_frame.clone();
+        let reader_credit = credit.clone();
+        let reader = tokio::spawn(async move {
+            let mut buf = vec![0u8; READ_BUF_SIZE];
+            loop {
+                // Wait for the peer to acknowledge before reading more.
+                match reader_credit.acquire_many(READ_BUF_SIZE as _).await {
+                    Ok(permit) => permit.forget(),
+                    Err(_) => break,
+                }
+                match reader.read(&mut buf).await {
+                    Ok(0) => {
+                        tx_frame.send(Frame::Close(id, "".to_owned())).ok();
+                        break;
+                    }
+                    Ok(n) => {
+                        reader_credit.add_permits(READ_BUF_SIZE - n);
+                        if tx_frame
+                            .send(Frame::Data(id, Bytes::copy_from_slice(&buf[..n])))
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        tx_frame.send(Frame::Close(id, e.to_string())).ok();
+                        break;
+                    }
+                }
+            }
+        });
+        let tx_frame = self.tx_frame.clone();
+        let writer_unacked = unacked.clone();
+        tokio::spawn(async move {
+            while let Some(data) = rx.recv().await {
+                if let Err(e) = writer.write_all(&data).await {
+                    tx_frame.send(Frame::Close(id, e.to_string())).ok();
+                    return;
+                }
+                writer_unacked.fetch_sub(data.len(), Ordering::SeqCst);
+                tx_frame.send(Frame::Ack(id, data.len() as _)).ok();
+            }
+            writer.shutdown().await.ok();
+        });
+        for data in self.pending.remove(&id).unwrap_or_default() {
+            unacked.fetch_add(data.len(), Ordering::SeqCst);
+            tx.send(data).ok();
+        }
+        self.channels.insert(
+            id,
+            Channel {
+                tx,
+                reader,
+                credit,
+                unacked,
+            },
+        );
+    }
+
+    /// Returns false if the channel does not exist, or is closed for exceeding the window.
+    pub fn write(&mut self, id: u32, data: Bytes) -> bool {
+        let exceeded = if let Some(c) = self.channels.get(&id) {
+            let len = data.len();
+            if c.unacked.fetch_add(len, Ordering::SeqCst) + len <= WINDOW {
+                return c.tx.send(data).is_ok();
+            }
+            true
+        } else if let Some(v) = self.pending.get_mut(&id) {
+            if v.iter().map(|x| x.len()).sum::<usize>() + data.len() <= WINDOW {
+                v.push(data);
+                return true;
+            }
+            true
+        } else {
+            false
+        };
+        if exceeded {
+            log::error!("mux channel {} exceeded the window", id);
+            self.close(id);
+        }
+        false
+    }
+
+    /// The peer has written `n` bytes of the channel to its socket.
+    pub fn ack(&mut self, id: u32, n: u32) {
+        if let Some(c) = self.channels.get(&id) {
+            c.credit
+                .add_permits((n as usize).min(WINDOW.saturating_sub(c.credit.available_permits())));
+        }
+    }
+
+    pub fn add_listener(&mut self, id: u32, listener: TcpListener) {
+        let tx = self.tx_accepted.clone();
+        let handle = tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((socket, addr)) => {
+                        log::info!("mux listener {} accepted {:?}", id, addr);
+                        if tx.send((id, socket)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        log::error!("mux listener {} failed to accept: {}", id, e);
+                        break;
+                    }
+                }
+            }
+        });
+        self.listeners.insert(id, handle);
+    }
+
+    pub fn has_listener(&self, id: u32) -> bool {
+        self.listeners.contains_key(&id)
+    }
+
+    pub fn close_listener(&mut self, id: u32) -> bool {
+        if let Some(handle) = self.listeners.remove(&id) {
+            handle.abort();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Close the channel, the buffered data is still written to the socket.
+    pub fn close(&mut self, id: u32) -> bool {
+        self.pending.remove(&id);
+        if let Some(c) = self.channels.remove(&id) {
+            c.reader.abort();
+            log::debug!("mux channel {} closed", id);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Drop for Mux {
+    fn drop(&mut self) {
+        for (_, c) in self.channels.drain() {
+            c.reader.abort();
+        }
+        for (_, handle) in self.listeners.drain() {
+            handle.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame() {
+        let frames = vec![
+            Frame::Open(1, "localhost:22".to_owned()),
+            Frame::Data(3, Bytes::from_static(b"hello")),
+            Frame::Close(u32::MAX, "".to_owned()),
+            Frame::Listen(2, "127.0.0.1:8000".to_owned()),
+            Frame::Ack(5, 65536),
+        ];
+        for f in frames {
+            assert_eq!(Frame::decode(&f.encode()), Some(f));
+        }
+        assert_eq!(Frame::decode(&[0x08, 0, 0, 0, 1]), None);
+        assert_eq!(Frame::decode(&[KIND_DATA, 0]), None);
+        assert_eq!(Frame::decode(&[KIND_ACK, 0, 0, 0, 1, 0]), None);
+    }
+}
@@ -0,0 +1,116 @@
+// The server side of SOCKS5 (RFC 1928) for dynamic port forwarding, only the `CONNECT` command
+// without authentication is supported.
+//
+// The destination is dialed by the peer, so success is replied as soon as the request is
+// parsed, a failed dial closes the connection instead of replying an error.
+
+use hbb_common::{
+    bail,
+    tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    },
+    ResultType,
+};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+const VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0;
+const METHOD_NO_ACCEPTABLE: u8 = 0xFF;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+const REP_SUCCEEDED: u8 = 0;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 7;
+const REP_ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+/// Negotiate with a local client, returns the requested `host:port`.
+pub async fn handshake(socket: &mut TcpStream) -> ResultType<String> {
+    let mut head = [0u8; 2];
+    socket.read_exact(&mut head).await?;
+    if head[0] != VERSION {
+        bail!("Unsupported socks version {}", head[0]);
+    }
+    let mut methods = vec![0u8; head[1] as usize];
+    socket.read_exact(&mut methods).await?;
+    if !methods.contains(&METHOD_NO_AUTH) {
+        socket.write_all(&[VERSION, METHOD_NO_ACCEPTABLE]).await?;
+        bail!("No acceptable socks authentication method");
+    }
+    socket.write_all(&[VERSION, METHOD_NO_AUTH]).await?;
+
+    let mut request = [0u8; 4];
+    socket.read_exact(&mut request).await?;
+    if request[0] != VERSION {
+        bail!("Unsupported socks version {}", request[0]);
+    }
+    let host = match request[3] {
+        ATYP_IPV4 => {
+            let mut addr = [0u8; 4];
+            socket.read_exact(&mut addr).await?;
+            Ipv4Addr::from(addr).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut addr = [0u8; 16];
+            socket.read_exact(&mut addr).await?;
+            format!("[{}]", Ipv6Addr::from(addr))
+        }
+        ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            socket.read_exact(&mut len).await?;
+            let mut domain = vec![0u8; len[0] as usize];
+            socket.read_exact(&mut domain).await?;
+            String::from_utf8(domain)?
+        }
+        atyp => {
+            reply(socket, REP_ADDRESS_NOT_SUPPORTED).await.ok();
+            bail!("Unsupported socks address type {}", atyp);
+        }
+    };
+    let mut port = [0u8; 2];
+    socket.read_exact(&mut port).await?;
+    if request[1] != CMD_CONNECT {
+        reply(socket, REP_COMMAND_NOT_SUPPORTED).await.ok();
+        bail!("Unsupported socks command {}", request[1]);
+    }
+    reply(socket, REP_SUCCEEDED).await?;
+    Ok(format!("{}:{}", host, u16::from_be_bytes(port)))
+}
+
+async fn reply(socket: &mut TcpStream, rep: u8) -> ResultType<()> {
+    // The bound address is unknown, the connection is made by the peer.
+    socket
+        .write_all(&[VERSION, rep, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hbb_common::tokio::{self, net::TcpListener};
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut s = TcpStream::connect(addr).await.unwrap();
+            s.write_all(&[5, 1, 0]).await.unwrap();
+            let mut buf = [0u8; 2];
+            s.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [5, 0]);
+            let mut req = vec![5, 1, 0, ATYP_DOMAIN, 11];
+            req.extend_from_slice(b"example.com");
+            req.extend_from_slice(&80u16.to_be_bytes());
+            s.write_all(&req).await.unwrap();
+            let mut buf = [0u8; 10];
+            s.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf[1], REP_SUCCEEDED);
+        });
+        let (mut socket, _) = listener.accept().await.unwrap();
+        assert_eq!(handshake(&mut socket).await.unwrap(), "example.com:80");
+        client.await.unwrap();
+    }
+}
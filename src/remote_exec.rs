@@ -0,0 +1,228 @@
+// One-shot command execution of the terminal connections, see `server::terminal_exec`.
+//
+// The commands are not in the terminal messages of the protocol, they are extension messages
+// (see `crate::common::new_ext_message`) with the id `EXT_ID`, `[kind: u8][exec_id: i32][payload]`,
+// integers are big endian and strings are `[len: u16][utf8]`.
+//
+// - `COMMAND timeout_ms: u32, command, cwd, args: [count: u16][arg], env: [count: u16][name, value]`,
+//   sent by the controlling side, a `timeout_ms` of 0 is the default timeout of the peer.
+// - `OUTPUT is_stderr: u8, data`, the rest of the message.
+// - `EXIT exit_code: i32, timed_out: u8, error, unsupported: u8`, the last message of the command.
+
+use hbb_common::{
+    bail,
+    bytes::{Buf, BufMut},
+    message_proto::Message,
+    ResultType,
+};
+
+pub const EXT_ID: &str = "@rustdesk/terminal-exec";
+
+const KIND_COMMAND: u8 = 0x01;
+const KIND_OUTPUT: u8 = 0x02;
+const KIND_EXIT: u8 = 0x03;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecCommand {
+    pub exec_id: i32,
+    pub command: String,
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+    /// Empty for the working dir of the peer service.
+    pub cwd: String,
+    pub timeout_ms: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecExit {
+    pub exec_id: i32,
+    pub exit_code: i32,
+    pub timed_out: bool,
+    /// Not empty if the command is not run, or killed for its output.
+    pub error: String,
+    /// The peer cannot run the commands, e.g. as a Windows service.
+    pub unsupported: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecMessage {
+    Command(ExecCommand),
+    Output {
+        exec_id: i32,
+        is_stderr: bool,
+        data: Vec<u8>,
+    },
+    Exit(ExecExit),
+}
+
+impl ExecMessage {
+    pub fn to_message(&self) -> Message {
+        crate::common::new_ext_message(EXT_ID, self.encode())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        match self {
+            ExecMessage::Command(c) => {
+                buf.put_u8(KIND_COMMAND);
+                buf.put_i32(c.exec_id);
+                buf.put_u32(c.timeout_ms);
+                put_str(&mut buf, &c.command);
+                put_str(&mut buf, &c.cwd);
+                buf.put_u16(c.args.len().min(u16::MAX as _) as _);
+                for arg in c.args.iter().take(u16::MAX as _) {
+                    put_str(&mut buf, arg);
+                }
+                buf.put_u16(c.env.len().min(u16::MAX as _) as _);
+                for (name, value) in c.env.iter().take(u16::MAX as _) {
+                    put_str(&mut buf, name);
+                    put_str(&mut buf, value);
+                }
+            }
+            ExecMessage::Output {
+                exec_id,
+                is_stderr,
+                data,
+            } => {
+                buf.put_u8(KIND_OUTPUT);
+                buf.put_i32(*exec_id);
+                buf.put_u8(*is_stderr as _);
+                buf.put_slice(data);
+            }
+            ExecMessage::Exit(e) => {
+                buf.put_u8(KIND_EXIT);
+                buf.put_i32(e.exec_id);
+                buf.put_i32(e.exit_code);
+                buf.put_u8(e.timed_out as _);
+                put_str(&mut buf, &e.error);
+                buf.put_u8(e.unsupported as _);
+            }
+        }
+        buf
+    }
+
+    pub fn decode(mut data: &[u8]) -> ResultType<Self> {
+        if data.remaining() < 5 {
+            bail!("short exec message");
+        }
+        let kind = data.get_u8();
+        let exec_id = data.get_i32();
+        match kind {
+            KIND_COMMAND => {
+                if data.remaining() < 4 {
+                    bail!("short exec command");
+                }
+                let timeout_ms = data.get_u32();
+                let command = get_str(&mut data)?;
+                let cwd = get_str(&mut data)?;
+                let mut args = vec![];
+                for _ in 0..get_u16(&mut data)? {
+                    args.push(get_str(&mut data)?);
+                }
+                let mut env = vec![];
+                for _ in 0..get_u16(&mut data)? {
+                    env.push((get_str(&mut data)?, get_str(&mut data)?));
+                }
+                Ok(ExecMessage::Command(ExecCommand {
+                    exec_id,
+                    command,
+                    args,
+                    env,
+                    cwd,
+                    timeout_ms,
+                }))
+            }
+            KIND_OUTPUT => {
+                if data.remaining() < 1 {
+                    bail!("short exec output");
+                }
+                Ok(ExecMessage::Output {
+                    exec_id,
+                    is_stderr: data.get_u8() != 0,
+                    data: data.to_vec(),
+                })
+            }
+            KIND_EXIT => {
+                if data.remaining() < 5 {
+                    bail!("short exec exit");
+                }
+                Ok(ExecMessage::Exit(ExecExit {
+                    exec_id,
+                    exit_code: data.get_i32(),
+                    timed_out: data.get_u8() != 0,
+                    error: get_str(&mut data)?,
+                    unsupported: get_u8(&mut data)? != 0,
+                }))
+            }
+            kind => bail!("unknown exec message {}", kind),
+        }
+    }
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    let s = s.as_bytes();
+    let s = &s[..s.len().min(u16::MAX as _)];
+    buf.put_u16(s.len() as _);
+    buf.put_slice(s);
+}
+
+fn get_u8(data: &mut &[u8]) -> ResultType<u8> {
+    if data.remaining() < 1 {
+        bail!("short exec message");
+    }
+    Ok(data.get_u8())
+}
+
+fn get_u16(data: &mut &[u8]) -> ResultType<u16> {
+    if data.remaining() < 2 {
+        bail!("short exec message");
+    }
+    Ok(data.get_u16())
+}
+
+fn get_str(data: &mut &[u8]) -> ResultType<String> {
+    let len = get_u16(data)? as usize;
+    if data.remaining() < len {
+        bail!("short exec message string");
+    }
+    let s = String::from_utf8_lossy(&data[..len]).into_owned();
+    data.advance(len);
+    Ok(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let messages = vec![
+            ExecMessage::Command(ExecCommand {
+                exec_id: 1,
+                command: "ls".to_owned(),
+                args: vec!["-l".to_owned(), "/tmp".to_owned()],
+                env: vec![("LANG".to_owned(), "C".to_owned())],
+                cwd: "/".to_owned(),
+                timeout_ms: 1000,
+            }),
+            ExecMessage::Output {
+                exec_id: 1,
+                is_stderr: true,
+                data: b"\x00\xff".to_vec(),
+            },
+            ExecMessage::Exit(ExecExit {
+                exec_id: 1,
+                exit_code: -1,
+                timed_out: true,
+                error: "error".to_owned(),
+                unsupported: true,
+            }),
+        ];
+        for m in messages.iter() {
+            assert_eq!(&ExecMessage::decode(&m.encode()).unwrap(), m);
+        }
+        let data = messages[0].encode();
+        assert!(ExecMessage::decode(&data[..data.len() - 1]).is_err());
+        assert!(ExecMessage::decode(&[KIND_EXIT, 0, 0, 0, 1]).is_err());
+    }
+}
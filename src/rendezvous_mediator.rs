@@ -68,6 +68,13 @@ impl RendezvousMediator {
         if crate::platform::is_installed() && crate::is_server() && !crate::is_custom_client() {
             crate::updater::start_auto_update();
         }
+        #[cfg(target_os = "linux")]
+        if ((crate::platform::is_installed() && crate::is_server())
+            || std::env::var("APPIMAGE").is_ok())
+            && !crate::is_custom_client()
+        {
+            crate::updater::start_auto_update();
+        }
         check_zombie();
         let server = new_server();
         if config::option2bool("stop-service", &Config::get_option("stop-service")) {
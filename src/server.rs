@@ -34,6 +34,10 @@ use crate::ipc::Data;
 
 pub mod audio_service;
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
+mod terminal_exec;
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+mod terminal_policy;
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
 pub mod terminal_service;
 cfg_if::cfg_if! {
 if #[cfg(not(target_os = "ios"))] {
@@ -64,6 +68,9 @@ pub mod input_service {
     pub const NAME_WINDOW_FOCUS: &'static str = "";
 }
 
+mod access_policy;
+pub mod audit_log;
+mod audit_sink;
 mod connection;
 pub mod display_service;
 #[cfg(windows)]
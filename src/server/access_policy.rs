@@ -0,0 +1,488 @@
+// Access policy of incoming connections.
+//
+// The policy is read from `AccessPolicy.toml` in the config dir, e.g.
+//
+// ```toml
+// default_action = "allow"
+//
+// [[rules]]
+// name = "office"
+// priority = 10
+// action = "allow"
+// cidrs = ["10.0.0.0/8"]
+// [rules.time_window]
+// start = "08:00"
+// end = "18:00"
+// weekdays = [1, 2, 3, 4, 5]
+//
+// [[rules]]
+// name = "blocked peers"
+// priority = 100
+// action = "deny"
+// peer_ids = ["123456789", "99*"]
+//
+// [lockout]
+// max_failures_per_minute = 6
+// max_failures = 30
+// lockout_secs = 300
+// backoff_factor = 2.0
+// max_lockout_secs = 86400
+// ```
+//
+// Lockouts are persisted to `AccessLockouts.toml`, so they survive service restarts.
+
+use chrono::{Datelike, Local, Timelike};
+use cidr_utils::cidr::IpCidr;
+use hbb_common::{
+    config::{load_path, store_path, Config},
+    get_time, log,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+lazy_static::lazy_static! {
+    static ref LOCKOUTS: Arc<Mutex<Lockouts>> = Arc::new(Mutex::new(load_path(Lockouts::path())));
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// `start` and `end` are local times formatted as `HH:MM`, `end` may be earlier than `start`
+/// for windows across midnight. `weekdays` are numbered from Monday (1) to Sunday (7), empty means every day.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TimeWindow {
+    pub start: String,
+    pub end: String,
+    pub weekdays: Vec<u32>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessRule {
+    pub name: String,
+    /// Rules with higher priority are evaluated first.
+    pub priority: i32,
+    pub action: RuleAction,
+    /// Empty means any ip.
+    pub cidrs: Vec<String>,
+    /// Empty means any peer, a trailing `*` matches by prefix.
+    pub peer_ids: Vec<String>,
+    pub time_window: Option<TimeWindow>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LockoutPolicy {
+    pub max_failures_per_minute: i32,
+    pub max_failures: i32,
+    pub lockout_secs: u64,
+    pub backoff_factor: f64,
+    pub max_lockout_secs: u64,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        Self {
+            max_failures_per_minute: 6,
+            max_failures: 30,
+            lockout_secs: 300,
+            backoff_factor: 2.0,
+            max_lockout_secs: 86400,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessPolicy {
+    /// Used when some rules exist but none of them matches.
+    pub default_action: RuleAction,
+    pub rules: Vec<AccessRule>,
+    pub lockout: LockoutPolicy,
+}
+
+/// The result of [`AccessPolicy::evaluate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    /// No rules are configured, the legacy whitelist applies.
+    NoRules,
+    Allow(String),
+    Deny(String),
+    /// Denied by a rule with peer ids.
+    DenyPeer(String),
+    /// Not decided before the peer id is known.
+    Undecided,
+}
+
+impl AccessPolicy {
+    fn path() -> PathBuf {
+        Config::path("AccessPolicy.toml")
+    }
+
+    pub fn load() -> Self {
+        load_path(Self::path())
+    }
+
+    /// Evaluate the rules for `ip`, and for `peer_id` once the login request is received.
+    /// Before the peer id is known, it is undecided if a matching rule with peer ids comes first,
+    /// or if no rule matches: the default action only applies with the peer id.
+    pub fn evaluate(&self, ip: &IpAddr, peer_id: Option<&str>) -> Decision {
+        self.evaluate_at(ip, peer_id, Self::now())
+    }
+
+    fn now() -> (u32, u32) {
+        let now = Local::now();
+        (
+            now.weekday().number_from_monday(),
+            now.hour() * 60 + now.minute(),
+        )
+    }
+
+    fn evaluate_at(&self, ip: &IpAddr, peer_id: Option<&str>, now: (u32, u32)) -> Decision {
+        if self.rules.is_empty() {
+            return Decision::NoRules;
+        }
+        let mut rules: Vec<&AccessRule> = self.rules.iter().collect();
+        rules.sort_by(|a, b| b.priority.cmp(&a.priority));
+        for rule in rules {
+            if !rule.matches(ip, now) {
+                continue;
+            }
+            if !rule.peer_ids.is_empty() {
+                match peer_id {
+                    // The rule may apply once the peer id is known.
+                    None => return Decision::Undecided,
+                    Some(peer_id) if !rule.matches_peer(peer_id) => continue,
+                    Some(_) => {}
+                }
+            }
+            return match rule.action {
+                RuleAction::Allow => Decision::Allow(rule.name.clone()),
+                RuleAction::Deny if !rule.peer_ids.is_empty() => {
+                    Decision::DenyPeer(rule.name.clone())
+                }
+                RuleAction::Deny => Decision::Deny(rule.name.clone()),
+            };
+        }
+        if peer_id.is_none() {
+            return Decision::Undecided;
+        }
+        match self.default_action {
+            RuleAction::Allow => Decision::Allow("default".to_owned()),
+            RuleAction::Deny => Decision::Deny("default".to_owned()),
+        }
+    }
+}
+
+impl AccessRule {
+    // Whether the ip and the time match, the peer ids are checked by `matches_peer()`.
+    fn matches(&self, ip: &IpAddr, now: (u32, u32)) -> bool {
+        if !self.cidrs.is_empty()
+            && !self
+                .cidrs
+                .iter()
+                .any(|x| x == "0.0.0.0" || IpCidr::from_str(x).map_or(false, |y| y.contains(*ip)))
+        {
+            return false;
+        }
+        if let Some(window) = &self.time_window {
+            if !window.contains(now) {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn matches_peer(&self, peer_id: &str) -> bool {
+        self.peer_ids.iter().any(|x| match x.strip_suffix('*') {
+            Some(prefix) => peer_id.starts_with(prefix),
+            None => x == peer_id,
+        })
+    }
+}
+
+impl TimeWindow {
+    fn parse_minutes(s: &str) -> Option<u32> {
+        let (h, m) = s.trim().split_once(':')?;
+        let (h, m) = (h.parse::<u32>().ok()?, m.parse::<u32>().ok()?);
+        if h > 23 || m > 59 {
+            return None;
+        }
+        Some(h * 60 + m)
+    }
+
+    fn contains(&self, (weekday, minutes): (u32, u32)) -> bool {
+        let (Some(start), Some(end)) = (
+            Self::parse_minutes(&self.start),
+            Self::parse_minutes(&self.end),
+        ) else {
+            log::warn!(
+                "Invalid time window of access policy: {}-{}",
+                self.start,
+                self.end
+            );
+            return false;
+        };
+        if start <= end {
+            (self.weekdays.is_empty() || self.weekdays.contains(&weekday))
+                && minutes >= start
+                && minutes < end
+        } else if minutes >= start {
+            self.weekdays.is_empty() || self.weekdays.contains(&weekday)
+        } else if minutes < end {
+            // The window started yesterday.
+            let yesterday = if weekday == 1 { 7 } else { weekday - 1 };
+            self.weekdays.is_empty() || self.weekdays.contains(&yesterday)
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FailureRecord {
+    /// The minute of the last failure.
+    pub minute: i32,
+    pub failures_in_minute: i32,
+    pub failures: i32,
+    /// Milliseconds since epoch, 0 if not locked.
+    pub locked_until: i64,
+    /// How many times the ip has been locked out, used for the backoff.
+    pub lockouts: u32,
+}
+
+/// Failure records of password (0) and 2fa (1) attempts by ip.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Lockouts {
+    password: HashMap<String, FailureRecord>,
+    tfa: HashMap<String, FailureRecord>,
+}
+
+impl Lockouts {
+    fn path() -> PathBuf {
+        Config::path("AccessLockouts.toml")
+    }
+
+    fn records(&mut self, i: usize) -> &mut HashMap<String, FailureRecord> {
+        if i == 0 {
+            &mut self.password
+        } else {
+            &mut self.tfa
+        }
+    }
+
+    fn store(&self) {
+        if let Err(e) = store_path(Self::path(), self) {
+            log::error!("Failed to store access lockouts: {}", e);
+        }
+    }
+}
+
+/// Why [`check_failure`] rejects an attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    TooManyAttempts,
+    TooFrequent,
+}
+
+/// Check whether `ip` may try to authenticate, `i` is 0 for password and 1 for 2fa.
+pub fn check_failure(policy: &LockoutPolicy, ip: &str, i: usize) -> Result<(), Rejection> {
+    let mut lock = LOCKOUTS.lock().unwrap();
+    let Some(record) = lock.records(i).get_mut(ip) else {
+        return Ok(());
+    };
+    let res = record.check(policy, get_time(), (get_time() / 60_000) as i32);
+    if res.is_ok() && record.locked_until != 0 {
+        // Lockout expired.
+        record.locked_until = 0;
+        lock.store();
+    }
+    res
+}
+
+/// Record a failed (`success` is false) or successful attempt of `ip`.
+pub fn update_failure(policy: &LockoutPolicy, ip: &str, i: usize, success: bool) {
+    let mut lock = LOCKOUTS.lock().unwrap();
+    let records = lock.records(i);
+    if success {
+        if records.remove(ip).is_some() {
+            lock.store();
+        }
+        return;
+    }
+    let record = records.entry(ip.to_owned()).or_default();
+    record.fail(policy, get_time(), (get_time() / 60_000) as i32);
+    lock.store();
+}
+
+impl FailureRecord {
+    fn check(&self, policy: &LockoutPolicy, now: i64, minute: i32) -> Result<(), Rejection> {
+        if self.locked_until > now {
+            Err(Rejection::TooManyAttempts)
+        } else if minute == self.minute && self.failures_in_minute > policy.max_failures_per_minute
+        {
+            Err(Rejection::TooFrequent)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn fail(&mut self, policy: &LockoutPolicy, now: i64, minute: i32) {
+        if self.minute == minute {
+            self.failures_in_minute += 1;
+        } else {
+            self.minute = minute;
+            self.failures_in_minute = 1;
+        }
+        self.failures += 1;
+        if self.failures > policy.max_failures {
+            let secs = policy.lockout_secs as f64
+                * policy
+                    .backoff_factor
+                    .max(1.)
+                    .powi(self.lockouts.min(32) as i32);
+            let secs = secs.min(policy.max_lockout_secs as f64) as i64;
+            self.locked_until = now + secs * 1000;
+            self.lockouts += 1;
+            self.failures = 0;
+            log::warn!(
+                "Lock out for {} seconds after too many wrong attempts",
+                secs
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(action: RuleAction, priority: i32, cidrs: &[&str], peer_ids: &[&str]) -> AccessRule {
+        AccessRule {
+            name: format!("{:?}{}", action, priority),
+            priority,
+            action,
+            cidrs: cidrs.iter().map(|x| x.to_string()).collect(),
+            peer_ids: peer_ids.iter().map(|x| x.to_string()).collect(),
+            time_window: None,
+        }
+    }
+
+    #[test]
+    fn test_evaluate_priority() {
+        let ip: IpAddr = "10.1.2.3".parse().unwrap();
+        let mut policy = AccessPolicy::default();
+        assert_eq!(policy.evaluate_at(&ip, None, (1, 0)), Decision::NoRules);
+        policy.default_action = RuleAction::Deny;
+        policy.rules = vec![
+            rule(RuleAction::Allow, 1, &["10.0.0.0/8"], &[]),
+            rule(RuleAction::Deny, 5, &[], &["99*"]),
+        ];
+        // The peer id rule comes first.
+        assert_eq!(policy.evaluate_at(&ip, None, (1, 0)), Decision::Undecided);
+        assert_eq!(
+            policy.evaluate_at(&ip, Some("123"), (1, 0)),
+            Decision::Allow("Allow1".to_owned())
+        );
+        assert_eq!(
+            policy.evaluate_at(&ip, Some("991234"), (1, 0)),
+            Decision::DenyPeer("Deny5".to_owned())
+        );
+        let ip: IpAddr = "192.168.1.1".parse().unwrap();
+        assert_eq!(
+            policy.evaluate_at(&ip, Some("123"), (1, 0)),
+            Decision::Deny("default".to_owned())
+        );
+        // A higher priority peer id rule beats a lower priority ip rule.
+        policy.rules = vec![
+            rule(RuleAction::Deny, 1, &["192.168.0.0/16"], &[]),
+            rule(RuleAction::Allow, 5, &["192.168.0.0/16"], &["123"]),
+        ];
+        assert_eq!(policy.evaluate_at(&ip, None, (1, 0)), Decision::Undecided);
+        assert_eq!(
+            policy.evaluate_at(&ip, Some("123"), (1, 0)),
+            Decision::Allow("Allow5".to_owned())
+        );
+        assert_eq!(
+            policy.evaluate_at(&ip, Some("456"), (1, 0)),
+            Decision::Deny("Deny1".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_evaluate_default_deny() {
+        let ip: IpAddr = "10.1.2.3".parse().unwrap();
+        let policy = AccessPolicy {
+            default_action: RuleAction::Deny,
+            rules: vec![rule(RuleAction::Allow, 1, &[], &["123456789"])],
+            ..Default::default()
+        };
+        // The default action waits for the peer id.
+        assert_eq!(policy.evaluate_at(&ip, None, (1, 0)), Decision::Undecided);
+        assert_eq!(
+            policy.evaluate_at(&ip, Some("123456789"), (1, 0)),
+            Decision::Allow("Allow1".to_owned())
+        );
+        assert_eq!(
+            policy.evaluate_at(&ip, Some("987654321"), (1, 0)),
+            Decision::Deny("default".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_time_window() {
+        let w = TimeWindow {
+            start: "08:00".to_owned(),
+            end: "18:00".to_owned(),
+            weekdays: vec![1, 2, 3, 4, 5],
+        };
+        assert!(w.contains((1, 8 * 60)));
+        assert!(!w.contains((1, 18 * 60)));
+        assert!(!w.contains((6, 9 * 60)));
+        let w = TimeWindow {
+            start: "22:00".to_owned(),
+            end: "06:00".to_owned(),
+            weekdays: vec![5],
+        };
+        assert!(w.contains((5, 23 * 60)));
+        assert!(w.contains((6, 60)));
+        assert!(!w.contains((5, 60)));
+    }
+
+    #[test]
+    fn test_lockout_backoff() {
+        let policy = LockoutPolicy {
+            max_failures: 2,
+            lockout_secs: 10,
+            ..Default::default()
+        };
+        let mut record = FailureRecord::default();
+        for i in 0..3 {
+            assert!(record.check(&policy, 0, i).is_ok());
+            record.fail(&policy, 0, i);
+        }
+        assert_eq!(record.locked_until, 10_000);
+        assert_eq!(
+            record.check(&policy, 5_000, 3),
+            Err(Rejection::TooManyAttempts)
+        );
+        for i in 0..3 {
+            record.fail(&policy, 20_000, 10 + i);
+        }
+        assert_eq!(record.locked_until, 40_000);
+    }
+}
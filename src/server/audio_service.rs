@@ -447,6 +447,8 @@ mod cpal_impl {
 }
 
 fn create_format_msg(sample_rate: u32, channels: u16) -> Message {
+    // The frames sent to the peers are recorded too.
+    super::video_service::set_record_audio_format(sample_rate, channels);
     let format = AudioFormat {
         sample_rate,
         channels: channels as _,
@@ -496,6 +498,7 @@ fn send_f32(data: &[f32], encoder: &mut Encoder, sp: &GenericService) {
                     .encode_vec_float(&data[i * BATCH_SIZE..(i + 1) * BATCH_SIZE], BATCH_SIZE)
                 {
                     Ok(data) => {
+                        super::video_service::write_record_audio(&data);
                         let mut msg_out = Message::new();
                         msg_out.set_audio_frame(AudioFrame {
                             data: data.into(),
@@ -515,6 +518,7 @@ fn send_f32(data: &[f32], encoder: &mut Encoder, sp: &GenericService) {
     #[cfg(not(target_os = "android"))]
     match encoder.encode_vec_float(data, data.len() * 6) {
         Ok(data) => {
+            super::video_service::write_record_audio(&data);
             let mut msg_out = Message::new();
             msg_out.set_audio_frame(AudioFrame {
                 data: data.into(),
@@ -0,0 +1,163 @@
+// Local audit log, independent of the audit api of the api-server.
+//
+// Enabled by the option `enable-local-audit-log`. Events are appended as json lines
+// to `audit/audit-YYYY-MM-DD.jsonl` in the config dir, one file per day. Files older than
+// `local-audit-log-retention-days` (default 180) are removed when a new file is started.
+//
+// All the events are also forwarded to the sinks of `audit_sink`, see its options.
+
+use chrono::{Local, NaiveDate};
+use hbb_common::{bail, config::Config, log, ResultType};
+use serde_json::{json, Value};
+
+use super::audit_sink::{self, AuditEvent};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+const OPTION_ENABLE: &str = "enable-local-audit-log";
+const OPTION_RETENTION_DAYS: &str = "local-audit-log-retention-days";
+const DEFAULT_RETENTION_DAYS: i64 = 180;
+const FILE_PREFIX: &str = "audit-";
+const FILE_SUFFIX: &str = ".jsonl";
+
+lazy_static::lazy_static! {
+    static ref CURRENT: Arc<Mutex<Option<(NaiveDate, File)>>> = Default::default();
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    Config::get_option(OPTION_ENABLE) == "Y"
+}
+
+fn dir() -> PathBuf {
+    Config::path("audit")
+}
+
+fn file_name(date: &NaiveDate) -> String {
+    format!("{}{}{}", FILE_PREFIX, date.format("%Y-%m-%d"), FILE_SUFFIX)
+}
+
+fn parse_file_name(name: &str) -> Option<NaiveDate> {
+    let date = name.strip_prefix(FILE_PREFIX)?.strip_suffix(FILE_SUFFIX)?;
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()
+}
+
+/// Log files of the audit dir sorted by date.
+fn files() -> Vec<(NaiveDate, PathBuf)> {
+    let mut v: Vec<(NaiveDate, PathBuf)> = fs::read_dir(dir())
+        .map(|rd| {
+            rd.filter_map(|e| e.ok())
+                .filter_map(|e| {
+                    parse_file_name(&e.file_name().to_string_lossy()).map(|d| (d, e.path()))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    v.sort_by(|a, b| a.0.cmp(&b.0));
+    v
+}
+
+fn remove_expired(today: &NaiveDate) {
+    let days = Config::get_option(OPTION_RETENTION_DAYS)
+        .parse::<i64>()
+        .unwrap_or(DEFAULT_RETENTION_DAYS);
+    if days <= 0 {
+        return;
+    }
+    for (date, path) in files() {
+        if (*today - date).num_days() > days {
+            if let Err(e) = fs::remove_file(&path) {
+                log::error!("Failed to remove audit log {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+fn open(date: &NaiveDate) -> ResultType<File> {
+    let dir = dir();
+    fs::create_dir_all(&dir)?;
+    Ok(OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(file_name(date)))?)
+}
+
+/// Append an event to the local audit log, `info` should be a json object.
+pub fn record(event: &str, info: Value) {
+    let now = Local::now();
+    let mut info = info;
+    info["id"] = json!(Config::get_id());
+    audit_sink::emit(AuditEvent::new(event, now.to_rfc3339(), info.clone()));
+    if !is_enabled() {
+        return;
+    }
+    let today = now.date_naive();
+    let mut v = json!({
+        "time": now.to_rfc3339(),
+        "event": event,
+    });
+    if let (Some(v), Value::Object(info)) = (v.as_object_mut(), info) {
+        for (k, x) in info {
+            v.entry(k).or_insert(x);
+        }
+    }
+    let mut lock = CURRENT.lock().unwrap();
+    if lock.as_ref().map(|(d, _)| d != &today).unwrap_or(true) {
+        remove_expired(&today);
+        match open(&today) {
+            Ok(f) => *lock = Some((today, f)),
+            Err(e) => {
+                log::error!("Failed to open audit log: {}", e);
+                *lock = None;
+                return;
+            }
+        }
+    }
+    if let Some((_, f)) = lock.as_mut() {
+        if let Err(e) = writeln!(f, "{}", v) {
+            log::error!("Failed to write audit log: {}", e);
+        }
+    }
+}
+
+/// Write the events from `from` to `to` (both inclusive, `YYYY-MM-DD`) to `out`.
+pub fn export(from: &str, to: &str, out: &mut dyn Write) -> ResultType<usize> {
+    let (Ok(from), Ok(to)) = (
+        NaiveDate::parse_from_str(from, "%Y-%m-%d"),
+        NaiveDate::parse_from_str(to, "%Y-%m-%d"),
+    ) else {
+        bail!("Invalid date, the format is YYYY-MM-DD");
+    };
+    let mut count = 0;
+    for (date, path) in files() {
+        if date < from || date > to {
+            continue;
+        }
+        for line in BufReader::new(File::open(&path)?).lines() {
+            let line = line?;
+            if !line.is_empty() {
+                writeln!(out, "{}", line)?;
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_name() {
+        let date = NaiveDate::from_ymd_opt(2024, 3, 9).unwrap();
+        assert_eq!(file_name(&date), "audit-2024-03-09.jsonl");
+        assert_eq!(parse_file_name(&file_name(&date)), Some(date));
+        assert_eq!(parse_file_name("audit-2024-03-09.log"), None);
+        assert_eq!(parse_file_name("other-2024-03-09.jsonl"), None);
+    }
+}
@@ -0,0 +1,342 @@
+// Forwarding of audit events to syslog and the systemd journal.
+//
+// Options:
+// - `audit-syslog-server`: `udp://host[:514]` or `tcp://host[:601]`, RFC 5424 messages,
+//   octet counting framing (RFC 6587) is used for tcp.
+// - `audit-syslog-facility`: numeric facility, default 13 (log audit).
+// - `enable-audit-journald`: `Y` to send the events to the systemd journal (Linux only).
+//
+// Events are sent from a dedicated thread, so that a slow or unreachable server never blocks
+// the connections.
+
+use hbb_common::{bail, config::Config, log, ResultType};
+use serde_json::Value;
+use std::{
+    io::Write,
+    net::{TcpStream, ToSocketAddrs, UdpSocket},
+    sync::{mpsc, Arc, Mutex},
+    time::Duration,
+};
+
+const OPTION_SYSLOG_SERVER: &str = "audit-syslog-server";
+const OPTION_SYSLOG_FACILITY: &str = "audit-syslog-facility";
+#[cfg(target_os = "linux")]
+const OPTION_ENABLE_JOURNALD: &str = "enable-audit-journald";
+const DEFAULT_FACILITY: u8 = 13;
+const SD_ID: &str = "audit@32473";
+
+lazy_static::lazy_static! {
+    static ref TX: Arc<Mutex<Option<mpsc::Sender<AuditEvent>>>> = Default::default();
+}
+
+pub const SEVERITY_WARNING: u8 = 4;
+pub const SEVERITY_NOTICE: u8 = 5;
+pub const SEVERITY_INFO: u8 = 6;
+
+#[derive(Debug, Clone)]
+pub struct AuditEvent {
+    pub event: String,
+    pub severity: u8,
+    /// RFC 3339
+    pub time: String,
+    /// A json object with all the fields of the event.
+    pub data: Value,
+}
+
+impl AuditEvent {
+    pub fn new(event: &str, time: String, data: Value) -> Self {
+        let severity = match event {
+            "alarm" => SEVERITY_WARNING,
+            "auth" if data["result"] == "failure" => SEVERITY_NOTICE,
+            _ => SEVERITY_INFO,
+        };
+        Self {
+            event: event.to_owned(),
+            severity,
+            time,
+            data,
+        }
+    }
+}
+
+pub trait AuditSink: Send {
+    fn name(&self) -> &'static str;
+    fn emit(&mut self, event: &AuditEvent) -> ResultType<()>;
+}
+
+/// Forward `event` to all the configured sinks.
+pub fn emit(event: AuditEvent) {
+    if !has_sinks() {
+        return;
+    }
+    let mut lock = TX.lock().unwrap();
+    if lock.is_none() {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || run(rx));
+        *lock = Some(tx);
+    }
+    if let Some(tx) = lock.as_ref() {
+        tx.send(event).ok();
+    }
+}
+
+fn has_sinks() -> bool {
+    #[cfg(target_os = "linux")]
+    if Config::get_option(OPTION_ENABLE_JOURNALD) == "Y" {
+        return true;
+    }
+    !Config::get_option(OPTION_SYSLOG_SERVER).is_empty()
+}
+
+fn sink_options() -> (String, String, bool) {
+    #[cfg(target_os = "linux")]
+    let journald = Config::get_option(OPTION_ENABLE_JOURNALD) == "Y";
+    #[cfg(not(target_os = "linux"))]
+    let journald = false;
+    (
+        Config::get_option(OPTION_SYSLOG_SERVER),
+        Config::get_option(OPTION_SYSLOG_FACILITY),
+        journald,
+    )
+}
+
+fn create_sinks((server, facility, _journald): &(String, String, bool)) -> Vec<Box<dyn AuditSink>> {
+    let mut sinks: Vec<Box<dyn AuditSink>> = vec![];
+    if !server.is_empty() {
+        let facility = facility.parse::<u8>().unwrap_or(DEFAULT_FACILITY).min(23);
+        match SyslogSink::new(server, facility) {
+            Ok(sink) => sinks.push(Box::new(sink)),
+            Err(e) => log::error!("Invalid audit syslog server {}: {}", server, e),
+        }
+    }
+    #[cfg(target_os = "linux")]
+    if *_journald {
+        sinks.push(Box::new(JournaldSink {}));
+    }
+    sinks
+}
+
+fn run(rx: mpsc::Receiver<AuditEvent>) {
+    let mut options = sink_options();
+    let mut sinks = create_sinks(&options);
+    while let Ok(event) = rx.recv() {
+        let new_options = sink_options();
+        if new_options != options {
+            options = new_options;
+            sinks = create_sinks(&options);
+        }
+        for sink in sinks.iter_mut() {
+            if let Err(e) = sink.emit(&event) {
+                log::error!("Failed to send audit event to {}: {}", sink.name(), e);
+            }
+        }
+    }
+}
+
+enum SyslogTransport {
+    Udp(UdpSocket),
+    Tcp(Option<TcpStream>),
+}
+
+pub struct SyslogSink {
+    addr: String,
+    facility: u8,
+    hostname: String,
+    transport: SyslogTransport,
+}
+
+impl SyslogSink {
+    pub fn new(url: &str, facility: u8) -> ResultType<Self> {
+        let (transport, addr, port) = if let Some(addr) = url.strip_prefix("tcp://") {
+            (SyslogTransport::Tcp(None), addr, 601)
+        } else {
+            let addr = url.strip_prefix("udp://").unwrap_or(url);
+            (
+                SyslogTransport::Udp(UdpSocket::bind("0.0.0.0:0")?),
+                addr,
+                514,
+            )
+        };
+        if addr.is_empty() {
+            bail!("empty address");
+        }
+        let addr = if addr.contains(':') && !addr.ends_with(']') {
+            addr.to_owned()
+        } else {
+            format!("{}:{}", addr, port)
+        };
+        Ok(Self {
+            addr,
+            facility,
+            hostname: hbb_common::whoami::hostname(),
+            transport,
+        })
+    }
+
+    pub fn format(&self, event: &AuditEvent) -> String {
+        let app_name: String = crate::get_app_name()
+            .chars()
+            .filter(|c| c.is_ascii_graphic())
+            .take(48)
+            .collect();
+        let hostname = if self.hostname.is_empty() {
+            "-"
+        } else {
+            &self.hostname
+        };
+        let mut sd = String::new();
+        if let Value::Object(map) = &event.data {
+            for (k, v) in map {
+                let name: String = k
+                    .chars()
+                    .filter(|c| c.is_ascii_graphic() && !matches!(c, '=' | ']' | '"' | ' '))
+                    .take(32)
+                    .collect();
+                let v = match v {
+                    Value::String(s) => s.clone(),
+                    Value::Null => continue,
+                    v => v.to_string(),
+                };
+                let v = v
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace(']', "\\]");
+                sd.push_str(&format!(" {}=\"{}\"", name, v));
+            }
+        }
+        format!(
+            "<{}>1 {} {} {} {} {} [{}{}] {}",
+            self.facility as u32 * 8 + event.severity as u32,
+            event.time,
+            hostname,
+            if app_name.is_empty() { "-" } else { &app_name },
+            std::process::id(),
+            event.event,
+            SD_ID,
+            sd,
+            event.data
+        )
+    }
+}
+
+impl AuditSink for SyslogSink {
+    fn name(&self) -> &'static str {
+        "syslog"
+    }
+
+    fn emit(&mut self, event: &AuditEvent) -> ResultType<()> {
+        let msg = self.format(event);
+        match &mut self.transport {
+            SyslogTransport::Udp(socket) => {
+                socket.send_to(msg.as_bytes(), &self.addr)?;
+            }
+            SyslogTransport::Tcp(stream) => {
+                let frame = format!("{} {}", msg.len(), msg);
+                if let Some(s) = stream.as_mut() {
+                    if s.write_all(frame.as_bytes()).is_ok() {
+                        return Ok(());
+                    }
+                }
+                // Reconnect once if the server closed the connection.
+                let Some(addr) = self.addr.to_socket_addrs()?.next() else {
+                    bail!("failed to resolve {}", self.addr);
+                };
+                let mut s = TcpStream::connect_timeout(&addr, Duration::from_secs(3))?;
+                s.set_write_timeout(Some(Duration::from_secs(3))).ok();
+                s.write_all(frame.as_bytes())?;
+                *stream = Some(s);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+struct JournaldSink {}
+
+#[cfg(target_os = "linux")]
+impl JournaldSink {
+    const SOCKET: &'static str = "/run/systemd/journal/socket";
+
+    fn field_name(key: &str) -> String {
+        key.chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    }
+
+    // Use the binary format of the native protocol for all fields, values may contain newlines.
+    fn push_field(buf: &mut Vec<u8>, name: &str, value: &str) {
+        buf.extend_from_slice(name.as_bytes());
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        buf.extend_from_slice(value.as_bytes());
+        buf.push(b'\n');
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl AuditSink for JournaldSink {
+    fn name(&self) -> &'static str {
+        "journald"
+    }
+
+    fn emit(&mut self, event: &AuditEvent) -> ResultType<()> {
+        let mut buf = Vec::new();
+        Self::push_field(
+            &mut buf,
+            "MESSAGE",
+            &format!("{}: {}", event.event, event.data),
+        );
+        Self::push_field(&mut buf, "PRIORITY", &event.severity.to_string());
+        Self::push_field(&mut buf, "SYSLOG_IDENTIFIER", &crate::get_app_name());
+        Self::push_field(&mut buf, "AUDIT_EVENT", &event.event);
+        if let Value::Object(map) = &event.data {
+            for (k, v) in map {
+                let v = match v {
+                    Value::String(s) => s.clone(),
+                    Value::Null => continue,
+                    v => v.to_string(),
+                };
+                Self::push_field(&mut buf, &format!("AUDIT_{}", Self::field_name(k)), &v);
+            }
+        }
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        socket.send_to(&buf, Self::SOCKET)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_syslog_udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(3)))
+            .unwrap();
+        let url = format!("udp://{}", server.local_addr().unwrap());
+        let mut sink = SyslogSink::new(&url, DEFAULT_FACILITY).unwrap();
+        let event = AuditEvent::new(
+            "alarm",
+            "2024-01-01T00:00:00+00:00".to_owned(),
+            json!({ "typ": "ip_whitelist", "info": "a \"quoted\" [value]" }),
+        );
+        sink.emit(&event).unwrap();
+        let mut buf = [0u8; 2048];
+        let n = server.recv(&mut buf).unwrap();
+        let msg = String::from_utf8_lossy(&buf[..n]).to_string();
+        assert!(msg.starts_with("<108>1 2024-01-01T00:00:00+00:00 "));
+        assert!(msg.contains(" alarm [audit@32473 "));
+        assert!(msg.contains(r#"info="a \"quoted\" [value\]""#));
+        assert!(msg.contains(r#"typ="ip_whitelist""#));
+    }
+}
@@ -1,4 +1,14 @@
-use super::{input_service::*, *};
+use super::{
+    access_policy::{self, AccessPolicy, Decision, Rejection},
+    audit_log,
+    input_service::*,
+    *,
+};
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+use super::{
+    terminal_exec,
+    terminal_policy::{self, TerminalPolicy},
+};
 #[cfg(feature = "unix-file-copy-paste")]
 use crate::clipboard::try_empty_clipboard_files;
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
@@ -17,7 +27,9 @@ use crate::{
     client::{
         new_voice_call_request, new_voice_call_response, start_audio_thread, MediaData, MediaSender,
     },
-    display_service, ipc, privacy_mode, video_service, VERSION,
+    display_service, ipc, privacy_mode,
+    transfer_limit::{self, TransferLimiter},
+    video_service, VERSION,
 };
 #[cfg(any(target_os = "android", target_os = "ios"))]
 use crate::{common::DEVICE_NAME, flutter::connection_manager::start_channel};
@@ -44,12 +56,16 @@ use hbb_common::{
 };
 #[cfg(any(target_os = "android", target_os = "ios"))]
 use scrap::android::{call_main_service_key_event, call_main_service_pointer_input};
-use scrap::camera;
+use scrap::{
+    camera,
+    record_index::{RecordEvent, RecordEventKind},
+};
 use serde_derive::Serialize;
 use serde_json::{json, value::Value};
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 use std::sync::atomic::Ordering;
 use std::{
+    net::IpAddr,
     num::NonZeroI64,
     path::PathBuf,
     sync::{atomic::AtomicI64, mpsc as std_mpsc},
@@ -66,7 +82,6 @@ use std::collections::HashSet;
 pub type Sender = mpsc::UnboundedSender<(Instant, Arc<Message>)>;
 
 lazy_static::lazy_static! {
-    static ref LOGIN_FAILURES: [Arc::<Mutex<HashMap<String, (i32, i32, i32)>>>; 2] = Default::default();
     static ref SESSIONS: Arc::<Mutex<HashMap<SessionKey, Session>>> = Default::default();
     static ref ALIVE_CONNS: Arc::<Mutex<Vec<i32>>> = Default::default();
     pub static ref AUTHED_CONNS: Arc::<Mutex<Vec<AuthedConn>>> = Default::default();
@@ -190,6 +205,14 @@ impl TerminalUserToken {
         }
     }
 }
+
+enum PortForwardTarget {
+    Socket(Framed<TcpStream, BytesCodec>),
+    // Many tcp connections multiplexed over the stream, see `port_forward::mux`.
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    Mux,
+}
+
 pub struct Connection {
     inner: ConnInner,
     display_idx: usize,
@@ -197,12 +220,13 @@ pub struct Connection {
     server: super::ServerPtrWeak,
     hash: Hash,
     read_jobs: Vec<fs::TransferJob>,
+    transfer_limiter: TransferLimiter,
     timer: crate::RustDeskInterval,
     file_timer: crate::RustDeskInterval,
     file_transfer: Option<(String, bool)>,
     view_camera: bool,
     terminal: bool,
-    port_forward_socket: Option<Framed<TcpStream, BytesCodec>>,
+    port_forward_socket: Option<PortForwardTarget>,
     port_forward_address: String,
     tx_to_cm: mpsc::UnboundedSender<ipc::Data>,
     authorized: bool,
@@ -216,6 +240,10 @@ pub struct Connection {
     block_input: bool,
     last_test_delay: Option<Instant>,
     network_delay: u32,
+    // The video delay relayed to the file transfer peer, for its uploads to back off.
+    relayed_network_delay: Option<u32>,
+    // The peer sent `EXT_HELLO_ID`, the extension messages can be sent to it.
+    ext_messages: bool,
     lock_after_session_end: bool,
     show_remote_cursor: bool,
     // by peer
@@ -237,6 +265,7 @@ pub struct Connection {
     video_ack_required: bool,
     server_audit_conn: String,
     server_audit_file: String,
+    access_policy: AccessPolicy,
     lr: LoginRequest,
     session_last_recv_time: Option<Arc<Mutex<Instant>>>,
     chat_unanswered: bool,
@@ -278,6 +307,12 @@ pub struct Connection {
     #[cfg(not(any(target_os = "android", target_os = "ios")))]
     terminal_user_token: Option<TerminalUserToken>,
     terminal_generic_service: Option<Box<GenericService>>,
+    // How the connection was authenticated, one of `terminal_policy::AUTH_*`
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    auth_method: &'static str,
+    // The flags to kill the running exec commands
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    exec_cancels: Vec<Arc<std::sync::atomic::AtomicBool>>,
 }
 
 impl ConnInner {
@@ -371,6 +406,7 @@ impl Connection {
             server,
             hash,
             read_jobs: Vec::new(),
+            transfer_limiter: TransferLimiter::new(Default::default()),
             timer: crate::rustdesk_interval(time::interval(SEC30)),
             file_timer: crate::rustdesk_interval(time::interval(SEC30)),
             file_transfer: None,
@@ -390,6 +426,8 @@ impl Connection {
             block_input: Connection::permission("enable-block-input"),
             last_test_delay: None,
             network_delay: 0,
+            relayed_network_delay: None,
+            ext_messages: false,
             lock_after_session_end: false,
             show_remote_cursor: false,
             follow_remote_cursor: false,
@@ -405,6 +443,7 @@ impl Connection {
             video_ack_required: false,
             server_audit_conn: "".to_owned(),
             server_audit_file: "".to_owned(),
+            access_policy: Default::default(),
             lr: Default::default(),
             session_last_recv_time: None,
             chat_unanswered: false,
@@ -444,6 +483,10 @@ impl Connection {
             #[cfg(not(any(target_os = "android", target_os = "ios")))]
             terminal_user_token: None,
             terminal_generic_service: None,
+            #[cfg(not(any(target_os = "android", target_os = "ios")))]
+            auth_method: "",
+            #[cfg(not(any(target_os = "android", target_os = "ios")))]
+            exec_cancels: Vec::new(),
         };
         let addr = hbb_common::try_into_v4(addr);
         if !conn.on_open(addr).await {
@@ -525,6 +568,10 @@ impl Connection {
                     match data {
                         ipc::Data::Authorize => {
                             conn.require_2fa.take();
+                            #[cfg(not(any(target_os = "android", target_os = "ios")))]
+                            {
+                                conn.auth_method = terminal_policy::AUTH_ACCEPTED;
+                            }
                             conn.send_logon_response().await;
                             if conn.port_forward_socket.is_some() {
                                 break;
@@ -545,6 +592,8 @@ impl Connection {
                             }
                         }
                         ipc::Data::ChatMessage{text} => {
+                            // Sent by this side, no peer.
+                            video_service::write_record_event(RecordEvent::new(RecordEventKind::Chat, "", text.clone()));
                             let mut misc = Misc::new();
                             misc.set_chat_message(ChatMessage {
                                 text,
@@ -557,6 +606,8 @@ impl Connection {
                         }
                         ipc::Data::SwitchPermission{name, enabled} => {
                             log::info!("Change permission {} -> {}", name, enabled);
+                            conn.record_audit_log("permission", json!({ "name": &name, "enabled": enabled }));
+                            conn.record_event(RecordEventKind::Permission, format!("{} {}", name, if enabled { "on" } else { "off" }));
                             if &name == "keyboard" {
                                 conn.keyboard = enabled;
                                 conn.send_permission(Permission::Keyboard, enabled).await;
@@ -626,6 +677,11 @@ impl Connection {
                                 conn.send_permission(Permission::BlockInput, enabled).await;
                             }
                         }
+                        ipc::Data::FileTransferLimit{kbps, priority} => {
+                            log::info!("Change file transfer limit -> {}kbps, {:?}", kbps, priority);
+                            conn.transfer_limiter.set_session_kbps(Some(kbps));
+                            conn.transfer_limiter.set_priority(priority);
+                        }
                         ipc::Data::RawMessage(bytes) => {
                             allow_err!(conn.stream.send_raw(bytes).await);
                         }
@@ -714,7 +770,7 @@ impl Connection {
                 _ = conn.file_timer.tick() => {
                     if !conn.read_jobs.is_empty() {
                         conn.send_to_cm(ipc::Data::FileTransferLog(("transfer".to_string(), fs::serialize_transfer_jobs(&conn.read_jobs))));
-                        match fs::handle_read_jobs(&mut conn.read_jobs, &mut conn.stream).await {
+                        match transfer_limit::handle_read_jobs(&mut conn.read_jobs, &mut conn.stream, &mut conn.transfer_limiter).await {
                             Ok(log) => {
                                 if !log.is_empty() {
                                     conn.send_to_cm(ipc::Data::FileTransferLog(("transfer".to_string(), log)));
@@ -767,6 +823,13 @@ impl Connection {
                                     conn.on_close("stop service", false).await;
                                     break;
                                 }
+                                // The peer does not handle the extension messages.
+                                Some(misc::Union::PluginRequest(p))
+                                    if !conn.ext_messages
+                                        && crate::common::is_ext_message(&p.id) =>
+                                {
+                                    continue;
+                                }
                                 _ => {},
                             }
                         }
@@ -786,6 +849,9 @@ impl Connection {
                             if let Some(new_msg) = conn.retina.on_cursor_pos(&pos, conn.display_idx) {
                                 msg = Arc::new(new_msg);
                             }
+                            if let Some(new_msg) = video_service::on_capture_cursor_pos(msg.cursor_position(), conn.display_idx) {
+                                msg = Arc::new(new_msg);
+                            }
                         }
                         Some(message::Union::MultiClipboards(_multi_clipboards)) => {
                             #[cfg(not(target_os = "ios"))]
@@ -830,6 +896,7 @@ impl Connection {
                         }
                     }
                     conn.file_remove_log_control.on_timer().drain(..).map(|x| conn.send_to_cm(x)).count();
+                    conn.relay_network_delay().await;
                     #[cfg(feature = "hwcodec")]
                     conn.update_supported_encoding();
                 }
@@ -844,7 +911,7 @@ impl Connection {
                         let mut msg_out = Message::new();
                         msg_out.set_test_delay(TestDelay{
                             last_delay: conn.network_delay,
-                            target_bitrate: video_service::VIDEO_QOS.lock().unwrap().bitrate(),
+                            target_bitrate: video_service::VIDEO_QOS.lock().unwrap().user_bitrate(id),
                             ..Default::default()
                         });
                         conn.send(msg_out.into()).await;
@@ -1018,7 +1085,12 @@ impl Connection {
         rx_from_cm: &mut mpsc::UnboundedReceiver<Data>,
     ) -> ResultType<()> {
         let mut last_recv_time = Instant::now();
-        if let Some(mut forward) = self.port_forward_socket.take() {
+        #[cfg(not(any(target_os = "android", target_os = "ios")))]
+        if let Some(PortForwardTarget::Mux) = self.port_forward_socket {
+            self.port_forward_socket.take();
+            return self.port_forward_mux_loop(rx_from_cm).await;
+        }
+        if let Some(PortForwardTarget::Socket(mut forward)) = self.port_forward_socket.take() {
             log::info!("Running port forwarding loop");
             self.stream.set_raw();
             let mut hbbs_rx = crate::hbbs_http::sync::signal_receiver();
@@ -1069,6 +1141,175 @@ impl Connection {
         Ok(())
     }
 
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    async fn port_forward_mux_loop(
+        &mut self,
+        rx_from_cm: &mut mpsc::UnboundedReceiver<Data>,
+    ) -> ResultType<()> {
+        use crate::port_forward::mux::{Frame, Mux};
+        log::info!("Running multiplexed port forwarding loop");
+        let mut last_recv_time = Instant::now();
+        let mut mux = Mux::new(false);
+        let (tx_connected, mut rx_connected) =
+            mpsc::unbounded_channel::<(u32, String, ResultType<TcpStream>)>();
+        let mut hbbs_rx = crate::hbbs_http::sync::signal_receiver();
+        loop {
+            tokio::select! {
+                Some(data) = rx_from_cm.recv() => {
+                    match data {
+                        ipc::Data::Close => {
+                            bail!("Close requested from connection manager");
+                        }
+                        ipc::Data::CmErr(e) => {
+                            log::error!("Connection manager error: {e}");
+                            bail!("{e}");
+                        }
+                        _ => {}
+                    }
+                }
+                res = self.stream.next() => {
+                    let Some(res) = res else {
+                        bail!("Stream reset by the peer");
+                    };
+                    last_recv_time = Instant::now();
+                    match Frame::decode(&res?) {
+                        Some(Frame::Open(id, addr)) => {
+                            if mux.contains(id) {
+                                continue;
+                            }
+                            mux.add_pending(id);
+                            let tx = tx_connected.clone();
+                            tokio::spawn(async move {
+                                let res = match timeout(3000, TcpStream::connect(&addr)).await {
+                                    Ok(Ok(sock)) => Ok(sock),
+                                    Ok(Err(e)) => Err(e.into()),
+                                    Err(e) => Err(e.into()),
+                                };
+                                tx.send((id, addr, res)).ok();
+                            });
+                        }
+                        Some(Frame::Data(id, data)) => {
+                            if !mux.write(id, data) {
+                                self.stream.send_bytes(Frame::Close(id, "".to_owned()).encode()).await?;
+                            }
+                        }
+                        Some(Frame::Close(id, _)) => {
+                            if !mux.close(id) {
+                                mux.close_listener(id);
+                            }
+                        }
+                        Some(Frame::Ack(id, n)) => mux.ack(id, n),
+                        Some(Frame::Listen(id, addr)) => {
+                            let reply = self.port_forward_listen(&mut mux, id, &addr).await;
+                            self.stream.send_bytes(reply.encode()).await?;
+                        }
+                        None => {}
+                    }
+                }
+                Some((listener, sock)) = mux.rx_accepted.recv() => {
+                    let id = mux.next_id();
+                    mux.add(id, sock);
+                    self.stream.send_bytes(Frame::Open(id, listener.to_string()).encode()).await?;
+                }
+                Some((id, addr, res)) = rx_connected.recv() => {
+                    if !mux.contains(id) {
+                        continue;
+                    }
+                    match res {
+                        Ok(sock) => {
+                            log::debug!("mux channel {} connected to {}", id, addr);
+                            mux.add(id, sock);
+                        }
+                        Err(e) => {
+                            mux.close(id);
+                            let err = format!("Failed to access remote {}: {}", addr, e);
+                            self.stream.send_bytes(Frame::Close(id, err).encode()).await?;
+                        }
+                    }
+                }
+                Some(frame) = mux.rx_frame.recv() => {
+                    last_recv_time = Instant::now();
+                    let exists = match &frame {
+                        Frame::Close(id, _) => mux.close(*id),
+                        _ => mux.contains(frame.channel()),
+                    };
+                    if !exists {
+                        continue;
+                    }
+                    self.stream.send_bytes(frame.encode()).await?;
+                }
+                _ = self.timer.tick() => {
+                    if last_recv_time.elapsed() >= H1 {
+                        bail!("Timeout");
+                    }
+                }
+                Ok(conns) = hbbs_rx.recv() => {
+                    if conns.contains(&self.inner.id) {
+                        bail!("Closed manually by the web console");
+                    }
+                }
+            }
+        }
+    }
+
+    // Reverse forwarding opens a listener on this side, so it is only allowed explicitly.
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    fn allow_reverse_tunnel() -> bool {
+        Connection::permission("enable-tunnel") && Config::get_option("allow-reverse-tunnel") == "Y"
+    }
+
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    async fn port_forward_listen(
+        &mut self,
+        mux: &mut crate::port_forward::mux::Mux,
+        id: u32,
+        addr: &str,
+    ) -> crate::port_forward::mux::Frame {
+        use crate::port_forward::mux::Frame;
+        if !Self::allow_reverse_tunnel() {
+            return Frame::Close(id, "No permission of reverse tunneling".to_owned());
+        }
+        if mux.has_listener(id) {
+            return Frame::Close(id, "Duplicated listener".to_owned());
+        }
+        let Some((host, port)) = addr.rsplit_once(':') else {
+            return Frame::Close(id, format!("Invalid address {}", addr));
+        };
+        if !port.parse::<i32>().map_or(false, |p| {
+            (crate::port_forward::MIN_REVERSE_PORT..=65535).contains(&p)
+        }) {
+            return Frame::Close(id, format!("Invalid port of {}", addr));
+        }
+        // Other hosts of the network can connect to a listener on a non loopback address.
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let loopback = host == "localhost"
+            || host.parse::<IpAddr>().map_or(false, |ip| ip.is_loopback());
+        if !loopback && Config::get_option("allow-reverse-tunnel-lan") != "Y" {
+            return Frame::Close(
+                id,
+                format!("No permission of listening on a non loopback address {}", addr),
+            );
+        }
+        let res = hbb_common::tcp::new_listener(addr, true)
+            .await
+            .and_then(|l| Ok((l.local_addr()?, l)));
+        self.record_audit_log(
+            "reverse_tunnel",
+            json!({
+                "addr": addr,
+                "result": if res.is_ok() { "success" } else { "failure" },
+            }),
+        );
+        match res {
+            Ok((local_addr, listener)) => {
+                log::info!("Reverse port forwarding listening on {}", local_addr);
+                mux.add_listener(id, listener);
+                Frame::Listen(id, local_addr.to_string())
+            }
+            Err(e) => Frame::Close(id, format!("Failed to listen on {}: {}", addr, e)),
+        }
+    }
+
     async fn send_permission(&mut self, permission: Permission, enabled: bool) {
         let mut misc = Misc::new();
         misc.set_permission_info(PermissionInfo {
@@ -1120,10 +1361,39 @@ impl Connection {
         true
     }
 
+    /// Check the access policy rules, `peer_id` is `None` before the login request is received.
+    async fn check_access_policy(&mut self, ip: &IpAddr, peer_id: Option<&str>) -> Option<bool> {
+        let (rule, error) = match self.access_policy.evaluate(ip, peer_id) {
+            Decision::NoRules => return None,
+            // Evaluated again with the peer id of the login request.
+            Decision::Allow(_) | Decision::Undecided => return Some(true),
+            Decision::Deny(rule) => (rule, "Your ip is blocked by the peer"),
+            Decision::DenyPeer(rule) => (rule, "Your id is blocked by the peer"),
+        };
+        self.send_login_error(error).await;
+        Self::post_alarm_audit(
+            AlarmAuditType::AccessPolicy,
+            json!({
+                "ip": ip,
+                "id": peer_id.unwrap_or_default(),
+                "name": self.lr.my_name.clone(),
+                "rule": rule,
+            }),
+        );
+        Some(false)
+    }
+
     async fn on_open(&mut self, addr: SocketAddr) -> bool {
         log::debug!("#{} Connection opened from {}.", self.inner.id, addr);
-        if !self.check_whitelist(&addr).await {
-            return false;
+        self.access_policy = AccessPolicy::load();
+        match self.check_access_policy(&addr.ip(), None).await {
+            Some(false) => return false,
+            Some(true) => {}
+            None => {
+                if !self.check_whitelist(&addr).await {
+                    return false;
+                }
+            }
         }
         #[cfg(not(any(target_os = "android", target_os = "ios")))]
         if crate::is_server() && Config::get_option("allow-only-conn-window-open") == "Y" {
@@ -1158,6 +1428,7 @@ impl Connection {
     }
 
     fn post_conn_audit(&self, v: Value) {
+        self.record_audit_log("conn", v.clone());
         if self.server_audit_conn.is_empty() {
             return;
         }
@@ -1170,6 +1441,117 @@ impl Connection {
         allow_err!(self.tx_post_seq.send((url, v)));
     }
 
+    fn record_audit_log(&self, event: &str, v: Value) {
+        let mut v = v;
+        v["conn_id"] = json!(self.inner.id);
+        v["session_id"] = json!(self.lr.session_id);
+        v["ip"] = json!(self.ip);
+        v["peer_id"] = json!(self.lr.my_id);
+        v["peer_name"] = json!(self.lr.my_name);
+        audit_log::record(event, v);
+    }
+
+    /// Add an event to the index of the recordings of this side.
+    fn record_event(&self, kind: RecordEventKind, detail: String) {
+        video_service::write_record_event(RecordEvent::new(kind, &self.lr.my_id, detail));
+    }
+
+    // Printable keys typed without a shortcut modifier are redacted, they may be passwords,
+    // unless `record-index-plain-keys` is `Y`.
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    fn record_key_event(&self, me: &KeyEvent) {
+        let mut keys: Vec<String> = me
+            .modifiers
+            .iter()
+            .filter_map(|m| m.enum_value().ok())
+            .map(|m| format!("{:?}", m))
+            .collect();
+        let shortcut = me.modifiers.iter().any(|m| {
+            matches!(
+                m.enum_value(),
+                Ok(ControlKey::Control
+                    | ControlKey::RControl
+                    | ControlKey::Alt
+                    | ControlKey::RAlt
+                    | ControlKey::Meta
+                    | ControlKey::RWin)
+            )
+        });
+        let plain = shortcut || Config::get_option("record-index-plain-keys") == "Y";
+        let key = match &me.union {
+            Some(key_event::Union::ControlKey(ck)) => match ck.enum_value() {
+                Ok(ck) => format!("{:?}", ck),
+                Err(_) => return,
+            },
+            _ if !plain => "*".to_owned(),
+            Some(key_event::Union::Chr(c))
+                if matches!(
+                    me.mode.enum_value(),
+                    Ok(KeyboardMode::Map | KeyboardMode::Translate)
+                ) =>
+            {
+                format!("keycode {}", c & 0x0000FFFF)
+            }
+            Some(key_event::Union::Chr(c)) | Some(key_event::Union::Unicode(c)) => {
+                match std::char::from_u32(*c & 0x0000FFFF) {
+                    Some(c) if !c.is_control() => c.to_string(),
+                    _ => format!("code {}", c),
+                }
+            }
+            Some(key_event::Union::Seq(seq)) => seq.clone(),
+            _ => return,
+        };
+        keys.push(key);
+        self.record_event(RecordEventKind::Key, keys.join("+"));
+    }
+
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    fn record_mouse_event(&self, me: &MouseEvent) {
+        if me.mask & 0x7 != crate::input::MOUSE_TYPE_DOWN {
+            return;
+        }
+        let button = match me.mask >> 3 {
+            crate::input::MOUSE_BUTTON_LEFT => "left",
+            crate::input::MOUSE_BUTTON_RIGHT => "right",
+            crate::input::MOUSE_BUTTON_WHEEL => "middle",
+            crate::input::MOUSE_BUTTON_BACK => "back",
+            crate::input::MOUSE_BUTTON_FORWARD => "forward",
+            _ => return,
+        };
+        self.record_event(
+            RecordEventKind::Mouse,
+            format!("{} click at ({}, {})", button, me.x, me.y),
+        );
+    }
+
+    // The content is not recorded.
+    fn record_clipboard_event(&self, cb: &Clipboard) {
+        self.record_event(
+            RecordEventKind::Clipboard,
+            format!(
+                "{:?}, {} bytes",
+                cb.format.enum_value_or_default(),
+                cb.content.len()
+            ),
+        );
+    }
+
+    fn record_file_action_log(&self, action: &str, log: &str) {
+        let v = serde_json::from_str::<Value>(log).unwrap_or_default();
+        let mut detail = format!("{} {}", action, v["path"].as_str().unwrap_or_default());
+        if let Some(new_name) = v["new_name"].as_str() {
+            detail = format!("{} -> {}", detail, new_name);
+        }
+        self.record_event(RecordEventKind::File, detail);
+        self.record_audit_log(
+            "file",
+            json!({
+                "action": action,
+                "detail": serde_json::from_str::<Value>(log).unwrap_or_default(),
+            }),
+        );
+    }
+
     fn get_files_for_audit(job_type: fs::JobType, mut files: Vec<FileEntry>) -> Vec<(String, i64)> {
         files
             .drain(..)
@@ -1193,6 +1575,30 @@ impl Connection {
         files: Vec<(String, i64)>,
         info: Value,
     ) {
+        self.record_event(
+            RecordEventKind::File,
+            format!(
+                "{} {}, {} files",
+                match r#type {
+                    FileAuditType::RemoteSend => "download",
+                    FileAuditType::RemoteReceive => "upload",
+                },
+                path,
+                files.len()
+            ),
+        );
+        self.record_audit_log(
+            "file",
+            json!({
+                "action": match r#type {
+                    FileAuditType::RemoteSend => "remote_send",
+                    FileAuditType::RemoteReceive => "remote_receive",
+                },
+                "path": path,
+                "num": files.len(),
+                "files": files.iter().take(100).collect::<Vec<_>>(),
+            }),
+        );
         if self.server_audit_file.is_empty() {
             return;
         }
@@ -1222,6 +1628,13 @@ impl Connection {
     }
 
     pub fn post_alarm_audit(typ: AlarmAuditType, info: Value) {
+        audit_log::record(
+            "alarm",
+            json!({
+                "typ": typ.name(),
+                "info": info.clone(),
+            }),
+        );
         let url = crate::get_audit_server(
             Config::get_option("api-server"),
             Config::get_option("custom-rendezvous-server"),
@@ -1304,6 +1717,7 @@ impl Connection {
             .unwrap()
             .get(&self.session_key())
             .map(|s| s.last_recv_time.clone());
+        self.record_audit_log("auth", json!({ "result": "success", "type": conn_type }));
         self.post_conn_audit(
             json!({"peer": ((&self.lr.my_id, &self.lr.my_name)), "type": conn_type}),
         );
@@ -1326,13 +1740,7 @@ impl Connection {
             pi.hostname = DEVICE_NAME.lock().unwrap().clone();
             pi.platform = "Android".into();
         }
-        #[cfg(all(target_os = "macos", not(feature = "unix-file-copy-paste")))]
-        let platform_additions = serde_json::Map::new();
-        #[cfg(any(
-            target_os = "windows",
-            target_os = "linux",
-            all(target_os = "macos", feature = "unix-file-copy-paste")
-        ))]
+        #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
         let mut platform_additions = serde_json::Map::new();
         #[cfg(target_os = "linux")]
         {
@@ -1384,6 +1792,12 @@ impl Connection {
             platform_additions.insert("support_view_camera".into(), json!(true));
         }
 
+        #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+        platform_additions.insert(
+            crate::common::PLATFORM_ADDITION_EXT_MESSAGES.into(),
+            json!(true),
+        );
+
         #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
         if !platform_additions.is_empty() {
             pi.platform_additions = serde_json::to_string(&platform_additions).unwrap_or("".into());
@@ -1737,6 +2151,10 @@ impl Connection {
     }
 
     async fn send_login_error<T: std::string::ToString>(&mut self, err: T) {
+        self.record_audit_log(
+            "auth",
+            json!({ "result": "failure", "error": err.to_string() }),
+        );
         let mut msg_out = Message::new();
         let mut res = LoginResponse::new();
         res.set_error(err.to_string());
@@ -1809,11 +2227,19 @@ impl Connection {
                     Some(password),
                     Some(false),
                 );
+                #[cfg(not(any(target_os = "android", target_os = "ios")))]
+                {
+                    self.auth_method = terminal_policy::AUTH_TEMPORARY_PASSWORD;
+                }
                 return true;
             }
         }
         if password::permanent_enabled() {
             if self.validate_one_password(Config::get_permanent_password()) {
+                #[cfg(not(any(target_os = "android", target_os = "ios")))]
+                {
+                    self.auth_method = terminal_policy::AUTH_PERMANENT_PASSWORD;
+                }
                 return true;
             }
         }
@@ -1952,6 +2378,12 @@ impl Connection {
             if self.authorized {
                 return true;
             }
+            if let Ok(ip) = self.ip.parse::<IpAddr>() {
+                if self.check_access_policy(&ip, Some(&lr.my_id)).await == Some(false) {
+                    sleep(1.).await;
+                    return false;
+                }
+            }
             match lr.union {
                 Some(login_request::Union::FileTransfer(ft)) => {
                     if !Connection::permission(keys::OPTION_ENABLE_FILE_TRANSFER) {
@@ -2001,21 +2433,21 @@ impl Connection {
                     }
 
                     #[cfg(not(any(target_os = "android", target_os = "ios")))]
-                    if let Some(is_user) =
-                        terminal_service::is_service_specified_user(&self.terminal_service_id)
-                    {
-                        if let Some(user_token) = &self.terminal_user_token {
-                            let has_service_token =
-                                user_token.to_terminal_service_token().is_some();
-                            if is_user != has_service_token {
-                                // This occurs when the service id (in the configuration) is manually changed by the user, causing a mismatch in validation.
-                                log::error!("Terminal service user mismatch detected. The service ID may have been manually changed in the configuration, causing validation to fail.");
-                                // No need to translate the following message, because it is in an abnormal case.
-                                self.send_login_error("Terminal service user mismatch detected.")
-                                    .await;
-                                sleep(1.).await;
-                                return false;
-                            }
+                    if let Some(user_token) = &self.terminal_user_token {
+                        // The terminals of the service must run as the user of this connection.
+                        if terminal_service::is_service_user(
+                            &self.terminal_service_id,
+                            user_token.to_terminal_service_token(),
+                        ) == Some(false)
+                        {
+                            // This occurs when the service id (in the configuration) is manually changed by the user,
+                            // or the service is of another user.
+                            log::error!("Terminal service user mismatch detected, the service {} is of another user.", self.terminal_service_id);
+                            // No need to translate the following message, because it is in an abnormal case.
+                            self.send_login_error("Terminal service user mismatch detected.")
+                                .await;
+                            sleep(1.).await;
+                            return false;
                         }
                     }
                 }
@@ -2025,31 +2457,46 @@ impl Connection {
                         sleep(1.).await;
                         return false;
                     }
-                    let mut is_rdp = false;
-                    if pf.host == "RDP" && pf.port == 0 {
-                        pf.host = "localhost".to_owned();
-                        pf.port = 3389;
-                        is_rdp = true;
-                    }
-                    if pf.host.is_empty() {
-                        pf.host = "localhost".to_owned();
-                    }
-                    let mut addr = format!("{}:{}", pf.host, pf.port);
-                    self.port_forward_address = addr.clone();
-                    match timeout(3000, TcpStream::connect(&addr)).await {
-                        Ok(Ok(sock)) => {
-                            self.port_forward_socket = Some(Framed::new(sock, BytesCodec::new()));
+                    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+                    let is_mux = pf.host == crate::port_forward::mux::MUX_HOST;
+                    #[cfg(any(target_os = "android", target_os = "ios"))]
+                    let is_mux = false;
+                    if is_mux {
+                        // The targets are opened on demand by the client.
+                        #[cfg(not(any(target_os = "android", target_os = "ios")))]
+                        {
+                            self.port_forward_address = "*".to_owned();
+                            self.port_forward_socket = Some(PortForwardTarget::Mux);
+                        }
+                    } else {
+                        let mut is_rdp = false;
+                        if pf.host == "RDP" && pf.port == 0 {
+                            pf.host = "localhost".to_owned();
+                            pf.port = 3389;
+                            is_rdp = true;
+                        }
+                        if pf.host.is_empty() {
+                            pf.host = "localhost".to_owned();
                         }
-                        _ => {
-                            if is_rdp {
-                                addr = "RDP".to_owned();
+                        let mut addr = format!("{}:{}", pf.host, pf.port);
+                        self.port_forward_address = addr.clone();
+                        match timeout(3000, TcpStream::connect(&addr)).await {
+                            Ok(Ok(sock)) => {
+                                self.port_forward_socket = Some(PortForwardTarget::Socket(
+                                    Framed::new(sock, BytesCodec::new()),
+                                ));
+                            }
+                            _ => {
+                                if is_rdp {
+                                    addr = "RDP".to_owned();
+                                }
+                                self.send_login_error(format!(
+                                    "Failed to access remote {}, please make sure if it is open",
+                                    addr
+                                ))
+                                .await;
+                                return false;
                             }
-                            self.send_login_error(format!(
-                                "Failed to access remote {}, please make sure if it is open",
-                                addr
-                            ))
-                            .await;
-                            return false;
                         }
                     }
                 }
@@ -2119,6 +2566,11 @@ impl Connection {
                 }
                 return true;
             } else if self.is_recent_session(false) {
+                // The session was authenticated by the temporary password.
+                #[cfg(not(any(target_os = "android", target_os = "ios")))]
+                {
+                    self.auth_method = terminal_policy::AUTH_TEMPORARY_PASSWORD;
+                }
                 if err_msg.is_empty() {
                     #[cfg(target_os = "linux")]
                     self.linux_headless_handle.wait_desktop_cm_ready().await;
@@ -2137,12 +2589,11 @@ impl Connection {
                     .await;
                 }
             } else {
-                let (failure, res) = self.check_failure(0).await;
-                if !res {
+                if !self.check_failure(0).await {
                     return true;
                 }
                 if !self.validate_password() {
-                    self.update_failure(failure, false, 0);
+                    self.update_failure(false, 0);
                     if err_msg.is_empty() {
                         self.send_login_error(crate::client::LOGIN_MSG_PASSWORD_WRONG)
                             .await;
@@ -2154,7 +2605,7 @@ impl Connection {
                         .await;
                     }
                 } else {
-                    self.update_failure(failure, true, 0);
+                    self.update_failure(true, 0);
                     if err_msg.is_empty() {
                         #[cfg(target_os = "linux")]
                         self.linux_headless_handle.wait_desktop_cm_ready().await;
@@ -2166,14 +2617,13 @@ impl Connection {
                 }
             }
         } else if let Some(message::Union::Auth2fa(tfa)) = msg.union {
-            let (failure, res) = self.check_failure(1).await;
-            if !res {
+            if !self.check_failure(1).await {
                 return true;
             }
             if let Some(totp) = self.require_2fa.as_ref() {
                 if let Ok(res) = totp.check_current(&tfa.code) {
                     if res {
-                        self.update_failure(failure, true, 1);
+                        self.update_failure(true, 1);
                         self.require_2fa.take();
                         raii::AuthedConnID::set_session_2fa(self.session_key());
                         self.send_logon_response().await;
@@ -2192,7 +2642,7 @@ impl Connection {
                             });
                         }
                     } else {
-                        self.update_failure(failure, false, 1);
+                        self.update_failure(false, 1);
                         self.send_login_error(crate::client::LOGIN_MSG_2FA_WRONG)
                             .await;
                     }
@@ -2260,6 +2710,8 @@ impl Connection {
                         } else {
                             MOUSE_MOVE_TIME.store(get_time(), Ordering::SeqCst);
                         }
+                        self.record_mouse_event(&me);
+                        video_service::on_capture_mouse_event(&mut me, self.display_idx);
                         #[cfg(target_os = "macos")]
                         self.retina.on_mouse_event(&mut me, self.display_idx);
                         self.input_mouse(me, self.inner.id());
@@ -2408,6 +2860,7 @@ impl Connection {
                         }
 
                         if is_press {
+                            self.record_key_event(&me);
                             match me.union {
                                 Some(key_event::Union::Unicode(_))
                                 | Some(key_event::Union::Seq(_)) => {
@@ -2425,6 +2878,7 @@ impl Connection {
                 }
                 Some(message::Union::Clipboard(cb)) => {
                     if self.clipboard {
+                        self.record_clipboard_event(&cb);
                         #[cfg(not(any(target_os = "android", target_os = "ios")))]
                         update_clipboard(vec![cb], ClipboardSide::Host);
                         // ios as the controlled side is actually not supported for now.
@@ -2452,6 +2906,11 @@ impl Connection {
                     }
                 }
                 Some(message::Union::MultiClipboards(_mcb)) => {
+                    if self.clipboard {
+                        for cb in _mcb.clipboards.iter() {
+                            self.record_clipboard_event(cb);
+                        }
+                    }
                     #[cfg(not(any(target_os = "android", target_os = "ios")))]
                     if self.clipboard {
                         update_clipboard(_mcb.clipboards, ClipboardSide::Host);
@@ -2666,7 +3125,11 @@ impl Connection {
                                     id: d.id,
                                     recursive: d.recursive,
                                 });
-                                self.file_remove_log_control.on_remove_dir(d);
+                                if let Some(ipc::Data::FileTransferLog((action, log))) =
+                                    self.file_remove_log_control.on_remove_dir(d)
+                                {
+                                    self.record_file_action_log(&action, &log);
+                                }
                             }
                             Some(file_action::Union::RemoveFile(f)) => {
                                 self.send_fs(ipc::FS::RemoveFile {
@@ -2674,22 +3137,28 @@ impl Connection {
                                     id: f.id,
                                     file_num: f.file_num,
                                 });
-                                self.file_remove_log_control.on_remove_file(f);
+                                if let Some(ipc::Data::FileTransferLog((action, log))) =
+                                    self.file_remove_log_control.on_remove_file(f)
+                                {
+                                    self.record_file_action_log(&action, &log);
+                                }
                             }
                             Some(file_action::Union::Create(c)) => {
                                 self.send_fs(ipc::FS::CreateDir {
                                     path: c.path.clone(),
                                     id: c.id,
                                 });
+                                let log = serde_json::to_string(&FileActionLog {
+                                    id: c.id,
+                                    conn_id: self.inner.id(),
+                                    path: c.path,
+                                    dir: true,
+                                })
+                                .unwrap_or_default();
+                                self.record_file_action_log("create_dir", &log);
                                 self.send_to_cm(ipc::Data::FileTransferLog((
                                     "create_dir".to_string(),
-                                    serde_json::to_string(&FileActionLog {
-                                        id: c.id,
-                                        conn_id: self.inner.id(),
-                                        path: c.path,
-                                        dir: true,
-                                    })
-                                    .unwrap_or_default(),
+                                    log,
                                 )));
                             }
                             Some(file_action::Union::Cancel(c)) => {
@@ -2712,14 +3181,16 @@ impl Connection {
                                     path: r.path.clone(),
                                     new_name: r.new_name.clone(),
                                 });
+                                let log = serde_json::to_string(&FileRenameLog {
+                                    conn_id: self.inner.id(),
+                                    path: r.path,
+                                    new_name: r.new_name,
+                                })
+                                .unwrap_or_default();
+                                self.record_file_action_log("rename", &log);
                                 self.send_to_cm(ipc::Data::FileTransferLog((
                                     "rename".to_string(),
-                                    serde_json::to_string(&FileRenameLog {
-                                        conn_id: self.inner.id(),
-                                        path: r.path,
-                                        new_name: r.new_name,
-                                    })
-                                    .unwrap_or_default(),
+                                    log,
                                 )));
                             }
                             _ => {}
@@ -2775,6 +3246,7 @@ impl Connection {
                         self.toggle_privacy_mode(t).await;
                     }
                     Some(misc::Union::ChatMessage(c)) => {
+                        self.record_event(RecordEventKind::Chat, c.text.clone());
                         self.send_to_cm(ipc::Data::ChatMessage { text: c.text });
                         self.chat_unanswered = true;
                         self.update_auto_disconnect_timer();
@@ -2860,6 +3332,9 @@ impl Connection {
                     Some(misc::Union::ChangeDisplayResolution(dr)) => {
                         self.change_resolution(Some(dr.display as _), &dr.resolution)
                     }
+                    Some(misc::Union::PluginRequest(p)) if crate::common::is_ext_message(&p.id) => {
+                        self.handle_ext_message(p).await;
+                    }
                     #[cfg(all(feature = "flutter", feature = "plugin_framework"))]
                     #[cfg(not(any(target_os = "android", target_os = "ios")))]
                     Some(misc::Union::PluginRequest(p)) => {
@@ -3061,61 +3536,38 @@ impl Connection {
         }
     }
 
-    fn update_failure(&self, (mut failure, time): ((i32, i32, i32), i32), remove: bool, i: usize) {
-        if remove {
-            if failure.0 != 0 {
-                LOGIN_FAILURES[i].lock().unwrap().remove(&self.ip);
-            }
-            return;
-        }
-        if failure.0 == time {
-            failure.1 += 1;
-            failure.2 += 1;
-        } else {
-            failure.0 = time;
-            failure.1 = 1;
-            failure.2 += 1;
-        }
-        LOGIN_FAILURES[i]
-            .lock()
-            .unwrap()
-            .insert(self.ip.clone(), failure);
+    fn update_failure(&self, remove: bool, i: usize) {
+        access_policy::update_failure(&self.access_policy.lockout, &self.ip, i, !remove);
     }
 
-    async fn check_failure(&mut self, i: usize) -> (((i32, i32, i32), i32), bool) {
-        let failure = LOGIN_FAILURES[i]
-            .lock()
-            .unwrap()
-            .get(&self.ip)
-            .map(|x| x.clone())
-            .unwrap_or((0, 0, 0));
-        let time = (get_time() / 60_000) as i32;
-        let res = if failure.2 > 30 {
-            self.send_login_error("Too many wrong attempts").await;
-            Self::post_alarm_audit(
-                AlarmAuditType::ExceedThirtyAttempts,
-                json!({
-                            "ip": self.ip,
-                            "id": self.lr.my_id.clone(),
-                            "name": self.lr.my_name.clone(),
-                }),
-            );
-            false
-        } else if time == failure.0 && failure.1 > 6 {
-            self.send_login_error("Please try 1 minute later").await;
-            Self::post_alarm_audit(
-                AlarmAuditType::SixAttemptsWithinOneMinute,
-                json!({
-                            "ip": self.ip,
-                            "id": self.lr.my_id.clone(),
-                            "name": self.lr.my_name.clone(),
-                }),
-            );
-            false
-        } else {
-            true
-        };
-        ((failure, time), res)
+    async fn check_failure(&mut self, i: usize) -> bool {
+        match access_policy::check_failure(&self.access_policy.lockout, &self.ip, i) {
+            Ok(()) => true,
+            Err(Rejection::TooManyAttempts) => {
+                self.send_login_error("Too many wrong attempts").await;
+                Self::post_alarm_audit(
+                    AlarmAuditType::ExceedThirtyAttempts,
+                    json!({
+                                "ip": self.ip,
+                                "id": self.lr.my_id.clone(),
+                                "name": self.lr.my_name.clone(),
+                    }),
+                );
+                false
+            }
+            Err(Rejection::TooFrequent) => {
+                self.send_login_error("Please try 1 minute later").await;
+                Self::post_alarm_audit(
+                    AlarmAuditType::SixAttemptsWithinOneMinute,
+                    json!({
+                                "ip": self.ip,
+                                "id": self.lr.my_id.clone(),
+                                "name": self.lr.my_name.clone(),
+                    }),
+                );
+                false
+            }
+        }
     }
 
     fn refresh_video_display(&self, display: Option<usize>) {
@@ -3132,6 +3584,10 @@ impl Connection {
     async fn handle_switch_display(&mut self, s: SwitchDisplay) {
         let display_idx = s.display as usize;
         if self.display_idx != display_idx {
+            self.record_event(
+                RecordEventKind::Display,
+                format!("switch display {} -> {}", self.display_idx, display_idx),
+            );
             if let Some(server) = self.server.upgrade() {
                 self.switch_display_to(display_idx, server.clone());
 
@@ -3213,6 +3669,13 @@ impl Connection {
     }
 
     async fn capture_displays(&mut self, add: &[usize], sub: &[usize], set: &[usize]) {
+        self.record_event(
+            RecordEventKind::Display,
+            format!(
+                "capture displays, add {:?}, sub {:?}, set {:?}",
+                add, sub, set
+            ),
+        );
         let video_source = self.video_source();
         if let Some(sever) = self.server.upgrade() {
             let mut lock = sever.write().unwrap();
@@ -3247,6 +3710,54 @@ impl Connection {
         }
     }
 
+    async fn handle_capture_region(&mut self, data: &[u8]) {
+        use scrap::region::RegionMessage;
+        if self.video_source() != VideoSource::Monitor {
+            return;
+        }
+        let (display, rect, window) = match RegionMessage::decode(data) {
+            Ok(RegionMessage::Set {
+                display,
+                rect,
+                window,
+            }) => (display, rect, window),
+            Ok(RegionMessage::List) => {
+                let windows = RegionMessage::Windows(video_service::get_capture_windows());
+                self.send(crate::common::new_ext_message(
+                    scrap::region::EXT_ID,
+                    windows.encode(),
+                ))
+                .await;
+                return;
+            }
+            Ok(m) => {
+                log::debug!("Unexpected capture region message: {:?}", m);
+                return;
+            }
+            Err(e) => {
+                log::error!("Invalid capture region message: {}", e);
+                return;
+            }
+        };
+        let region = if window != 0 {
+            // A window which can not be tracked, e.g. on Wayland, would blank the stream.
+            if !video_service::is_capture_window(window) {
+                log::warn!("Can not capture the window {}", window);
+                return;
+            }
+            Some(video_service::CaptureRegion::Window(window))
+        } else if rect.width > 0 && rect.height > 0 {
+            Some(video_service::CaptureRegion::Rect(rect))
+        } else {
+            None
+        };
+        self.record_event(
+            RecordEventKind::Display,
+            format!("capture region of display {}: {:?}", display, region),
+        );
+        video_service::set_capture_region(self.inner.id(), display as _, region);
+    }
+
     #[cfg(windows)]
     async fn toggle_virtual_display(&mut self, t: ToggleVirtualDisplay) {
         let make_msg = |text: String| {
@@ -3761,6 +4272,56 @@ impl Connection {
         });
     }
 
+    async fn handle_ext_message(&mut self, p: PluginRequest) {
+        match p.id.as_str() {
+            crate::common::EXT_HELLO_ID => self.ext_messages = true,
+            crate::file_delta::ID => {
+                if self.file_transfer.is_some() {
+                    let one_way =
+                        crate::get_builtin_option(keys::OPTION_ONE_WAY_FILE_TRANSFER) == "Y";
+                    self.send_fs(ipc::FS::FileDelta {
+                        data: p.content,
+                        one_way,
+                    });
+                }
+            }
+            scrap::region::EXT_ID => {
+                self.handle_capture_region(&p.content).await;
+            }
+            #[cfg(not(any(target_os = "android", target_os = "ios")))]
+            crate::terminal_control::EXT_ID => {
+                self.handle_terminal_control(&p.content).await;
+            }
+            #[cfg(not(any(target_os = "android", target_os = "ios")))]
+            crate::remote_exec::EXT_ID => {
+                self.handle_exec(&p.content).await;
+            }
+            scrap::tile::EXT_ID => {
+                scrap::codec::Encoder::update(scrap::codec::EncodingUpdate::TileDecoding(
+                    self.inner.id(),
+                ));
+            }
+            _ => log::debug!("Unknown extension message {}", p.id),
+        }
+    }
+
+    // Sent every second while there is a video stream, the peer drops a delay not refreshed.
+    async fn relay_network_delay(&mut self) {
+        if self.file_transfer.is_none() || !self.ext_messages {
+            return;
+        }
+        let delay = transfer_limit::local_network_delay();
+        if delay.is_none() && self.relayed_network_delay.is_none() {
+            return;
+        }
+        self.relayed_network_delay = delay;
+        self.send(crate::common::new_ext_message(
+            transfer_limit::EXT_ID,
+            transfer_limit::encode_delay(delay),
+        ))
+        .await;
+    }
+
     fn read_dir(&mut self, dir: &str, include_hidden: bool) {
         let dir = dir.to_string();
         self.send_fs(ipc::FS::ReadDir {
@@ -4014,11 +4575,62 @@ impl Connection {
             self.terminal_service_id.clone(),
             self.terminal_persistent,
             user_token.to_terminal_service_token(),
+            self.inner.id(),
+            self.lr.my_id.clone(),
+            self.lr.my_name.clone(),
+            self.terminal_identity(),
         ));
         s.on_subscribe(self.inner.clone());
         self.terminal_generic_service = Some(s);
     }
 
+    // The identity to match the terminal policy profiles, not the peer id which the peer claims.
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    fn terminal_identity(&self) -> terminal_policy::Identity {
+        terminal_policy::Identity {
+            auth: self.auth_method.to_owned(),
+            // The OS login is only verified on Windows, by `fill_terminal_user_token()`.
+            #[cfg(target_os = "windows")]
+            os_user: self.lr.os_login.username.clone(),
+            #[cfg(not(target_os = "windows"))]
+            os_user: "".to_owned(),
+        }
+    }
+
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    async fn handle_terminal_control(&mut self, data: &[u8]) {
+        use crate::terminal_control::ControlMessage;
+        if !self.terminal || self.terminal_user_token.is_none() {
+            return;
+        }
+        let to = match ControlMessage::parse(data) {
+            Ok(ControlMessage::TransferControl { conn_id }) => conn_id,
+            Ok(m) => {
+                log::debug!("Unexpected terminal control message: {:?}", m);
+                return;
+            }
+            Err(e) => {
+                log::error!("Invalid terminal control message: {}", e);
+                return;
+            }
+        };
+        let proxy = terminal_service::TerminalServiceProxy::new(
+            self.terminal_service_id.clone(),
+            self.inner.id(),
+            Some(self.terminal_persistent),
+            None,
+        );
+        if let Err(err) = proxy.transfer_control(to) {
+            let mut response = TerminalResponse::new();
+            let mut error = TerminalError::new();
+            error.message = format!("Failed to transfer the control: {}", err);
+            response.set_error(error);
+            let mut msg_out = Message::new();
+            msg_out.set_terminal_response(response);
+            self.send(msg_out).await;
+        }
+    }
+
     #[cfg(not(any(target_os = "android", target_os = "ios")))]
     async fn handle_terminal_action(&mut self, action: TerminalAction) -> ResultType<()> {
         debug_assert!(self.terminal_user_token.is_some());
@@ -4028,6 +4640,7 @@ impl Connection {
         };
         let mut proxy = terminal_service::TerminalServiceProxy::new(
             self.terminal_service_id.clone(),
+            self.inner.id(),
             Some(self.terminal_persistent),
             user_token.to_terminal_service_token(),
         );
@@ -4054,6 +4667,105 @@ impl Connection {
 
         Ok(())
     }
+
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    async fn handle_exec(&mut self, data: &[u8]) {
+        use crate::remote_exec::ExecMessage;
+        if !self.terminal {
+            return;
+        }
+        let Some(_user_token) = self.terminal_user_token.clone() else {
+            return;
+        };
+        let mut exec = match ExecMessage::decode(data) {
+            Ok(ExecMessage::Command(exec)) => exec,
+            Ok(m) => {
+                log::debug!("Unexpected exec message: {:?}", m);
+                return;
+            }
+            Err(e) => {
+                log::error!("Invalid exec message: {}", e);
+                return;
+            }
+        };
+        let mut error = None;
+        let mut unsupported = false;
+        // The command is spawned by this process, it does not run as the logon user.
+        #[cfg(target_os = "windows")]
+        if _user_token.to_terminal_service_token().is_some() {
+            error = Some("Exec is not supported when running as a service".to_owned());
+            unsupported = true;
+        }
+        if let Some(profile) = TerminalPolicy::load().profile(&self.terminal_identity()) {
+            let result = if !profile.shell.is_empty() {
+                // The restricted shell cannot be bypassed.
+                Err("restricted shell".to_owned())
+            } else if !exec.env.is_empty() || !exec.cwd.is_empty() {
+                // They change what the command runs, like `LD_PRELOAD` or a relative path.
+                Err("environment or working dir".to_owned())
+            } else {
+                // Matched and run by its absolute path.
+                terminal_exec::resolve_command(&exec.command)
+                    .map_err(|e| e.to_string())
+                    .and_then(|command| {
+                        exec.command = command.to_string_lossy().to_string();
+                        profile.check(&Self::exec_line(&exec))
+                    })
+            };
+            if let Err(reason) = result {
+                let line = Self::exec_line(&exec);
+                log::warn!(
+                    "Blocked exec of {} by the policy profile {}: {}, {}",
+                    self.lr.my_id,
+                    profile.name,
+                    line,
+                    reason
+                );
+                Self::post_alarm_audit(
+                    AlarmAuditType::TerminalCommand,
+                    json!({
+                        "id": self.lr.my_id,
+                        "conn_id": self.inner.id,
+                        "profile": &profile.name,
+                        "command": &line,
+                        "reason": &reason,
+                    }),
+                );
+                error = Some(format!("Blocked by the terminal policy: {}", reason));
+                unsupported = false;
+            }
+        }
+        self.record_audit_log(
+            "exec",
+            json!({
+                "command": &exec.command,
+                "args": &exec.args,
+                "cwd": &exec.cwd,
+                "error": &error,
+            }),
+        );
+        if let Some(error) = error {
+            let exit = crate::remote_exec::ExecExit {
+                unsupported,
+                ..terminal_exec::error_exit(exec.exec_id, error)
+            };
+            self.send(ExecMessage::Exit(exit).to_message()).await;
+            return;
+        }
+        // Forget the finished commands, their threads hold the other references.
+        self.exec_cancels.retain(|c| Arc::strong_count(c) > 1);
+        self.exec_cancels
+            .push(terminal_exec::start(exec, self.inner.clone()));
+    }
+
+    // The command with its arguments, as a line of the terminal policy.
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    fn exec_line(exec: &crate::remote_exec::ExecCommand) -> String {
+        std::iter::once(exec.command.as_str())
+            .chain(exec.args.iter().map(|x| x.as_str()))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
 }
 
 pub fn insert_switch_sides_uuid(id: String, uuid: uuid::Uuid) {
@@ -4194,6 +4906,9 @@ async fn start_ipc(
                             compressed}) = data {
                                 stream.send(&Data::FS(ipc::FS::WriteBlock{id, file_num, data: Bytes::new(), compressed})).await?;
                                 stream.send_raw(data).await?;
+                        } else if let Data::FS(ipc::FS::FileDelta{data, one_way}) = data {
+                                stream.send(&Data::FS(ipc::FS::FileDelta{data: Bytes::new(), one_way})).await?;
+                                stream.send_raw(data).await?;
                         } else {
                             stream.send(&data).await?;
                         }
@@ -4221,6 +4936,20 @@ pub enum AlarmAuditType {
     IpWhitelist = 0,
     ExceedThirtyAttempts = 1,
     SixAttemptsWithinOneMinute = 2,
+    AccessPolicy = 3,
+    TerminalCommand = 4,
+}
+
+impl AlarmAuditType {
+    fn name(&self) -> &'static str {
+        match self {
+            AlarmAuditType::IpWhitelist => "ip_whitelist",
+            AlarmAuditType::ExceedThirtyAttempts => "exceed_thirty_attempts",
+            AlarmAuditType::SixAttemptsWithinOneMinute => "six_attempts_within_one_minute",
+            AlarmAuditType::AccessPolicy => "access_policy",
+            AlarmAuditType::TerminalCommand => "terminal_command",
+        }
+    }
 }
 
 pub enum FileAuditType {
@@ -4430,6 +5159,10 @@ impl Drop for Connection {
         if let Some(s) = self.terminal_generic_service.as_ref() {
             s.join();
         }
+        #[cfg(not(any(target_os = "android", target_os = "ios")))]
+        for cancel in self.exec_cancels.iter() {
+            cancel.store(true, std::sync::atomic::Ordering::SeqCst);
+        }
 
         #[cfg(target_os = "windows")]
         if let Some(TerminalUserToken::CurrentLogonUser(token)) = self.terminal_user_token.take() {
@@ -4720,6 +5453,7 @@ mod raii {
                     .lock()
                     .unwrap()
                     .on_connection_close(self.0);
+                video_service::clear_capture_region(self.0);
             }
             AUTHED_CONNS.lock().unwrap().retain(|c| c.conn_id != self.0);
             let remote_count = AUTHED_CONNS
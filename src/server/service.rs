@@ -188,6 +188,15 @@ impl<T: Subscriber + From<ConnInner>> ServiceTmpl<T> {
         self.0.read().unwrap().has_subscribes()
     }
 
+    pub fn subscriber_ids(&self) -> Vec<i32> {
+        let lock = self.0.read().unwrap();
+        lock.subscribes
+            .keys()
+            .chain(lock.new_subscribes.keys())
+            .cloned()
+            .collect()
+    }
+
     pub fn snapshot<F>(&self, callback: F) -> ResultType<()>
     where
         F: FnMut(ServiceSwap<T>) -> ResultType<()>,
@@ -242,6 +251,20 @@ impl<T: Subscriber + From<ConnInner>> ServiceTmpl<T> {
         conn_ids
     }
 
+    // Send to the subscribers accepted by `filter`, returns their ids.
+    pub fn send_video_frame_to(&self, msg: Message, filter: impl Fn(i32) -> bool) -> HashSet<i32> {
+        let mut conn_ids = HashSet::new();
+        let msg = Arc::new(msg);
+        let mut lock = self.0.write().unwrap();
+        for s in lock.subscribes.values_mut() {
+            if filter(s.id()) {
+                s.send(msg.clone());
+                conn_ids.insert(s.id());
+            }
+        }
+        conn_ids
+    }
+
     pub fn send_without(&self, msg: Message, sub: i32) {
         let mut lock = self.0.write().unwrap();
         let msg = Arc::new(msg);
@@ -0,0 +1,387 @@
+// One-shot command execution of the terminal connections, without a PTY.
+//
+// The command is run directly, not by a shell, with its arguments, environment and working dir.
+// stdout and stderr are streamed separately with the output messages of `crate::remote_exec`, then
+// the exit message carries the exit code. The process and the processes it starts are killed when
+// it runs longer than the timeout, when its output exceeds `MAX_OUTPUT_SIZE`, or when the
+// connection is closed.
+//
+// The output is sent at most at `MAX_OUTPUT_RATE`, the pipes are not read faster, so a command
+// writing more is slowed down instead of filling the queue of the connection.
+
+use super::*;
+use crate::remote_exec::{ExecCommand, ExecExit, ExecMessage};
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+    },
+    thread,
+    time::Instant,
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3600);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+// Background processes of the command may keep the pipes open after it exits.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+const READ_BUFFER_SIZE: usize = 4096;
+// Chunks read from the pipes and not sent yet
+const CHANNEL_BUFFER_SIZE: usize = 16;
+const MAX_OUTPUT_RATE: usize = 4 * 1024 * 1024; // Bytes per second
+const MAX_OUTPUT_SIZE: usize = 64 * 1024 * 1024;
+
+/// Run `exec` in a new thread, the responses are sent to `conn`.
+/// Returns the flag to kill the process.
+pub fn start(exec: ExecCommand, conn: ConnInner) -> Arc<AtomicBool> {
+    let cancel = Arc::new(AtomicBool::new(false));
+    let cancel_cloned = cancel.clone();
+    thread::spawn(move || {
+        let mut conn = conn;
+        let exit = match run(&exec, &mut conn, &cancel_cloned) {
+            Ok(exit) => exit,
+            Err(e) => {
+                log::error!("Failed to exec {}: {}", exec.command, e);
+                error_exit(exec.exec_id, e.to_string())
+            }
+        };
+        log::info!(
+            "Exec {} exit, code: {}, timed out: {}",
+            exec.exec_id,
+            exit.exit_code,
+            exit.timed_out
+        );
+        send(&mut conn, ExecMessage::Exit(exit));
+    });
+    cancel
+}
+
+/// The absolute path of `command`, searched in `PATH` if it is a bare name, like the process
+/// does but without the current dir.
+pub fn resolve_command(command: &str) -> ResultType<PathBuf> {
+    let path = Path::new(command);
+    if path.is_absolute() || path.components().count() > 1 {
+        return Ok(path.canonicalize()?);
+    }
+    #[cfg(windows)]
+    let extensions: Vec<String> = if path.extension().is_some() {
+        vec![String::new()]
+    } else {
+        std::env::var("PATHEXT")
+            .unwrap_or(".COM;.EXE;.BAT;.CMD".to_owned())
+            .split(';')
+            .map(|x| x.to_owned())
+            .collect()
+    };
+    #[cfg(not(windows))]
+    let extensions = vec![String::new()];
+    let paths = std::env::var_os("PATH").unwrap_or_default();
+    for dir in std::env::split_paths(&paths).filter(|dir| dir.is_absolute()) {
+        for ext in extensions.iter() {
+            let path = dir.join(format!("{}{}", command, ext));
+            if path.is_file() {
+                return Ok(path);
+            }
+        }
+    }
+    bail!("{} is not found", command)
+}
+
+/// The exit of an exec which is not started.
+pub fn error_exit(exec_id: i32, error: String) -> ExecExit {
+    ExecExit {
+        exec_id,
+        exit_code: -1,
+        error,
+        ..Default::default()
+    }
+}
+
+fn send(conn: &mut ConnInner, message: ExecMessage) {
+    conn.send(Arc::new(message.to_message()));
+}
+
+fn read_pipe(
+    mut pipe: impl Read + Send + 'static,
+    is_stderr: bool,
+    tx: mpsc::SyncSender<(bool, Vec<u8>)>,
+) {
+    thread::spawn(move || {
+        let mut buf = vec![0u8; READ_BUFFER_SIZE];
+        loop {
+            match pipe.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if tx.send((is_stderr, buf[..n].to_vec())).is_err() {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(_) => break,
+            }
+        }
+    });
+}
+
+/// The output sent of a command, to pace and cap it.
+struct OutputLimit {
+    total: usize,
+    window_start: Instant,
+    window_size: usize,
+}
+
+impl OutputLimit {
+    fn new() -> Self {
+        Self {
+            total: 0,
+            window_start: Instant::now(),
+            window_size: 0,
+        }
+    }
+
+    /// Wait until `len` bytes can be sent, false if the output exceeds `MAX_OUTPUT_SIZE`.
+    fn consume(&mut self, len: usize) -> bool {
+        self.total += len;
+        if self.total > MAX_OUTPUT_SIZE {
+            return false;
+        }
+        if self.window_size >= MAX_OUTPUT_RATE {
+            let elapsed = self.window_start.elapsed();
+            if elapsed < Duration::from_secs(1) {
+                thread::sleep(Duration::from_secs(1) - elapsed);
+            }
+            self.window_start = Instant::now();
+            self.window_size = 0;
+        }
+        self.window_size += len;
+        true
+    }
+}
+
+/// The process of the command with the processes it starts, to kill them together.
+struct ProcessTree {
+    child: Child,
+    #[cfg(windows)]
+    job: Option<job::Job>,
+}
+
+impl ProcessTree {
+    fn spawn(cmd: &mut Command) -> ResultType<Self> {
+        // The command leads its own process group.
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            cmd.process_group(0);
+        }
+        let child = cmd.spawn()?;
+        // A process started by the command before the assignment is not in the job.
+        #[cfg(windows)]
+        let job = match job::Job::new().and_then(|job| job.assign(&child).map(|_| job)) {
+            Ok(job) => Some(job),
+            Err(e) => {
+                log::error!("Failed to create the job object of the exec: {}", e);
+                None
+            }
+        };
+        Ok(Self {
+            child,
+            #[cfg(windows)]
+            job,
+        })
+    }
+
+    fn kill(&mut self) {
+        // The process is not reaped yet, its id is still the id of the group.
+        #[cfg(unix)]
+        unsafe {
+            hbb_common::libc::killpg(self.child.id() as _, hbb_common::libc::SIGKILL);
+        }
+        #[cfg(windows)]
+        if let Some(job) = self.job.as_ref() {
+            job.kill();
+        }
+        self.child.kill().ok();
+    }
+}
+
+#[cfg(windows)]
+mod job {
+    use hbb_common::{bail, ResultType};
+    use std::{os::windows::io::AsRawHandle, process::Child, ptr::null_mut};
+    use winapi::{
+        shared::minwindef::FALSE,
+        um::{
+            handleapi::CloseHandle,
+            jobapi2::{AssignProcessToJobObject, CreateJobObjectW, TerminateJobObject},
+            winnt::HANDLE,
+        },
+    };
+
+    pub struct Job(HANDLE);
+
+    // The handle is only used by the thread of the exec.
+    unsafe impl Send for Job {}
+
+    impl Job {
+        pub fn new() -> ResultType<Self> {
+            let handle = unsafe { CreateJobObjectW(null_mut(), null_mut()) };
+            if handle.is_null() {
+                bail!("{}", std::io::Error::last_os_error());
+            }
+            Ok(Job(handle))
+        }
+
+        pub fn assign(&self, child: &Child) -> ResultType<()> {
+            if unsafe { AssignProcessToJobObject(self.0, child.as_raw_handle() as _) } == FALSE {
+                bail!("{}", std::io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        pub fn kill(&self) {
+            unsafe {
+                TerminateJobObject(self.0, 1);
+            }
+        }
+    }
+
+    impl Drop for Job {
+        fn drop(&mut self) {
+            unsafe {
+                CloseHandle(self.0);
+            }
+        }
+    }
+}
+
+fn output_limit_error() -> String {
+    format!("The output exceeds {} MB", MAX_OUTPUT_SIZE / (1024 * 1024))
+}
+
+fn run(exec: &ExecCommand, conn: &mut ConnInner, cancel: &AtomicBool) -> ResultType<ExecExit> {
+    log::info!(
+        "Exec {}: {} {:?}, cwd: {}",
+        exec.exec_id,
+        exec.command,
+        exec.args,
+        exec.cwd
+    );
+    let mut cmd = Command::new(&exec.command);
+    cmd.args(&exec.args)
+        .envs(exec.env.iter().map(|(k, v)| (k, v)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if !exec.cwd.is_empty() {
+        cmd.current_dir(&exec.cwd);
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        cmd.creation_flags(winapi::um::winbase::CREATE_NO_WINDOW);
+    }
+    let mut process = ProcessTree::spawn(&mut cmd)?;
+
+    let (tx, rx) = mpsc::sync_channel(CHANNEL_BUFFER_SIZE);
+    if let Some(stdout) = process.child.stdout.take() {
+        read_pipe(stdout, false, tx.clone());
+    }
+    if let Some(stderr) = process.child.stderr.take() {
+        read_pipe(stderr, true, tx.clone());
+    }
+    drop(tx);
+
+    let timeout = if exec.timeout_ms > 0 {
+        Duration::from_millis(exec.timeout_ms as _)
+    } else {
+        DEFAULT_TIMEOUT
+    };
+    let start = Instant::now();
+    let mut limit = OutputLimit::new();
+    let mut timed_out = false;
+    let mut error = String::new();
+    let mut send_output = |conn: &mut ConnInner, is_stderr: bool, data: Vec<u8>| -> bool {
+        if !limit.consume(data.len()) {
+            return false;
+        }
+        send(
+            conn,
+            ExecMessage::Output {
+                exec_id: exec.exec_id,
+                is_stderr,
+                data,
+            },
+        );
+        true
+    };
+    let status = loop {
+        let mut exceeded = false;
+        match rx.recv_timeout(POLL_INTERVAL) {
+            Ok((is_stderr, data)) => exceeded = !send_output(conn, is_stderr, data),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => thread::sleep(POLL_INTERVAL),
+        }
+        if !exceeded {
+            if let Some(status) = process.child.try_wait()? {
+                break status;
+            }
+        }
+        let cancelled = cancel.load(Ordering::SeqCst);
+        if exceeded || cancelled || start.elapsed() > timeout {
+            timed_out = !exceeded && !cancelled;
+            let reason = if exceeded {
+                error = output_limit_error();
+                "output limit"
+            } else if cancelled {
+                "the connection is closed"
+            } else {
+                "timeout"
+            };
+            log::warn!("Kill exec {}, {}", exec.exec_id, reason);
+            process.kill();
+            break process.child.wait()?;
+        }
+    };
+
+    if error.is_empty() {
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            match rx.recv_timeout(left) {
+                Ok((is_stderr, data)) => {
+                    if !send_output(conn, is_stderr, data) {
+                        error = output_limit_error();
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    }
+
+    Ok(ExecExit {
+        exec_id: exec.exec_id,
+        exit_code: status.code().unwrap_or(-1),
+        timed_out,
+        error,
+        unsupported: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_resolve_command() {
+        let sh = resolve_command("sh").unwrap();
+        assert!(sh.is_absolute() && sh.ends_with("sh"));
+        assert_eq!(
+            resolve_command("/bin/../bin/sh").unwrap(),
+            Path::new("/bin/sh").canonicalize().unwrap()
+        );
+        assert!(resolve_command("no-such-command-of-the-test").is_err());
+    }
+}
@@ -0,0 +1,471 @@
+// Command policy of the terminals, for restricted support roles.
+//
+// The policy is read from `TerminalPolicy.toml` in the config dir, e.g.
+//
+// ```toml
+// [[profiles]]
+// name = "junior support"
+// auth = ["temporary-password"]
+// shell = "/usr/local/bin/support-shell"
+// shell_args = ["--restricted"]
+// allow = ['^(ls|df|du|ps|top|uptime|journalctl|systemctl status)\b']
+// deny = ['\brm\s+-[a-zA-Z]*[rf]', '\bsudo\b']
+// ```
+//
+// The first profile matching the identity of the writer applies, peers without a profile are not
+// restricted. The identity is what the server verified, not the peer id which the peer claims:
+// - `auth`: how the connection was authenticated, `temporary-password`, `permanent-password` or
+//   `accepted` by the local user.
+// - `os_users`: the OS account the peer logged in with, only verified on Windows.
+// An empty list matches every connection, a profile matches if all its lists match.
+//
+// A profile with a `shell` restricts the terminals: the participant only controls the terminals
+// spawned with its shell, the control is not transferred to it if other terminals are open.
+//
+// The input is inspected line by line: the submitted line is split into the commands separated
+// by `;`, `&`, `|`, backquotes and parentheses. A line is blocked if any command matches a deny
+// pattern, or if `allow` is not empty and any command matches none of the allow patterns.
+// A line ending in `\` or in an open quote is continued by the shell, it is inspected with its
+// continuation lines once it is submitted. A here document is blocked, its lines are not seen.
+// The line cannot be inspected if it is edited with keys which the shell interprets, like the
+// history, the completion or the cursor movements, so it is blocked too.
+// The commands run without a terminal (`--cli-exec`) are matched with the absolute path of the
+// program, e.g. `^/usr/bin/(ls|df)\b`, and refused with an environment or a working dir.
+// The inspection only sees the keys typed by the peer, a restricted shell or wrapper is the
+// reliable way to limit what can be run.
+
+use hbb_common::{
+    config::{load_path, Config},
+    log,
+    regex::Regex,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{path::PathBuf, sync::Arc};
+
+const CTRL_C: u8 = 0x03;
+const CTRL_L: u8 = 0x0c;
+const CTRL_U: u8 = 0x15;
+const CTRL_W: u8 = 0x17;
+const ESC: u8 = 0x1b;
+const PASTE_START: &[u8] = b"\x1b[200~";
+const PASTE_END: &[u8] = b"\x1b[201~";
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProfileConfig {
+    pub name: String,
+    /// The authentication methods, empty means any.
+    pub auth: Vec<String>,
+    /// The OS accounts, case insensitive, empty means any.
+    pub os_users: Vec<String>,
+    /// Run instead of the default shell, empty means the default shell.
+    pub shell: String,
+    pub shell_args: Vec<String>,
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TerminalPolicy {
+    pub profiles: Vec<ProfileConfig>,
+}
+
+impl TerminalPolicy {
+    fn path() -> PathBuf {
+        Config::path("TerminalPolicy.toml")
+    }
+
+    pub fn load() -> Self {
+        load_path(Self::path())
+    }
+
+    /// The profile restricting `identity`, `None` if it is not restricted.
+    pub fn profile(&self, identity: &Identity) -> Option<Arc<Profile>> {
+        self.profiles
+            .iter()
+            .find(|p| {
+                (p.auth.is_empty() || p.auth.iter().any(|x| *x == identity.auth))
+                    && (p.os_users.is_empty()
+                        || p.os_users
+                            .iter()
+                            .any(|x| x.eq_ignore_ascii_case(&identity.os_user)))
+            })
+            .map(|p| Arc::new(Profile::new(p)))
+    }
+}
+
+pub const AUTH_TEMPORARY_PASSWORD: &str = "temporary-password";
+pub const AUTH_PERMANENT_PASSWORD: &str = "permanent-password";
+pub const AUTH_ACCEPTED: &str = "accepted";
+
+/// What the server verified about a connection, the profiles are matched by it.
+#[derive(Debug, Default, Clone)]
+pub struct Identity {
+    /// One of the `AUTH_*`, empty if it is none of them.
+    pub auth: String,
+    /// Empty if the peer did not log in with a verified OS account.
+    pub os_user: String,
+}
+
+/// A profile with the compiled patterns.
+#[derive(Debug)]
+pub struct Profile {
+    pub name: String,
+    pub shell: String,
+    pub shell_args: Vec<String>,
+    allow: Vec<Regex>,
+    deny: Vec<Regex>,
+    // Some patterns are invalid, every command is blocked rather than ignoring a deny pattern.
+    invalid: bool,
+}
+
+impl Profile {
+    pub fn new(config: &ProfileConfig) -> Self {
+        let mut invalid = false;
+        let mut compile = |patterns: &Vec<String>| -> Vec<Regex> {
+            patterns
+                .iter()
+                .filter_map(|x| match Regex::new(x) {
+                    Ok(re) => Some(re),
+                    Err(e) => {
+                        log::error!(
+                            "Invalid pattern of terminal policy profile {}: {}",
+                            config.name,
+                            e
+                        );
+                        invalid = true;
+                        None
+                    }
+                })
+                .collect()
+        };
+        let allow = compile(&config.allow);
+        let deny = compile(&config.deny);
+        Self {
+            name: config.name.clone(),
+            shell: config.shell.clone(),
+            shell_args: config.shell_args.clone(),
+            allow,
+            deny,
+            invalid,
+        }
+    }
+
+    /// Check a submitted line, returns the reason if it is blocked.
+    pub fn check(&self, line: &str) -> Result<(), String> {
+        if self.invalid {
+            return Err("invalid policy".to_owned());
+        }
+        for command in split_commands(line) {
+            if let Some(re) = self.deny.iter().find(|re| re.is_match(command)) {
+                return Err(format!("denied by `{}`", re.as_str()));
+            }
+            if !self.allow.is_empty() && !self.allow.iter().any(|re| re.is_match(command)) {
+                return Err("not allowed".to_owned());
+            }
+        }
+        Ok(())
+    }
+}
+
+// The commands of a line, without the separators and the parts without a word like `$`.
+fn split_commands(line: &str) -> Vec<&str> {
+    line.split(|c| matches!(c, ';' | '&' | '|' | '`' | '(' | ')' | '\n'))
+        .map(|x| x.trim())
+        .filter(|x| x.chars().any(|c| c.is_alphanumeric()))
+        .collect()
+}
+
+/// The line being typed into a terminal, to check it when it is submitted.
+#[derive(Debug, Default)]
+pub struct CommandLine {
+    line: Vec<u8>,
+    // The line is edited with keys which the shell interprets.
+    tainted: bool,
+    // The escape sequence being received
+    esc: Option<Vec<u8>>,
+    // In a bracketed paste, the shell inserts the new lines into the line instead of running it.
+    pasting: bool,
+}
+
+// How the shell reads a line on enter.
+#[derive(Debug, PartialEq)]
+enum LineEnd {
+    Complete,
+    // The line ends in an unescaped `\` or in an open quote.
+    Continued,
+    HereDoc,
+}
+
+fn line_end(line: &[u8]) -> LineEnd {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut heredoc = false;
+    for (i, &c) in line.iter().enumerate() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match (quote, c) {
+            (Some(b'\''), b'\'') => quote = None,
+            (Some(b'\''), _) => {}
+            (_, b'\\') => escaped = true,
+            (Some(q), _) if q == c => quote = None,
+            (Some(_), _) => {}
+            (None, b'\'' | b'"') => quote = Some(c),
+            // A comment
+            (None, b'#') if i == 0 || line[i - 1].is_ascii_whitespace() => break,
+            // `<<` but not the here string `<<<`
+            (None, b'<') => {
+                heredoc |= line.get(i + 1) == Some(&b'<')
+                    && line.get(i + 2) != Some(&b'<')
+                    && (i == 0 || line[i - 1] != b'<');
+            }
+            _ => {}
+        }
+    }
+    if escaped || quote.is_some() {
+        LineEnd::Continued
+    } else if heredoc {
+        LineEnd::HereDoc
+    } else {
+        LineEnd::Complete
+    }
+}
+
+/// A line blocked by [`CommandLine::filter`].
+#[derive(Debug)]
+pub struct Blocked {
+    pub line: String,
+    pub reason: String,
+}
+
+impl CommandLine {
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Returns the input to write to the terminal and the blocked lines.
+    /// The enter of a blocked line is replaced by ctrl-c, to make the shell discard the line.
+    pub fn filter(&mut self, profile: &Profile, data: &[u8]) -> (Vec<u8>, Vec<Blocked>) {
+        let mut out = Vec::with_capacity(data.len());
+        let mut blocked = Vec::new();
+        for &b in data {
+            if let Some(esc) = self.esc.as_mut() {
+                esc.push(b);
+                out.push(b);
+                if Self::is_esc_finished(esc) {
+                    match esc.as_slice() {
+                        PASTE_START => self.pasting = true,
+                        PASTE_END => self.pasting = false,
+                        _ => self.tainted = true,
+                    }
+                    self.esc = None;
+                }
+                continue;
+            }
+            match b {
+                b'\r' | b'\n' if self.pasting => {
+                    self.line.push(b'\n');
+                    out.push(b);
+                }
+                b'\r' | b'\n' => {
+                    let end = line_end(&self.line);
+                    if !self.tainted && end == LineEnd::Continued {
+                        self.line.push(b'\n');
+                        out.push(b);
+                        continue;
+                    }
+                    // The shell removes `\` with the new line.
+                    let line = String::from_utf8_lossy(&self.line).replace("\\\n", "");
+                    let result = if self.tainted {
+                        Err("edited line".to_owned())
+                    } else if end == LineEnd::HereDoc {
+                        Err("here document".to_owned())
+                    } else {
+                        profile.check(&line)
+                    };
+                    match result {
+                        Ok(()) => out.push(b),
+                        Err(reason) => {
+                            out.push(CTRL_C);
+                            blocked.push(Blocked { line, reason });
+                        }
+                    }
+                    self.line.clear();
+                    self.tainted = false;
+                }
+                ESC => {
+                    self.esc = Some(vec![b]);
+                    out.push(b);
+                }
+                0x7f | 0x08 => {
+                    // Remove the last character, not the continued lines
+                    while self.line.last().map_or(false, |c| *c != b'\n') {
+                        if let Some(c) = self.line.pop() {
+                            if (c & 0xC0) != 0x80 {
+                                break;
+                            }
+                        }
+                    }
+                    out.push(b);
+                }
+                CTRL_C => {
+                    self.line.clear();
+                    self.tainted = false;
+                    out.push(b);
+                }
+                CTRL_U => {
+                    // Clear the current line, the continued lines stay
+                    while self.line.last().map_or(false, |c| *c != b'\n') {
+                        self.line.pop();
+                    }
+                    if self.line.is_empty() {
+                        self.tainted = false;
+                    }
+                    out.push(b);
+                }
+                CTRL_W => {
+                    // Remove the last word
+                    while self.line.last() == Some(&b' ') {
+                        self.line.pop();
+                    }
+                    while self
+                        .line
+                        .last()
+                        .map_or(false, |c| *c != b' ' && *c != b'\n')
+                    {
+                        self.line.pop();
+                    }
+                    out.push(b);
+                }
+                CTRL_L => out.push(b),
+                _ if b < 0x20 => {
+                    self.tainted = true;
+                    out.push(b);
+                }
+                _ => {
+                    self.line.push(b);
+                    out.push(b);
+                }
+            }
+        }
+        (out, blocked)
+    }
+
+    // `ESC [ ... final`, `ESC O x`, or `ESC x`
+    fn is_esc_finished(esc: &[u8]) -> bool {
+        match esc {
+            [_] => false,
+            [_, b'['] | [_, b'O'] => false,
+            [_, b'[', .., last] => (0x40..=0x7e).contains(last),
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(allow: &[&str], deny: &[&str]) -> Profile {
+        Profile::new(&ProfileConfig {
+            name: "test".to_owned(),
+            allow: allow.iter().map(|x| x.to_string()).collect(),
+            deny: deny.iter().map(|x| x.to_string()).collect(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_check() {
+        let p = profile(&[r"^(ls|df|ps)\b"], &[r"\brm\s+-[a-zA-Z]*[rf]"]);
+        assert!(p.check("").is_ok());
+        assert!(p.check("ls -l /tmp").is_ok());
+        assert!(p.check("ps aux | ls").is_ok());
+        assert!(p.check("cat /etc/passwd").is_err());
+        assert!(p.check("ls; rm -rf /").is_err());
+        assert!(p.check("ls $(rm -rf /)").is_err());
+        assert!(p.check("ls `reboot`").is_err());
+        let p = profile(&[], &[r"\brm\s+-[a-zA-Z]*[rf]"]);
+        assert!(p.check("cat /etc/hosts").is_ok());
+        assert!(p.check("rm -fr /").is_err());
+        // An invalid pattern blocks everything.
+        let p = profile(&[], &["("]);
+        assert!(p.check("ls").is_err());
+    }
+
+    #[test]
+    fn test_profile() {
+        let policy = TerminalPolicy {
+            profiles: vec![
+                ProfileConfig {
+                    name: "support".to_owned(),
+                    auth: vec![AUTH_TEMPORARY_PASSWORD.to_owned()],
+                    os_users: vec!["Support".to_owned()],
+                    ..Default::default()
+                },
+                ProfileConfig {
+                    name: "temporary".to_owned(),
+                    auth: vec![AUTH_TEMPORARY_PASSWORD.to_owned()],
+                    ..Default::default()
+                },
+            ],
+        };
+        let name = |auth: &str, os_user: &str| {
+            policy
+                .profile(&Identity {
+                    auth: auth.to_owned(),
+                    os_user: os_user.to_owned(),
+                })
+                .map(|p| p.name.clone())
+        };
+        assert_eq!(
+            name(AUTH_TEMPORARY_PASSWORD, "support").as_deref(),
+            Some("support")
+        );
+        assert_eq!(
+            name(AUTH_TEMPORARY_PASSWORD, "").as_deref(),
+            Some("temporary")
+        );
+        assert_eq!(name(AUTH_PERMANENT_PASSWORD, "support"), None);
+        assert_eq!(name("", ""), None);
+    }
+
+    #[test]
+    fn test_filter() {
+        let p = profile(&[r"^ls\b"], &[]);
+        let mut line = CommandLine::default();
+        let (out, blocked) = line.filter(&p, b"ls -l\r");
+        assert_eq!(out, b"ls -l\r");
+        assert!(blocked.is_empty());
+        // Typed in several messages, with a backspace
+        line.filter(&p, b"rebooo");
+        let (out, blocked) = line.filter(&p, b"\x7ft\r");
+        assert_eq!(out, b"\x7ft\x03");
+        assert_eq!(blocked[0].line, "reboot");
+        // Cleared with ctrl-u
+        let (_, blocked) = line.filter(&p, b"reboot\x15ls\r");
+        assert!(blocked.is_empty());
+        // Recalled from the history
+        let (out, blocked) = line.filter(&p, b"\x1b[A\r");
+        assert_eq!(out, b"\x1b[A\x03");
+        assert_eq!(blocked[0].reason, "edited line");
+        // Bracketed paste of several lines
+        let (out, blocked) = line.filter(&p, b"\x1b[200~ls\rreboot\x1b[201~\r");
+        assert_eq!(out, b"\x1b[200~ls\rreboot\x1b[201~\x03");
+        assert_eq!(blocked.len(), 1);
+        // Continued lines
+        let p = profile(&[], &[r"\brm\s+-[a-zA-Z]*[rf]"]);
+        let (out, blocked) = line.filter(&p, b"r\\\rm -rf /\r");
+        assert_eq!(out, b"r\\\rm -rf /\x03");
+        assert_eq!(blocked[0].line, "rm -rf /");
+        let (_, blocked) = line.filter(&p, b"echo 'a\r\x7f\x15b' \\\r\x7f\x7f&& rm -f /\r");
+        assert_eq!(blocked.len(), 1);
+        let (_, blocked) = line.filter(&p, b"echo \\\\ 'a\\' # it's\r");
+        assert!(blocked.is_empty());
+        let (_, blocked) = line.filter(&p, b"cat <<<a; sh <<EOF\r");
+        assert_eq!(blocked[0].reason, "here document");
+    }
+}
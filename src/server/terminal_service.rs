@@ -1,9 +1,14 @@
-use super::*;
+use super::{
+    terminal_policy::{CommandLine, Identity, Profile, TerminalPolicy},
+    *,
+};
 use hbb_common::{
     anyhow::{anyhow, Context, Result},
     compress,
 };
 use portable_pty::{Child, CommandBuilder, PtySize};
+use scrap::record_cast::CastRecorder;
+use serde_json::json;
 use std::{
     collections::{HashMap, VecDeque},
     io::{Read, Write},
@@ -23,6 +28,10 @@ const MAX_SERVICES: usize = 100; // Maximum number of persistent terminal servic
 const SERVICE_IDLE_TIMEOUT: Duration = Duration::from_secs(3600); // 1 hour idle timeout
 const CHANNEL_BUFFER_SIZE: usize = 100; // Number of messages to buffer in channel
 const COMPRESS_THRESHOLD: usize = 512; // Compress terminal data larger than this
+const REPLAY_CHUNK_SIZE: usize = 64 * 1024; // Split the scrollback replay into messages of this size
+
+// Scrollback replayed on reconnect in KB, the whole buffer by default, 0 to disable
+const OPTION_REPLAY_KB: &str = "terminal-replay-kb";
 
 lazy_static::lazy_static! {
     // Global registry of persistent terminal services indexed by service_id
@@ -98,15 +107,40 @@ fn get_default_shell() -> String {
     }
 }
 
-pub fn is_service_specified_user(service_id: &str) -> Option<bool> {
-    get_service(service_id).map(|s| s.lock().unwrap().is_specified_user)
+// The shell of a terminal policy profile and its arguments.
+fn profile_shell(profile: &Profile) -> Vec<String> {
+    std::iter::once(profile.shell.clone())
+        .chain(profile.shell_args.iter().cloned())
+        .collect()
+}
+
+/// Whether the terminals of the service run as the user of `user_token`, None if there is no such
+/// service.
+pub fn is_service_user(service_id: &str, user_token: Option<UserToken>) -> Option<bool> {
+    let service = get_service(service_id)?;
+    let service = service.lock().unwrap();
+    Some(
+        service.is_specified_user == user_token.is_some()
+            && service.user == terminal_user(user_token),
+    )
+}
+
+// The user the terminals run as, the SID on Windows. The terminals run as this process elsewhere.
+fn terminal_user(_user_token: Option<UserToken>) -> Vec<u8> {
+    #[cfg(target_os = "windows")]
+    match crate::platform::get_token_user_sid(_user_token.map_or(std::ptr::null_mut(), |t| t as _))
+    {
+        Ok(sid) => return sid,
+        Err(e) => log::error!("Failed to get the user of the terminal: {}", e),
+    }
+    vec![]
 }
 
 /// Get or create a persistent terminal service
 fn get_or_create_service(
     service_id: String,
     is_persistent: bool,
-    is_specified_user: bool,
+    user_token: Option<UserToken>,
 ) -> Result<Arc<Mutex<PersistentTerminalService>>> {
     let mut services = TERMINAL_SERVICES.lock().unwrap();
 
@@ -129,7 +163,8 @@ fn get_or_create_service(
             Arc::new(Mutex::new(PersistentTerminalService::new(
                 service_id.clone(),
                 is_persistent,
-                is_specified_user,
+                user_token.is_some(),
+                terminal_user(user_token),
             )))
         })
         .clone();
@@ -286,6 +321,7 @@ pub type UserToken = u64;
 pub struct TerminalService {
     sp: GenericService,
     user_token: Option<UserToken>,
+    conn_id: i32,
 }
 
 impl Deref for TerminalService {
@@ -310,16 +346,32 @@ pub fn new(
     service_id: String,
     is_persistent: bool,
     user_token: Option<UserToken>,
+    conn_id: i32,
+    peer_id: String,
+    name: String,
+    identity: Identity,
 ) -> GenericService {
+    let profile = TerminalPolicy::load().profile(&identity);
+    if let Some(profile) = profile.as_ref() {
+        log::info!(
+            "Terminal of {} ({:?}) restricted by the policy profile {}",
+            peer_id,
+            identity,
+            profile.name
+        );
+    }
     // Create the service with initial persistence setting
-    allow_err!(get_or_create_service(
-        service_id.clone(),
-        is_persistent,
-        user_token.is_some()
-    ));
+    match get_or_create_service(service_id.clone(), is_persistent, user_token) {
+        Ok(service) => service
+            .lock()
+            .unwrap()
+            .attach(conn_id, peer_id, name, profile),
+        Err(e) => log::error!("Failed to create terminal service {}: {}", service_id, e),
+    }
     let svc = TerminalService {
         sp: GenericService::new(service_id.clone(), false),
         user_token,
+        conn_id,
     };
     GenericService::run(&svc.clone(), move |sp| run(sp, service_id.clone()));
     svc.sp
@@ -327,20 +379,30 @@ pub fn new(
 
 fn run(sp: TerminalService, service_id: String) -> ResultType<()> {
     while sp.ok() {
-        let responses = TerminalServiceProxy::new(service_id.clone(), None, sp.user_token.clone())
-            .read_outputs();
+        let responses =
+            TerminalServiceProxy::new(service_id.clone(), sp.conn_id, None, sp.user_token.clone())
+                .read_outputs();
         for response in responses {
             let mut msg_out = Message::new();
             msg_out.set_terminal_response(response);
             sp.send(msg_out);
         }
+        if let Some(service) = get_service(&service_id) {
+            if let Some(msg_out) = service.lock().unwrap().participants_message(sp.conn_id) {
+                sp.send(msg_out);
+            }
+        }
 
         thread::sleep(Duration::from_millis(30)); // Read at ~33fps for responsive terminal
     }
 
-    // Clean up non-persistent service when loop exits
+    // Clean up non-persistent service when the last participant leaves
     if let Some(service) = get_service(&service_id) {
-        let should_remove = !service.lock().unwrap().is_persistent;
+        let should_remove = {
+            let mut service = service.lock().unwrap();
+            service.detach(sp.conn_id);
+            !service.is_persistent && service.participants.is_empty()
+        };
         if should_remove {
             remove_service(&service_id);
         }
@@ -421,7 +483,7 @@ impl OutputBuffer {
     }
 
     fn get_recent(&self, max_bytes: usize) -> Vec<u8> {
-        let mut result = Vec::new();
+        let mut start = self.lines.len();
         let mut size = 0;
 
         // Get recent lines up to max_bytes
@@ -430,13 +492,136 @@ impl OutputBuffer {
                 break;
             }
             size += line.len();
-            result.splice(0..0, line.iter().cloned());
+            start -= 1;
         }
 
+        let mut result = Vec::with_capacity(max_bytes.min(size + REPLAY_CHUNK_SIZE));
+        // Keep the tail of the line which does not fit,
+        // without splitting a UTF-8 character or an escape sequence.
+        if start > 0 && size < max_bytes {
+            let line = &self.lines[start - 1];
+            let pos = safe_cut_position(line, line.len() - (max_bytes - size));
+            result.extend_from_slice(&line[pos..]);
+        }
+        for line in self.lines.range(start..) {
+            result.extend_from_slice(line);
+        }
         result
     }
 }
 
+/// Returns the first position from `min` which is neither inside a UTF-8 character nor inside an
+/// escape sequence, `data` must not start inside them.
+fn safe_cut_position(data: &[u8], min: usize) -> usize {
+    #[derive(Clone, Copy, PartialEq)]
+    enum State {
+        Ground,
+        Esc,
+        // Control sequence, `ESC [`
+        Csi,
+        // Strings like OSC `ESC ]`, terminated by BEL or `ESC \`
+        Str,
+        StrEsc,
+    }
+
+    let mut state = State::Ground;
+    for (i, &b) in data.iter().enumerate() {
+        if i >= min && state == State::Ground && (b & 0xC0) != 0x80 {
+            return i;
+        }
+        state = match (state, b) {
+            (State::Ground, 0x1b) => State::Esc,
+            (State::Ground, _) => State::Ground,
+            (State::Esc, b'[') => State::Csi,
+            (State::Esc, b']' | b'P' | b'X' | b'^' | b'_') => State::Str,
+            // Intermediate bytes, e.g. `ESC ( B`
+            (State::Esc, 0x20..=0x2f) => State::Esc,
+            (State::Esc, _) => State::Ground,
+            (State::Csi, 0x40..=0x7e) => State::Ground,
+            (State::Csi, _) => State::Csi,
+            (State::Str, 0x07) => State::Ground,
+            (State::Str, 0x1b) => State::StrEsc,
+            (State::Str, _) => State::Str,
+            (State::StrEsc, b'\\') => State::Ground,
+            (State::StrEsc, _) => State::Str,
+        };
+    }
+    data.len()
+}
+
+/// Split the replay after newlines, so that each message holds complete lines if possible.
+fn split_replay(data: &[u8]) -> Vec<&[u8]> {
+    let mut chunks = Vec::new();
+    let mut rest = data;
+    while rest.len() > REPLAY_CHUNK_SIZE {
+        let pos = match rest[..REPLAY_CHUNK_SIZE].iter().rposition(|&b| b == b'\n') {
+            Some(pos) => pos + 1,
+            None => safe_cut_position(rest, REPLAY_CHUNK_SIZE),
+        };
+        let (chunk, tail) = rest.split_at(pos);
+        chunks.push(chunk);
+        rest = tail;
+    }
+    if !rest.is_empty() {
+        chunks.push(rest);
+    }
+    chunks
+}
+
+fn replay_size() -> usize {
+    let option = Config::get_option(OPTION_REPLAY_KB);
+    if option.is_empty() {
+        return MAX_OUTPUT_BUFFER_SIZE;
+    }
+    match option.parse::<usize>() {
+        Ok(kb) => (kb * 1024).min(MAX_OUTPUT_BUFFER_SIZE),
+        Err(_) => {
+            log::error!("Invalid {}: {}", OPTION_REPLAY_KB, option);
+            MAX_OUTPUT_BUFFER_SIZE
+        }
+    }
+}
+
+// Record the terminal with the option of the screen recordings. The input is only recorded if
+// `record-terminal-input` is `Y`, like the plain keys of the recording index.
+fn new_cast_recorder(terminal_id: i32, rows: u16, cols: u16, shell: &str) -> Option<CastRecorder> {
+    let record_incoming = hbb_common::config::option2bool(
+        "allow-auto-record-incoming",
+        &Config::get_option("allow-auto-record-incoming"),
+    );
+    if !record_incoming {
+        return None;
+    }
+    let record_input = Config::get_option("record-terminal-input") == "Y";
+    video_service::incoming_record_context(0, false)
+        .and_then(|ctx| CastRecorder::new(ctx, terminal_id, cols, rows, shell, record_input))
+        .map_err(|e| log::error!("Failed to record terminal {}: {}", terminal_id, e))
+        .ok()
+}
+
+fn make_data_response(terminal_id: i32, data: Vec<u8>) -> TerminalResponse {
+    let mut response = TerminalResponse::new();
+    let mut terminal_data = TerminalData::new();
+    terminal_data.terminal_id = terminal_id;
+
+    // Compress data if it exceeds threshold
+    if data.len() > COMPRESS_THRESHOLD {
+        let compressed = compress::compress(&data);
+        if compressed.len() < data.len() {
+            terminal_data.data = bytes::Bytes::from(compressed);
+            terminal_data.compressed = true;
+        } else {
+            // Compression didn't help, send uncompressed
+            terminal_data.data = bytes::Bytes::from(data);
+        }
+    } else {
+        terminal_data.data = bytes::Bytes::from(data);
+    }
+
+    response.set_data(terminal_data);
+    response
+}
+
 pub struct TerminalSession {
     pub created_at: Instant,
     last_activity: Instant,
@@ -457,7 +642,14 @@ pub struct TerminalSession {
     cols: u16,
     // Track if we've already sent the closed message
     closed_message_sent: bool,
-    is_opened: bool,
+    // The output not sent yet to each connection which opened the terminal, by connection id.
+    // It starts with the scrollback replay when attaching to a running terminal.
+    viewers: HashMap<i32, Vec<Vec<u8>>>,
+    recorder: Option<CastRecorder>,
+    // The line being typed by the writer, checked if the writer is restricted by the terminal policy
+    command_line: CommandLine,
+    // The shell and its arguments
+    shell: Vec<String>,
 }
 
 impl TerminalSession {
@@ -478,7 +670,10 @@ impl TerminalSession {
             rows,
             cols,
             closed_message_sent: false,
-            is_opened: false,
+            viewers: HashMap::new(),
+            recorder: None,
+            command_line: CommandLine::default(),
+            shell: Vec::new(),
         }
     }
 
@@ -489,7 +684,7 @@ impl TerminalSession {
     // This helper function is to ensure that the threads are joined before the child process is dropped.
     // Though this is not strictly necessary on macOS.
     fn stop(&mut self) {
-        self.is_opened = false;
+        self.viewers.clear();
         self.exiting.store(true, Ordering::SeqCst);
 
         // Drop the input channel to signal writer thread to exit
@@ -548,6 +743,21 @@ impl Drop for TerminalSession {
     }
 }
 
+/// A connection attached to a terminal service
+struct Participant {
+    conn_id: i32,
+    peer_id: String,
+    name: String,
+    // Send the other terminals with the next opened response, to restore them on the peer
+    needs_session_sync: bool,
+    // The terminal policy profile restricting the participant
+    profile: Option<Arc<Profile>>,
+    // Responses caused by the other participants, sent by `read_outputs()`
+    responses: Vec<TerminalResponse>,
+    // The participant list changed since it was sent
+    participants_changed: bool,
+}
+
 /// Persistent terminal service that can survive connection drops
 pub struct PersistentTerminalService {
     service_id: String,
@@ -555,20 +765,32 @@ pub struct PersistentTerminalService {
     pub created_at: Instant,
     last_activity: Instant,
     pub is_persistent: bool,
-    needs_session_sync: bool,
     is_specified_user: bool,
+    // The user the terminals run as, a connection of another user can not attach
+    user: Vec<u8>,
+    // In attaching order
+    participants: Vec<Participant>,
+    // The participant allowed to write to the terminals, the others are read-only observers
+    writer: Option<i32>,
 }
 
 impl PersistentTerminalService {
-    pub fn new(service_id: String, is_persistent: bool, is_specified_user: bool) -> Self {
+    pub fn new(
+        service_id: String,
+        is_persistent: bool,
+        is_specified_user: bool,
+        user: Vec<u8>,
+    ) -> Self {
         Self {
             service_id,
             sessions: HashMap::new(),
             created_at: Instant::now(),
             last_activity: Instant::now(),
             is_persistent,
-            needs_session_sync: false,
             is_specified_user,
+            user,
+            participants: Vec::new(),
+            writer: None,
         }
     }
 
@@ -614,16 +836,189 @@ impl PersistentTerminalService {
 
     fn reset_status(&mut self, is_persistent: bool) {
         self.is_persistent = is_persistent;
-        self.needs_session_sync = true;
+    }
+
+    fn participant(&mut self, conn_id: i32) -> Option<&mut Participant> {
+        self.participants.iter_mut().find(|p| p.conn_id == conn_id)
+    }
+
+    fn is_writer(&self, conn_id: i32) -> bool {
+        self.writer == Some(conn_id)
+    }
+
+    fn set_writer(&mut self, writer: Option<i32>) {
+        if self.writer != writer {
+            // The new writer starts typing a new line.
+            for session in self.sessions.values() {
+                session.lock().unwrap().command_line.reset();
+            }
+            self.writer = writer;
+        }
+    }
+
+    /// The terminal policy profile and the peer id of a restricted participant.
+    fn restriction(&mut self, conn_id: i32) -> Option<(Arc<Profile>, String)> {
+        let p = self.participant(conn_id)?;
+        Some((p.profile.clone()?, p.peer_id.clone()))
+    }
+
+    /// Whether the participant can control all the terminals.
+    /// A participant restricted to a shell only controls the terminals spawned with the shell.
+    fn can_write(&self, conn_id: i32) -> bool {
+        let Some(profile) = self
+            .participants
+            .iter()
+            .find(|p| p.conn_id == conn_id)
+            .and_then(|p| p.profile.as_ref())
+            .filter(|p| !p.shell.is_empty())
+        else {
+            return true;
+        };
+        let shell = profile_shell(profile);
+        self.sessions
+            .values()
+            .all(|s| s.lock().unwrap().shell == shell)
+    }
+
+    // Give the control to the earliest attached participant who can write, if there is no writer.
+    fn assign_writer(&mut self) {
+        if self.writer.is_none() {
+            let writer = self
+                .participants
+                .iter()
+                .map(|p| p.conn_id)
+                .find(|id| self.can_write(*id));
+            self.set_writer(writer);
+        }
+    }
+
+    /// Attach a connection, it becomes the writer if there is none and it can write.
+    ///
+    /// The writer is only known by its connection id, the peer id is claimed by the peer. A
+    /// reconnecting peer gets the control when its stale connection is detached and it is the
+    /// earliest attached participant, or when the control is transferred to it.
+    fn attach(
+        &mut self,
+        conn_id: i32,
+        peer_id: String,
+        name: String,
+        profile: Option<Arc<Profile>>,
+    ) {
+        if self.participant(conn_id).is_some() {
+            return;
+        }
+        self.participants.push(Participant {
+            conn_id,
+            peer_id: peer_id.clone(),
+            name,
+            needs_session_sync: true,
+            profile,
+            responses: Vec::new(),
+            participants_changed: true,
+        });
+        self.assign_writer();
+        log::info!(
+            "Connection {} ({}) attached to terminal service {}, writer: {:?}",
+            conn_id,
+            peer_id,
+            self.service_id,
+            self.writer
+        );
+        self.send_participants();
+    }
+
+    /// Detach a connection, the control goes to the earliest attached participant if it was the writer.
+    fn detach(&mut self, conn_id: i32) {
+        let Some(pos) = self.participants.iter().position(|p| p.conn_id == conn_id) else {
+            return;
+        };
+        self.participants.remove(pos);
         for session in self.sessions.values() {
-            let mut session = session.lock().unwrap();
-            session.is_opened = false;
+            session.lock().unwrap().viewers.remove(&conn_id);
+        }
+        if self.is_writer(conn_id) {
+            self.set_writer(None);
+            self.assign_writer();
+        }
+        log::info!(
+            "Connection {} detached from terminal service {}, writer: {:?}",
+            conn_id,
+            self.service_id,
+            self.writer
+        );
+        self.send_participants();
+    }
+
+    fn transfer_control(&mut self, conn_id: i32, to: i32) -> Result<()> {
+        if !self.is_writer(conn_id) {
+            return Err(anyhow!(
+                "Only the controlling participant can transfer the control"
+            ));
+        }
+        if self.participant(to).is_none() {
+            return Err(anyhow!("Participant {} is not attached", to));
+        }
+        if !self.can_write(to) {
+            return Err(anyhow!(
+                "Participant {} is restricted to a shell which some terminals do not run",
+                to
+            ));
+        }
+        log::info!(
+            "Terminal service {} control transferred from {} to {}",
+            self.service_id,
+            conn_id,
+            to
+        );
+        self.set_writer(Some(to));
+        self.send_participants();
+        Ok(())
+    }
+
+    // Send the participant list to everyone, by `participants_message()`.
+    fn send_participants(&mut self) {
+        for p in self.participants.iter_mut() {
+            p.participants_changed = true;
+        }
+    }
+
+    /// The participant list with the receiver's own connection id, if it changed since it was sent.
+    fn participants_message(&mut self, conn_id: i32) -> Option<Message> {
+        let p = self.participant(conn_id)?;
+        if !std::mem::take(&mut p.participants_changed) {
+            return None;
+        }
+        let participants = self
+            .participants
+            .iter()
+            .map(|p| crate::terminal_control::Participant {
+                conn_id: p.conn_id,
+                peer_id: p.peer_id.clone(),
+                name: p.name.clone(),
+                is_writer: self.writer == Some(p.conn_id),
+            })
+            .collect();
+        Some(
+            crate::terminal_control::ControlMessage::Participants {
+                participants,
+                my_conn_id: conn_id,
+            }
+            .to_message(),
+        )
+    }
+
+    fn queue_response(&mut self, conn_ids: &[i32], response: &TerminalResponse) {
+        for p in self.participants.iter_mut() {
+            if conn_ids.contains(&p.conn_id) {
+                p.responses.push(response.clone());
+            }
         }
     }
 }
 
 pub struct TerminalServiceProxy {
     service_id: String,
+    conn_id: i32,
     is_persistent: bool,
     #[cfg(target_os = "windows")]
     user_token: Option<UserToken>,
@@ -641,6 +1036,7 @@ pub fn set_persistent(service_id: &str, is_persistent: bool) -> Result<()> {
 impl TerminalServiceProxy {
     pub fn new(
         service_id: String,
+        conn_id: i32,
         is_persistent: Option<bool>,
         _user_token: Option<UserToken>,
     ) -> Self {
@@ -653,6 +1049,7 @@ impl TerminalServiceProxy {
             });
         TerminalServiceProxy {
             service_id,
+            conn_id,
             is_persistent,
             #[cfg(target_os = "windows")]
             user_token: _user_token,
@@ -680,22 +1077,17 @@ impl TerminalServiceProxy {
                 self.handle_open(&mut service.lock().unwrap(), open)
             }
             Some(terminal_action::Union::Resize(resize)) => {
-                let session = service
-                    .lock()
-                    .unwrap()
-                    .sessions
-                    .get(&resize.terminal_id)
-                    .cloned();
+                let Some(session) = self.writable_session(&service, resize.terminal_id) else {
+                    return Ok(None);
+                };
                 self.handle_resize(session, resize)
             }
             Some(terminal_action::Union::Data(data)) => {
-                let session = service
-                    .lock()
-                    .unwrap()
-                    .sessions
-                    .get(&data.terminal_id)
-                    .cloned();
-                self.handle_data(session, data)
+                let Some(session) = self.writable_session(&service, data.terminal_id) else {
+                    return Ok(None);
+                };
+                let restriction = service.lock().unwrap().restriction(self.conn_id);
+                self.handle_data(session, data, restriction)
             }
             Some(terminal_action::Union::Close(close)) => {
                 self.handle_close(&mut service.lock().unwrap(), close)
@@ -704,49 +1096,87 @@ impl TerminalServiceProxy {
         }
     }
 
+    // The session if this connection is the writer, the input of the observers is ignored.
+    fn writable_session(
+        &self,
+        service: &Mutex<PersistentTerminalService>,
+        terminal_id: i32,
+    ) -> Option<Arc<Mutex<TerminalSession>>> {
+        let service = service.lock().unwrap();
+        if !service.is_writer(self.conn_id) {
+            log::debug!(
+                "Ignore the input of observer {} to terminal {}",
+                self.conn_id,
+                terminal_id
+            );
+            return None;
+        }
+        service.sessions.get(&terminal_id).cloned()
+    }
+
     fn handle_open(
         &self,
         service: &mut PersistentTerminalService,
         open: &OpenTerminal,
     ) -> Result<Option<TerminalResponse>> {
         let mut response = TerminalResponse::new();
+        let needs_session_sync = service
+            .participant(self.conn_id)
+            .map(|p| std::mem::replace(&mut p.needs_session_sync, false))
+            .unwrap_or_default();
 
         // Check if terminal already exists
         if let Some(session_arc) = service.sessions.get(&open.terminal_id) {
-            // Reconnect to existing terminal
+            // Reconnect to existing terminal, or observe it
             let mut session = session_arc.lock().unwrap();
-            session.is_opened = true;
             let mut opened = TerminalOpened::new();
             opened.terminal_id = open.terminal_id;
             opened.success = true;
             opened.message = "Reconnected to existing terminal".to_string();
             opened.pid = session.pid;
             opened.service_id = self.service_id.clone();
-            if service.needs_session_sync {
-                if service.sessions.len() > 1 {
-                    // No need to include the current terminal in the list.
-                    // Because the `persistent_sessions` is used to restore the other sessions.
-                    opened.persistent_sessions = service
-                        .sessions
-                        .keys()
-                        .filter(|&id| *id != open.terminal_id)
-                        .cloned()
-                        .collect();
-                }
-                service.needs_session_sync = false;
+            if needs_session_sync && service.sessions.len() > 1 {
+                // No need to include the current terminal in the list.
+                // Because the `persistent_sessions` is used to restore the other sessions.
+                opened.persistent_sessions = service
+                    .sessions
+                    .keys()
+                    .filter(|&id| *id != open.terminal_id)
+                    .cloned()
+                    .collect();
             }
             response.set_opened(opened);
 
-            // Send buffered output
-            let buffer = session.output_buffer.get_recent(4096);
-            if !buffer.is_empty() {
-                // We'll need to send this separately or extend the protocol
-                // For now, just acknowledge the reconnection
+            // The buffered output is sent by `read_outputs()` after the opened response,
+            // the output not read from the channel yet follows it.
+            let buffer = session.output_buffer.get_recent(replay_size());
+            log::info!(
+                "Replay {} bytes of terminal {}",
+                buffer.len(),
+                open.terminal_id
+            );
+            let replay = split_replay(&buffer)
+                .into_iter()
+                .map(|chunk| chunk.to_vec())
+                .collect();
+            session.viewers.insert(self.conn_id, replay);
+            if let Some(recorder) = session.recorder.as_mut() {
+                recorder.write_marker("reconnected");
             }
 
             return Ok(Some(response));
         }
 
+        // No one could write to the closed terminals.
+        service.assign_writer();
+        if !service.is_writer(self.conn_id) {
+            let mut error = TerminalError::new();
+            error.terminal_id = open.terminal_id;
+            error.message = "Only the controlling participant can open a new terminal".to_string();
+            response.set_error(error);
+            return Ok(Some(response));
+        }
+
         // Create new terminal session
         log::info!(
             "Creating new terminal {} for service: {}",
@@ -767,12 +1197,19 @@ impl TerminalServiceProxy {
         let pty_system = portable_pty::native_pty_system();
         let pty_pair = pty_system.openpty(pty_size).context("Failed to open PTY")?;
 
-        // Use default shell for the platform
-        let shell = get_default_shell();
-        log::debug!("Using shell: {}", shell);
+        // Use the shell of the terminal policy, or the default shell for the platform
+        session.shell = match service
+            .restriction(self.conn_id)
+            .filter(|(profile, _)| !profile.shell.is_empty())
+        {
+            Some((profile, _)) => profile_shell(&profile),
+            None => vec![get_default_shell()],
+        };
+        let shell = session.shell[0].clone();
+        log::debug!("Using shell: {:?}", session.shell);
 
-        #[allow(unused_mut)]
         let mut cmd = CommandBuilder::new(&shell);
+        cmd.args(&session.shell[1..]);
 
         #[cfg(target_os = "windows")]
         if let Some(token) = &self.user_token {
@@ -796,6 +1233,8 @@ impl TerminalServiceProxy {
             .context("Failed to get reader")?;
 
         session.pid = child.process_id().unwrap_or(0) as u32;
+        session.recorder =
+            new_cast_recorder(open.terminal_id, open.rows as u16, open.cols as u16, &shell);
 
         // Create channels for input/output
         let (input_tx, input_rx) = mpsc::sync_channel::<Vec<u8>>(CHANNEL_BUFFER_SIZE);
@@ -885,7 +1324,6 @@ impl TerminalServiceProxy {
         session.output_rx = Some(output_rx);
         session.reader_thread = Some(reader_thread);
         session.writer_thread = Some(writer_thread);
-        session.is_opened = true;
 
         let mut opened = TerminalOpened::new();
         opened.terminal_id = open.terminal_id;
@@ -893,11 +1331,23 @@ impl TerminalServiceProxy {
         opened.message = "Terminal opened".to_string();
         opened.pid = session.pid;
         opened.service_id = service.service_id.clone();
-        if service.needs_session_sync {
-            if !service.sessions.is_empty() {
-                opened.persistent_sessions = service.sessions.keys().cloned().collect();
-            }
-            service.needs_session_sync = false;
+
+        // The observers follow the terminals opened by the writer.
+        for conn_id in service.participants.iter().map(|p| p.conn_id) {
+            session.viewers.insert(conn_id, Vec::new());
+        }
+        let others: Vec<i32> = session
+            .viewers
+            .keys()
+            .filter(|&id| *id != self.conn_id)
+            .cloned()
+            .collect();
+        let mut observer_response = TerminalResponse::new();
+        observer_response.set_opened(opened.clone());
+        service.queue_response(&others, &observer_response);
+
+        if needs_session_sync && !service.sessions.is_empty() {
+            opened.persistent_sessions = service.sessions.keys().cloned().collect();
         }
         response.set_opened(opened);
 
@@ -925,6 +1375,9 @@ impl TerminalServiceProxy {
             session.update_activity();
             session.rows = resize.rows as u16;
             session.cols = resize.cols as u16;
+            if let Some(recorder) = session.recorder.as_mut() {
+                recorder.write_resize(resize.cols as u16, resize.rows as u16);
+            }
 
             if let Some(pty_pair) = &session.pty_pair {
                 pty_pair.master.resize(PtySize {
@@ -942,13 +1395,57 @@ impl TerminalServiceProxy {
         &self,
         session: Option<Arc<Mutex<TerminalSession>>>,
         data: &TerminalData,
+        restriction: Option<(Arc<Profile>, String)>,
     ) -> Result<Option<TerminalResponse>> {
+        let mut response = None;
         if let Some(session_arc) = session {
             let mut session = session_arc.lock().unwrap();
             session.update_activity();
+            if let Some(recorder) = session.recorder.as_mut() {
+                recorder.write_input(&data.data);
+            }
+            let input = match restriction {
+                Some((profile, peer_id)) => {
+                    let (input, blocked) = session.command_line.filter(&profile, &data.data);
+                    let mut notice = String::new();
+                    for blocked in blocked {
+                        log::warn!(
+                            "Blocked command of {} in terminal {} by the policy profile {}: {}, {}",
+                            peer_id,
+                            data.terminal_id,
+                            profile.name,
+                            blocked.line,
+                            blocked.reason
+                        );
+                        if let Some(recorder) = session.recorder.as_mut() {
+                            recorder.write_marker(&format!("blocked: {}", blocked.line));
+                        }
+                        Connection::post_alarm_audit(
+                            AlarmAuditType::TerminalCommand,
+                            json!({
+                                "id": &peer_id,
+                                "conn_id": self.conn_id,
+                                "terminal_id": data.terminal_id,
+                                "profile": &profile.name,
+                                "command": &blocked.line,
+                                "reason": &blocked.reason,
+                            }),
+                        );
+                        notice.push_str(&format!(
+                            "\r\n\x1b[31mBlocked by the terminal policy: {}\x1b[0m\r\n",
+                            blocked.reason
+                        ));
+                    }
+                    if !notice.is_empty() {
+                        response = Some(make_data_response(data.terminal_id, notice.into_bytes()));
+                    }
+                    input
+                }
+                None => data.data.to_vec(),
+            };
             if let Some(input_tx) = &session.input_tx {
                 // Send data to writer thread
-                if let Err(e) = input_tx.send(data.data.to_vec()) {
+                if let Err(e) = input_tx.send(input) {
                     log::error!(
                         "Failed to send data to terminal {}: {}",
                         data.terminal_id,
@@ -958,7 +1455,7 @@ impl TerminalServiceProxy {
             }
         }
 
-        Ok(None)
+        Ok(response)
     }
 
     fn handle_close(
@@ -968,6 +1465,18 @@ impl TerminalServiceProxy {
     ) -> Result<Option<TerminalResponse>> {
         let mut response = TerminalResponse::new();
 
+        if !service.is_writer(self.conn_id) {
+            // An observer only stops watching the terminal.
+            let Some(session_arc) = service.sessions.get(&close.terminal_id) else {
+                return Ok(None);
+            };
+            session_arc.lock().unwrap().viewers.remove(&self.conn_id);
+            let mut closed = TerminalClosed::new();
+            closed.terminal_id = close.terminal_id;
+            response.set_closed(closed);
+            return Ok(Some(response));
+        }
+
         // Always close and remove the terminal
         if let Some(session_arc) = service.sessions.remove(&close.terminal_id) {
             let mut session = session_arc.lock().unwrap();
@@ -983,6 +1492,13 @@ impl TerminalServiceProxy {
             closed.terminal_id = close.terminal_id;
             closed.exit_code = exit_code;
             response.set_closed(closed);
+            let others: Vec<i32> = session
+                .viewers
+                .keys()
+                .filter(|&id| *id != self.conn_id)
+                .cloned()
+                .collect();
+            service.queue_response(&others, &response);
             Ok(Some(response))
         } else {
             Ok(None)
@@ -1008,6 +1524,7 @@ impl TerminalServiceProxy {
         };
 
         let mut responses = Vec::new();
+        // The terminal ids and the other connections which opened them
         let mut closed_terminals = Vec::new();
 
         // Process each session with its own lock
@@ -1027,12 +1544,13 @@ impl TerminalServiceProxy {
                 // Because the `reader_thread` is joined in `stop()`,
                 // and `stop()` is called before the session is dropped.
                 if should_send_closed {
-                    closed_terminals.push(terminal_id);
-                }
-
-                if !session.is_opened {
-                    // Skip the session if it is not opened.
-                    continue;
+                    let others: Vec<i32> = session
+                        .viewers
+                        .keys()
+                        .filter(|&id| *id != self.conn_id)
+                        .cloned()
+                        .collect();
+                    closed_terminals.push((terminal_id, others));
                 }
 
                 // Read from output channel
@@ -1049,42 +1567,37 @@ impl TerminalServiceProxy {
                 // Update buffer after reading
                 for data in &received_data {
                     session.output_buffer.append(data);
-                }
-
-                // Process received data for responses
-                for data in received_data {
-                    let mut response = TerminalResponse::new();
-                    let mut terminal_data = TerminalData::new();
-                    terminal_data.terminal_id = terminal_id;
-
-                    // Compress data if it exceeds threshold
-                    if data.len() > COMPRESS_THRESHOLD {
-                        let compressed = compress::compress(&data);
-                        if compressed.len() < data.len() {
-                            terminal_data.data = bytes::Bytes::from(compressed);
-                            terminal_data.compressed = true;
-                        } else {
-                            // Compression didn't help, send uncompressed
-                            terminal_data.data = bytes::Bytes::from(data);
-                        }
-                    } else {
-                        terminal_data.data = bytes::Bytes::from(data);
+                    if let Some(recorder) = session.recorder.as_mut() {
+                        recorder.write_output(data);
                     }
-
-                    response.set_data(terminal_data);
-                    responses.push(response);
+                }
+                if should_send_closed {
+                    // Finish the recording, the reader thread has exited.
+                    session.recorder = None;
                 }
 
                 if has_activity {
                     session.update_activity();
                 }
+
+                // The output is only buffered if no connection opened the terminal,
+                // to replay it on reconnect.
+                // The reader thread drops the output if the channel is full.
+                for pending in session.viewers.values_mut() {
+                    pending.extend(received_data.iter().cloned());
+                }
+                if let Some(pending) = session.viewers.get_mut(&self.conn_id) {
+                    for data in pending.drain(..) {
+                        responses.push(make_data_response(terminal_id, data));
+                    }
+                }
             }
         }
 
         // Clean up closed terminals (requires service lock briefly)
         if !closed_terminals.is_empty() {
             let mut sessions = service.lock().unwrap().sessions.clone();
-            for terminal_id in closed_terminals {
+            for (terminal_id, others) in closed_terminals {
                 let mut exit_code = 0;
 
                 if !self.is_persistent {
@@ -1119,13 +1632,30 @@ impl TerminalServiceProxy {
                 closed.terminal_id = terminal_id;
                 closed.exit_code = exit_code;
                 response.set_closed(closed);
+                service.lock().unwrap().queue_response(&others, &response);
                 responses.push(response);
             }
         }
 
+        // The responses caused by the other participants
+        if let Some(p) = service.lock().unwrap().participant(self.conn_id) {
+            responses.append(&mut p.responses);
+        }
+
         responses
     }
 
+    /// Hand the control to the participant `to`, the new participant list is sent to everyone by
+    /// the service.
+    pub fn transfer_control(&self, to: i32) -> Result<()> {
+        let Some(service) = get_service(&self.service_id) else {
+            return Err(anyhow!("Terminal service {} not found", self.service_id));
+        };
+        let mut service = service.lock().unwrap();
+        service.update_activity();
+        service.transfer_control(self.conn_id, to)
+    }
+
     /// Cleanup when connection drops
     pub fn on_disconnect(&self) {
         if !self.is_persistent {
@@ -1134,3 +1664,127 @@ impl TerminalServiceProxy {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{terminal_policy::ProfileConfig, *};
+
+    #[test]
+    fn test_get_recent() {
+        let mut buffer = OutputBuffer::new();
+        buffer.append(b"first line\r\n");
+        buffer.append("\x1b[31m\u{4f60}\u{597d}\x1b[0m\r\n".as_bytes());
+        buffer.append(b"prompt$ ");
+        assert_eq!(buffer.get_recent(0), b"");
+        assert_eq!(buffer.get_recent(1024).len(), buffer.total_size);
+        // Whole lines which fit
+        let line = "\x1b[31m\u{4f60}\u{597d}\x1b[0m\r\n".as_bytes();
+        let mut expected = line.to_vec();
+        expected.extend_from_slice(b"prompt$ ");
+        assert_eq!(buffer.get_recent(expected.len()), expected);
+        // Cut inside a line: skip the escape sequence and the UTF-8 character
+        assert_eq!(
+            buffer.get_recent(expected.len() + 6),
+            [b"line\r\n".as_slice(), expected.as_slice()].concat()
+        );
+        assert_eq!(
+            buffer.get_recent(8 + 15),
+            line[5..]
+                .iter()
+                .chain(b"prompt$ ")
+                .copied()
+                .collect::<Vec<u8>>()
+        );
+        assert_eq!(
+            buffer.get_recent(8 + 10),
+            line[8..]
+                .iter()
+                .chain(b"prompt$ ")
+                .copied()
+                .collect::<Vec<u8>>()
+        );
+    }
+
+    #[test]
+    fn test_participants() {
+        let mut service =
+            PersistentTerminalService::new("ts_test".to_owned(), false, false, vec![]);
+        service.attach(1, "a".to_owned(), "A".to_owned(), None);
+        service.attach(2, "b".to_owned(), "B".to_owned(), None);
+        service.attach(3, "c".to_owned(), "C".to_owned(), None);
+        assert!(service.is_writer(1));
+        // Only the writer can transfer the control, to an attached participant.
+        assert!(service.transfer_control(2, 3).is_err());
+        assert!(service.transfer_control(1, 4).is_err());
+        assert!(service.transfer_control(1, 3).is_ok());
+        assert!(service.is_writer(3));
+        // The earliest attached participant takes the control when the writer leaves.
+        service.detach(3);
+        assert!(service.is_writer(1));
+        // A peer claiming the id of the writer does not take the control.
+        service.attach(4, "a".to_owned(), "A".to_owned(), None);
+        assert!(service.is_writer(1));
+        service.detach(1);
+        assert!(service.is_writer(2));
+        let msg = service.participants_message(2).unwrap();
+        let Some(message::Union::Misc(misc)) = msg.union else {
+            panic!("not an extension message");
+        };
+        let crate::terminal_control::ControlMessage::Participants {
+            participants,
+            my_conn_id,
+        } = crate::terminal_control::ControlMessage::parse(&misc.plugin_request().content).unwrap()
+        else {
+            panic!("not the participants");
+        };
+        assert_eq!(my_conn_id, 2);
+        assert_eq!(
+            participants
+                .iter()
+                .map(|p| (p.conn_id, p.is_writer))
+                .collect::<Vec<_>>(),
+            vec![(2, true), (4, false)]
+        );
+        // Sent once until the list changes.
+        assert!(service.participants_message(2).is_none());
+        assert!(service.participants_message(4).is_some());
+    }
+
+    #[test]
+    fn test_restricted_shell() {
+        let profile = Arc::new(Profile::new(&ProfileConfig {
+            name: "support".to_owned(),
+            shell: "/usr/local/bin/support-shell".to_owned(),
+            shell_args: vec!["--restricted".to_owned()],
+            ..Default::default()
+        }));
+        let mut service =
+            PersistentTerminalService::new("ts_test".to_owned(), false, false, vec![]);
+        let mut session = TerminalSession::new(1, 24, 80);
+        session.shell = vec![get_default_shell()];
+        service.sessions.insert(1, Arc::new(Mutex::new(session)));
+        // A restricted participant does not control the terminal of the default shell.
+        service.attach(1, "a".to_owned(), "A".to_owned(), Some(profile.clone()));
+        assert_eq!(service.writer, None);
+        service.attach(2, "b".to_owned(), "B".to_owned(), None);
+        assert!(service.is_writer(2));
+        assert!(service.transfer_control(2, 1).is_err());
+        // It does if all the terminals run its shell.
+        service.sessions.get(&1).unwrap().lock().unwrap().shell = profile_shell(&profile);
+        assert!(service.transfer_control(2, 1).is_ok());
+        assert!(service.is_writer(1));
+        service.detach(1);
+        assert!(service.is_writer(2));
+    }
+
+    #[test]
+    fn test_safe_cut_position() {
+        let data = "a\x1b]0;title\x07b\u{4f60}".as_bytes();
+        assert_eq!(safe_cut_position(data, 0), 0);
+        assert_eq!(safe_cut_position(data, 2), 11);
+        assert_eq!(safe_cut_position(data, 12), 12);
+        assert_eq!(safe_cut_position(data, 13), data.len());
+        let data = b"\x1b]8;;url\x1b\\link";
+        assert_eq!(safe_cut_position(data, 3), 10);
+    }
+}
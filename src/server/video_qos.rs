@@ -1,7 +1,7 @@
 use super::*;
 use scrap::codec::{Quality, BR_BALANCED, BR_BEST, BR_SPEED};
 use std::{
-    collections::VecDeque,
+    collections::{HashSet, VecDeque},
     time::{Duration, Instant},
 };
 
@@ -11,7 +11,7 @@ a. new user connected =>set to INIT_FPS
 b. TestDelay receive => update user's fps according to network delay
     When network delay < DELAY_THRESHOLD_150MS, set minimum fps according to image quality, and increase fps;
     When network delay >= DELAY_THRESHOLD_150MS, set minimum fps according to image quality, and decrease fps;
-c. second timeout / TestDelay receive => update real fps to the minimum fps from all users
+c. second timeout / TestDelay receive => update real fps of each tier to the minimum fps of its users
 
 ratio adjust:
 a. user set image quality => update to the maximum ratio of the latest quality
@@ -25,6 +25,16 @@ adjust betwen FPS and ratio:
 
 delay:
     use delay minus RTT as the actual network delay
+
+low bandwidth:
+    When the ratio stays at the minimum and network delay >= DELAY_THRESHOLD_300MS for LOW_BANDWIDTH_ENTER, switch to the tile codec;
+    When network delay < 50ms for LOW_BANDWIDTH_LEAVE, switch back to the video codecs;
+
+tiers:
+    The users are grouped in up to MAX_TIERS tiers, each tier has its own fps and ratio, and its own encoder in the video service.
+    Every ratio adjustment, the users with network delay >= DELAY_THRESHOLD_300MS in a tier with a user < DELAY_THRESHOLD_150MS move to the next tier;
+    When a tier's network delay < DELAY_THRESHOLD_150MS for TIER_MERGE_DURATION and its fps and ratio catch up with the tier above, merge them;
+    With one user, or if an encoder can not change quality or run twice, there is only the main tier;
 */
 
 // Constants
@@ -43,6 +53,12 @@ const HISTORY_DELAY_LEN: usize = 2;
 const ADJUST_RATIO_INTERVAL: usize = 3; // Adjust quality ratio every 3 seconds
 const DYNAMIC_SCREEN_THRESHOLD: usize = 2; // Allow increase quality ratio if encode more than 2 times in one second
 const DELAY_THRESHOLD_150MS: u32 = 150; // 150ms is the threshold for good network condition
+const DELAY_THRESHOLD_300MS: u32 = 300; // 300ms at the minimum ratio, the video codecs can not keep up
+const LOW_BANDWIDTH_ENTER: Duration = Duration::from_secs(15);
+const LOW_BANDWIDTH_LEAVE: Duration = Duration::from_secs(60);
+const MAX_TIERS: usize = 3;
+const TIER_MERGE_DURATION: Duration = Duration::from_secs(30);
+const TIER_MERGE_RATIO: f32 = 0.9; // Merge if the ratio is at least 90% of the tier above
 
 #[derive(Default, Debug, Clone)]
 struct UserDelay {
@@ -93,12 +109,24 @@ struct UserData {
     quality: Option<(i64, Quality)>, // (time, quality)
     delay: UserDelay,
     record: bool,
+    tier: usize, // 0 is the main tier
 }
 
 #[derive(Default, Debug, Clone)]
 struct DisplayData {
     send_counter: usize, // Number of times encode during period
     support_changing_quality: bool,
+    support_tiers: bool,
+}
+
+// A tier below the main one, the main tier uses the fps, ratio and bitrate of VideoQoS
+#[derive(Debug, Clone)]
+struct TierData {
+    fps: u32,
+    ratio: f32,
+    bitrate: u32,
+    // Since when the network delay of all users is good, to merge with the tier above
+    good_instant: Option<Instant>,
 }
 
 // Main QoS controller structure
@@ -111,6 +139,11 @@ pub struct VideoQoS {
     adjust_ratio_instant: Instant,
     abr_config: bool,
     new_user_instant: Instant,
+    low_bandwidth: bool,
+    // Since when the network is slow (or fast if low_bandwidth), to switch the codec
+    low_bandwidth_instant: Option<Instant>,
+    tiers: Vec<TierData>, // tiers[0] is tier 1
+    tiers_version: usize,
 }
 
 impl Default for VideoQoS {
@@ -124,6 +157,10 @@ impl Default for VideoQoS {
             adjust_ratio_instant: Instant::now(),
             abr_config: true,
             new_user_instant: Instant::now(),
+            low_bandwidth: false,
+            low_bandwidth_instant: None,
+            tiers: Default::default(),
+            tiers_version: 0,
         }
     }
 }
@@ -192,8 +229,17 @@ impl VideoQoS {
     // Clean up user session
     pub fn on_connection_close(&mut self, id: i32) {
         self.users.remove(&id);
+        self.update_transfer_backoff();
         if self.users.is_empty() {
+            if self.low_bandwidth {
+                scrap::codec::Encoder::set_low_bandwidth(false);
+            }
+            let tiers_version = self.tiers_version;
             *self = Default::default();
+            // Keep increasing, the video services may still compare with it
+            self.tiers_version = tiers_version;
+        } else if self.remove_empty_tiers() {
+            self.tiers_version += 1;
         }
     }
 
@@ -260,13 +306,15 @@ impl VideoQoS {
         let dividend_ms = DELAY_THRESHOLD_150MS * min_fps;
 
         let mut adjust_ratio = false;
+        let tier = self.users.get(&id).map(|u| u.tier).unwrap_or_default();
+        let tier_fps = self.current_tier_fps(tier);
         if let Some(user) = self.users.get_mut(&id) {
             let delay = delay.max(10);
             let old_avg_delay = user.delay.avg_delay();
             user.delay.add_delay(delay);
             let mut avg_delay = user.delay.avg_delay();
             avg_delay = avg_delay.max(10);
-            let mut fps = self.fps;
+            let mut fps = tier_fps;
 
             // Adaptive FPS adjustment based on network delay:
             if avg_delay < 50 {
@@ -331,6 +379,7 @@ impl VideoQoS {
             //Reduce the possibility of vaapi being created twice
             self.adjust_ratio(false);
         }
+        self.update_transfer_backoff();
     }
 
     pub fn user_delay_response_elapsed(&mut self, id: i32, elapsed: u128) {
@@ -339,9 +388,21 @@ impl VideoQoS {
             if user.delay.response_delayed {
                 user.delay.add_delay(elapsed as u32);
                 self.adjust_fps();
+                self.update_transfer_backoff();
             }
         }
     }
+
+    // The file transfers back off by the highest delay of the users
+    fn update_transfer_backoff(&self) {
+        let delay = self
+            .users
+            .values()
+            .filter(|u| !u.delay.delay_history.is_empty())
+            .map(|u| u.delay.avg_delay())
+            .max();
+        crate::transfer_limit::on_network_delay(crate::transfer_limit::LOCAL, delay);
+    }
 }
 
 // Common adjust functions
@@ -370,9 +431,11 @@ impl VideoQoS {
                 self.displays.iter_mut().for_each(|d| {
                     d.1.send_counter = 0;
                 });
+                self.adjust_tiers();
                 self.adjust_ratio(dynamic_screen);
             }
         } else {
+            self.adjust_tiers();
             self.ratio = self.latest_quality().ratio();
         }
     }
@@ -412,21 +475,40 @@ impl VideoQoS {
             .1
     }
 
-    // Adjust quality ratio based on network delay and screen changes
+    // Adjust quality ratio of every tier based on network delay and screen changes
     fn adjust_ratio(&mut self, dynamic_screen: bool) {
-        if !self.in_vbr_state() {
+        if !self.in_vbr_state() || self.users.is_empty() {
             return;
         }
-        // Get maximum delay from all users
-        let max_delay = self.users.iter().map(|u| u.1.delay.avg_delay()).max();
-        let Some(max_delay) = max_delay else {
-            return;
-        };
+        for tier in 0..self.tier_count() {
+            // Get maximum delay from the users of the tier
+            let Some(max_delay) = self.tier_max_delay(tier) else {
+                continue;
+            };
+            if tier == 0 {
+                let (ratio, min) =
+                    self.next_ratio(self.ratio, self.bitrate(), max_delay, dynamic_screen);
+                self.ratio = ratio;
+                self.adjust_low_bandwidth(max_delay, ratio <= min);
+            } else {
+                let t = &self.tiers[tier - 1];
+                let (ratio, _) = self.next_ratio(t.ratio, t.bitrate, max_delay, dynamic_screen);
+                self.tiers[tier - 1].ratio = ratio;
+            }
+        }
+        self.adjust_ratio_instant = Instant::now();
+    }
 
+    // Returns the next ratio of a tier and its minimum
+    fn next_ratio(
+        &self,
+        current_ratio: f32,
+        current_bitrate: u32,
+        max_delay: u32,
+        dynamic_screen: bool,
+    ) -> (f32, f32) {
         let target_quality = self.latest_quality();
         let target_ratio = self.latest_quality().ratio();
-        let current_ratio = self.ratio;
-        let current_bitrate = self.bitrate();
 
         // Calculate minimum ratio for high resolution (1Mbps baseline)
         let ratio_1mbps = if current_bitrate > 0 {
@@ -503,22 +585,54 @@ impl VideoQoS {
             }
         }
 
-        self.ratio = v.clamp(min, max);
-        self.adjust_ratio_instant = Instant::now();
+        (v.clamp(min, max), min)
+    }
+
+    // Switch to the tile codec if the video codecs are too slow even at the minimum ratio
+    fn adjust_low_bandwidth(&mut self, max_delay: u32, min_ratio: bool) {
+        let (keep, duration) = if self.low_bandwidth {
+            (max_delay >= 50, LOW_BANDWIDTH_LEAVE)
+        } else {
+            (
+                max_delay < DELAY_THRESHOLD_300MS || !min_ratio,
+                LOW_BANDWIDTH_ENTER,
+            )
+        };
+        if keep {
+            self.low_bandwidth_instant = None;
+            return;
+        }
+        let since = *self.low_bandwidth_instant.get_or_insert_with(Instant::now);
+        if since.elapsed() >= duration {
+            self.low_bandwidth = !self.low_bandwidth;
+            self.low_bandwidth_instant = None;
+            log::info!(
+                "low bandwidth: {}, max delay: {}ms",
+                self.low_bandwidth,
+                max_delay
+            );
+            scrap::codec::Encoder::set_low_bandwidth(self.low_bandwidth);
+        }
     }
 
     // Adjust fps based on network delay and user response time
     fn adjust_fps(&mut self) {
+        self.fps = self.tier_fps(0);
+        for tier in 1..self.tier_count() {
+            self.tiers[tier - 1].fps = self.tier_fps(tier);
+        }
+    }
+
+    fn tier_fps(&self, tier: usize) -> u32 {
         let highest_fps = self.highest_fps();
-        // Get minimum fps from all users
-        let mut fps = self
-            .users
-            .iter()
-            .map(|u| u.1.delay.fps.unwrap_or(INIT_FPS))
+        let users = || self.users.values().filter(|u| u.tier == tier);
+        // Get minimum fps from the users of the tier
+        let mut fps = users()
+            .map(|u| u.delay.fps.unwrap_or(INIT_FPS))
             .min()
             .unwrap_or(INIT_FPS);
 
-        if self.users.iter().any(|u| u.1.delay.response_delayed) {
+        if users().any(|u| u.delay.response_delayed) {
             if fps > MIN_FPS + 1 {
                 fps = MIN_FPS + 1;
             }
@@ -532,7 +646,190 @@ impl VideoQoS {
         }
 
         // Ensure fps stays within valid range
-        self.fps = fps.clamp(MIN_FPS, highest_fps);
+        fps.clamp(MIN_FPS, highest_fps)
+    }
+}
+
+// Tiers
+impl VideoQoS {
+    pub fn set_support_tiers(&mut self, video_service_name: &str, support: bool) {
+        if let Some(display) = self.displays.get_mut(video_service_name) {
+            display.support_tiers = support;
+        }
+    }
+
+    fn tiers_enabled(&self) -> bool {
+        self.in_vbr_state()
+            && !self.displays.is_empty()
+            && self.displays.iter().all(|e| e.1.support_tiers)
+    }
+
+    // Number of tiers, including the main tier
+    pub fn tier_count(&self) -> usize {
+        self.tiers.len() + 1
+    }
+
+    // Increased when users move between tiers, the encoders of the tiers need to be recreated
+    pub fn tiers_version(&self) -> usize {
+        self.tiers_version
+    }
+
+    pub fn tier_users(&self, tier: usize) -> HashSet<i32> {
+        self.users
+            .iter()
+            .filter(|u| u.1.tier == tier)
+            .map(|u| *u.0)
+            .collect()
+    }
+
+    pub fn tier_spf(&self, tier: usize) -> Duration {
+        Duration::from_secs_f32(1. / (self.current_tier_fps(tier) as f32))
+    }
+
+    pub fn tier_ratio(&mut self, tier: usize) -> f32 {
+        match tier.checked_sub(1).and_then(|i| self.tiers.get_mut(i)) {
+            Some(t) => {
+                if t.ratio < BR_MIN_HIGH_RESOLUTION || t.ratio > BR_MAX {
+                    t.ratio = BR_BALANCED;
+                }
+                t.ratio
+            }
+            None => self.ratio(),
+        }
+    }
+
+    pub fn store_tier_bitrate(&mut self, tier: usize, bitrate: u32) {
+        match tier.checked_sub(1).and_then(|i| self.tiers.get_mut(i)) {
+            Some(t) => t.bitrate = bitrate,
+            None => self.store_bitrate(bitrate),
+        }
+    }
+
+    // Bitrate of the stream the user receives
+    pub fn user_bitrate(&self, id: i32) -> u32 {
+        let tier = self.users.get(&id).map(|u| u.tier).unwrap_or_default();
+        match tier.checked_sub(1).and_then(|i| self.tiers.get(i)) {
+            Some(t) => t.bitrate,
+            None => self.bitrate(),
+        }
+    }
+
+    fn current_tier_fps(&self, tier: usize) -> u32 {
+        match tier.checked_sub(1).and_then(|i| self.tiers.get(i)) {
+            Some(t) if t.fps >= MIN_FPS && t.fps <= MAX_FPS => t.fps,
+            _ => self.fps(),
+        }
+    }
+
+    fn tier_max_delay(&self, tier: usize) -> Option<u32> {
+        self.users
+            .values()
+            .filter(|u| u.tier == tier)
+            .map(|u| u.delay.avg_delay())
+            .max()
+    }
+
+    // Move the slow users to a lower tier, or merge a tier with the one above
+    fn adjust_tiers(&mut self) {
+        let mut moves: Vec<(i32, usize)> = vec![];
+        if !self.tiers_enabled() || self.users.len() < 2 {
+            moves.extend(
+                self.users
+                    .iter()
+                    .filter(|u| u.1.tier != 0)
+                    .map(|u| (*u.0, 0)),
+            );
+        } else {
+            for tier in 1..self.tier_count() {
+                let max_delay = self.tier_max_delay(tier).unwrap_or_default();
+                let (upper_fps, upper_ratio) = if tier == 1 {
+                    (self.fps, self.ratio)
+                } else {
+                    (self.tiers[tier - 2].fps, self.tiers[tier - 2].ratio)
+                };
+                let t = &mut self.tiers[tier - 1];
+                if max_delay >= DELAY_THRESHOLD_150MS {
+                    t.good_instant = None;
+                    continue;
+                }
+                let since = *t.good_instant.get_or_insert_with(Instant::now);
+                if since.elapsed() >= TIER_MERGE_DURATION
+                    && t.fps >= upper_fps
+                    && t.ratio >= upper_ratio * TIER_MERGE_RATIO
+                {
+                    moves.extend(
+                        self.users
+                            .iter()
+                            .filter(|u| u.1.tier == tier)
+                            .map(|u| (*u.0, tier - 1)),
+                    );
+                }
+            }
+            if moves.is_empty() {
+                for tier in 0..(self.tier_count().min(MAX_TIERS - 1)) {
+                    let users = || self.users.iter().filter(|u| u.1.tier == tier);
+                    let min_delay = users().map(|u| u.1.delay.avg_delay()).min();
+                    // If all users of the tier are slow, the ratio of the tier is decreased for all of them.
+                    if min_delay.unwrap_or(DELAY_THRESHOLD_150MS) >= DELAY_THRESHOLD_150MS {
+                        continue;
+                    }
+                    moves.extend(
+                        users()
+                            .filter(|u| u.1.delay.avg_delay() >= DELAY_THRESHOLD_300MS)
+                            .map(|u| (*u.0, tier + 1)),
+                    );
+                }
+            }
+        }
+        if moves.is_empty() {
+            return;
+        }
+        for (id, tier) in moves {
+            while self.tier_count() <= tier {
+                // Start from the half of the ratio of the tier above, the network of its users is slow
+                let ratio = self.tier_ratio(self.tier_count() - 1);
+                self.tiers.push(TierData {
+                    fps: INIT_FPS,
+                    ratio: (ratio / 2.0).max(BR_MIN_HIGH_RESOLUTION),
+                    bitrate: 0,
+                    good_instant: None,
+                });
+            }
+            if let Some(user) = self.users.get_mut(&id) {
+                log::info!("move user {} from tier {} to tier {}", id, user.tier, tier);
+                user.tier = tier;
+            }
+        }
+        self.remove_empty_tiers();
+        self.tiers_version += 1;
+        self.adjust_fps();
+    }
+
+    // Returns true if the tier of any user is changed
+    fn remove_empty_tiers(&mut self) -> bool {
+        let mut changed = false;
+        for tier in (0..self.tier_count()).rev() {
+            if self.users.values().any(|u| u.tier == tier) {
+                continue;
+            }
+            if tier == 0 {
+                if self.tiers.is_empty() {
+                    break;
+                }
+                // Promote tier 1 to the main tier
+                let t = self.tiers.remove(0);
+                self.fps = t.fps;
+                self.ratio = t.ratio;
+                self.bitrate_store = t.bitrate;
+            } else {
+                self.tiers.remove(tier - 1);
+            }
+            for u in self.users.values_mut().filter(|u| u.tier > tier) {
+                u.tier -= 1;
+                changed = true;
+            }
+        }
+        changed
     }
 }
 
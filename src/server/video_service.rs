@@ -47,7 +47,11 @@ use scrap::Capturer;
 use scrap::{
     aom::AomEncoderConfig,
     codec::{Encoder, EncoderCfg},
-    record::{Recorder, RecorderContext},
+    record::{RecordAudioFormat, Recorder, RecorderContext},
+    record_crypt,
+    record_index::RecordEvent,
+    region::CaptureRect,
+    tile::TileEncoderConfig,
     vpxcodec::{VpxEncoderConfig, VpxVideoCodecId},
     CodecFormat, Display, EncodeInput, TraitCapturer, TraitPixelBuffer,
 };
@@ -71,6 +75,59 @@ lazy_static::lazy_static! {
     pub static ref IS_UAC_RUNNING: Arc<Mutex<bool>> = Default::default();
     pub static ref IS_FOREGROUND_WINDOW_ELEVATED: Arc<Mutex<bool>> = Default::default();
     static ref SCREENSHOTS: Mutex<HashMap<usize, Screenshot>> = Default::default();
+    // conn id -> (display index, region)
+    static ref CAPTURE_REGIONS: Mutex<HashMap<i32, (usize, CaptureRegion)>> = Default::default();
+    // display index -> the origin of the sent part of the display
+    static ref CAPTURE_OFFSETS: Mutex<HashMap<usize, (i32, i32)>> = Default::default();
+    static ref RECORDERS: Mutex<Recorders> = Default::default();
+}
+
+// The recorders of the incoming sessions, written by the audio service and the connections.
+#[derive(Default)]
+struct Recorders {
+    format: Option<RecordAudioFormat>,
+    recorders: Vec<std::sync::Weak<Mutex<Option<Recorder>>>>,
+}
+
+impl Recorders {
+    fn for_each(&mut self, f: impl Fn(&mut Recorder)) {
+        self.recorders.retain(|r| {
+            let Some(r) = r.upgrade() else {
+                return false;
+            };
+            if let Some(r) = r.lock().unwrap().as_mut() {
+                f(r);
+            }
+            true
+        });
+    }
+}
+
+pub fn set_record_audio_format(sample_rate: u32, channels: u16) {
+    let format = RecordAudioFormat {
+        sample_rate,
+        channels,
+    };
+    let mut lock = RECORDERS.lock().unwrap();
+    if lock.format != Some(format) {
+        lock.format = Some(format);
+        lock.for_each(|r| r.set_audio_format(format));
+    }
+}
+
+pub fn write_record_audio(data: &[u8]) {
+    let mut lock = RECORDERS.lock().unwrap();
+    if !lock.recorders.is_empty() {
+        lock.for_each(|r| r.write_audio(data));
+    }
+}
+
+/// Add an event to the index of the recordings of the incoming sessions.
+pub fn write_record_event(event: RecordEvent) {
+    let mut lock = RECORDERS.lock().unwrap();
+    if !lock.recorders.is_empty() {
+        lock.for_each(|r| r.write_event(&event));
+    }
 }
 
 struct Screenshot {
@@ -79,6 +136,119 @@ struct Screenshot {
     restore_vram: bool,
 }
 
+/// The part of a display requested by a controller. It is only captured while all the viewers of
+/// the display requested the same region, the others see the whole display.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaptureRegion {
+    Rect(CaptureRect),
+    // A top-level window, tracked as it moves. The other windows over it are captured too.
+    Window(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CaptureArea {
+    Display,
+    Rect(CaptureRect),
+    // The window is closed, minimized or out of the display, nothing is sent.
+    Hidden,
+}
+
+impl CaptureArea {
+    fn size(&self, c: &CapturerInfo) -> Option<(usize, usize)> {
+        match self {
+            CaptureArea::Display => Some((c.width, c.height)),
+            CaptureArea::Rect(rect) => Some((rect.width, rect.height)),
+            CaptureArea::Hidden => None,
+        }
+    }
+}
+
+fn get_capture_area(display_idx: usize, c: &CapturerInfo, conn_ids: &[i32]) -> CaptureArea {
+    let region = {
+        let regions = CAPTURE_REGIONS.lock().unwrap();
+        let mut requested = conn_ids.iter().map(|id| match regions.get(id) {
+            Some((idx, region)) if *idx == display_idx => Some(*region),
+            _ => None,
+        });
+        match requested.next().flatten() {
+            Some(region) if requested.all(|r| r == Some(region)) => region,
+            _ => return CaptureArea::Display,
+        }
+    };
+    let rect = match region {
+        CaptureRegion::Rect(rect) => Some(rect),
+        // The window is in the coordinates of the display origin, scaled to the pixels.
+        CaptureRegion::Window(window) => get_window_rect(window).map(|(x, y, w, h)| CaptureRect {
+            x: ((x - c.origin.0) as f64 * c.scale) as _,
+            y: ((y - c.origin.1) as f64 * c.scale) as _,
+            width: (w.max(0) as f64 * c.scale) as _,
+            height: (h.max(0) as f64 * c.scale) as _,
+        }),
+    };
+    match rect.and_then(|r| r.clamp(c.width, c.height)) {
+        Some(rect) if (rect.width, rect.height) == (c.width, c.height) => CaptureArea::Display,
+        Some(rect) => CaptureArea::Rect(rect),
+        None => CaptureArea::Hidden,
+    }
+}
+
+#[cfg(any(windows, target_os = "linux", target_os = "macos"))]
+fn get_window_rect(window: u64) -> Option<(i32, i32, i32, i32)> {
+    crate::platform::get_window_rect(window)
+}
+
+#[cfg(not(any(windows, target_os = "linux", target_os = "macos")))]
+fn get_window_rect(_window: u64) -> Option<(i32, i32, i32, i32)> {
+    None
+}
+
+fn set_capture_offset(display_idx: usize, area: &CaptureArea) {
+    let mut offsets = CAPTURE_OFFSETS.lock().unwrap();
+    match area {
+        CaptureArea::Rect(rect) => {
+            offsets.insert(display_idx, (rect.x, rect.y));
+        }
+        CaptureArea::Display => {
+            offsets.remove(&display_idx);
+        }
+        // Nothing is sent, the viewers keep the last frame.
+        CaptureArea::Hidden => {}
+    }
+}
+
+/// The origin of the part of the display sent to the viewers, in pixels, None if the whole
+/// display is sent. The mouse events and the cursor positions of the viewers are moved by it.
+pub fn get_capture_offset(display_idx: usize) -> Option<(i32, i32)> {
+    CAPTURE_OFFSETS.lock().unwrap().get(&display_idx).cloned()
+}
+
+/// Move a mouse event of a viewer of the region to the display.
+pub fn on_capture_mouse_event(e: &mut MouseEvent, display_idx: usize) {
+    let evt_type = e.mask & 0x7;
+    if evt_type == crate::input::MOUSE_TYPE_WHEEL || evt_type == crate::input::MOUSE_TYPE_TRACKPAD {
+        return;
+    }
+    if let Some((x, y)) = get_capture_offset(display_idx) {
+        e.x += x;
+        e.y += y;
+    }
+}
+
+/// The cursor position in the region, None if the whole display is sent.
+pub fn on_capture_cursor_pos(pos: &CursorPosition, display_idx: usize) -> Option<Message> {
+    let (x, y) = get_capture_offset(display_idx)?;
+    let mut pos = pos.clone();
+    pos.x -= x;
+    pos.y -= y;
+    let mut msg = Message::new();
+    msg.set_cursor_position(pos);
+    Some(msg)
+}
+
+pub fn is_capture_window(window: u64) -> bool {
+    get_window_rect(window).is_some()
+}
+
 #[inline]
 pub fn notify_video_frame_fetched(conn_id: i32, frame_tm: Option<Instant>) {
     FRAME_FETCHED_NOTIFIER.0.send((conn_id, frame_tm)).ok();
@@ -134,6 +304,104 @@ impl VideoFrameController {
     }
 }
 
+// The encoder of a tier below the main one, for the viewers with a slow network.
+// The frames of the main encoder are sent to the other viewers.
+struct TierEncoder {
+    tier: usize,
+    encoder: Encoder,
+    conn_ids: HashSet<i32>,
+    ratio: f32,
+    spf: Duration,
+    last_send: Option<Instant>,
+    encode_fail_counter: usize,
+    first_frame: bool,
+}
+
+impl TierEncoder {
+    fn encode(
+        &mut self,
+        display: usize,
+        sp: &GenericService,
+        yuv: &[u8],
+        ms: i64,
+        now: Instant,
+        width: usize,
+        height: usize,
+    ) -> ResultType<()> {
+        if let Some(last_send) = self.last_send {
+            if now.saturating_duration_since(last_send) < self.spf {
+                return Ok(());
+            }
+        }
+        let conn_ids = &self.conn_ids;
+        let send_conn_ids = handle_one_frame(
+            display,
+            sp,
+            EncodeInput::YUV(yuv),
+            ms,
+            &mut self.encoder,
+            None,
+            &mut self.encode_fail_counter,
+            &mut self.first_frame,
+            width,
+            height,
+            |id| conn_ids.contains(&id),
+        )?;
+        if !send_conn_ids.is_empty() {
+            self.last_send = Some(now);
+        }
+        Ok(())
+    }
+}
+
+// Create the encoders of the tiers below the main one, returns them with the tiers version.
+fn setup_tiers(
+    encoder_cfg: &EncoderCfg,
+    use_i444: bool,
+    support_tiers: bool,
+) -> (Vec<TierEncoder>, usize) {
+    let mut video_qos = VIDEO_QOS.lock().unwrap();
+    let tiers_version = video_qos.tiers_version();
+    let mut tiers = vec![];
+    if !support_tiers {
+        return (tiers, tiers_version);
+    }
+    for tier in 1..video_qos.tier_count() {
+        let ratio = video_qos.tier_ratio(tier);
+        let encoder = Encoder::new(encoder_cfg.clone(), use_i444).and_then(|mut encoder| {
+            encoder.set_quality(ratio)?;
+            Ok(encoder)
+        });
+        match encoder {
+            Ok(encoder) => {
+                video_qos.store_tier_bitrate(tier, encoder.bitrate());
+                tiers.push(TierEncoder {
+                    tier,
+                    encoder,
+                    conn_ids: video_qos.tier_users(tier),
+                    ratio,
+                    spf: video_qos.tier_spf(tier),
+                    last_send: None,
+                    encode_fail_counter: 0,
+                    first_frame: true,
+                });
+            }
+            // The users of the tier receive the frames of the main encoder
+            Err(e) => log::error!("Failed to create the encoder of tier {tier}: {e:?}"),
+        }
+    }
+    if !tiers.is_empty() {
+        log::info!(
+            "tiers: {:?}",
+            tiers
+                .iter()
+                .map(|t| (t.tier, &t.conn_ids, t.ratio))
+                .collect::<Vec<_>>()
+        );
+    }
+    (tiers, tiers_version)
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum VideoSource {
     Monitor,
@@ -297,6 +565,8 @@ pub(super) struct CapturerInfo {
     pub origin: (i32, i32),
     pub width: usize,
     pub height: usize,
+    // Pixels per unit of the origin, the origin is in points on macOS.
+    pub scale: f64,
     pub ndisplay: usize,
     pub current: usize,
     pub privacy_mode_id: i32,
@@ -352,6 +622,10 @@ fn get_capturer_monitor(
     }
 
     let (origin, width, height) = (display.origin(), display.width(), display.height());
+    #[cfg(target_os = "macos")]
+    let scale = display.scale();
+    #[cfg(not(target_os = "macos"))]
+    let scale = 1.0;
     let name = display.name();
     log::debug!(
         "#displays={}, current={}, origin: {:?}, width={}, height={}, cpus={}/{}, name:{}",
@@ -404,6 +678,7 @@ fn get_capturer_monitor(
         origin,
         width,
         height,
+        scale,
         ndisplay,
         current,
         privacy_mode_id,
@@ -445,6 +720,7 @@ fn get_capturer_camera(current: usize) -> ResultType<CapturerInfo> {
         origin,
         width,
         height,
+        scale: 1.0,
         ndisplay: ncamera,
         current,
         privacy_mode_id,
@@ -502,8 +778,21 @@ fn run(vs: VideoService) -> ResultType<()> {
     );
     let client_record = video_qos.record();
     drop(video_qos);
+    let mut capture_area = if vs.source.is_monitor() {
+        get_capture_area(display_idx, &c, &sp.subscriber_ids())
+    } else {
+        CaptureArea::Display
+    };
+    if vs.source.is_monitor() {
+        set_capture_offset(display_idx, &capture_area);
+    }
+    let (capture_width, capture_height) = capture_area.size(&c).unwrap_or((c.width, c.height));
+    if capture_area != CaptureArea::Display {
+        log::info!("capture area: {:?}", capture_area);
+    }
     let (mut encoder, encoder_cfg, codec_format, use_i444, recorder) = match setup_encoder(
         &c,
+        (capture_width, capture_height),
         sp.name(),
         quality,
         client_record,
@@ -524,6 +813,7 @@ fn run(vs: VideoService) -> ResultType<()> {
             }));
             setup_encoder(
                 &c,
+                (capture_width, capture_height),
                 sp.name(),
                 quality,
                 client_record,
@@ -549,6 +839,16 @@ fn run(vs: VideoService) -> ResultType<()> {
         .unwrap()
         .set_support_changing_quality(&sp.name(), encoder.support_changing_quality());
     log::info!("initial quality: {quality:?}");
+    // Hardware encoders are limited in number, and textures can not be shared between encoders
+    let support_tiers = encoder.support_changing_quality() && !encoder.is_hardware();
+    #[cfg(feature = "vram")]
+    let support_tiers = support_tiers && !encoder.input_texture();
+    VIDEO_QOS
+        .lock()
+        .unwrap()
+        .set_support_tiers(&sp.name(), support_tiers);
+    let (mut tiers, tiers_version) = setup_tiers(&encoder_cfg, use_i444, support_tiers);
+    let tier_conn_ids: HashSet<i32> = tiers.iter().flat_map(|t| t.conn_ids.clone()).collect();
 
     if sp.is_option_true(OPTION_REFRESH) {
         sp.set_option_bool(OPTION_REFRESH, false);
@@ -573,8 +873,6 @@ fn run(vs: VideoService) -> ResultType<()> {
     let repeat_encode_max = 10;
     let mut encode_fail_counter = 0;
     let mut first_frame = true;
-    let capture_width = c.width;
-    let capture_height = c.height;
     let (mut second_instant, mut send_counter) = (Instant::now(), 0);
 
     while sp.ok() {
@@ -584,6 +882,8 @@ fn run(vs: VideoService) -> ResultType<()> {
             &mut encoder,
             &mut quality,
             &mut spf,
+            &mut tiers,
+            tiers_version,
             client_record,
             &mut send_counter,
             &mut second_instant,
@@ -596,6 +896,19 @@ fn run(vs: VideoService) -> ResultType<()> {
             log::info!("switch to refresh");
             bail!("SWITCH");
         }
+        if vs.source.is_monitor() {
+            let area = get_capture_area(display_idx, &c, &sp.subscriber_ids());
+            if let Some(size) = area.size(&c) {
+                if size != (capture_width, capture_height) {
+                    log::info!("switch due to capture area changed: {:?}", area);
+                    bail!("SWITCH");
+                }
+            }
+            if area != capture_area {
+                set_capture_offset(display_idx, &area);
+                capture_area = area;
+            }
+        }
         if codec_format != Encoder::negotiated_codec() {
             log::info!(
                 "switch due to codec changed, {:?} -> {:?}",
@@ -645,7 +958,7 @@ fn run(vs: VideoService) -> ResultType<()> {
         let res = match c.frame(spf) {
             Ok(frame) => {
                 repeat_encode_counter = 0;
-                if frame.valid() {
+                if frame.valid() && capture_area != CaptureArea::Hidden {
                     let screenshot = SCREENSHOTS.lock().unwrap().remove(&display_idx);
                     if let Some(mut screenshot) = screenshot {
                         let restore_vram = screenshot.restore_vram;
@@ -689,19 +1002,36 @@ fn run(vs: VideoService) -> ResultType<()> {
                         }
                     }
 
-                    let frame = frame.to(encoder.yuvfmt(), &mut yuv, &mut mid_data)?;
+                    let frame = match &capture_area {
+                        CaptureArea::Rect(rect) => {
+                            frame.to_region(encoder.yuvfmt(), rect, &mut yuv, &mut mid_data)?
+                        }
+                        _ => frame.to(encoder.yuvfmt(), &mut yuv, &mut mid_data)?,
+                    };
                     let send_conn_ids = handle_one_frame(
                         display_idx,
                         &sp,
                         frame,
                         ms,
                         &mut encoder,
-                        recorder.clone(),
+                        Some(recorder.clone()),
                         &mut encode_fail_counter,
                         &mut first_frame,
                         capture_width,
                         capture_height,
+                        |id| !tier_conn_ids.contains(&id),
                     )?;
+                    for tier in tiers.iter_mut() {
+                        tier.encode(
+                            display_idx,
+                            &sp,
+                            &yuv,
+                            ms,
+                            now,
+                            capture_width,
+                            capture_height,
+                        )?;
+                    }
                     frame_controller.set_send(now, send_conn_ids);
                     send_counter += 1;
                 }
@@ -745,7 +1075,7 @@ fn run(vs: VideoService) -> ResultType<()> {
                         }
                     }
                 }
-                if !encoder.latency_free() && yuv.len() > 0 {
+                if !encoder.latency_free() && yuv.len() > 0 && capture_area != CaptureArea::Hidden {
                     // yun.len() > 0 means the frame is not texture.
                     if repeat_encode_counter < repeat_encode_max {
                         repeat_encode_counter += 1;
@@ -755,12 +1085,24 @@ fn run(vs: VideoService) -> ResultType<()> {
                             EncodeInput::YUV(&yuv),
                             ms,
                             &mut encoder,
-                            recorder.clone(),
+                            Some(recorder.clone()),
                             &mut encode_fail_counter,
                             &mut first_frame,
                             capture_width,
                             capture_height,
+                            |id| !tier_conn_ids.contains(&id),
                         )?;
+                        for tier in tiers.iter_mut() {
+                            tier.encode(
+                                display_idx,
+                                &sp,
+                                &yuv,
+                                ms,
+                                now,
+                                capture_width,
+                                capture_height,
+                            )?;
+                        }
                         frame_controller.set_send(now, send_conn_ids);
                         send_counter += 1;
                     }
@@ -845,6 +1187,7 @@ impl Drop for Raii {
 
 fn setup_encoder(
     c: &CapturerInfo,
+    size: (usize, usize),
     name: String,
     quality: f32,
     client_record: bool,
@@ -861,6 +1204,7 @@ fn setup_encoder(
 )> {
     let encoder_cfg = get_encoder_config(
         &c,
+        size,
         name.to_string(),
         quality,
         client_record || record_incoming,
@@ -877,15 +1221,24 @@ fn setup_encoder(
 
 fn get_encoder_config(
     c: &CapturerInfo,
+    (width, height): (usize, usize),
     _name: String,
     quality: f32,
     record: bool,
     _portable_service: bool,
     _source: VideoSource,
 ) -> EncoderCfg {
+    // A texture can not be cropped.
+    #[cfg(all(windows, feature = "vram"))]
+    let cropped = (width, height) != (c.width, c.height);
     #[cfg(all(windows, feature = "vram"))]
-    if _portable_service || c.is_gdi() || _source == VideoSource::Camera {
-        log::info!("gdi:{}, portable:{}", c.is_gdi(), _portable_service);
+    if _portable_service || c.is_gdi() || _source == VideoSource::Camera || cropped {
+        log::info!(
+            "gdi:{}, portable:{}, cropped:{}",
+            c.is_gdi(),
+            _portable_service,
+            cropped
+        );
         VRamEncoder::set_not_use(_name, true);
     }
     #[cfg(feature = "vram")]
@@ -899,8 +1252,8 @@ fn get_encoder_config(
             if let Some(feature) = VRamEncoder::try_get(&c.device(), negotiated_codec) {
                 return EncoderCfg::VRAM(VRamEncoderConfig {
                     device: c.device(),
-                    width: c.width,
-                    height: c.height,
+                    width,
+                    height,
                     quality,
                     feature,
                     keyframe_interval,
@@ -911,23 +1264,23 @@ fn get_encoder_config(
                 return EncoderCfg::HWRAM(HwRamEncoderConfig {
                     name: hw.name,
                     mc_name: hw.mc_name,
-                    width: c.width,
-                    height: c.height,
+                    width,
+                    height,
                     quality,
                     keyframe_interval,
                 });
             }
             EncoderCfg::VPX(VpxEncoderConfig {
-                width: c.width as _,
-                height: c.height as _,
+                width: width as _,
+                height: height as _,
                 quality,
                 codec: VpxVideoCodecId::VP9,
                 keyframe_interval,
             })
         }
         format @ (CodecFormat::VP8 | CodecFormat::VP9) => EncoderCfg::VPX(VpxEncoderConfig {
-            width: c.width as _,
-            height: c.height as _,
+            width: width as _,
+            height: height as _,
             quality,
             codec: if format == CodecFormat::VP8 {
                 VpxVideoCodecId::VP8
@@ -937,14 +1290,20 @@ fn get_encoder_config(
             keyframe_interval,
         }),
         CodecFormat::AV1 => EncoderCfg::AOM(AomEncoderConfig {
-            width: c.width as _,
-            height: c.height as _,
+            width: width as _,
+            height: height as _,
             quality,
             keyframe_interval,
         }),
+        // The recording needs a video stream.
+        CodecFormat::Tile if !record => EncoderCfg::TILE(TileEncoderConfig {
+            width: width as _,
+            height: height as _,
+            quality,
+        }),
         _ => EncoderCfg::VPX(VpxEncoderConfig {
-            width: c.width as _,
-            height: c.height as _,
+            width: width as _,
+            height: height as _,
             quality,
             codec: VpxVideoCodecId::VP9,
             keyframe_interval,
@@ -952,34 +1311,60 @@ fn get_encoder_config(
     }
 }
 
+// The context of the incoming recordings, with the directory, the upload and the encryption
+// of the options.
+pub fn incoming_record_context(display_idx: usize, camera: bool) -> ResultType<RecorderContext> {
+    use crate::hbbs_http::record_upload;
+
+    #[cfg(windows)]
+    let root = crate::platform::is_root();
+    #[cfg(not(windows))]
+    let root = false;
+    let tx = if record_upload::is_enable() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        record_upload::run(rx);
+        Some(tx)
+    } else {
+        None
+    };
+    // Do not record in plain text if the key is invalid.
+    let public_key =
+        record_crypt::public_key_option(&Config::get_option(record_crypt::OPTION_PUBLIC_KEY))?;
+    Ok(RecorderContext {
+        server: true,
+        id: Config::get_id(),
+        dir: crate::ui_interface::video_save_directory(root),
+        display_idx,
+        camera,
+        multi_display: Config::get_option("record-displays-in-one-file") == "Y",
+        public_key,
+        tx,
+    })
+}
+
 fn get_recorder(
     record_incoming: bool,
     display_idx: usize,
     camera: bool,
 ) -> Arc<Mutex<Option<Recorder>>> {
-    #[cfg(windows)]
-    let root = crate::platform::is_root();
-    #[cfg(not(windows))]
-    let root = false;
     let recorder = if record_incoming {
-        use crate::hbbs_http::record_upload;
-
-        let tx = if record_upload::is_enable() {
-            let (tx, rx) = std::sync::mpsc::channel();
-            record_upload::run(rx);
-            Some(tx)
-        } else {
-            None
-        };
-        Recorder::new(RecorderContext {
-            server: true,
-            id: Config::get_id(),
-            dir: crate::ui_interface::video_save_directory(root),
-            display_idx,
-            camera,
-            tx,
-        })
-        .map_or(Default::default(), |r| Arc::new(Mutex::new(Some(r))))
+        incoming_record_context(display_idx, camera)
+            .and_then(Recorder::new)
+            .map_or_else(
+                |e| {
+                    log::error!("Failed to record: {}", e);
+                    Default::default()
+                },
+                |mut r| {
+                    let mut lock = RECORDERS.lock().unwrap();
+                    if let Some(format) = lock.format {
+                        r.set_audio_format(format);
+                    }
+                    let r = Arc::new(Mutex::new(Some(r)));
+                    lock.recorders.push(Arc::downgrade(&r));
+                    r
+                },
+            )
     } else {
         Default::default()
     };
@@ -1053,11 +1438,12 @@ fn handle_one_frame(
     frame: EncodeInput,
     ms: i64,
     encoder: &mut Encoder,
-    recorder: Arc<Mutex<Option<Recorder>>>,
+    recorder: Option<Arc<Mutex<Option<Recorder>>>>,
     encode_fail_counter: &mut usize,
     first_frame: &mut bool,
     width: usize,
     height: usize,
+    to: impl Fn(i32) -> bool,
 ) -> ResultType<HashSet<i32>> {
     sp.snapshot(|sps| {
         // so that new sub and old sub share the same encoder after switch
@@ -1077,12 +1463,14 @@ fn handle_one_frame(
             vf.display = display as _;
             let mut msg = Message::new();
             msg.set_video_frame(vf);
-            recorder
-                .lock()
-                .unwrap()
-                .as_mut()
-                .map(|r| r.write_message(&msg, width, height));
-            send_conn_ids = sp.send_video_frame(msg);
+            if let Some(recorder) = recorder {
+                recorder
+                    .lock()
+                    .unwrap()
+                    .as_mut()
+                    .map(|r| r.write_message(&msg, width, height));
+            }
+            send_conn_ids = sp.send_video_frame_to(msg, to);
         }
         Err(e) => {
             *encode_fail_counter += 1;
@@ -1232,6 +1620,8 @@ fn check_qos(
     encoder: &mut Encoder,
     ratio: &mut f32,
     spf: &mut Duration,
+    tiers: &mut [TierEncoder],
+    tiers_version: usize,
     client_record: bool,
     send_counter: &mut usize,
     second_instant: &mut Instant,
@@ -1256,6 +1646,19 @@ fn check_qos(
         log::info!("switch due to record changed");
         bail!("SWITCH");
     }
+    if tiers_version != video_qos.tiers_version() {
+        log::info!("switch due to tiers changed");
+        bail!("SWITCH");
+    }
+    for tier in tiers.iter_mut() {
+        tier.spf = video_qos.tier_spf(tier.tier);
+        let ratio = video_qos.tier_ratio(tier.tier);
+        if tier.ratio != ratio {
+            tier.ratio = ratio;
+            allow_err!(tier.encoder.set_quality(ratio));
+            video_qos.store_tier_bitrate(tier.tier, tier.encoder.bitrate());
+        }
+    }
     if second_instant.elapsed() > Duration::from_secs(1) {
         *second_instant = Instant::now();
         video_qos.update_display_data(&name, *send_counter);
@@ -1276,6 +1679,35 @@ pub fn set_take_screenshot(display_idx: usize, sid: String, tx: Sender) {
     );
 }
 
+/// Capture only `region` of the display for the connection, or the whole display if it is None.
+pub fn set_capture_region(conn_id: i32, display_idx: usize, region: Option<CaptureRegion>) {
+    let mut lock = CAPTURE_REGIONS.lock().unwrap();
+    match region {
+        Some(region) => {
+            log::info!("capture region of display {display_idx}: {region:?}, by {conn_id}");
+            lock.insert(conn_id, (display_idx, region));
+        }
+        None => {
+            if lock.remove(&conn_id).is_some() {
+                log::info!("capture the whole display {display_idx}, by {conn_id}");
+            }
+        }
+    }
+}
+
+/// Remove the region requested by a closed connection.
+pub fn clear_capture_region(conn_id: i32) {
+    CAPTURE_REGIONS.lock().unwrap().remove(&conn_id);
+}
+
+/// The visible top-level windows, to choose the window of a region.
+pub fn get_capture_windows() -> Vec<scrap::region::WindowInfo> {
+    #[cfg(any(windows, target_os = "linux", target_os = "macos"))]
+    return crate::platform::get_windows();
+    #[cfg(not(any(windows, target_os = "linux", target_os = "macos")))]
+    return vec![];
+}
+
 // We need to this function, because the `stride` may be larger than `width * 4`.
 fn get_rgba_from_pixelbuf<'a>(pixbuf: &scrap::PixelBuffer<'a>) -> ResultType<Vec<u8>> {
     let w = pixbuf.width();
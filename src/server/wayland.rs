@@ -277,6 +277,7 @@ pub(super) fn get_capturer() -> ResultType<super::video_service::CapturerInfo> {
                 origin: rect.0,
                 width: rect.1,
                 height: rect.2,
+                scale: 1.0,
                 ndisplay: cap_display_info.num,
                 current: cap_display_info.current,
                 privacy_mode_id: 0,
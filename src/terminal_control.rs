@@ -0,0 +1,67 @@
+// Control of a terminal service shared by several connections, see `server::terminal_service`.
+//
+// The participants are not in the terminal messages of the protocol, they are extension messages
+// (see `crate::common::new_ext_message`) with the id `EXT_ID`, the JSON of a `ControlMessage`:
+// - `participants`, sent by the controlled side to every participant when the list changes.
+// - `transfer_control`, sent by the writer to hand the control to another participant.
+
+use hbb_common::{message_proto::Message, ResultType};
+use serde_derive::{Deserialize, Serialize};
+
+pub const EXT_ID: &str = "@rustdesk/terminal-control";
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Participant {
+    pub conn_id: i32,
+    pub peer_id: String,
+    pub name: String,
+    pub is_writer: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlMessage {
+    Participants {
+        participants: Vec<Participant>,
+        my_conn_id: i32,
+    },
+    TransferControl {
+        conn_id: i32,
+    },
+}
+
+impl ControlMessage {
+    pub fn to_message(&self) -> Message {
+        crate::common::new_ext_message(EXT_ID, serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    pub fn parse(data: &[u8]) -> ResultType<Self> {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let messages = vec![
+            ControlMessage::Participants {
+                participants: vec![Participant {
+                    conn_id: 1,
+                    peer_id: "123456789".to_owned(),
+                    name: "a".to_owned(),
+                    is_writer: true,
+                }],
+                my_conn_id: 1,
+            },
+            ControlMessage::TransferControl { conn_id: 2 },
+        ];
+        for m in messages {
+            let data = serde_json::to_vec(&m).unwrap();
+            assert_eq!(ControlMessage::parse(&data).unwrap(), m);
+        }
+        assert!(ControlMessage::parse(br#"{"type":"transfer_control"}"#).is_err());
+    }
+}
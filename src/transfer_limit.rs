@@ -0,0 +1,371 @@
+// Rate limiting of the file transfer blocks sent by a session.
+//
+// Options, changes are applied within a second:
+// - `file-transfer-max-kbps`: cap shared by all the sessions of this process, empty or 0 for none.
+// - `file-transfer-session-max-kbps`: default cap of one session, it can be changed at runtime by
+//   [`TransferLimiter::set_session_kbps`].
+// - `file-transfer-limit-hours`: `HH:MM-HH:MM` in local time, the caps only apply within these
+//   hours, e.g. `08:00-18:00`, `22:00-06:00` wraps around midnight. Empty for always.
+//
+// The shared cap is split among the sessions which are sending, by the weight of their priority.
+// All the sessions also back off while the network delay of the video streams rises, see
+// [`on_network_delay`].
+//
+// Blocks are throttled by their sender only, the receiver can not slow a peer down without
+// stalling the whole connection, video included. So the controlled side feeds the delay smoothed
+// by its `VideoQoS` and relays it to the file transfer peers with the [`EXT_ID`] extension
+// message, which feed it to their own uploads.
+
+use chrono::{Local, NaiveTime};
+use hbb_common::{config::Config, fs, log, ResultType, Stream};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const OPTION_MAX_KBPS: &str = "file-transfer-max-kbps";
+const OPTION_SESSION_MAX_KBPS: &str = "file-transfer-session-max-kbps";
+const OPTION_LIMIT_HOURS: &str = "file-transfer-limit-hours";
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+// A session is sharing the cap if it has sent within this time.
+const ACTIVE_TIMEOUT: Duration = Duration::from_secs(2);
+const BURST_SECS: f64 = 0.5;
+const DELAY_THRESHOLD_MS: u32 = 150;
+const DELAY_GOOD_MS: u32 = 100;
+const MIN_BACKOFF_RATE: f64 = 32. * 1024.;
+// A relayed delay is dropped if it is not refreshed within this time.
+const DELAY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The extension message relaying the network delay of the video streams, a big endian u32 in ms,
+/// empty once there is no video stream.
+pub const EXT_ID: &str = "@rustdesk/network-delay";
+/// The source of the delay of the local video streams, the relayed ones use the peer id.
+pub const LOCAL: &str = "";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum TransferPriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl TransferPriority {
+    fn weight(&self) -> u32 {
+        match self {
+            TransferPriority::Low => 1,
+            TransferPriority::Normal => 2,
+            TransferPriority::High => 4,
+        }
+    }
+
+    pub fn from_name(s: &str) -> Option<Self> {
+        match s {
+            "low" => Some(TransferPriority::Low),
+            "normal" => Some(TransferPriority::Normal),
+            "high" => Some(TransferPriority::High),
+            _ => None,
+        }
+    }
+}
+
+struct Session {
+    weight: u32,
+    last_active: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Shared {
+    sessions: HashMap<u64, Session>,
+    next_id: u64,
+    // Bytes sent by all the sessions since `window_start`.
+    sent: u64,
+    window_start: Option<Instant>,
+    // Bytes per second of the last window.
+    rate: f64,
+    // Cap in bytes per second while backing off for the video streams.
+    backoff: Option<f64>,
+    // Highest network delay in ms of the last update.
+    delay: u32,
+    // Network delay in ms and when it was fed, by source.
+    delays: HashMap<String, (u32, Instant)>,
+}
+
+impl Shared {
+    fn active_weight(&self) -> u32 {
+        self.sessions
+            .values()
+            .filter(|s| {
+                s.last_active
+                    .map_or(false, |t| t.elapsed() < ACTIVE_TIMEOUT)
+            })
+            .map(|s| s.weight)
+            .sum()
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref SHARED: Mutex<Shared> = Default::default();
+}
+
+fn kbps_option(name: &str) -> Option<u32> {
+    Config::get_option(name)
+        .parse::<u32>()
+        .ok()
+        .filter(|v| *v > 0)
+}
+
+fn parse_hours(s: &str) -> Option<(NaiveTime, NaiveTime)> {
+    let (from, to) = s.split_once('-')?;
+    Some((
+        NaiveTime::parse_from_str(from.trim(), "%H:%M").ok()?,
+        NaiveTime::parse_from_str(to.trim(), "%H:%M").ok()?,
+    ))
+}
+
+fn in_hours(hours: &str, now: NaiveTime) -> bool {
+    if hours.is_empty() {
+        return true;
+    }
+    let Some((from, to)) = parse_hours(hours) else {
+        log::error!("Invalid {}: {}", OPTION_LIMIT_HOURS, hours);
+        return true;
+    };
+    if from <= to {
+        now >= from && now < to
+    } else {
+        now >= from || now < to
+    }
+}
+
+/// Feed the network delay in ms of the video streams of `source`, as smoothed by `VideoQoS`, `None`
+/// once it has no video stream. The file transfers back off while the highest delay rises above
+/// the threshold and recover once it is low again.
+pub fn on_network_delay(source: &str, delay: Option<u32>) {
+    let mut shared = SHARED.lock().unwrap();
+    match delay {
+        Some(delay) => {
+            shared
+                .delays
+                .insert(source.to_owned(), (delay, Instant::now()));
+        }
+        None => {
+            shared.delays.remove(source);
+        }
+    }
+    shared
+        .delays
+        .retain(|_, (_, t)| t.elapsed() < DELAY_TIMEOUT);
+    let Some(delay) = shared.delays.values().map(|(d, _)| *d).max() else {
+        shared.delay = 0;
+        shared.backoff = None;
+        return;
+    };
+    let rising = delay > shared.delay;
+    shared.delay = delay;
+    if shared.active_weight() == 0 {
+        shared.backoff = None;
+        return;
+    }
+    if delay >= DELAY_THRESHOLD_MS && rising {
+        let current = shared.backoff.unwrap_or(shared.rate).min(shared.rate);
+        let backoff = (current / 2.).max(MIN_BACKOFF_RATE);
+        if shared.backoff.is_none() {
+            log::info!(
+                "network delay {}ms, file transfer backs off to {:.0}kB/s",
+                delay,
+                backoff / 1024.
+            );
+        }
+        shared.backoff = Some(backoff);
+    } else if delay < DELAY_GOOD_MS {
+        if let Some(backoff) = shared.backoff {
+            let backoff = backoff * 1.25;
+            // The cap is not reached any more.
+            shared.backoff = if backoff > shared.rate * 2. {
+                None
+            } else {
+                Some(backoff)
+            };
+        }
+    }
+}
+
+/// The delay of the local video streams, to relay to the file transfer peers.
+pub fn local_network_delay() -> Option<u32> {
+    SHARED.lock().unwrap().delays.get(LOCAL).map(|(d, _)| *d)
+}
+
+pub fn encode_delay(delay: Option<u32>) -> Vec<u8> {
+    delay.map(|d| d.to_be_bytes().to_vec()).unwrap_or_default()
+}
+
+pub fn decode_delay(data: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(data.try_into().ok()?))
+}
+
+/// The rate limiter of the blocks sent by one session.
+pub struct TransferLimiter {
+    id: u64,
+    priority: TransferPriority,
+    session_kbps: Option<u32>,
+    // Bytes per second, `None` for no limit.
+    rate: Option<f64>,
+    allowance: f64,
+    last: Instant,
+    last_refresh: Instant,
+}
+
+impl TransferLimiter {
+    pub fn new(priority: TransferPriority) -> Self {
+        let mut shared = SHARED.lock().unwrap();
+        shared.next_id += 1;
+        let id = shared.next_id;
+        shared.sessions.insert(
+            id,
+            Session {
+                weight: priority.weight(),
+                last_active: None,
+            },
+        );
+        drop(shared);
+        let mut limiter = Self {
+            id,
+            priority,
+            session_kbps: kbps_option(OPTION_SESSION_MAX_KBPS),
+            rate: None,
+            allowance: 0.,
+            last: Instant::now(),
+            last_refresh: Instant::now(),
+        };
+        limiter.refresh();
+        limiter
+    }
+
+    /// `None` for no cap.
+    pub fn set_session_kbps(&mut self, kbps: Option<u32>) {
+        self.session_kbps = kbps.filter(|v| *v > 0);
+        self.refresh();
+    }
+
+    pub fn set_priority(&mut self, priority: TransferPriority) {
+        self.priority = priority;
+        if let Some(s) = SHARED.lock().unwrap().sessions.get_mut(&self.id) {
+            s.weight = priority.weight();
+        }
+        self.refresh();
+    }
+
+    fn refresh(&mut self) {
+        self.last_refresh = Instant::now();
+        let in_hours = in_hours(&Config::get_option(OPTION_LIMIT_HOURS), Local::now().time());
+        let shared = SHARED.lock().unwrap();
+        let mut rate: Option<f64> = None;
+        let mut limit = |r: f64| rate = Some(rate.map_or(r, |x| x.min(r)));
+        if in_hours {
+            if let Some(kbps) = self.session_kbps {
+                limit(kbps as f64 * 1024.);
+            }
+            if let Some(kbps) = kbps_option(OPTION_MAX_KBPS) {
+                let weight = self.priority.weight();
+                let total = shared.active_weight().max(weight);
+                limit(kbps as f64 * 1024. * weight as f64 / total as f64);
+            }
+        }
+        if let Some(backoff) = shared.backoff {
+            let total = shared.active_weight().max(1);
+            limit(backoff * self.priority.weight() as f64 / total as f64);
+        }
+        self.rate = rate;
+    }
+
+    /// Whether the next blocks can be sent now.
+    pub fn ready(&mut self) -> bool {
+        if self.last_refresh.elapsed() >= REFRESH_INTERVAL {
+            self.refresh();
+        }
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        let Some(rate) = self.rate else {
+            self.allowance = 0.;
+            return true;
+        };
+        self.allowance = (self.allowance + rate * elapsed).min(rate * BURST_SECS);
+        self.allowance >= 0.
+    }
+
+    pub fn consume(&mut self, bytes: u64) {
+        if bytes == 0 {
+            return;
+        }
+        if self.rate.is_some() {
+            self.allowance -= bytes as f64;
+        }
+        let mut shared = SHARED.lock().unwrap();
+        if let Some(s) = shared.sessions.get_mut(&self.id) {
+            s.last_active = Some(Instant::now());
+        }
+        shared.sent += bytes;
+        let start = *shared.window_start.get_or_insert_with(Instant::now);
+        let elapsed = start.elapsed();
+        if elapsed >= REFRESH_INTERVAL {
+            shared.rate = shared.sent as f64 / elapsed.as_secs_f64();
+            shared.sent = 0;
+            shared.window_start = Some(Instant::now());
+        }
+    }
+}
+
+impl Drop for TransferLimiter {
+    fn drop(&mut self) {
+        SHARED.lock().unwrap().sessions.remove(&self.id);
+    }
+}
+
+/// [`fs::handle_read_jobs`] within the rate of `limiter`.
+pub async fn handle_read_jobs(
+    jobs: &mut Vec<fs::TransferJob>,
+    stream: &mut Stream,
+    limiter: &mut TransferLimiter,
+) -> ResultType<String> {
+    if !limiter.ready() {
+        return Ok(String::new());
+    }
+    let before: HashMap<i32, u64> = jobs.iter().map(|j| (j.id(), j.finished_size())).collect();
+    let log = fs::handle_read_jobs(jobs, stream).await?;
+    let sent = jobs
+        .iter()
+        .map(|j| {
+            j.finished_size()
+                .saturating_sub(before.get(&j.id()).copied().unwrap_or(0))
+        })
+        .sum();
+    limiter.consume(sent);
+    Ok(log)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_hours() {
+        let t = |s: &str| NaiveTime::parse_from_str(s, "%H:%M").unwrap();
+        assert!(in_hours("", t("03:00")));
+        assert!(in_hours("08:00-18:00", t("08:00")));
+        assert!(!in_hours("08:00-18:00", t("18:00")));
+        assert!(in_hours("22:00-06:00", t("23:30")));
+        assert!(in_hours("22:00-06:00", t("05:59")));
+        assert!(!in_hours("22:00-06:00", t("12:00")));
+    }
+
+    #[test]
+    fn test_delay() {
+        assert_eq!(decode_delay(&encode_delay(Some(180))), Some(180));
+        assert_eq!(decode_delay(&encode_delay(None)), None);
+        assert_eq!(decode_delay(&[1, 2]), None);
+    }
+}
@@ -120,6 +120,10 @@ impl SciterConnectionManager {
         crate::ui_cm_interface::switch_permission(id, name, enabled);
     }
 
+    fn set_file_transfer_limit(&self, id: i32, kbps: i32, priority: String) {
+        crate::ui_cm_interface::set_file_transfer_limit(id, kbps.max(0) as _, priority);
+    }
+
     fn close(&self, id: i32) {
         crate::ui_cm_interface::close(id);
     }
@@ -176,6 +180,7 @@ impl sciter::EventHandler for SciterConnectionManager {
         fn quit();
         fn authorize(i32);
         fn switch_permission(i32, String, bool);
+        fn set_file_transfer_limit(i32, i32, String);
         fn send_msg(i32, String);
         fn can_elevate();
         fn elevate_portable(i32);
@@ -6,6 +6,7 @@ use crate::ipc::Connection;
 use crate::{
     clipboard::ClipboardSide,
     ipc::{self, Data},
+    transfer_limit::TransferPriority,
 };
 #[cfg(target_os = "windows")]
 use clipboard::ContextSend;
@@ -309,6 +310,18 @@ pub fn switch_permission(id: i32, name: String, enabled: bool) {
     };
 }
 
+#[inline]
+#[cfg(not(any(target_os = "ios")))]
+pub fn set_file_transfer_limit(id: i32, kbps: u32, priority: String) {
+    let Some(priority) = TransferPriority::from_name(&priority) else {
+        log::error!("Invalid file transfer priority: {}", priority);
+        return;
+    };
+    if let Some(client) = CLIENTS.read().unwrap().get(&id) {
+        allow_err!(client.tx.send(Data::FileTransferLimit { kbps, priority }));
+    };
+}
+
 #[inline]
 #[cfg(target_os = "android")]
 pub fn switch_permission_all(name: String, enabled: bool) {
@@ -350,6 +363,7 @@ impl<T: InvokeUiCM> IpcTaskRunner<T> {
 
         // for tmp use, without real conn id
         let mut write_jobs: Vec<fs::TransferJob> = Vec::new();
+        let uploads = crate::file_delta::Uploads::default();
 
         #[cfg(target_os = "windows")]
         let is_authorized = self.cm.is_authorized(self.conn_id);
@@ -442,10 +456,15 @@ impl<T: InvokeUiCM> IpcTaskRunner<T> {
                                     if let ipc::FS::WriteBlock { id, file_num, data: _, compressed } = fs {
                                         if let Ok(bytes) = self.stream.next_raw().await {
                                             fs = ipc::FS::WriteBlock{id, file_num, data:bytes.into(), compressed};
-                                            handle_fs(fs, &mut write_jobs, &self.tx, Some(&tx_log)).await;
+                                            handle_fs(fs, &mut write_jobs, &uploads, &self.tx, Some(&tx_log)).await;
+                                        }
+                                    } else if let ipc::FS::FileDelta { data: _, one_way } = fs {
+                                        if let Ok(bytes) = self.stream.next_raw().await {
+                                            fs = ipc::FS::FileDelta { data: bytes.into(), one_way };
+                                            handle_fs(fs, &mut write_jobs, &uploads, &self.tx, Some(&tx_log)).await;
                                         }
                                     } else {
-                                        handle_fs(fs, &mut write_jobs, &self.tx, Some(&tx_log)).await;
+                                        handle_fs(fs, &mut write_jobs, &uploads, &self.tx, Some(&tx_log)).await;
                                     }
                                     let log = fs::serialize_transfer_jobs(&write_jobs);
                                     self.cm.ui_handler.file_transfer_log("transfer", &log);
@@ -673,6 +692,7 @@ pub async fn start_listen<T: InvokeUiCM>(
 ) {
     let mut current_id = 0;
     let mut write_jobs: Vec<fs::TransferJob> = Vec::new();
+    let uploads = crate::file_delta::Uploads::default();
     loop {
         match rx.recv().await {
             Some(Data::Login {
@@ -719,7 +739,7 @@ pub async fn start_listen<T: InvokeUiCM>(
                 cm.new_message(current_id, text);
             }
             Some(Data::FS(fs)) => {
-                handle_fs(fs, &mut write_jobs, &tx, None).await;
+                handle_fs(fs, &mut write_jobs, &uploads, &tx, None).await;
             }
             Some(Data::Close) => {
                 break;
@@ -746,6 +766,7 @@ pub async fn start_listen<T: InvokeUiCM>(
 async fn handle_fs(
     fs: ipc::FS,
     write_jobs: &mut Vec<fs::TransferJob>,
+    uploads: &crate::file_delta::Uploads,
     tx: &UnboundedSender<Data>,
     tx_log: Option<&UnboundedSender<String>>,
 ) {
@@ -788,6 +809,11 @@ async fn handle_fs(
             total_size,
             conn_id,
         } => {
+            uploads.lock().unwrap().extend(
+                files
+                    .iter()
+                    .map(|f| fs::TransferJob::join(&PathBuf::from(&path), &f.0)),
+            );
             // cm has no show_hidden context
             // dummy remote, show_hidden, is_remote
             let mut job = fs::TransferJob::new_write(
@@ -912,6 +938,17 @@ async fn handle_fs(
         ipc::FS::Rename { id, path, new_name } => {
             rename_file(path, new_name, id, tx).await;
         }
+        ipc::FS::FileDelta { data, one_way } => {
+            let uploads = uploads.clone();
+            match spawn_blocking(move || crate::file_delta::handle(&data, one_way, &uploads)).await
+            {
+                Ok(res) => send_raw(
+                    crate::common::new_ext_message(crate::file_delta::ID, res),
+                    tx,
+                ),
+                Err(e) => log::error!("file delta: {}", e),
+            }
+        }
         _ => {}
     }
 }
@@ -945,12 +982,16 @@ async fn read_dir(dir: &str, include_hidden: bool, tx: &UnboundedSender<Data>) {
             fs::get_path(dir)
         }
     };
-    if let Ok(Ok(fd)) = spawn_blocking(move || fs::read_dir(&path, include_hidden)).await {
-        let mut msg_out = Message::new();
-        let mut file_response = FileResponse::new();
-        file_response.set_dir(fd);
-        msg_out.set_file_response(file_response);
-        send_raw(msg_out, tx);
+    match spawn_blocking(move || fs::read_dir(&path, include_hidden)).await {
+        Ok(Ok(fd)) => {
+            let mut msg_out = Message::new();
+            let mut file_response = FileResponse::new();
+            file_response.set_dir(fd);
+            msg_out.set_file_response(file_response);
+            send_raw(msg_out, tx);
+        }
+        Ok(Err(err)) => send_raw(fs::new_error(crate::common::READ_DIR_ERROR_ID, err, 0), tx),
+        Err(err) => send_raw(fs::new_error(crate::common::READ_DIR_ERROR_ID, err, 0), tx),
     }
 }
 
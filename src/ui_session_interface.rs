@@ -808,6 +808,39 @@ impl<T: InvokeUiSession> Session<T> {
         self.send(Data::Message(msg_out));
     }
 
+    pub fn transfer_terminal_control(&self, conn_id: i32) {
+        let msg_out =
+            crate::terminal_control::ControlMessage::TransferControl { conn_id }.to_message();
+        self.send(Data::Message(msg_out));
+    }
+
+    /// Run a command on the peer without a terminal, `env` items are `NAME=VALUE`.
+    /// The output and the exit code are returned with `exec_id`.
+    pub fn exec_command(
+        &self,
+        exec_id: i32,
+        command: String,
+        args: Vec<String>,
+        env: Vec<String>,
+        cwd: String,
+        timeout_ms: u32,
+    ) {
+        let msg_out = crate::remote_exec::ExecMessage::Command(crate::remote_exec::ExecCommand {
+            exec_id,
+            command,
+            args,
+            env: env
+                .iter()
+                .filter_map(|x| x.split_once('='))
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect(),
+            cwd,
+            timeout_ms,
+        })
+        .to_message();
+        self.send(Data::Message(msg_out));
+    }
+
     pub fn capture_displays(&self, add: Vec<i32>, sub: Vec<i32>, set: Vec<i32>) {
         let mut misc = Misc::new();
         misc.set_capture_displays(CaptureDisplays {
@@ -821,6 +854,51 @@ impl<T: InvokeUiSession> Session<T> {
         self.send(Data::Message(msg_out));
     }
 
+    /// Capture only a rectangle of `display`, or a window if `window` is not 0, or the whole
+    /// display if both are empty.
+    pub fn capture_region(
+        &self,
+        display: i32,
+        x: i32,
+        y: i32,
+        width: i32,
+        height: i32,
+        window: u64,
+    ) {
+        if !self.is_support_capture_region() {
+            return;
+        }
+        let region = scrap::region::RegionMessage::Set {
+            display,
+            rect: scrap::region::CaptureRect {
+                x,
+                y,
+                width: width.max(0) as _,
+                height: height.max(0) as _,
+            },
+            window,
+        };
+        self.send(Data::Message(crate::common::new_ext_message(
+            scrap::region::EXT_ID,
+            region.encode(),
+        )));
+    }
+
+    /// List the windows of the peer to capture, returned by `update_capture_windows`.
+    pub fn list_capture_windows(&self) {
+        if !self.is_support_capture_region() {
+            return;
+        }
+        self.send(Data::Message(crate::common::new_ext_message(
+            scrap::region::EXT_ID,
+            scrap::region::RegionMessage::List.encode(),
+        )));
+    }
+
+    fn is_support_capture_region(&self) -> bool {
+        self.lc.read().unwrap().ext_messages
+    }
+
     pub fn switch_display(&self, display: i32) {
         let (w, h) = match self.lc.read().unwrap().get_custom_resolution(display) {
             Some((w, h)) => (w, h),
@@ -1663,9 +1741,17 @@ pub trait InvokeUiSession: Send + Sync + Clone + 'static + Sized + Default {
     fn is_multi_ui_session(&self) -> bool;
     fn update_record_status(&self, start: bool);
     fn update_empty_dirs(&self, _res: ReadEmptyDirsResponse) {}
+    fn update_capture_windows(&self, _windows: Vec<scrap::region::WindowInfo>) {}
     fn printer_request(&self, id: i32, path: String);
     fn handle_screenshot_resp(&self, sid: String, msg: String);
     fn handle_terminal_response(&self, response: TerminalResponse);
+    fn update_terminal_participants(
+        &self,
+        _participants: Vec<crate::terminal_control::Participant>,
+        _my_conn_id: i32,
+    ) {
+    }
+    fn handle_exec_message(&self, _message: crate::remote_exec::ExecMessage) {}
 }
 
 impl<T: InvokeUiSession> Deref for Session<T> {
@@ -2,7 +2,7 @@ use crate::{common::do_check_software_update, hbbs_http::create_http_client};
 use hbb_common::{bail, config, log, ResultType};
 use std::{
     io::{self, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicUsize, Ordering},
         mpsc::{channel, Receiver, Sender},
@@ -11,6 +11,11 @@ use std::{
     time::{Duration, Instant},
 };
 
+#[cfg(target_os = "linux")]
+mod linux;
+pub mod manifest;
+pub mod release;
+
 enum UpdateMsg {
     CheckUpdate,
     Exit,
@@ -119,42 +124,196 @@ fn start_auto_update_check_(rx_msg: Receiver<UpdateMsg>) {
 fn check_update(manually: bool) -> ResultType<()> {
     #[cfg(target_os = "windows")]
     let is_msi = crate::platform::is_msi_installed()?;
-    if !(manually || config::Config::get_bool_option(config::keys::OPTION_ALLOW_AUTO_UPDATE)) {
+    let allowed =
+        manually || config::Config::get_bool_option(config::keys::OPTION_ALLOW_AUTO_UPDATE);
+    // A release required by the manifest is installed regardless of the option.
+    let server_url = manifest::server_url();
+    if !allowed && server_url.is_none() {
+        return Ok(());
+    }
+    let client = create_http_client();
+    // The version, the base url of the artifacts, the release manifest, whether it is signed and
+    // the release of this version in the update server manifest.
+    let (version, download_url, release, verified, current) = match server_url {
+        Some(url) => match manifest::check(&client, &url)? {
+            Some(update) => {
+                crate::common::set_software_update_url(update.notice_url());
+                if !allowed {
+                    if !update.required {
+                        return Ok(());
+                    }
+                    // Only a signed manifest can force an update.
+                    if !update.verified {
+                        log::warn!(
+                            "{} is not set, required update {} is not installed",
+                            release::OPTION_UPDATE_PUBLIC_KEY,
+                            update.release.version
+                        );
+                        return Ok(());
+                    }
+                }
+                (
+                    update.release.version.clone(),
+                    update.base_url,
+                    Some(update.release),
+                    update.verified,
+                    update.current,
+                )
+            }
+            None => {
+                log::debug!("No update available.");
+                return Ok(());
+            }
+        },
+        None => {
+            if !do_check_software_update().is_ok() {
+                // ignore
+                return Ok(());
+            }
+            let update_url = crate::common::SOFTWARE_UPDATE_URL.lock().unwrap().clone();
+            if update_url.is_empty() {
+                log::debug!("No update available.");
+                return Ok(());
+            }
+            let download_url = update_url.replace("tag", "download");
+            let version = download_url
+                .split('/')
+                .last()
+                .unwrap_or_default()
+                .to_owned();
+            let release = match release::public_key()? {
+                Some(pk) => Some(release::fetch(&client, &download_url, &pk)?),
+                None => None,
+            };
+            let verified = release.is_some();
+            (version, download_url, release, verified, None)
+        }
+    };
+    log::debug!("New version available: {}", &version);
+    #[cfg(target_os = "macos")]
+    let _ = (client, release, download_url);
+    #[cfg(not(target_os = "linux"))]
+    let _ = (verified, current);
+    #[cfg(target_os = "linux")]
+    check_update_linux(
+        &client,
+        &download_url,
+        &version,
+        release.filter(|_| verified),
+        current.filter(|_| verified),
+    )?;
+    #[cfg(target_os = "windows")]
+    {
+        let kind = if is_msi { "msi" } else { "exe" };
+        let artifact = match &release {
+            Some(release) => match release.artifact(kind) {
+                Some(artifact) => Some(artifact.clone()),
+                None => bail!("No {} in the release manifest of {}", kind, version),
+            },
+            None => None,
+        };
+        let download_url = match &artifact {
+            Some(artifact) => artifact.download_url(&download_url),
+            None if cfg!(feature = "flutter") => {
+                format!("{}/rustdesk-{}-x86_64.{}", download_url, version, kind)
+            }
+            None => format!("{}/rustdesk-{}-x86-sciter.exe", download_url, version),
+        };
+        let file_path = match &artifact {
+            Some(artifact) => std::env::temp_dir().join(artifact.file_name()?),
+            None => match get_download_file_from_url(&download_url) {
+                Some(file_path) => file_path,
+                None => bail!("Failed to get the file path from the URL: {}", download_url),
+            },
+        };
+        download(
+            &client,
+            &download_url,
+            &file_path,
+            artifact.as_ref().map(|a| a.size),
+        )?;
+        if let Some(artifact) = &artifact {
+            if let Err(e) = release::check_artifact(artifact, &file_path) {
+                std::fs::remove_file(&file_path).ok();
+                return Err(e);
+            }
+        }
+        // We have checked if the `conns`` is empty before, but we need to check again.
+        // No need to care about the downloaded file here, because it's rare case that the `conns` are empty
+        // before the download, but not empty after the download.
+        if has_no_active_conns() {
+            update_new_version(is_msi, &version, &file_path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn check_update_linux(
+    client: &reqwest::blocking::Client,
+    download_url: &str,
+    version: &str,
+    release: Option<release::Release>,
+    current: Option<release::Release>,
+) -> ResultType<()> {
+    // Unlike the installers of Windows, the packages are not signed, the release manifest is
+    // the only proof of origin.
+    let Some(release) = release else {
+        log::info!(
+            "{} is not set, update {} is not installed",
+            release::OPTION_UPDATE_PUBLIC_KEY,
+            version
+        );
+        return Ok(());
+    };
+    if linux::is_failed_version(&release.version) {
+        log::info!("update {} failed before, skipped", release.version);
         return Ok(());
     }
-    if !do_check_software_update().is_ok() {
-        // ignore
+    let Some(kind) = linux::PackageKind::current() else {
+        bail!("Unknown installation, can not update");
+    };
+    let Some(artifact) = release.artifact(kind.artifact_kind()) else {
+        bail!(
+            "No {} in the release manifest of {}",
+            kind.artifact_kind(),
+            release.version
+        );
+    };
+    if !has_no_active_conns() {
         return Ok(());
     }
+    let url = artifact.download_url(download_url);
+    linux::stage(
+        client,
+        kind,
+        &release.version,
+        artifact,
+        &url,
+        download_url,
+        current.as_ref(),
+    )
+}
 
-    let update_url = crate::common::SOFTWARE_UPDATE_URL.lock().unwrap().clone();
-    if update_url.is_empty() {
-        log::debug!("No update available.");
-    } else {
-        let download_url = update_url.replace("tag", "download");
-        let version = download_url.split('/').last().unwrap_or_default();
-        #[cfg(target_os = "windows")]
-        let download_url = if cfg!(feature = "flutter") {
-            format!(
-                "{}/rustdesk-{}-x86_64.{}",
-                download_url,
-                version,
-                if is_msi { "msi" } else { "exe" }
-            )
-        } else {
-            format!("{}/rustdesk-{}-x86-sciter.exe", download_url, version)
-        };
-        log::debug!("New version available: {}", &version);
-        let client = create_http_client();
-        let Some(file_path) = get_download_file_from_url(&download_url) else {
-            bail!("Failed to get the file path from the URL: {}", download_url);
-        };
-        let mut is_file_exists = false;
-        if file_path.exists() {
-            // Check if the file size is the same as the server file size
-            // If the file size is the same, we don't need to download it again.
-            let file_size = std::fs::metadata(&file_path)?.len();
-            let response = client.head(&download_url).send()?;
+/// `--apply-update`, install the update staged by the service.
+#[cfg(target_os = "linux")]
+pub fn apply_staged_update() -> ResultType<()> {
+    linux::apply_staged()
+}
+
+/// Download `url` to `path`, resuming from the `.part` file left by an interrupted download.
+/// The download is skipped if `path` exists with the expected size.
+#[cfg_attr(target_os = "macos", allow(dead_code))]
+pub fn download(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    path: &Path,
+    size: Option<u64>,
+) -> ResultType<()> {
+    let size = match size {
+        Some(size) => size,
+        None => {
+            let response = client.head(url).send()?;
             if !response.status().is_success() {
                 bail!("Failed to get the file size: {}", response.status());
             }
@@ -166,32 +325,55 @@ fn check_update(manually: bool) -> ResultType<()> {
             let Some(total_size) = total_size else {
                 bail!("Failed to get content length");
             };
-            if file_size == total_size {
-                is_file_exists = true;
-            } else {
-                std::fs::remove_file(&file_path)?;
-            }
+            total_size
         }
-        if !is_file_exists {
-            let response = client.get(&download_url).send()?;
-            if !response.status().is_success() {
-                bail!(
-                    "Failed to download the new version file: {}",
-                    response.status()
-                );
-            }
-            let file_data = response.bytes()?;
-            let mut file = std::fs::File::create(&file_path)?;
-            file.write_all(&file_data)?;
-        }
-        // We have checked if the `conns`` is empty before, but we need to check again.
-        // No need to care about the downloaded file here, because it's rare case that the `conns` are empty
-        // before the download, but not empty after the download.
-        if has_no_active_conns() {
-            #[cfg(target_os = "windows")]
-            update_new_version(is_msi, &version, &file_path);
+    };
+    if path.exists() {
+        if std::fs::metadata(path)?.len() == size {
+            return Ok(());
         }
+        std::fs::remove_file(path)?;
+    }
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    let part = PathBuf::from(part);
+    let mut offset = std::fs::metadata(&part).map(|m| m.len()).unwrap_or(0);
+    if offset > size {
+        offset = 0;
+    }
+    if offset == size {
+        std::fs::rename(&part, path)?;
+        return Ok(());
+    }
+    let mut request = client.get(url);
+    if offset > 0 {
+        request = request.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+    }
+    let mut response = request.send()?;
+    if !response.status().is_success() {
+        bail!(
+            "Failed to download the new version file: {}",
+            response.status()
+        );
+    }
+    let resumed = response.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(resumed)
+        .truncate(!resumed)
+        .open(&part)?;
+    if resumed {
+        log::info!("download resumed at {} of {}", offset, size);
+    }
+    io::copy(&mut response, &mut file)?;
+    file.flush()?;
+    drop(file);
+    let len = std::fs::metadata(&part)?.len();
+    if len != size {
+        bail!("Downloaded {} bytes, expected {}", len, size);
     }
+    std::fs::rename(&part, path)?;
     Ok(())
 }
 
@@ -0,0 +1,548 @@
+// Staged updates of the Linux packages, with rollback.
+//
+// deb and rpm: the service downloads and verifies the package into the staging directory, with
+// the package of the running version to roll back to, then hands over to `--apply-update` in a
+// transient systemd unit, because the package scripts restart the service and would kill the
+// installer with it. The installer installs the new package and checks the health of the new
+// service, and reinstalls the previous package if it fails, so that the package database and the
+// files agree. The failed version is remembered so that it is not installed again. The package of
+// the installed version is kept for the next rollback.
+//
+// AppImage: the new image is checked before it replaces the running one, the previous image is
+// kept as `<image>.prev`. The running program exits and `--apply-update` of the previous image
+// restarts the new one, and puts the previous one back if the new one does not stay up.
+
+use super::release::{self, Artifact, Release};
+use hbb_common::{
+    bail,
+    config::{load_path, store_path, Config},
+    log, ResultType,
+};
+use reqwest::blocking::Client;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, Instant},
+};
+
+// The service must stay active without restarts for this time after the update.
+const HEALTH_STABLE: Duration = Duration::from_secs(30);
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(120);
+const HEALTH_POLL: Duration = Duration::from_secs(5);
+// The previous AppImage waits this long for the updated program to exit.
+const EXIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PackageKind {
+    Deb,
+    Rpm,
+    AppImage,
+}
+
+impl PackageKind {
+    /// How this program is installed.
+    pub fn current() -> Option<Self> {
+        if std::env::var("APPIMAGE").map_or(false, |p| !p.is_empty()) {
+            return Some(PackageKind::AppImage);
+        }
+        let name = package_name();
+        if has_cmd("dpkg-query")
+            && output("dpkg-query", &["-W", "-f=${Status}", &name])
+                .map_or(false, |s| s.contains("install ok installed"))
+        {
+            return Some(PackageKind::Deb);
+        }
+        if has_cmd("rpm") && output("rpm", &["-q", &name]).is_ok() {
+            return Some(PackageKind::Rpm);
+        }
+        None
+    }
+
+    /// The `kind` of the artifacts in the release manifest.
+    pub fn artifact_kind(&self) -> &'static str {
+        match self {
+            PackageKind::Deb => "deb",
+            PackageKind::Rpm => "rpm",
+            PackageKind::AppImage => "AppImage",
+        }
+    }
+
+    // The package of the installed version, kept for the next rollback.
+    fn installed_path(&self) -> PathBuf {
+        staging_dir().join(format!("installed.{}", self.artifact_kind()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+enum Stage {
+    #[default]
+    Idle,
+    Staged,
+    Installing,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+struct UpdateState {
+    stage: Stage,
+    version: String,
+    previous_version: String,
+    package: String,
+    kind: Option<PackageKind>,
+    /// Versions which failed the health check, they are not installed again.
+    failed_versions: Vec<String>,
+    /// The package of `previous_version`, reinstalled if the update fails.
+    rollback_package: String,
+    /// The package kept from the last update, and its version.
+    installed_package: String,
+    installed_version: String,
+    /// The AppImage being updated, and the arguments and the pid of the program which updates it.
+    image: String,
+    args: Vec<String>,
+    pid: u32,
+}
+
+fn package_name() -> String {
+    crate::get_app_name().to_lowercase()
+}
+
+fn staging_dir() -> PathBuf {
+    Config::path("update")
+}
+
+fn state_path() -> PathBuf {
+    staging_dir().join("state.toml")
+}
+
+fn load_state() -> UpdateState {
+    load_path(state_path())
+}
+
+fn store_state(state: &UpdateState) {
+    std::fs::create_dir_all(staging_dir()).ok();
+    if let Err(e) = store_path(state_path(), state) {
+        log::error!("Failed to store update state: {}", e);
+    }
+}
+
+fn has_cmd(cmd: &str) -> bool {
+    Command::new("which")
+        .arg(cmd)
+        .output()
+        .map_or(false, |o| o.status.success())
+}
+
+fn output(cmd: &str, args: &[&str]) -> ResultType<String> {
+    let o = Command::new(cmd).args(args).output()?;
+    if !o.status.success() {
+        bail!(
+            "{} failed: {}",
+            cmd,
+            String::from_utf8_lossy(&o.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&o.stdout).to_string())
+}
+
+pub fn is_failed_version(version: &str) -> bool {
+    load_state().failed_versions.iter().any(|v| v == version)
+}
+
+/// Download and verify `artifact` of `version`, and start installing it. `base_url` is the
+/// directory of the release manifest, `current` the release of the running version in the
+/// manifest of the update server.
+pub fn stage(
+    client: &Client,
+    kind: PackageKind,
+    version: &str,
+    artifact: &Artifact,
+    url: &str,
+    base_url: &str,
+    current: Option<&Release>,
+) -> ResultType<()> {
+    let dir = staging_dir();
+    std::fs::create_dir_all(&dir)?;
+    let mut state = load_state();
+    let rollback_package = match kind {
+        PackageKind::AppImage => PathBuf::new(),
+        PackageKind::Deb | PackageKind::Rpm => {
+            rollback_package(client, kind, &state, base_url, version, current)?
+        }
+    };
+    let path = dir.join(artifact.file_name()?);
+    super::download(client, url, &path, Some(artifact.size))?;
+    if let Err(e) = release::check_artifact(artifact, &path) {
+        std::fs::remove_file(&path).ok();
+        return Err(e);
+    }
+    state.stage = Stage::Staged;
+    state.version = version.to_owned();
+    state.previous_version = crate::VERSION.to_owned();
+    state.package = path.to_string_lossy().to_string();
+    state.kind = Some(kind);
+    state.rollback_package = rollback_package.to_string_lossy().to_string();
+    store_state(&state);
+    log::info!("update {} staged: {:?}", version, path);
+    match kind {
+        PackageKind::AppImage => apply_appimage(state),
+        PackageKind::Deb | PackageKind::Rpm => {
+            if !has_cmd("systemd-run") {
+                bail!("systemd-run is required to install the update");
+            }
+            let exe = std::env::current_exe()?;
+            let status = Command::new("systemd-run")
+                .arg("--collect")
+                .arg(format!("--unit={}-update", package_name()))
+                .arg(exe)
+                .arg("--apply-update")
+                .status()?;
+            if !status.success() {
+                bail!("Failed to start the installer: {}", status);
+            }
+            Ok(())
+        }
+    }
+}
+
+// Download and verify the artifact of `kind` of `release` into `dir`.
+fn download_artifact(
+    client: &Client,
+    kind: PackageKind,
+    release: &Release,
+    base_url: &str,
+    dir: &Path,
+) -> ResultType<PathBuf> {
+    let Some(artifact) = release.artifact(kind.artifact_kind()) else {
+        bail!("No {} in the release manifest", kind.artifact_kind());
+    };
+    let path = dir.join(artifact.file_name()?);
+    super::download(
+        client,
+        &artifact.download_url(base_url),
+        &path,
+        Some(artifact.size),
+    )?;
+    if let Err(e) = release::check_artifact(artifact, &path) {
+        std::fs::remove_file(&path).ok();
+        return Err(e);
+    }
+    Ok(path)
+}
+
+// The package of the running version: the one kept from the last update, else the artifact of
+// its release, in the manifest of the update server or next to the GitHub release of `version`,
+// else repacked from the installed files.
+fn rollback_package(
+    client: &Client,
+    kind: PackageKind,
+    state: &UpdateState,
+    base_url: &str,
+    version: &str,
+    current: Option<&Release>,
+) -> ResultType<PathBuf> {
+    let installed = PathBuf::from(&state.installed_package);
+    if state.installed_version == crate::VERSION && installed.is_file() {
+        return Ok(installed);
+    }
+    let dir = staging_dir();
+    let res = match current {
+        // The artifacts of the update server manifest are relative to its directory.
+        Some(current) => Some(download_artifact(client, kind, current, base_url, &dir)),
+        None => match (base_url.strip_suffix(version), release::public_key()?) {
+            (Some(prefix), Some(pk)) => {
+                let base_url = format!("{}{}", prefix, crate::VERSION);
+                Some(release::fetch(client, &base_url, &pk).and_then(|previous| {
+                    download_artifact(client, kind, &previous, &base_url, &dir)
+                }))
+            }
+            _ => None,
+        },
+    };
+    match res {
+        Some(Ok(path)) => return Ok(path),
+        Some(Err(e)) => log::warn!("No package of {} in its release: {}", crate::VERSION, e),
+        None => {}
+    }
+    if kind == PackageKind::Deb && has_cmd("dpkg-repack") {
+        let name = package_name();
+        let status = Command::new("dpkg-repack")
+            .arg(&name)
+            .current_dir(&dir)
+            .status()?;
+        let prefix = format!("{}_{}", name, crate::VERSION);
+        if status.success() {
+            for entry in std::fs::read_dir(&dir)?.flatten() {
+                let file_name = entry.file_name().to_string_lossy().to_string();
+                if file_name.starts_with(&prefix) && file_name.ends_with(".deb") {
+                    return Ok(entry.path());
+                }
+            }
+        }
+    }
+    bail!(
+        "No package of {} to roll back to, update {} is not installed",
+        crate::VERSION,
+        version
+    );
+}
+
+/// `--apply-update`, install the staged package and roll back if the new service is unhealthy.
+pub fn apply_staged() -> ResultType<()> {
+    let mut state = load_state();
+    if state.stage == Stage::Installing && state.kind == Some(PackageKind::AppImage) {
+        return check_appimage(state);
+    }
+    if state.stage != Stage::Staged {
+        bail!("No update staged");
+    }
+    let Some(kind) = state.kind else {
+        bail!("Unknown package kind");
+    };
+    if !crate::platform::is_root() {
+        println!("Administrative privileges required!");
+        bail!("Administrative privileges required");
+    }
+    let rollback = PathBuf::from(&state.rollback_package);
+    if !rollback.is_file() {
+        bail!("No package to roll back to");
+    }
+    // Before the new package replaces it.
+    let exe = installed_binary(kind)?;
+    state.stage = Stage::Installing;
+    store_state(&state);
+    log::info!(
+        "installing {}, previous version {}",
+        state.version,
+        state.previous_version
+    );
+    let res = install(kind, &state.package).and_then(|_| wait_healthy(&exe, &state.version));
+    match &res {
+        Ok(()) => {
+            log::info!("update {} installed", state.version);
+            let (package, version) = (state.package.clone(), state.version.clone());
+            keep_installed(&mut state, kind, &package, &version);
+        }
+        Err(e) => {
+            log::error!("update {} failed, rolling back: {}", state.version, e);
+            match restore(kind, &rollback) {
+                Ok(()) => {
+                    let (package, version) = (
+                        state.rollback_package.clone(),
+                        state.previous_version.clone(),
+                    );
+                    keep_installed(&mut state, kind, &package, &version);
+                }
+                Err(e) => log::error!("Failed to roll back: {}", e),
+            }
+            if !state.failed_versions.contains(&state.version) {
+                state.failed_versions.push(state.version.clone());
+            }
+        }
+    }
+    if Path::new(&state.rollback_package) != kind.installed_path() {
+        std::fs::remove_file(&state.rollback_package).ok();
+    }
+    std::fs::remove_file(&state.package).ok();
+    state.rollback_package.clear();
+    state.stage = Stage::Idle;
+    store_state(&state);
+    res
+}
+
+// Keep `package` of the installed `version` for the next rollback.
+fn keep_installed(state: &mut UpdateState, kind: PackageKind, package: &str, version: &str) {
+    let path = kind.installed_path();
+    if Path::new(package) != path {
+        if let Err(e) = std::fs::rename(package, &path) {
+            log::error!("Failed to keep the installed package: {}", e);
+            return;
+        }
+    }
+    state.installed_package = path.to_string_lossy().to_string();
+    state.installed_version = version.to_owned();
+}
+
+fn package_files(kind: PackageKind) -> ResultType<Vec<String>> {
+    let name = package_name();
+    let files = match kind {
+        PackageKind::Deb => output("dpkg-query", &["-L", &name])?,
+        PackageKind::Rpm => output("rpm", &["-ql", &name])?,
+        PackageKind::AppImage => bail!("AppImage is not installed by a package manager"),
+    };
+    Ok(files.lines().map(|f| f.to_owned()).collect())
+}
+
+// The program of the installed package, `current_exe` of the installer is the deleted file once
+// the package is replaced.
+fn installed_binary(kind: PackageKind) -> ResultType<PathBuf> {
+    let name = package_name();
+    let files = package_files(kind)?;
+    let bin = format!("/usr/bin/{}", name);
+    if files.contains(&bin) {
+        return Ok(bin.into());
+    }
+    match files
+        .iter()
+        .map(Path::new)
+        .find(|f| f.file_name().map_or(false, |n| n.to_string_lossy() == name) && f.is_file())
+    {
+        Some(f) => Ok(f.to_owned()),
+        None => bail!("No program in the package {}", name),
+    }
+}
+
+fn install(kind: PackageKind, package: &str) -> ResultType<()> {
+    match kind {
+        PackageKind::Deb => output("dpkg", &["-i", package])?,
+        // `--oldpackage` for the rollback.
+        PackageKind::Rpm => output("rpm", &["-U", "--force", "--oldpackage", package])?,
+        PackageKind::AppImage => bail!("AppImage is not installed by a package manager"),
+    };
+    Ok(())
+}
+
+fn restore(kind: PackageKind, package: &Path) -> ResultType<()> {
+    install(kind, &package.to_string_lossy())?;
+    output("systemctl", &["daemon-reload"]).ok();
+    output("systemctl", &["restart", &package_name()])?;
+    log::info!("previous version restored");
+    Ok(())
+}
+
+fn service_restarts(name: &str) -> Option<u32> {
+    output("systemctl", &["show", "-p", "NRestarts", "--value", name])
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+// The installed program reports the new version, and the service stays active without restarts.
+fn wait_healthy(exe: &Path, version: &str) -> ResultType<()> {
+    let name = package_name();
+    let installed = output(&exe.to_string_lossy(), &["--version"])?;
+    if installed.trim() != version {
+        bail!("The installed version is {}", installed.trim());
+    }
+    let start = Instant::now();
+    let mut stable_since: Option<(Instant, Option<u32>)> = None;
+    while start.elapsed() < HEALTH_TIMEOUT {
+        let active =
+            output("systemctl", &["is-active", &name]).map_or(false, |s| s.trim() == "active");
+        let restarts = service_restarts(&name);
+        match stable_since {
+            Some((since, r)) if active && r == restarts => {
+                if since.elapsed() >= HEALTH_STABLE {
+                    return Ok(());
+                }
+            }
+            _ => stable_since = active.then(|| (Instant::now(), restarts)),
+        }
+        std::thread::sleep(HEALTH_POLL);
+    }
+    bail!("The service {} is not healthy", name);
+}
+
+// Check the new image and swap it with the running one, then exit for the previous image to
+// restart the new one.
+fn apply_appimage(mut state: UpdateState) -> ResultType<()> {
+    let image = PathBuf::from(std::env::var("APPIMAGE")?);
+    let staged = PathBuf::from(&state.package);
+    let new = image.with_extension("new");
+    let prev = image.with_extension("prev");
+    let res = (|| -> ResultType<()> {
+        std::fs::copy(&staged, &new)?;
+        let mut permissions = std::fs::metadata(&new)?.permissions();
+        std::os::unix::fs::PermissionsExt::set_mode(&mut permissions, 0o755);
+        std::fs::set_permissions(&new, permissions)?;
+        let version = output(&new.to_string_lossy(), &["--version"])?;
+        if version.trim() != state.version {
+            bail!("The new image reports version {}", version.trim());
+        }
+        std::fs::copy(&image, &prev)?;
+        std::fs::rename(&new, &image)?;
+        Ok(())
+    })();
+    std::fs::remove_file(&new).ok();
+    std::fs::remove_file(&staged).ok();
+    if let Err(e) = res {
+        if !state.failed_versions.contains(&state.version) {
+            state.failed_versions.push(state.version.clone());
+        }
+        state.stage = Stage::Idle;
+        store_state(&state);
+        return Err(e);
+    }
+    state.stage = Stage::Installing;
+    state.image = image.to_string_lossy().to_string();
+    state.args = std::env::args().skip(1).collect();
+    state.pid = std::process::id();
+    store_state(&state);
+    if let Err(e) = Command::new(&prev)
+        .arg("--apply-update")
+        .process_group(0)
+        .spawn()
+    {
+        std::fs::rename(&prev, &image).ok();
+        state.stage = Stage::Idle;
+        store_state(&state);
+        bail!("Failed to start the previous image: {}", e);
+    }
+    log::info!(
+        "AppImage updated to {}, the previous one is kept as {:?}, restarting",
+        state.version,
+        prev
+    );
+    std::process::exit(0);
+}
+
+// `--apply-update` of the previous image, restart the new one once the updated program exited,
+// and put the previous one back if the new one does not stay up.
+fn check_appimage(mut state: UpdateState) -> ResultType<()> {
+    let image = PathBuf::from(&state.image);
+    let prev = image.with_extension("prev");
+    let start = Instant::now();
+    while Path::new(&format!("/proc/{}", state.pid)).exists() && start.elapsed() < EXIT_TIMEOUT {
+        std::thread::sleep(Duration::from_millis(300));
+    }
+    let res = run_healthy(&image, &state.args);
+    match &res {
+        Ok(()) => log::info!("AppImage {} is healthy", state.version),
+        Err(e) => {
+            log::error!("AppImage {} failed, rolling back: {}", state.version, e);
+            if !state.failed_versions.contains(&state.version) {
+                state.failed_versions.push(state.version.clone());
+            }
+            match std::fs::rename(&prev, &image) {
+                Ok(()) => {
+                    if let Err(e) = Command::new(&image)
+                        .args(&state.args)
+                        .process_group(0)
+                        .spawn()
+                    {
+                        log::error!("Failed to restart the previous image: {}", e);
+                    }
+                }
+                Err(e) => log::error!("Failed to roll back: {}", e),
+            }
+        }
+    }
+    state.stage = Stage::Idle;
+    store_state(&state);
+    res
+}
+
+// Start the image, it must stay up for `HEALTH_STABLE`.
+fn run_healthy(image: &Path, args: &[String]) -> ResultType<()> {
+    let mut child = Command::new(image).args(args).process_group(0).spawn()?;
+    let start = Instant::now();
+    while start.elapsed() < HEALTH_STABLE {
+        if let Some(status) = child.try_wait()? {
+            bail!("The new image exited: {}", status);
+        }
+        std::thread::sleep(Duration::from_secs(1));
+    }
+    Ok(())
+}
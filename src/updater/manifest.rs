@@ -0,0 +1,234 @@
+// Update channel served by a self-hosted update server, instead of the releases of GitHub.
+//
+// Options:
+// - `update-server-url`: url of the manifest, e.g. `https://updates.example.com/manifest.json`,
+//   signed by `manifest.json.sig` if `update-public-key` is set.
+// - `update-channel`: `stable` by default, or `beta`, which also gets the stable releases.
+//
+// {"releases": [{"version": "1.4.1", "channel": "stable", "min_version": "1.3.0",
+//   "rollout": 20, "artifacts": [{"os": "windows", "arch": "x86_64", "kind": "msi",
+//   "name": "ofarchdesk-1.4.1-x86_64.msi", "url": "1.4.1/ofarchdesk-1.4.1-x86_64.msi",
+//   "size": 123, "sha256": "..."}]}]}
+//
+// `rollout` is the percentage of the devices which get the release, by a hash of the device id,
+// and 100 if it is missing. A device older than `min_version` of the newest release gets it
+// regardless of the rollout, and installs it even if the automatic update is disabled.
+//
+// The release of the running version in the manifest is the one rolled back to on Linux.
+//
+// The new version notice of the UI is also based on this manifest, the GitHub release is not
+// checked then.
+
+use super::release::{self, Artifact, Release};
+use hbb_common::{
+    config::Config,
+    get_version_number, log,
+    sha2::{Digest, Sha256},
+    ResultType,
+};
+use reqwest::blocking::Client;
+use serde_derive::{Deserialize, Serialize};
+
+pub const OPTION_UPDATE_SERVER_URL: &str = "update-server-url";
+pub const OPTION_UPDATE_CHANNEL: &str = "update-channel";
+const CHANNEL_STABLE: &str = "stable";
+const CHANNEL_BETA: &str = "beta";
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ManifestRelease {
+    pub version: String,
+    pub channel: String,
+    /// The versions before it must update.
+    pub min_version: String,
+    pub rollout: Option<u8>,
+    pub artifacts: Vec<Artifact>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Manifest {
+    pub releases: Vec<ManifestRelease>,
+}
+
+pub fn server_url() -> Option<String> {
+    let url = Config::get_option(OPTION_UPDATE_SERVER_URL);
+    let url = url.trim();
+    (!url.is_empty()).then(|| url.to_owned())
+}
+
+fn channels(channel: &str) -> &'static [&'static str] {
+    match channel {
+        CHANNEL_BETA => &[CHANNEL_STABLE, CHANNEL_BETA],
+        _ => &[CHANNEL_STABLE],
+    }
+}
+
+// 0..100, stable for a device and a version, so that a device does not move in and out of the
+// rollout between checks.
+fn rollout_bucket(id: &str, version: &str) -> u8 {
+    let hash = Sha256::digest(format!("{}:{}", id, version).as_bytes());
+    (u16::from_be_bytes([hash[0], hash[1]]) % 100) as u8
+}
+
+impl Manifest {
+    /// The release of `version`.
+    pub fn release(&self, version: &str) -> Option<&ManifestRelease> {
+        let version = get_version_number(version);
+        self.releases
+            .iter()
+            .find(|r| get_version_number(&r.version) == version)
+    }
+
+    fn candidates<'a>(
+        &'a self,
+        channel: &str,
+        current: i64,
+    ) -> impl Iterator<Item = &'a ManifestRelease> + 'a {
+        let channels = channels(channel);
+        self.releases.iter().filter(move |r| {
+            channels.contains(&r.channel.as_str()) && get_version_number(&r.version) > current
+        })
+    }
+
+    /// A newer release of `channel` which `current` is older than the `min_version` of.
+    pub fn required(&self, channel: &str, current: &str) -> Option<&ManifestRelease> {
+        let current = get_version_number(current);
+        self.candidates(channel, current)
+            .find(|r| !r.min_version.is_empty() && current < get_version_number(&r.min_version))
+    }
+
+    /// The newest release of `channel` newer than `current` which `id` is in the rollout of.
+    pub fn select(&self, channel: &str, current: &str, id: &str) -> Option<&ManifestRelease> {
+        let required = self.required(channel, current).is_some();
+        self.candidates(channel, get_version_number(current))
+            .filter(|r| {
+                required || rollout_bucket(id, &r.version) < r.rollout.unwrap_or(100).min(100)
+            })
+            .max_by_key(|r| get_version_number(&r.version))
+    }
+}
+
+/// The release selected for this device.
+pub struct Update {
+    pub release: Release,
+    /// The base url of the artifacts.
+    pub base_url: String,
+    /// Whether the manifest is signed.
+    pub verified: bool,
+    /// This version is older than `min_version` of the release.
+    pub required: bool,
+    /// The release of this version, if it is still in the manifest.
+    pub current: Option<Release>,
+}
+
+impl Update {
+    /// The url of the new version notice, the version is its last segment.
+    pub fn notice_url(&self) -> String {
+        format!("{}/{}", self.base_url, self.release.version)
+    }
+}
+
+/// Get the manifest of `update-server-url` and select the release for this device.
+pub fn check(client: &Client, url: &str) -> ResultType<Option<Update>> {
+    let (data, verified) = match release::public_key()? {
+        Some(pk) => (release::get_signed(client, url, &pk)?, true),
+        None => (release::get(client, url)?, false),
+    };
+    let manifest: Manifest = serde_json::from_slice(&data)?;
+    let mut channel = Config::get_option(OPTION_UPDATE_CHANNEL);
+    if channel.is_empty() {
+        channel = CHANNEL_STABLE.to_owned();
+    }
+    let Some(selected) = manifest.select(&channel, crate::VERSION, &Config::get_id()) else {
+        return Ok(None);
+    };
+    let required = manifest.required(&channel, crate::VERSION);
+    if let Some(r) = required {
+        log::warn!(
+            "{} is required by the {} channel, this is {}",
+            r.min_version,
+            channel,
+            crate::VERSION
+        );
+    }
+    let base_url = url
+        .rsplit_once('/')
+        .map_or(url, |(base, _)| base)
+        .to_owned();
+    Ok(Some(Update {
+        release: Release {
+            version: selected.version.clone(),
+            artifacts: selected.artifacts.clone(),
+        },
+        base_url,
+        verified,
+        required: required.is_some(),
+        current: manifest.release(crate::VERSION).map(|r| Release {
+            version: r.version.clone(),
+            artifacts: r.artifacts.clone(),
+        }),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select() {
+        let release = |version: &str, channel: &str, rollout: Option<u8>, min_version: &str| {
+            ManifestRelease {
+                version: version.to_owned(),
+                channel: channel.to_owned(),
+                min_version: min_version.to_owned(),
+                rollout,
+                artifacts: vec![],
+            }
+        };
+        let manifest = Manifest {
+            releases: vec![
+                release("1.4.0", "stable", None, ""),
+                release("1.4.1", "stable", Some(0), ""),
+                release("1.5.0", "beta", None, ""),
+            ],
+        };
+        let version = |m: &Manifest, channel: &str, current: &str| {
+            m.select(channel, current, "123456789")
+                .map(|r| r.version.clone())
+        };
+        assert_eq!(version(&manifest, "stable", "1.3.0"), Some("1.4.0".into()));
+        assert_eq!(version(&manifest, "beta", "1.3.0"), Some("1.5.0".into()));
+        assert_eq!(version(&manifest, "stable", "1.4.0"), None);
+        assert_eq!(version(&manifest, "", "1.3.0"), Some("1.4.0".into()));
+        let mut required = manifest.clone();
+        required.releases[1].min_version = "1.3.5".to_owned();
+        assert_eq!(version(&required, "stable", "1.3.0"), Some("1.4.1".into()));
+        assert_eq!(version(&required, "stable", "1.4.0"), None);
+        assert!(required.required("stable", "1.3.0").is_some());
+        assert!(required.required("stable", "1.3.5").is_none());
+        assert!(manifest.required("stable", "1.3.0").is_none());
+    }
+
+    #[test]
+    fn test_release() {
+        let manifest: Manifest = serde_json::from_str(
+            r#"{"releases": [{"version": "1.4.1", "channel": "stable"},
+                {"version": "1.4.0", "channel": "stable", "artifacts": [{"os": "linux",
+                "arch": "x86_64", "kind": "deb", "name": "a-1.4.0.deb", "size": 3}]}]}"#,
+        )
+        .unwrap();
+        let release = manifest.release("1.4.0").unwrap();
+        assert_eq!(release.artifacts[0].name, "a-1.4.0.deb");
+        assert!(manifest.release("1.3.0").is_none());
+    }
+
+    #[test]
+    fn test_rollout_bucket() {
+        let count = (0..1000)
+            .filter(|i| rollout_bucket(&i.to_string(), "1.4.1") < 20)
+            .count();
+        assert!(count > 150 && count < 250, "{}", count);
+        assert_eq!(rollout_bucket("1", "1.4.1"), rollout_bucket("1", "1.4.1"));
+    }
+}
@@ -0,0 +1,201 @@
+// The release manifest published next to the artifacts of a release, `release.json`, with its
+// detached Ed25519 signature in base64, `release.json.sig`:
+//
+// {"version": "1.4.1", "artifacts": [{"os": "linux", "arch": "x86_64", "kind": "deb",
+//   "name": "rustdesk-1.4.1-x86_64.deb", "size": 123, "sha256": "..."}]}
+//
+// The manifest is only trusted if it is signed by the key of the option `update-public-key`,
+// and the artifacts are only installed if they match its size and SHA-256.
+
+use hbb_common::{
+    bail,
+    config::Config,
+    log,
+    sha2::{Digest, Sha256},
+    sodiumoxide::{base64, crypto::sign},
+    ResultType,
+};
+use reqwest::blocking::Client;
+use serde_derive::{Deserialize, Serialize};
+use std::{ffi::OsStr, io::Read, path::Path};
+
+pub const OPTION_UPDATE_PUBLIC_KEY: &str = "update-public-key";
+const MANIFEST_NAME: &str = "release.json";
+const SIGNATURE_SUFFIX: &str = ".sig";
+const MAX_MANIFEST_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Artifact {
+    pub os: String,
+    pub arch: String,
+    /// `exe`, `msi`, `deb`, `rpm`, `AppImage`...
+    pub kind: String,
+    pub name: String,
+    /// Absolute, or relative to the manifest, `name` next to the manifest if empty.
+    pub url: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl Artifact {
+    /// The download url, `base_url` is the directory of the manifest.
+    pub fn download_url(&self, base_url: &str) -> String {
+        if self.url.contains("://") {
+            self.url.clone()
+        } else if !self.url.is_empty() {
+            format!("{}/{}", base_url, self.url.trim_start_matches('/'))
+        } else {
+            format!("{}/{}", base_url, self.name)
+        }
+    }
+
+    /// The file name to save the artifact as, `name` without its directories.
+    pub fn file_name(&self) -> ResultType<&OsStr> {
+        match Path::new(&self.name).file_name() {
+            Some(name) => Ok(name),
+            None => bail!("Invalid artifact name {}", self.name),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Release {
+    pub version: String,
+    pub artifacts: Vec<Artifact>,
+}
+
+impl Release {
+    /// The artifact of `kind` for this platform.
+    pub fn artifact(&self, kind: &str) -> Option<&Artifact> {
+        self.artifacts.iter().find(|a| {
+            a.os == std::env::consts::OS && a.arch == std::env::consts::ARCH && a.kind == kind
+        })
+    }
+}
+
+/// The key of `update-public-key`, `None` if it is not set.
+pub fn public_key() -> ResultType<Option<sign::PublicKey>> {
+    let value = Config::get_option(OPTION_UPDATE_PUBLIC_KEY);
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    let Ok(bytes) = base64::decode(value, base64::Variant::Original) else {
+        bail!("Invalid {}, not base64", OPTION_UPDATE_PUBLIC_KEY);
+    };
+    match sign::PublicKey::from_slice(&bytes) {
+        Some(pk) => Ok(Some(pk)),
+        None => bail!("Invalid {}", OPTION_UPDATE_PUBLIC_KEY),
+    }
+}
+
+pub fn verify_signature(data: &[u8], signature: &str, pk: &sign::PublicKey) -> ResultType<()> {
+    let Ok(signature) = base64::decode(signature.trim(), base64::Variant::Original) else {
+        bail!("Invalid signature of the release manifest, not base64");
+    };
+    if signature.len() != sign::SIGNATUREBYTES {
+        bail!("Invalid signature of the release manifest");
+    }
+    let mut signed = signature;
+    signed.extend_from_slice(data);
+    if sign::verify(&signed, pk).is_err() {
+        bail!(
+            "The release manifest is not signed by {}",
+            OPTION_UPDATE_PUBLIC_KEY
+        );
+    }
+    Ok(())
+}
+
+pub fn verify(manifest: &[u8], signature: &str, pk: &sign::PublicKey) -> ResultType<Release> {
+    verify_signature(manifest, signature, pk)?;
+    Ok(serde_json::from_slice(manifest)?)
+}
+
+/// Get `url` and verify it with its signature at `url.sig`.
+pub fn get_signed(client: &Client, url: &str, pk: &sign::PublicKey) -> ResultType<Vec<u8>> {
+    let data = get(client, url)?;
+    let signature = get(client, &format!("{}{}", url, SIGNATURE_SUFFIX))?;
+    verify_signature(&data, &String::from_utf8_lossy(&signature), pk)?;
+    Ok(data)
+}
+
+pub fn get(client: &Client, url: &str) -> ResultType<Vec<u8>> {
+    let response = client.get(url).send()?;
+    if !response.status().is_success() {
+        bail!("Failed to get {}: {}", url, response.status());
+    }
+    let mut data = vec![];
+    response.take(MAX_MANIFEST_SIZE).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Download and verify the manifest of the release at `base_url`.
+pub fn fetch(client: &Client, base_url: &str, pk: &sign::PublicKey) -> ResultType<Release> {
+    let url = format!("{}/{}", base_url, MANIFEST_NAME);
+    let release: Release = serde_json::from_slice(&get_signed(client, &url, pk)?)?;
+    log::info!("release manifest of {} verified", release.version);
+    Ok(release)
+}
+
+pub fn sha256_file(path: &Path) -> ResultType<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Check a downloaded artifact against the manifest.
+pub fn check_artifact(artifact: &Artifact, path: &Path) -> ResultType<()> {
+    let size = std::fs::metadata(path)?.len();
+    if size != artifact.size {
+        bail!(
+            "Size of {} is {}, expected {}",
+            artifact.name,
+            size,
+            artifact.size
+        );
+    }
+    let sha256 = sha256_file(path)?;
+    if !sha256.eq_ignore_ascii_case(&artifact.sha256) {
+        bail!("SHA-256 of {} mismatched", artifact.name);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_name() {
+        let artifact = |name: &str| Artifact {
+            name: name.to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(artifact("a.deb").file_name().unwrap(), "a.deb");
+        assert_eq!(artifact("../../etc/a.deb").file_name().unwrap(), "a.deb");
+        assert!(artifact("..").file_name().is_err());
+        assert!(artifact("").file_name().is_err());
+    }
+
+    #[test]
+    fn test_verify() {
+        let (pk, sk) = sign::gen_keypair();
+        let (other_pk, _) = sign::gen_keypair();
+        let manifest = br#"{"version":"1.4.1","artifacts":[{"os":"linux","arch":"x86_64","kind":"deb","name":"a.deb","size":3,"sha256":"ab"}]}"#;
+        let signature = base64::encode(
+            &sign::sign(manifest, &sk)[..sign::SIGNATUREBYTES],
+            base64::Variant::Original,
+        );
+        let release = verify(manifest, &signature, &pk).unwrap();
+        assert_eq!(release.version, "1.4.1");
+        assert_eq!(release.artifacts[0].kind, "deb");
+        assert!(verify(manifest, &signature, &other_pk).is_err());
+        let mut tampered = manifest.to_vec();
+        tampered[13] = b'5';
+        assert!(verify(&tampered, &signature, &pk).is_err());
+    }
+}